@@ -0,0 +1,63 @@
+/*
+ * C API for the QwertDvert remapping core (src/ffi.rs).
+ *
+ * Build the shared library with `cargo build --release` (target/release/libqwertdvert.so)
+ * and link with -lqwertdvert. A remapper runs the daemon's pipeline, with the stages the
+ * default config sets up, on events you feed it; it does not grab devices or create a uinput
+ * keyboard. Stages that wait on a timer act when you call qd_remapper_tick, which
+ * qd_remapper_deadline_ms says when to do. Handles are not thread-safe; use one per keyboard.
+ *
+ *     qd_remapper *r = qd_remapper_new("dvorak");
+ *     qd_event in = { EV_KEY, KEY_Q, 1 }, out[8];
+ *     size_t n = qd_remapper_feed(r, in);
+ *     qd_remapper_outputs(r, out, 8);   // out[0] = { EV_KEY, KEY_APOSTROPHE, 1 }
+ *     qd_remapper_free(r);
+ */
+
+#ifndef QWERTDVERT_H
+#define QWERTDVERT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* An input event as (type, code, value), matching struct input_event minus the timestamp. */
+typedef struct qd_event {
+    uint16_t type;
+    uint16_t code;
+    int32_t value;
+} qd_event;
+
+typedef struct qd_remapper qd_remapper;
+
+/* Creates a remapper for the named layout (NULL for the default). Returns NULL if the
+ * layout is not registered. */
+qd_remapper *qd_remapper_new(const char *layout);
+
+/* Frees a remapper. NULL is ignored. */
+void qd_remapper_free(qd_remapper *remapper);
+
+/* Feeds one event and returns how many events it produced. Non-key events are passed
+ * through unchanged. Discards the outputs of the previous call. */
+size_t qd_remapper_feed(qd_remapper *remapper, qd_event event);
+
+/* Milliseconds until qd_remapper_tick should next be called, rounded up: 0 if a timer is
+ * already due, -1 if none is pending. */
+int64_t qd_remapper_deadline_ms(const qd_remapper *remapper);
+
+/* Fires the timers that are due and returns how many events they produced. Discards the
+ * outputs of the previous call. */
+size_t qd_remapper_tick(qd_remapper *remapper);
+
+/* Copies up to `capacity` outputs of the last qd_remapper_feed or qd_remapper_tick into
+ * `out`; returns the number copied. */
+size_t qd_remapper_outputs(const qd_remapper *remapper, qd_event *out, size_t capacity);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* QWERTDVERT_H */
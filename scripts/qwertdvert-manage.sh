@@ -124,7 +124,7 @@ install() {
     echo "Building (release)…"
     (cd "$REPO_DIR" && cargo build --release)
   else
-    if [[ ! -x "$REPO_DIR/target/release/qwertdvert" || ! -x "$REPO_DIR/target/release/qwertdvert-tray" ]]; then
+    if [[ ! -x "$REPO_DIR/target/release/qwertdvert" || ! -x "$REPO_DIR/target/release/qwertdvert-device-helper" ]]; then
       echo "ERROR: release binaries not found in target/release." >&2
       echo "Run: cargo build --release" >&2
       exit 1
@@ -133,11 +133,18 @@ install() {
 
   echo "Installing binaries to $INSTALL_DIR…"
   mkdir -p "$INSTALL_DIR"
-  cp -f "$REPO_DIR/target/release/qwertdvert" "$REPO_DIR/target/release/qwertdvert-tray" "$INSTALL_DIR/"
+  cp -f "$REPO_DIR/target/release/qwertdvert" "$REPO_DIR/target/release/qwertdvert-device-helper" "$INSTALL_DIR/"
+  # The tray and the control CLI are subcommands of qwertdvert now; the link keeps `qwertdvertctl` working.
+  rm -f "$INSTALL_DIR/qwertdvert-tray"
+  ln -sf qwertdvert "$INSTALL_DIR/qwertdvertctl"
 
   echo "Installing systemd user units…"
   mkdir -p "$SYSTEMD_USER_DIR"
   cp -a "$REPO_DIR/systemd/user/." "$SYSTEMD_USER_DIR/"
+  # Built without the tray feature, there is no tray to start.
+  if ! "$INSTALL_DIR/qwertdvert" help tray >/dev/null 2>&1; then
+    rm -f "$SYSTEMD_USER_DIR/qwertdvert-tray.service"
+  fi
   systemctl --user daemon-reload
 
   echo "Installing desktop launcher…"
@@ -150,7 +157,7 @@ install() {
 
   if [[ $enable_autostart -eq 1 ]]; then
     echo "Enabling + starting qwertdvert.target…"
-    systemctl --user enable --now qwertdvert.target
+    systemctl --user enable --now --no-block qwertdvert.target
   else
     echo "Not enabling autostart (opt-in)."
     echo "Start it from the app launcher or run: systemctl --user start qwertdvert.target"
@@ -184,6 +191,20 @@ status() {
   echo "systemd --user status:"
   systemctl --user status qwertdvert.target qwertdvert-daemon.service qwertdvert-tray.service --no-pager || true
 
+  local typing_stats="${XDG_RUNTIME_DIR:-/run/user/$(id -u)}/qwertdvert/typing-stats"
+  if [[ -r "$typing_stats" ]]; then
+    echo
+    echo "Typing speed (last minute):"
+    sed -e 's/^kpm=/  keys\/min: /' -e 's/^wpm=/  WPM:      /' "$typing_stats"
+  fi
+
+  local drop_alert="${XDG_RUNTIME_DIR:-/run/user/$(id -u)}/qwertdvert/drop-alert"
+  if [[ -r "$drop_alert" ]]; then
+    echo
+    echo "Drop alert (events dropped faster than the output device keeps up):"
+    sed -e 's/^dropped=/  dropped: /' -e 's/^window_secs=/  window:  /' -e '/window/s/$/ s/' "$drop_alert"
+  fi
+
   if command -v journalctl >/dev/null 2>&1; then
     echo
     echo "Recent daemon logs:"
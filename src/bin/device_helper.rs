@@ -0,0 +1,12 @@
+//! Privileged device helper for the QwertDvert daemon
+//!
+//! Opens and grabs the keyboards and passes them to the daemon over the socket it was given
+//! as stdin, then exits. See [`qwertdvert::helper`]; the daemon starts this itself when run
+//! with `--device-helper`.
+
+fn main() {
+    if let Err(e) = qwertdvert::helper::serve(0) {
+        eprintln!("qwertdvert-device-helper: {}", e);
+        std::process::exit(qwertdvert::error::EXIT_FAILURE);
+    }
+}
@@ -0,0 +1,63 @@
+//! `qwertdvert ctl`, or `qwertdvertctl`: command-line control for a running QwertDvert daemon
+//!
+//! Sends one command over the daemon's control socket (see [`qwertdvert::control`]) and prints
+//! the reply.
+
+use qwertdvert::control::send_command;
+
+fn print_usage(program: &str) {
+    println!("Usage: {program} COMMAND");
+    println!();
+    println!("  status                   Show whether remapping is running or paused, the layout, and the keyboard count");
+    println!("  pause                    Pass keys through unmapped until resumed");
+    println!("  resume                   Remap again after a pause");
+    println!("  toggle                   Pause if remapping, resume if paused");
+    println!("  layout [NAME]            Show the layout in use, or switch every keyboard to NAME");
+    println!("  devices                  List the grabbed keyboards");
+    println!("  sticky [on|off]          Show whether sticky keys are on, or turn them on or off");
+    println!("  explain [on|off]         Show whether the explain trace is on, or turn it on or off");
+    println!("  log-level [LEVEL]        Show which levels are logged, or log LEVEL (e.g. debug) and above from now on");
+    println!("  histogram                Print presses per key as JSON (needs key_histogram = true)");
+    println!("  latency                  Print how long keys take to get through (needs latency_histogram = true)");
+    println!("  dump-map [json|diagram]  Print what every key sends as JSON, or draw it as a keyboard");
+}
+
+/// Sends the command in `args` and prints the reply, exiting with 1 if the daemon can't be
+/// reached or refuses it. `program` is how the command was invoked, for the usage message.
+pub fn run(program: &str, args: &[String]) -> ! {
+    let command = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["-h" | "--help"] => {
+            print_usage(program);
+            std::process::exit(0);
+        }
+        [command @ ("status" | "pause" | "resume" | "toggle" | "layout" | "devices" | "sticky")] => command.to_string(),
+        [command @ ("explain" | "log-level" | "histogram" | "latency")] => command.to_string(),
+        ["layout", name] => format!("layout {name}"),
+        ["log-level", level] => format!("log-level {level}"),
+        ["dump-map"] => "dump-map".to_string(),
+        ["dump-map", format @ ("json" | "diagram")] => format!("dump-map {format}"),
+        [verb @ ("sticky" | "explain"), state @ ("on" | "off")] => format!("{verb} {state}"),
+        _ => {
+            print_usage(program);
+            std::process::exit(2);
+        }
+    };
+
+    match send_command(&command) {
+        Ok(reply) => {
+            let reply = reply.trim_end();
+            if let Some(error) = reply.strip_prefix("error: ") {
+                eprintln!("{program}: {error}");
+                std::process::exit(1);
+            }
+            if !reply.is_empty() {
+                println!("{reply}");
+            }
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("{program}: cannot reach the daemon: {e}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,563 @@
+//! QWERTY to Dvorak keyboard remapper: the daemon, the tray and the control CLI in one binary
+//!
+//! `qwertdvert` (or `qwertdvert daemon`) parses the command line, sets up logging and signal
+//! handling, and runs [`qwertdvert::Daemon`] until systemd stops it. `qwertdvert tray` shows the
+//! tray icon and `qwertdvert ctl` controls a running daemon; linked or copied as
+//! `qwertdvert-tray` or `qwertdvertctl`, the binary runs the tray or the CLI by that name alone.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use qwertdvert::config::{CONFIG_FILE, DEFAULT_DROP_ALERT_THRESHOLD, DEFAULT_DROP_ALERT_WINDOW, DEFAULT_HEARTBEAT_MINUTES};
+use qwertdvert::control::send_command;
+use qwertdvert::enumeration::DeviceId;
+use qwertdvert::error::{ConfigError, EXIT_FAILURE};
+use qwertdvert::import::ImportFormat;
+use qwertdvert::logging::{self, LogFormat};
+use qwertdvert::observe::Observer;
+use qwertdvert::pipeline::KeyEvent;
+use qwertdvert::replay::describe;
+use qwertdvert::shutdown::Shutdown;
+use qwertdvert::watch::spawn_config_watcher;
+use qwertdvert::{Config, Daemon, DaemonError};
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
+
+mod ctl;
+#[cfg(feature = "tray")]
+mod tray;
+
+// Layout name `record-layout` saves under when none is given.
+const RECORDED_LAYOUT: &str = "custom";
+
+/// QWERTY to Dvorak keyboard remapper. Without a command it runs the daemon, as systemd starts
+/// it; the flags override the config file.
+#[derive(Parser)]
+#[command(name = "qwertdvert", version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    daemon: DaemonArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the remapping daemon (the default without a command)
+    Daemon(Box<DaemonArgs>),
+    /// Show the system tray icon (also run as qwertdvert-tray)
+    #[cfg(feature = "tray")]
+    Tray,
+    /// Send a command to the running daemon (also run as qwertdvertctl); 'ctl --help' lists them
+    #[command(disable_help_flag = true)]
+    Ctl {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, value_name = "COMMAND")]
+        args: Vec<String>,
+    },
+    /// Record a layout file by pressing, for each character, the key that should type it
+    RecordLayout {
+        /// Name to save the layout under
+        #[arg(default_value = RECORDED_LAYOUT)]
+        name: String,
+        /// Read settings from PATH [default: ~/.config/qwertdvert/config.toml]
+        #[arg(long, value_name = "PATH")]
+        config: Option<PathBuf>,
+        /// Read only keyboards whose name contains NAME; repeatable [default: all]
+        #[arg(long = "device-name", value_name = "NAME")]
+        device_names: Vec<String>,
+    },
+    /// Translate a keyd or kanata config's key remaps, or an XKB layout, into a layout file
+    ImportLayout {
+        /// The keyd (.conf) or kanata (.kbd) config, or XKB symbols file (or its name, e.g. fr)
+        file: PathBuf,
+        /// Name to save the layout under [default: the file's name, without its extension, and VARIANT]
+        #[arg(long)]
+        name: Option<String>,
+        /// Which tool FILE is for [default: guessed from its extension and contents]
+        #[arg(long, value_enum)]
+        format: Option<ImportFormatArg>,
+        /// The variant of an XKB layout to import, e.g. bepo [default: the file's default]
+        #[arg(long)]
+        variant: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ImportFormatArg {
+    Keyd,
+    Kanata,
+    Xkb,
+}
+
+impl From<ImportFormatArg> for ImportFormat {
+    fn from(format: ImportFormatArg) -> Self {
+        match format {
+            ImportFormatArg::Keyd => ImportFormat::Keyd,
+            ImportFormatArg::Kanata => ImportFormat::Kanata,
+            ImportFormatArg::Xkb => ImportFormat::Xkb,
+        }
+    }
+}
+
+/// The daemon's options, and the modes that run instead of it.
+#[derive(Args, Clone)]
+struct DaemonArgs {
+    /// Read settings from PATH [default: ~/.config/qwertdvert/config.toml]
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    #[arg(long, value_name = "NAME", help = layout_help())]
+    layout: Option<String>,
+
+    /// Grab only keyboards whose name contains NAME; repeatable [default: all]
+    #[arg(long = "device-name", value_name = "NAME", help_heading = "Keyboards")]
+    device_names: Vec<String>,
+    /// Grab only keyboards with this USB ID; repeatable
+    #[arg(long = "include-device", value_name = "VENDOR:PRODUCT", help_heading = "Keyboards")]
+    include_devices: Vec<DeviceId>,
+    /// Never grab keyboards with this USB ID; repeatable
+    #[arg(long = "exclude-device", value_name = "VENDOR:PRODUCT", help_heading = "Keyboards")]
+    exclude_devices: Vec<DeviceId>,
+    /// Get keyboards from this privileged helper instead of opening them
+    #[arg(long, value_name = "PATH", help_heading = "Keyboards")]
+    device_helper: Option<PathBuf>,
+    /// If keyboards are not readable, run this helper through pkexec
+    #[arg(long, value_name = "PATH", help_heading = "Keyboards")]
+    polkit_helper: Option<PathBuf>,
+    /// Capture and inject through the desktop portals instead of evdev/uinput
+    #[cfg(feature = "portal")]
+    #[arg(long, help_heading = "Keyboards")]
+    portal: bool,
+
+    /// Publish a rolling keys-per-minute/WPM figure for the tray
+    #[arg(long)]
+    typing_stats: bool,
+    /// Count presses per key for the 'histogram' control command
+    #[arg(long)]
+    key_histogram: bool,
+    /// Log which rule produced each output key (qwertdvertctl explain toggles it)
+    #[arg(long)]
+    explain: bool,
+    #[arg(long, value_name = "N", help = format!("Log a summary line every N minutes (default {DEFAULT_HEARTBEAT_MINUTES}, 0 disables)"))]
+    heartbeat_minutes: Option<u64>,
+    #[arg(long, value_name = "N", help = format!(
+        "Warn when more than N events are dropped in {} s (default {DEFAULT_DROP_ALERT_THRESHOLD}, 0 disables)",
+        DEFAULT_DROP_ALERT_WINDOW.as_secs()
+    ))]
+    drop_alert_threshold: Option<u64>,
+    /// Record every event read from the keyboards to PATH, passwords included
+    #[arg(long, value_name = "PATH")]
+    record_events: Option<PathBuf>,
+    /// Inject faults at the given rates, e.g. write=0.01,fetch=0.001,stall=0.001,stall-ms=250
+    #[cfg(feature = "fault-injection")]
+    #[arg(long, value_name = "SPEC")]
+    inject_faults: Option<qwertdvert::faults::FaultConfig>,
+
+    /// 'text', 'json' (an object per line) or 'journald' [default: journald under systemd, else text]
+    #[arg(long, value_name = "FORMAT", help_heading = "Logging")]
+    log_format: Option<LogFormatArg>,
+    /// Log LEVEL and above: error, warn, info (default), debug, trace or off
+    #[arg(long, value_name = "LEVEL", help_heading = "Logging")]
+    log_level: Option<LevelFilter>,
+    /// Log more: -v for debug, -vv for trace
+    #[arg(short, long, action = ArgAction::Count, conflicts_with_all = ["log_level", "quiet"], help_heading = "Logging")]
+    verbose: u8,
+    /// Log only warnings and errors
+    #[arg(short, long, conflicts_with = "log_level", help_heading = "Logging")]
+    quiet: bool,
+
+    /// Type every key on a virtual keyboard through the daemon and check the output
+    #[arg(long, group = "mode", help_heading = "Other modes")]
+    self_test: bool,
+    /// Check the config file, flags and layouts, and exit with 0 if they are valid
+    #[arg(long, group = "mode", help_heading = "Other modes")]
+    check_config: bool,
+    /// List the input devices, with their paths and IDs, and which would be grabbed
+    #[arg(long, group = "mode", help_heading = "Other modes")]
+    list_devices: bool,
+    /// Print what each key would be remapped to, without grabbing or remapping
+    #[arg(long, group = "mode", help_heading = "Other modes")]
+    observe: bool,
+    /// Run the events recorded in PATH through the remapping and print the output
+    #[arg(long, value_name = "PATH", group = "mode", help_heading = "Other modes")]
+    replay: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormatArg {
+    Text,
+    Json,
+    Journald,
+}
+
+impl From<LogFormatArg> for LogFormat {
+    fn from(format: LogFormatArg) -> Self {
+        match format {
+            LogFormatArg::Text => LogFormat::Text,
+            LogFormatArg::Json => LogFormat::Json,
+            LogFormatArg::Journald => LogFormat::Journald,
+        }
+    }
+}
+
+fn layout_help() -> String {
+    format!(
+        "Layout to type with (default {}; registered: {})",
+        qwertdvert::layout::DVORAK,
+        qwertdvert::layout::registered().join(", ")
+    )
+}
+
+/// Loads the config file at `path`, or the default one if there is one. Also returns the config
+/// file in use, or where the default one would be, to watch for changes.
+fn load_config(path: Option<&Path>) -> Result<(Config, Option<PathBuf>), ConfigError> {
+    Ok(match path {
+        Some(path) => (Config::load(path)?, Some(path.to_path_buf())),
+        None => match Config::default_path() {
+            Some(path) => (Config::load(&path)?, Some(path)),
+            None => (Config::default(), Config::dir().map(|dir| dir.join(CONFIG_FILE))),
+        },
+    })
+}
+
+impl DaemonArgs {
+    /// Loads the config file and applies the command line on top. Also returns the config
+    /// file in use, or where the default one would be, to watch for changes.
+    fn config(&self) -> Result<(Config, Option<PathBuf>), ConfigError> {
+        let (mut config, config_file) = load_config(self.config.as_deref())?;
+        if let Some(layout) = &self.layout {
+            config.layout = layout.clone();
+        }
+        // Device flags replace the config file's lists rather than adding to them.
+        if !self.device_names.is_empty() {
+            config.devices.names = self.device_names.clone();
+        }
+        if !self.include_devices.is_empty() {
+            config.devices.include = self.include_devices.clone();
+        }
+        if !self.exclude_devices.is_empty() {
+            config.devices.exclude = self.exclude_devices.clone();
+        }
+        config.device_helper = self.device_helper.clone().or(config.device_helper);
+        config.polkit_helper = self.polkit_helper.clone().or(config.polkit_helper);
+        #[cfg(feature = "portal")]
+        {
+            config.portal |= self.portal;
+        }
+        config.typing_stats |= self.typing_stats;
+        config.key_histogram |= self.key_histogram;
+        config.explain |= self.explain;
+        config.heartbeat_minutes = self.heartbeat_minutes.unwrap_or(config.heartbeat_minutes);
+        config.drop_alert_threshold = self.drop_alert_threshold.unwrap_or(config.drop_alert_threshold);
+        config.record_events = self.record_events.clone();
+        #[cfg(feature = "fault-injection")]
+        {
+            config.faults = self.inject_faults.clone().or(config.faults);
+        }
+        config.log_level = match (self.log_level, self.verbose, self.quiet) {
+            (Some(level), _, _) => level,
+            (None, 0, true) => LevelFilter::WARN,
+            (None, 0, false) => config.log_level,
+            (None, 1, _) => LevelFilter::DEBUG,
+            (None, _, _) => LevelFilter::TRACE,
+        };
+        Ok((config, config_file))
+    }
+
+    fn log_format(&self) -> LogFormat {
+        self.log_format.map_or_else(LogFormat::detect, LogFormat::from)
+    }
+}
+
+/// `qwertdvert record-layout [NAME]`: records a layout file interactively and exits. Keys are
+/// read from the keyboards `config` selects.
+fn record_layout(name: &str, config: &Config) -> ! {
+    match qwertdvert::record::record_layout(name, &config.devices) {
+        Ok(path) => {
+            println!("Saved {}.", path.display());
+            println!("Type with it by passing --layout {name} or setting layout = \"{name}\" in {CONFIG_FILE}.");
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(e.exit_code());
+        }
+    }
+}
+
+/// `qwertdvert --self-test`: types on a virtual keyboard through a daemon with `config`, checks
+/// what comes out and exits, with 0 if it was all as expected.
+fn self_test(config: Config) -> ! {
+    println!("Typing every key on a virtual keyboard through the daemon...");
+    match qwertdvert::selftest::self_test(config) {
+        Ok(report) if report.passed() => {
+            println!(
+                "Self-test passed: all {} key events, typed with the {} layout, came out as expected.",
+                report.typed, report.layout
+            );
+            std::process::exit(0);
+        }
+        Ok(report) => {
+            println!("Self-test FAILED with the {} layout:", report.layout);
+            for mismatch in &report.mismatches {
+                println!("  {mismatch}");
+            }
+            std::process::exit(EXIT_FAILURE);
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(e.exit_code());
+        }
+    }
+}
+
+/// `qwertdvert --replay PATH`: runs a recording made with `--record-events` through the
+/// remapping `config` sets up, printing what each key turned into, and exits. Exits with 1 if
+/// keys were left held down that the keyboards had released.
+fn replay(config: Config, path: &Path) -> ! {
+    let result = qwertdvert::replay::load(path)
+        .map_err(DaemonError::from)
+        .and_then(|events| qwertdvert::replay::replay(&config, &events, print_key));
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(e.exit_code());
+        }
+    };
+    println!("Replayed {} key events with the {} layout.", report.keys, report.layout);
+    let key_names = |keys: &std::collections::BTreeSet<u16>| {
+        keys.iter().map(|&code| format!("{:?}", evdev::Key::new(code))).collect::<Vec<_>>().join(", ")
+    };
+    for (device, held) in report.held_out.iter().filter(|(_, held)| !held.is_empty()) {
+        println!("{device} left held on the virtual keyboard: {}", key_names(held));
+    }
+    for (device, held) in report.held_in.iter().filter(|(_, held)| !held.is_empty()) {
+        println!("{device} was still holding when the recording ended: {}", key_names(held));
+    }
+    std::process::exit(if report.stuck() { EXIT_FAILURE } else { 0 });
+}
+
+/// `qwertdvert import-layout FILE`: saves what can be translated from a keyd or kanata config or
+/// XKB layout as the layout file for `name`, lists what could not be, and exits.
+fn import_layout(file: &Path, format: Option<ImportFormat>, variant: Option<&str>, name: &str) -> ! {
+    match qwertdvert::import::import_layout(file, format, variant, name) {
+        Ok((path, skipped)) => {
+            println!("Saved {}.", path.display());
+            if !skipped.is_empty() {
+                println!("Not imported:");
+                for line in &skipped {
+                    println!("  {line}");
+                }
+            }
+            println!("Type with it by passing --layout {name} or setting layout = \"{name}\" in {CONFIG_FILE}.");
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(DaemonError::from(e).exit_code());
+        }
+    }
+}
+
+/// `qwertdvert --check-config`: checks what the config file and flags, already parsed into
+/// `config`, refer to, and exits with 0 if the daemon could start with them.
+fn check_config(config: &Config, config_file: Option<&Path>) -> ! {
+    let file = match config_file {
+        Some(path) if path.exists() => path.display().to_string(),
+        _ => "no config file".to_string(),
+    };
+    let lints = match qwertdvert::daemon::check_config(config) {
+        Ok(lints) => lints,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(DaemonError::from(e).exit_code());
+        }
+    };
+    for lint in &lints {
+        eprintln!("warning: {lint}");
+    }
+    match lints.len() {
+        0 => println!("Config OK ({file}), typing with the {} layout.", config.layout),
+        1 => println!("Config OK ({file}), typing with the {} layout, with 1 warning.", config.layout),
+        count => println!("Config OK ({file}), typing with the {} layout, with {count} warnings.", config.layout),
+    }
+    std::process::exit(0);
+}
+
+/// `qwertdvert --list-devices`: lists every input device, with whether the daemon would grab it
+/// with `config` and, if not, why not, and exits.
+fn list_devices(config: &Config) -> ! {
+    let devices = qwertdvert::enumeration::list_devices(&config.devices);
+    for device in &devices {
+        println!("{}  {}", device.path.display(), device.name);
+        println!("    ID {}, bus {}", device.id, device.bus_type);
+        match device.skipped {
+            None => println!("    grabbed"),
+            Some(reason) => println!("    not grabbed: {reason}"),
+        }
+    }
+    let grabbed = devices.iter().filter(|device| device.skipped.is_none()).count();
+    println!("{} input devices, {grabbed} of them grabbed.", devices.len());
+    if qwertdvert::enumeration::input_access_denied() {
+        println!(
+            "Some devices in /dev/input could not be opened and are not listed; the daemon needs read access to \
+             them (see the udev rule in the README)."
+        );
+    }
+    std::process::exit(0);
+}
+
+/// `qwertdvert --observe`: prints what each key typed on the keyboards `config` selects would be
+/// remapped to, without grabbing them, until interrupted or a keyboard fails.
+fn observe(config: Config) -> ! {
+    let observer = match Observer::new(&config) {
+        Ok(observer) => observer,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(e.exit_code());
+        }
+    };
+    if send_command("status").is_ok() {
+        println!("The daemon is running and has the keyboards grabbed, so nothing typed will be seen; stop it first.");
+    }
+    println!("Observing with the {} layout; nothing is grabbed or remapped. Ctrl+C stops.", observer.layout());
+    for name in observer.keyboards() {
+        println!("  keyboard: {name}");
+    }
+    let e = observer.run(print_key);
+    eprintln!("{e}");
+    std::process::exit(e.exit_code());
+}
+
+/// Prints a key event read, or a stage's timer firing, and what it was remapped to, `at` into
+/// a replay or observation.
+fn print_key(at: Duration, device: &str, input: Option<KeyEvent>, outputs: &[KeyEvent]) {
+    let outputs: Vec<String> = outputs.iter().map(describe).collect();
+    let outputs = if outputs.is_empty() { "nothing".to_string() } else { outputs.join(", ") };
+    let input = input.map_or("(timer)".to_string(), |input| describe(&input));
+    println!("{:>10.3}  {device}: {input} -> {outputs}", at.as_secs_f64());
+}
+
+/// Reads the config file again, applies the command line `args` on top, and applies the result
+/// to the running daemon. If the file is invalid, the daemon carries on with the config it has.
+fn reload_config(daemon: &Daemon, args: &DaemonArgs) {
+    info!("Reloading the config");
+    if let Err(e) = args.config().and_then(|(config, _)| daemon.reload(config)) {
+        warn!("Keeping the current config: {e}");
+    }
+}
+
+/// `qwertdvert tray`: shows the tray icon and exits when it is quit.
+#[cfg(feature = "tray")]
+fn run_tray() -> ! {
+    if let Err(e) = tray::run() {
+        eprintln!("{e}");
+        std::process::exit(EXIT_FAILURE);
+    }
+    std::process::exit(0);
+}
+
+/// The config, or if it is invalid, exits after saying why. Logging depends on the config, so
+/// this is reported before it is set up.
+fn exit_on_config_error<T>(config: Result<T, ConfigError>) -> T {
+    config.unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(DaemonError::from(e).exit_code());
+    })
+}
+
+fn main() {
+    // Linked or copied under the name of one of the binaries this one replaced, act as it.
+    let argv: Vec<String> = std::env::args().collect();
+    match argv.first().and_then(|arg0| Path::new(arg0).file_name()).and_then(|name| name.to_str()) {
+        Some(program @ "qwertdvertctl") => ctl::run(program, &argv[1..]),
+        #[cfg(feature = "tray")]
+        Some("qwertdvert-tray") => run_tray(),
+        _ => {}
+    }
+
+    let cli = match Cli::parse() {
+        Cli { command: None, daemon } => daemon,
+        Cli { command: Some(Command::Daemon(daemon)), .. } => *daemon,
+        #[cfg(feature = "tray")]
+        Cli { command: Some(Command::Tray), .. } => run_tray(),
+        Cli { command: Some(Command::Ctl { args }), .. } => ctl::run("qwertdvert ctl", &args),
+        Cli { command: Some(Command::RecordLayout { name, config, device_names }), .. } => {
+            let mut config = exit_on_config_error(load_config(config.as_deref())).0;
+            if !device_names.is_empty() {
+                config.devices.names = device_names;
+            }
+            record_layout(&name, &config);
+        }
+        Cli { command: Some(Command::ImportLayout { file, name, format, variant }), .. } => {
+            let name = name.unwrap_or_else(|| {
+                let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+                match &variant {
+                    Some(variant) => format!("{stem}-{variant}"),
+                    None => stem.into_owned(),
+                }
+            });
+            import_layout(&file, format.map(ImportFormat::from), variant.as_deref(), &name);
+        }
+    };
+    let (config, config_file) = exit_on_config_error(cli.config());
+    if cli.self_test {
+        // Only what went wrong, between the test's own lines.
+        logging::init(LogFormat::Text, LevelFilter::WARN);
+        self_test(config);
+    }
+    if cli.check_config {
+        logging::init(LogFormat::Text, LevelFilter::WARN);
+        check_config(&config, config_file.as_deref());
+    }
+    if cli.list_devices {
+        list_devices(&config);
+    }
+    if cli.observe {
+        logging::init(LogFormat::Text, LevelFilter::WARN);
+        observe(config);
+    }
+    if let Some(path) = &cli.replay {
+        logging::init(LogFormat::Text, LevelFilter::WARN);
+        replay(config, path);
+    }
+    logging::init(cli.log_format(), config.log_level);
+
+    let mut daemon = Daemon::new(config);
+    // systemd manages lifecycle; exit cleanly on SIGTERM/SIGINT. SIGUSR1 logs a state dump,
+    // SIGUSR2 pauses or resumes remapping and SIGHUP reloads the config. This comes before any
+    // thread is started, so none of them is killed by a signal meant for the daemon.
+    let args = Arc::new(cli);
+    let signal_args = args.clone();
+    if let Err(e) = daemon.handle_signals(move |daemon| reload_config(daemon, &signal_args)) {
+        warn!("Failed to register signal handlers: {e}");
+    }
+    let daemon = Arc::new(daemon);
+
+    // Saving the config file reloads it, as SIGHUP does.
+    let watcher = config_file.and_then(|path| {
+        let daemon_watcher = daemon.clone();
+        Shutdown::new()
+            .and_then(|stop| {
+                let stop = Arc::new(stop);
+                spawn_config_watcher(path.clone(), stop.clone(), move || reload_config(&daemon_watcher, &args))
+                    .map(|handle| (stop, handle))
+            })
+            .inspect_err(|e| info!("Not watching {} for changes: {e}", path.display()))
+            .ok()
+    });
+
+    let result = daemon.run();
+
+    if let Some((stop, handle)) = watcher {
+        stop.request();
+        let _ = handle.join();
+    }
+
+    // The error has already been logged; a failure exit code lets systemd restart the daemon.
+    if let Err(e) = result {
+        std::process::exit(e.exit_code());
+    }
+}
@@ -0,0 +1,212 @@
+//! `qwertdvert tray`: system tray UI for QwertDvert using KDE StatusNotifierItem protocol.
+//!
+//! Provides a "Pause remapping" toggle, sent to the daemon over its control socket, and a
+//! "Quit" menu that stops the daemon via systemd. The icon and tooltip follow the daemon: what
+//! it reports over the control socket while it is up, and its systemd unit's state otherwise.
+
+use ksni::menu::{CheckmarkItem, MenuItem, StandardItem};
+use ksni::{Status, ToolTip, Tray, TrayService};
+use qwertdvert::control::send_command;
+use qwertdvert::logging::{self, LogFormat};
+use qwertdvert::stats::{read_drop_alert, read_typing_stats};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use signal_hook::consts::signal::*;
+use signal_hook::flag;
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+
+// UI configuration
+const KEYBOARD_ICON_NAME: &str = "input-keyboard";
+const APP_TITLE: &str = "QwertDvert";
+const TRAY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+// The systemd user unit that runs the daemon.
+const DAEMON_UNIT: &str = "qwertdvert-daemon.service";
+
+/// What the tray knows about the daemon.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum DaemonState {
+    Running,
+    Paused,
+    /// The unit is up (or restarting) but the daemon isn't answering yet, e.g. while it waits
+    /// for keyboards.
+    #[default]
+    Starting,
+    Stopped,
+    Failed,
+}
+
+impl DaemonState {
+    fn from_reply(reply: &str) -> Option<Self> {
+        reply.lines().find_map(|line| match line.strip_prefix("state=").unwrap_or(line) {
+            "paused" => Some(DaemonState::Paused),
+            "running" => Some(DaemonState::Running),
+            _ => None,
+        })
+    }
+}
+
+/// Asks the daemon for its state, falling back to the systemd unit when it doesn't answer.
+fn read_daemon_state() -> DaemonState {
+    if let Some(state) = send_command("status").ok().and_then(|reply| DaemonState::from_reply(&reply)) {
+        return state;
+    }
+    let unit_state = std::process::Command::new("systemctl")
+        .args(["--user", "is-active", DAEMON_UNIT])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    match unit_state.as_deref() {
+        Ok("active" | "activating" | "reloading") => DaemonState::Starting,
+        Ok("failed") => DaemonState::Failed,
+        _ => DaemonState::Stopped,
+    }
+}
+
+/// Pauses or resumes remapping and returns the daemon's new state.
+fn set_paused(paused: bool) -> DaemonState {
+    send_command(if paused { "pause" } else { "resume" })
+        .ok()
+        .and_then(|reply| DaemonState::from_reply(&reply))
+        .unwrap_or_else(read_daemon_state)
+}
+
+fn stop_qwertdvert_via_systemd() {
+    // Preferred integration: systemd manages singleton, startup, and shutdown.
+    // If systemd isn't available (or the user isn't running the services via systemd),
+    // we still exit cleanly and avoid any custom process-control hacks.
+    let _ = std::process::Command::new("systemctl")
+        .arg("--user")
+        .arg("stop")
+        .arg("qwertdvert.target")
+        .status();
+}
+
+fn stop_and_exit() -> ! {
+    stop_qwertdvert_via_systemd();
+    std::process::exit(0);
+}
+
+/// Minimal tray implementation. All state is managed by systemd services.
+#[derive(Default)]
+struct MyTray {
+    /// Latest (keys per minute, WPM) published by the daemon, if enabled.
+    typing_stats: Option<(u32, u32)>,
+    /// Latest drop alert (events dropped, window in seconds), if one has fired.
+    drop_alert: Option<(u64, u64)>,
+    daemon: DaemonState,
+}
+
+impl Tray for MyTray {
+    fn icon_name(&self) -> String {
+        KEYBOARD_ICON_NAME.to_string()
+    }
+
+    fn title(&self) -> String {
+        APP_TITLE.to_string()
+    }
+
+    fn status(&self) -> Status {
+        match self.daemon {
+            DaemonState::Failed => Status::NeedsAttention,
+            DaemonState::Stopped => Status::Passive,
+            _ if self.drop_alert.is_some() => Status::NeedsAttention,
+            _ => Status::Active,
+        }
+    }
+
+    fn tool_tip(&self) -> ToolTip {
+        let pid = std::process::id();
+        let icon = KEYBOARD_ICON_NAME.to_string();
+        let mut description = match self.daemon {
+            DaemonState::Running => format!("QWERTY to Dvorak remapper running (PID {})", pid),
+            DaemonState::Paused => format!("QWERTY to Dvorak remapper paused (PID {})", pid),
+            DaemonState::Starting => "QWERTY to Dvorak remapper starting; waiting for keyboards".to_string(),
+            DaemonState::Stopped => "QWERTY to Dvorak remapper stopped".to_string(),
+            DaemonState::Failed => {
+                format!("QWERTY to Dvorak remapper failed; see journalctl --user -u {}", DAEMON_UNIT)
+            }
+        };
+        if let Some((kpm, wpm)) = self.typing_stats {
+            description.push_str(&format!("\nTyping speed: {} WPM ({} keys/min)", wpm, kpm));
+        }
+        if let Some((dropped, window_secs)) = self.drop_alert {
+            description.push_str(&format!(
+                "\nWarning: {} events dropped in {} s; see the daemon log",
+                dropped, window_secs
+            ));
+        }
+        ToolTip {
+            icon_name: icon,
+            icon_pixmap: Vec::new(),
+            title: APP_TITLE.to_string(),
+            description,
+        }
+    }
+
+    fn activate(&mut self, _x: i32, _y: i32) {
+        // Left-click handler (currently just logs the click).
+        info!("Tray icon clicked");
+    }
+
+    fn menu(&self) -> Vec<MenuItem<Self>> {
+        vec![
+            CheckmarkItem {
+                label: "Pause remapping".to_string(),
+                enabled: matches!(self.daemon, DaemonState::Running | DaemonState::Paused),
+                checked: self.daemon == DaemonState::Paused,
+                activate: Box::new(|tray: &mut MyTray| {
+                    tray.daemon = set_paused(tray.daemon != DaemonState::Paused);
+                }),
+                ..Default::default()
+            }
+            .into(),
+            StandardItem {
+                label: "Quit".to_string(),
+                activate: Box::new(|_tray: &mut MyTray| {
+                    stop_and_exit();
+                }),
+                ..Default::default()
+            }
+            .into(),
+        ]
+    }
+}
+
+/// Shows the tray icon until the tray is quit or signalled to stop.
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    logging::init(LogFormat::detect(), LevelFilter::INFO);
+
+    // Register signal handlers for clean shutdown.
+    let shutdown_flag = Arc::new(AtomicBool::new(false));
+    flag::register(SIGTERM, Arc::clone(&shutdown_flag))?;
+    flag::register(SIGINT, Arc::clone(&shutdown_flag))?;
+
+    let tray = MyTray::default();
+    let service = TrayService::new(tray);
+    let handle = service.handle();
+    service.spawn();
+
+    // Keep the tray process running in foreground for KDE integration.
+    loop {
+        std::thread::sleep(TRAY_POLL_INTERVAL);
+
+        // Check if we received a shutdown signal.
+        if shutdown_flag.load(Ordering::Relaxed) {
+            info!("Shutting down due to signal...");
+            stop_and_exit();
+        }
+
+        // Refresh the tooltip and menu only when something changed, e.g. the daemon published
+        // a new figure, was paused from the command line or its pause chord, or stopped.
+        let typing_stats = read_typing_stats();
+        let drop_alert = read_drop_alert();
+        let daemon = read_daemon_state();
+        if handle.update(|tray| (tray.typing_stats, tray.drop_alert, tray.daemon)) != (typing_stats, drop_alert, daemon) {
+            handle.update(|tray| {
+                tray.typing_stats = typing_stats;
+                tray.drop_alert = drop_alert;
+                tray.daemon = daemon;
+            });
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,319 @@
+//! The daemon's D-Bus interface on the session bus, for controlling it without a restart.
+//!
+//! ```text
+//! busctl --user call io.github.imathew.QwertDvert /io/github/imathew/QwertDvert \
+//!     io.github.imathew.QwertDvert SetLayout s colemak
+//! ```
+//!
+//! Methods of `io.github.imathew.QwertDvert`:
+//!
+//! - `SetLayout(s name)` - switch every keyboard to another layout; fails with
+//!   `io.github.imathew.QwertDvert.Error.UnknownLayout` if there is no such layout
+//! - `GetLayout() -> s` - the layout in use
+//! - `Pause()`, `Resume()` - pass keys through unmapped, and remap again
+//!
+//! Properties, through `org.freedesktop.DBus.Properties`, with `PropertiesChanged` emitted
+//! whenever one changes, however it was changed:
+//!
+//! - `Layout` (s, writable) - the layout in use
+//! - `Paused` (b, writable) - whether remapping is paused
+//! - `Devices` (as) - names of the grabbed keyboards
+//! - `DroppedEvents` (t) - events dropped since the daemon started because the writer fell behind
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+use dbus::blocking::Connection;
+use dbus::channel::{BusType, Channel, MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::strings::ErrorName;
+use dbus::Message;
+use tracing::{info, warn};
+
+use crate::daemon::set_paused;
+use crate::layout::ActiveLayout;
+use crate::shutdown::Shutdown;
+use crate::stats::Counters;
+use crate::status::StateWatch;
+
+// Addressing
+pub const BUS_NAME: &str = "io.github.imathew.QwertDvert";
+pub const OBJECT_PATH: &str = "/io/github/imathew/QwertDvert";
+pub const INTERFACE: &str = "io.github.imathew.QwertDvert";
+
+// Errors returned to callers.
+const UNKNOWN_LAYOUT: &str = "io.github.imathew.QwertDvert.Error.UnknownLayout";
+const UNKNOWN_METHOD: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+const UNKNOWN_PROPERTY: &str = "org.freedesktop.DBus.Error.UnknownProperty";
+const READ_ONLY: &str = "org.freedesktop.DBus.Error.PropertyReadOnly";
+const INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="io.github.imathew.QwertDvert">
+    <method name="SetLayout">
+      <arg name="name" type="s" direction="in"/>
+    </method>
+    <method name="GetLayout">
+      <arg name="name" type="s" direction="out"/>
+    </method>
+    <method name="Pause"/>
+    <method name="Resume"/>
+    <property name="Layout" type="s" access="readwrite"/>
+    <property name="Paused" type="b" access="readwrite"/>
+    <property name="Devices" type="as" access="read"/>
+    <property name="DroppedEvents" type="t" access="read"/>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <method name="Set">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="in"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface_name" type="s"/>
+      <arg name="changed_properties" type="a{sv}"/>
+      <arg name="invalidated_properties" type="as"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+/// State the D-Bus methods act on.
+pub struct BusState {
+    pub layout: Arc<ActiveLayout>,
+    pub paused: Arc<AtomicBool>,
+    pub counters: Arc<Counters>,
+}
+
+/// The property values, compared with the last ones sent to find out what changed.
+#[derive(Clone, PartialEq)]
+struct Properties {
+    layout: String,
+    paused: bool,
+    devices: Vec<String>,
+    dropped_events: u64,
+}
+
+impl Properties {
+    /// Those of `self` that differ from `previous` (all of them without one).
+    fn changed_since(&self, previous: Option<&Properties>) -> PropMap {
+        fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+            Variant(Box::new(value))
+        }
+        let mut changed = HashMap::new();
+        if previous.is_none_or(|previous| previous.layout != self.layout) {
+            changed.insert("Layout".to_string(), variant(self.layout.clone()));
+        }
+        if previous.is_none_or(|previous| previous.paused != self.paused) {
+            changed.insert("Paused".to_string(), variant(self.paused));
+        }
+        if previous.is_none_or(|previous| previous.devices != self.devices) {
+            changed.insert("Devices".to_string(), variant(self.devices.clone()));
+        }
+        if previous.is_none_or(|previous| previous.dropped_events != self.dropped_events) {
+            changed.insert("DroppedEvents".to_string(), variant(self.dropped_events));
+        }
+        changed
+    }
+}
+
+impl BusState {
+    fn properties(&self) -> Properties {
+        Properties {
+            layout: self.layout.name(),
+            paused: self.paused.load(Ordering::Relaxed),
+            devices: self.counters.grabbed_names.lock().unwrap().clone(),
+            dropped_events: self.counters.dropped_total.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Answers one method call.
+    fn handle(&self, call: &Message) -> Message {
+        let error = |name: &'static str, text: String| {
+            let text = CString::new(text).unwrap_or_default();
+            call.error(&ErrorName::from(name), &text)
+        };
+        let interface = call.interface();
+        let member = call.member();
+        match (interface.as_deref(), member.as_deref()) {
+            (Some(INTERFACE), Some("SetLayout")) => match call.read1::<&str>() {
+                Ok(name) => match self.layout.select(name) {
+                    Ok(()) => {
+                        info!("Switched to the {} layout over D-Bus", name);
+                        call.method_return()
+                    }
+                    Err(e) => error(UNKNOWN_LAYOUT, e.to_string()),
+                },
+                Err(e) => error(INVALID_ARGS, e.to_string()),
+            },
+            (Some(INTERFACE), Some("GetLayout")) => call.method_return().append1(self.layout.name()),
+            (Some(INTERFACE), Some("Pause")) => {
+                set_paused(&self.paused, true);
+                call.method_return()
+            }
+            (Some(INTERFACE), Some("Resume")) => {
+                set_paused(&self.paused, false);
+                call.method_return()
+            }
+            (Some(PROPERTIES), Some("Get")) => match call.read2::<&str, &str>() {
+                Ok((INTERFACE, name)) => match self.properties().changed_since(None).remove(name) {
+                    Some(value) => call.method_return().append1(value),
+                    None => error(UNKNOWN_PROPERTY, format!("No property {name}")),
+                },
+                Ok((interface, _)) => error(INVALID_ARGS, format!("No interface {interface}")),
+                Err(e) => error(INVALID_ARGS, e.to_string()),
+            },
+            (Some(PROPERTIES), Some("GetAll")) => match call.read1::<&str>() {
+                Ok(INTERFACE) => call.method_return().append1(self.properties().changed_since(None)),
+                Ok(interface) => error(INVALID_ARGS, format!("No interface {interface}")),
+                Err(e) => error(INVALID_ARGS, e.to_string()),
+            },
+            (Some(PROPERTIES), Some("Set")) => match call.read2::<&str, &str>() {
+                Ok((INTERFACE, "Layout")) => match call.read3::<&str, &str, Variant<&str>>() {
+                    Ok((_, _, Variant(name))) => match self.layout.select(name) {
+                        Ok(()) => {
+                            info!("Switched to the {} layout over D-Bus", name);
+                            call.method_return()
+                        }
+                        Err(e) => error(UNKNOWN_LAYOUT, e.to_string()),
+                    },
+                    Err(e) => error(INVALID_ARGS, e.to_string()),
+                },
+                Ok((INTERFACE, "Paused")) => match call.read3::<&str, &str, Variant<bool>>() {
+                    Ok((_, _, Variant(paused))) => {
+                        set_paused(&self.paused, paused);
+                        call.method_return()
+                    }
+                    Err(e) => error(INVALID_ARGS, e.to_string()),
+                },
+                Ok((INTERFACE, name @ ("Devices" | "DroppedEvents"))) => {
+                    error(READ_ONLY, format!("{name} is read-only"))
+                }
+                Ok((INTERFACE, name)) => error(UNKNOWN_PROPERTY, format!("No property {name}")),
+                Ok((interface, _)) => error(INVALID_ARGS, format!("No interface {interface}")),
+                Err(e) => error(INVALID_ARGS, e.to_string()),
+            },
+            (Some("org.freedesktop.DBus.Introspectable"), Some("Introspect")) => {
+                call.method_return().append1(INTROSPECTION)
+            }
+            _ => error(UNKNOWN_METHOD, format!("No method {:?} on {:?}", member, interface)),
+        }
+    }
+}
+
+/// A connection to `bus` that can say which fd it reads, so the thread serving it can block
+/// until there is something to read.
+pub(crate) fn connect(bus: BusType) -> Result<Connection, dbus::Error> {
+    let mut channel = Channel::get_private(bus)?;
+    channel.set_watch_enabled(true);
+    Ok(Connection::from(channel))
+}
+
+/// Handles the messages `connection` has received and sends what is queued, then waits until
+/// more arrive, one of `others` is readable, or shutdown is requested. Returns false for
+/// shutdown.
+pub(crate) fn dispatch_and_wait(
+    connection: &Connection,
+    others: &[BorrowedFd<'_>],
+    shutdown: &Shutdown,
+) -> Result<bool, dbus::Error> {
+    while connection.process(Duration::ZERO)? {}
+    connection.channel().flush();
+    // SAFETY: The fd is the connection's, which is open until the connection is dropped.
+    let fd = unsafe { BorrowedFd::borrow_raw(connection.channel().watch().fd) };
+    let mut fds = vec![fd];
+    fds.extend_from_slice(others);
+    Ok(shutdown.wait_readable(&fds, None))
+}
+
+/// Serves the D-Bus interface until shutdown. If there is no session bus, or another daemon
+/// already owns the name, it logs a warning and the daemon runs without it.
+pub fn spawn_bus_service(state: BusState, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let connection = match connect(BusType::Session) {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("No D-Bus session bus; the D-Bus interface is disabled: {}", e);
+                return;
+            }
+        };
+        match connection.request_name(BUS_NAME, false, true, true) {
+            Ok(RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner) => {}
+            Ok(_) => {
+                warn!("{} is already owned by another process; the D-Bus interface is disabled", BUS_NAME);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to claim {} on the session bus: {}", BUS_NAME, e);
+                return;
+            }
+        }
+        let state = Arc::new(state);
+        let handler_state = state.clone();
+        connection.start_receive(
+            MatchRule::new_method_call().with_path(OBJECT_PATH),
+            Box::new(move |call, connection| {
+                let reply = handler_state.handle(&call);
+                if !call.get_no_reply() {
+                    let _ = connection.send(reply);
+                }
+                true
+            }),
+        );
+        info!("Serving {} on the session bus", BUS_NAME);
+        // Properties change from many places (the control socket, the pause chord, keyboards
+        // coming and going), which all wake `changes`.
+        let changes = match StateWatch::new() {
+            Ok(changes) => changes,
+            Err(e) => {
+                warn!("Failed to watch the daemon's state; the D-Bus interface is disabled: {}", e);
+                return;
+            }
+        };
+        let mut last = state.properties();
+        loop {
+            changes.clear();
+            let current = state.properties();
+            if current != last {
+                let changed = current.changed_since(Some(&last));
+                let signal = Message::new_signal(OBJECT_PATH, PROPERTIES, "PropertiesChanged")
+                    .expect("signal names are valid")
+                    .append3(INTERFACE, changed, Vec::<String>::new());
+                let _ = connection.send(signal);
+                last = current;
+            }
+            match dispatch_and_wait(&connection, &[changes.as_fd()], &shutdown) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    warn!("D-Bus connection failed; the D-Bus interface is disabled: {}", e);
+                    return;
+                }
+            }
+        }
+    })
+}
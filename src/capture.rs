@@ -0,0 +1,494 @@
+//! Reading grabbed keyboards and queueing remapped events for the writer.
+//!
+//! Every keyboard is read on the event loop's thread, a frame (everything up to and including
+//! a SYN_REPORT) at a time, so when two keyboards are typed on at once their frames reach the
+//! virtual keyboard whole and in the order they were read.
+
+use std::collections::BTreeSet;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use evdev::{EventType, InputEvent, Key, MiscType};
+use tracing::{debug, info, warn};
+
+use crate::chord::ChordDetector;
+use crate::daemon::{keyboard_layout, set_paused, LiveConfig};
+use crate::enumeration::other_virtual_keyboards;
+use crate::error::DeviceError;
+use crate::feedback::Feedback;
+use crate::layout::ActiveLayout;
+use crate::output::{OutputEvent, QueuedEvent, QueuedFrame};
+use crate::pipeline::KeyEvent;
+use crate::remap::{explain_key_event, is_modifier, ModifierState, RemapRule};
+use crate::remapper::Remapper;
+use crate::replay::EventRecorder;
+use crate::ring::{Producer, Producers};
+use crate::scancode::ScanCodes;
+use crate::source::BoxedKeyboard;
+use crate::stats::{Counters, GrabGuard, KeyHistogram, TypingStats};
+
+// Grab retries
+// A keyboard another program has grabbed is retried for GRAB_RETRY_TIME, starting after
+// GRAB_RETRY_FIRST_DELAY and doubling the delay up to GRAB_RETRY_MAX_DELAY.
+const GRAB_RETRY_TIME: Duration = Duration::from_secs(10);
+const GRAB_RETRY_FIRST_DELAY: Duration = Duration::from_millis(100);
+const GRAB_RETRY_MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Everything the keyboards share with the rest of the daemon.
+pub struct Capture {
+    /// Gives each keyboard a ring buffer of its own to the writer.
+    pub tx: Producers<QueuedFrame>,
+    /// While set, key events skip the pipeline and go out unmapped.
+    pub paused: Arc<AtomicBool>,
+    /// While cleared, another login session is active, and key events go out unmapped as when
+    /// paused.
+    pub session_active: Arc<AtomicBool>,
+    /// While set, a game from `game_apps` is running, and key events go out unmapped as when
+    /// paused.
+    pub game_running: Arc<AtomicBool>,
+    pub counters: Arc<Counters>,
+    pub explain: Arc<AtomicBool>,
+    pub typing_stats: Option<Arc<Mutex<TypingStats>>>,
+    pub key_histogram: Option<Arc<Mutex<KeyHistogram>>>,
+    /// Reloaded configs, which each keyboard's remapper is rebuilt from.
+    pub config: Arc<LiveConfig>,
+    /// The layout switched at runtime, which a pipeline types with unless the config gives its
+    /// keyboard its own.
+    pub layout: Arc<ActiveLayout>,
+    /// Shortcut modifiers held on any keyboard, shared with every pipeline.
+    pub modifiers: Arc<ModifierState>,
+    /// LED and repeat settings sent to the virtual keyboard, copied to every keyboard.
+    pub feedback: Feedback,
+    /// Where every event read is recorded, with `--record-events`.
+    pub(crate) recorder: Option<EventRecorder>,
+    #[cfg(feature = "otel")]
+    pub telemetry: Option<Arc<crate::telemetry::Telemetry>>,
+    /// How many keyboards have been captured, so each gets its own fault sequence.
+    #[cfg(feature = "fault-injection")]
+    pub opened: u64,
+}
+
+impl Capture {
+    /// Sets up capturing `device`, with its own remapper so stage state isn't shared between
+    /// keyboards. It is not grabbed until [`Keyboard::grab`] is called.
+    pub fn keyboard(&mut self, device: BoxedKeyboard) -> Keyboard {
+        let name = device.name();
+        let (config_generation, config) = self.config.current_generation();
+        let layout = keyboard_layout(&config, &self.layout, &name, device.device_id());
+        #[cfg(feature = "fault-injection")]
+        {
+            self.opened += 1;
+        }
+        Keyboard {
+            remapper: Remapper::for_keyboard(&config, layout, self.modifiers.clone()),
+            pause_chord: config.pause_chord.clone().map(ChordDetector::new),
+            config_generation,
+            unmapped_keys: BTreeSet::new(),
+            scan_codes: ScanCodes::default(),
+            feedback_generation: None,
+            events: Vec::new(),
+            frame: Vec::new(),
+            grab: Grab::Pending { since: Instant::now(), delay: GRAB_RETRY_FIRST_DELAY, retry_at: None },
+            events_read: self.counters.events_read_from(&name),
+            tx: self.tx.add(),
+            #[cfg(feature = "fault-injection")]
+            fetch_faults: config
+                .faults
+                .clone()
+                .map(|config| crate::faults::FaultInjector::new(config, self.opened)),
+            device,
+            name,
+        }
+    }
+}
+
+/// Whether a keyboard is grabbed yet.
+enum Grab {
+    /// Not yet, or another program has it and it is retried at `retry_at`.
+    Pending { since: Instant, delay: Duration, retry_at: Option<Instant> },
+    /// Grabbed; the guard counts the keyboard as grabbed until it is dropped.
+    Held { _guard: GrabGuard },
+}
+
+/// One keyboard being captured, and the state of its remapping.
+pub struct Keyboard {
+    device: BoxedKeyboard,
+    name: String,
+    /// The keyboard's pipeline, and the keys it holds down on the virtual keyboard.
+    remapper: Remapper,
+    /// Watches this keyboard for the pause chord, if one is configured.
+    pause_chord: Option<ChordDetector>,
+    /// The config generation `remapper` and `pause_chord` were built from.
+    config_generation: u64,
+    /// Keys pressed while paused. They bypass the pipeline until released, and keys pressed
+    /// before a pause still go through it, so a press and its release always match.
+    unmapped_keys: BTreeSet<u16>,
+    /// Scancodes read from this keyboard, to send with the keys it outputs.
+    scan_codes: ScanCodes,
+    /// The feedback last written to this keyboard, by generation.
+    feedback_generation: Option<u64>,
+    events: Vec<InputEvent>,
+    /// Events of the current frame, queued together once it is complete.
+    frame: Vec<QueuedEvent>,
+    grab: Grab,
+    /// Events read from this keyboard since start, shared with [`Counters::events_read`].
+    events_read: Arc<AtomicU64>,
+    /// The keyboard's ring buffer to the writer.
+    tx: Producer<QueuedFrame>,
+    #[cfg(feature = "fault-injection")]
+    fetch_faults: Option<crate::faults::FaultInjector>,
+}
+
+impl AsRawFd for Keyboard {
+    fn as_raw_fd(&self) -> RawFd {
+        self.device.as_raw_fd()
+    }
+}
+
+impl Keyboard {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_grabbed(&self) -> bool {
+        matches!(self.grab, Grab::Held { .. })
+    }
+
+    /// One line on the keyboard's state, for the state dump: whether it is grabbed, the keys
+    /// it holds down on the virtual keyboard, the frames in its ring buffer, and anything its
+    /// pipeline is waiting on.
+    pub fn describe(&self) -> String {
+        let keys = |codes: &BTreeSet<u16>| {
+            let names: Vec<String> = codes.iter().map(|&code| format!("{:?}", Key::new(code))).collect();
+            if names.is_empty() { "none".to_string() } else { names.join(" ") }
+        };
+        let held_keys = self.remapper.pressed().map(|key| key.code()).collect();
+        let mut line = format!(
+            "{}: {}, holding {}, {} frames queued",
+            self.name,
+            if self.is_grabbed() { "grabbed" } else { "not grabbed yet" },
+            keys(&held_keys),
+            self.tx.queued()
+        );
+        if !self.unmapped_keys.is_empty() {
+            line.push_str(&format!(", pressed while paused {}", keys(&self.unmapped_keys)));
+        }
+        if let Some(deadline) = self.remapper.deadline() {
+            let due = deadline.saturating_duration_since(Instant::now());
+            line.push_str(&format!(", pipeline timer due in {:?}", due));
+        }
+        line
+    }
+
+    /// When [`grab`](Keyboard::grab) or [`service`](Keyboard::service) next needs calling
+    /// without the keyboard having become readable: a grab retry or a pipeline timer.
+    pub fn deadline(&self) -> Option<Instant> {
+        match &self.grab {
+            Grab::Pending { retry_at, .. } => *retry_at,
+            Grab::Held { .. } => self.remapper.deadline(),
+        }
+    }
+
+    /// Tries to grab the keyboard, returning whether it is grabbed. While another program has
+    /// it, perhaps only for a moment, e.g. one just exiting, it returns false and should be
+    /// tried again at [`deadline`](Keyboard::deadline), until `GRAB_RETRY_TIME` has passed.
+    pub fn grab(&mut self, counters: &Arc<Counters>) -> Result<bool, DeviceError> {
+        let Grab::Pending { since, delay, retry_at } = &mut self.grab else {
+            return Ok(true);
+        };
+        match self.device.grab() {
+            Ok(()) => {}
+            Err(source) if source.raw_os_error() == Some(nix::libc::EBUSY) => {
+                if since.elapsed() >= GRAB_RETRY_TIME {
+                    let virtual_keyboards = other_virtual_keyboards();
+                    return Err(DeviceError::Busy { device: self.name.clone(), virtual_keyboards });
+                }
+                if *delay == GRAB_RETRY_FIRST_DELAY {
+                    info!("{} is grabbed by another program; retrying for {:?}", self.name, GRAB_RETRY_TIME);
+                }
+                *retry_at = Some(Instant::now() + *delay);
+                *delay = (*delay * 2).min(GRAB_RETRY_MAX_DELAY);
+                return Ok(false);
+            }
+            Err(source) => return Err(DeviceError::Grab { device: self.name.clone(), source }),
+        }
+        info!(device = self.name.as_str(), "Grabbed keyboard device: {}", self.name);
+
+        // Reads return WouldBlock once everything ready has been read, so the event loop can
+        // go back to waiting.
+        let raw_fd = self.device.as_raw_fd();
+        if let Err(e) = (|| -> Result<(), nix::Error> {
+            use nix::fcntl::{fcntl, FcntlArg, OFlag};
+            let current = OFlag::from_bits_truncate(fcntl(raw_fd, FcntlArg::F_GETFL)?);
+            let new_flags = current | OFlag::O_NONBLOCK;
+            fcntl(raw_fd, FcntlArg::F_SETFL(new_flags))?;
+            Ok(())
+        })() {
+            warn!("Failed to set O_NONBLOCK for {}: {}", self.name, e);
+        }
+        self.grab = Grab::Held { _guard: GrabGuard::new(counters.clone(), &self.name) };
+        Ok(true)
+    }
+
+    /// Writes the LED and repeat settings to the keyboard if they changed since last time.
+    pub fn sync_feedback(&mut self, feedback: &Feedback) {
+        let generation = feedback.generation();
+        if self.feedback_generation != Some(generation) {
+            if let Err(e) = self.device.send_events(&feedback.events()) {
+                debug!("Failed to set the LEDs and key repeat of {}: {}", self.name, e);
+            }
+            self.feedback_generation = Some(generation);
+        }
+    }
+
+    /// Catches up on everything due: switching to a reloaded config, pipeline timers, and the
+    /// events ready to read. Called when the keyboard is readable or its deadline has passed.
+    pub fn service(&mut self, capture: &Capture) -> Result<(), DeviceError> {
+        let Keyboard {
+            device,
+            name: device_name,
+            remapper,
+            pause_chord,
+            config_generation,
+            unmapped_keys,
+            scan_codes,
+            events,
+            frame,
+            events_read,
+            tx,
+            #[cfg(feature = "fault-injection")]
+            fetch_faults,
+            ..
+        } = self;
+        let device_name = device_name.as_str();
+
+        // Adds one pipeline output to the frame; `input` is the key that produced it.
+        let emit = |output: &KeyEvent,
+                    input: Key,
+                    frame: &mut Vec<QueuedEvent>,
+                    scan_codes: &ScanCodes,
+                    #[cfg(feature = "otel")] trace: Option<crate::telemetry::EventTrace>| {
+            let rule = output.rule.unwrap_or(RemapRule::Unmapped);
+            if capture.explain.load(Ordering::Relaxed) {
+                explain_key_event(device_name, input, output);
+            }
+            let queued = QueuedEvent {
+                event: OutputEvent::Key { code: output.code, value: output.value },
+                #[cfg(feature = "otel")]
+                trace,
+            };
+
+            // Count typed keys (not shortcuts or modifiers) for the optional WPM figure.
+            if output.value == 1
+                && rule != RemapRule::ModifierPassthrough
+                && !is_modifier(Key::new(output.code))
+                && let Some(stats) = &capture.typing_stats
+            {
+                stats.lock().unwrap().record_press(Instant::now());
+            }
+            if output.value == 1
+                && let Some(histogram) = &capture.key_histogram
+            {
+                histogram.lock().unwrap().record_press(output.code);
+            }
+            if output.value != 2
+                && let Some(scan) = scan_codes.scan_code(output.code)
+            {
+                frame.push(OutputEvent::new(EventType::MISC, MiscType::MSC_SCAN.0, scan).into());
+            }
+            frame.push(queued);
+        };
+
+        // Switch to a reloaded config once nothing is held or pending, so every press the
+        // old pipeline produced also gets its release from it.
+        if remapper.pressed().next().is_none()
+            && remapper.deadline().is_none()
+            && let Some((generation, config)) = capture.config.newer_than(*config_generation)
+        {
+            let layout = keyboard_layout(&config, &capture.layout, device_name, device.device_id());
+            *remapper = Remapper::for_keyboard(&config, layout, capture.modifiers.clone());
+            *pause_chord = config.pause_chord.clone().map(ChordDetector::new);
+            *config_generation = generation;
+            debug!("{} now uses the reloaded config", device_name);
+        }
+
+        // Stages with timers (tap-hold) may have events due without any new input.
+        let now = Instant::now();
+        if remapper.deadline().is_some_and(|deadline| deadline <= now) {
+            let outputs = remapper.tick_keys(now);
+            for output in &outputs {
+                emit(
+                    output,
+                    Key::new(output.code),
+                    frame,
+                    scan_codes,
+                    #[cfg(feature = "otel")]
+                    None,
+                );
+            }
+            if !outputs.is_empty() {
+                frame.push(OutputEvent::Syn.into());
+                flush(capture, tx, device_name, frame, None)?;
+            }
+        }
+
+        loop {
+            events.clear();
+            #[cfg(feature = "fault-injection")]
+            let fetched = match fetch_faults.as_mut().is_some_and(|f| f.fetch_error()) {
+                true => Err(std::io::Error::other("injected fetch_events fault")),
+                false => device.fetch_events(events),
+            };
+            #[cfg(not(feature = "fault-injection"))]
+            let fetched = device.fetch_events(events);
+
+            match fetched {
+                Ok(()) => {
+                    events_read.fetch_add(events.len() as u64, Ordering::Relaxed);
+                    if let Some(recorder) = &capture.recorder
+                        && let Err(e) = recorder.record(device_name, events)
+                    {
+                        warn!("Failed to record events to {}: {}", recorder.path().display(), e);
+                    }
+                }
+                // Everything ready has been read.
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => {
+                    capture.counters.failures.fetch_add(1, Ordering::Relaxed);
+                    return Err(DeviceError::Read { device: device_name.to_string(), source: e });
+                }
+            }
+
+            #[cfg(feature = "otel")]
+            let read_at = std::time::SystemTime::now();
+            for &event in events.iter() {
+                if event.event_type() == EventType::KEY {
+                    #[cfg(feature = "otel")]
+                    let transform_start = std::time::SystemTime::now();
+                    let key_code = event.code();
+                    let value = event.value();
+                    let key = Key::new(key_code);
+                    scan_codes.key(key_code);
+                    if let Some(chord) = pause_chord
+                        && chord.feed(key_code, value, Instant::now())
+                    {
+                        set_paused(&capture.paused, !capture.paused.load(Ordering::Relaxed));
+                    }
+                    let bypass = match value {
+                        1 => {
+                            capture.paused.load(Ordering::Relaxed)
+                                || capture.game_running.load(Ordering::Relaxed)
+                                || !capture.session_active.load(Ordering::Relaxed)
+                        }
+                        _ => unmapped_keys.contains(&key_code),
+                    };
+                    if bypass {
+                        if value == 1 {
+                            unmapped_keys.insert(key_code);
+                        } else if value == 0 {
+                            unmapped_keys.remove(&key_code);
+                        }
+                        let input = KeyEvent { rule: Some(RemapRule::Paused), ..KeyEvent::new(key_code, value) };
+                        for output in &remapper.bypass(input) {
+                            emit(
+                                output,
+                                key,
+                                frame,
+                                scan_codes,
+                                #[cfg(feature = "otel")]
+                                None,
+                            );
+                        }
+                        continue;
+                    }
+                    let outputs = remapper.process_key(KeyEvent::new(key_code, value));
+                    #[cfg(feature = "otel")]
+                    let transform_end = std::time::SystemTime::now();
+
+                    for output in &outputs {
+                        emit(
+                            output,
+                            key,
+                            frame,
+                            scan_codes,
+                            #[cfg(feature = "otel")]
+                            capture.telemetry.as_ref().map(|telemetry| {
+                                telemetry.key_event(
+                                    device_name,
+                                    event.timestamp(),
+                                    read_at,
+                                    transform_start,
+                                    transform_end,
+                                    key_code,
+                                    output.code,
+                                    &crate::remap::describe_rule(output),
+                                )
+                            }),
+                        );
+                    }
+                } else if event.event_type() == EventType::MISC && event.code() == MiscType::MSC_SCAN.0 {
+                    // Replaced by the scancode of whatever key the next key event becomes.
+                    scan_codes.read(event.value());
+                } else {
+                    // Pass through other events; a SYN_REPORT completes the frame.
+                    frame.push(OutputEvent::new(event.event_type(), event.code(), event.value()).into());
+                    if event.event_type() == EventType::SYNCHRONIZATION {
+                        flush(capture, tx, device_name, frame, Some(event.timestamp()))?;
+                    }
+                }
+            }
+            // The kernel delivers whole frames, so anything left is a source that doesn't end
+            // its frames; don't hold it back.
+            flush(capture, tx, device_name, frame, events.last().map(InputEvent::timestamp))?;
+        }
+    }
+
+    /// Releases every key the keyboard holds down on the virtual keyboard, before it is
+    /// dropped. If it vanished mid-keypress, its releases will never arrive, and the virtual
+    /// keyboard would be left with stuck keys.
+    pub fn release_held(&mut self, capture: &Capture) {
+        let releases = self.remapper.release_all();
+        if releases.is_empty() {
+            return;
+        }
+        let name = self.name.as_str();
+        info!(device = name, "Releasing {} keys held by {}", releases.len(), name);
+        let releases = releases.iter().map(|release| OutputEvent::Key { code: release.code, value: 0 }.into());
+        self.frame.extend(releases);
+        self.frame.push(OutputEvent::Syn.into());
+        let _ = flush(capture, &self.tx, name, &mut self.frame, None);
+    }
+}
+
+/// Queues a keyboard's frame for the writer, which writes it whole. `timestamp` is the
+/// kernel's on the events it was read from, if any.
+fn flush(
+    capture: &Capture,
+    tx: &Producer<QueuedFrame>,
+    device: &str,
+    frame: &mut Vec<QueuedEvent>,
+    timestamp: Option<SystemTime>,
+) -> Result<(), DeviceError> {
+    if frame.is_empty() {
+        return Ok(());
+    }
+    let frame = QueuedFrame { events: std::mem::take(frame), timestamp, queued_at: Instant::now() };
+    let writer_gone = || DeviceError::WriterGone { device: device.to_string() };
+    if frame.events.iter().any(|queued| queued.event.is_critical()) {
+        return tx.send(frame).map_err(|_| writer_gone());
+    }
+    match tx.try_send(frame) {
+        Ok(()) => Ok(()),
+        Err(mpsc::TrySendError::Full(frame)) => {
+            // Drop frames of repeats and other non-critical events under pressure
+            for class in frame.events.iter().filter_map(|queued| queued.event.drop_class()) {
+                capture.counters.record_drop(device, class);
+            }
+            Ok(())
+        }
+        Err(mpsc::TrySendError::Disconnected(_)) => Err(writer_gone()),
+    }
+}
@@ -0,0 +1,72 @@
+//! The pause chord: a key combination, pressed a number of times in a row, that pauses or
+//! resumes remapping from the keyboard itself, e.g. both Shift keys twice.
+//!
+//! The chord is matched on the physical keys, before the pipeline, and its keys still reach
+//! applications as usual.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use evdev::Key;
+
+// CHORD_TIMEOUT: Longest gap between one press of the chord and the next.
+const CHORD_TIMEOUT: Duration = Duration::from_secs(1);
+// DEFAULT_CHORD_PRESSES: How many times the chord is pressed unless configured otherwise.
+pub const DEFAULT_CHORD_PRESSES: u32 = 2;
+
+/// Keys held together `presses` times in a row to pause or resume remapping.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PauseChord {
+    pub keys: Vec<Key>,
+    pub presses: u32,
+}
+
+/// Watches one keyboard's key events for the pause chord.
+pub struct ChordDetector {
+    chord: PauseChord,
+    /// Chord keys currently held down.
+    down: BTreeSet<u16>,
+    /// Presses of the chord so far, and when the last one was.
+    count: u32,
+    last: Option<Instant>,
+}
+
+impl ChordDetector {
+    pub fn new(chord: PauseChord) -> Self {
+        ChordDetector { chord, down: BTreeSet::new(), count: 0, last: None }
+    }
+
+    /// Feeds a key event; returns true when it completes the chord's last press.
+    pub fn feed(&mut self, code: u16, value: i32, now: Instant) -> bool {
+        if !self.chord.keys.iter().any(|key| key.code() == code) {
+            // Any other key typed in between starts the count over.
+            if value == 1 {
+                self.count = 0;
+            }
+            return false;
+        }
+        match value {
+            1 => {
+                self.down.insert(code);
+            }
+            0 => {
+                self.down.remove(&code);
+                return false;
+            }
+            _ => return false,
+        }
+        if self.down.len() < self.chord.keys.len() {
+            return false;
+        }
+        if self.last.is_none_or(|last| now.duration_since(last) > CHORD_TIMEOUT) {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last = Some(now);
+        if self.count < self.chord.presses {
+            return false;
+        }
+        self.count = 0;
+        true
+    }
+}
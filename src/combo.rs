@@ -0,0 +1,168 @@
+//! Combos: keys pressed together that type something else, e.g. J and K at once for Escape.
+//!
+//! A press of a key that belongs to a combo is held back for up to the combo term. If the rest
+//! of a combo's keys go down in that time, the combo's output is pressed instead, and released
+//! as soon as any of its keys is; otherwise the held-back presses go on as they were. Combos
+//! are matched on the physical keys (after swaps), and their output is not remapped by the
+//! layout.
+
+use std::time::{Duration, Instant};
+
+use evdev::Key;
+
+use crate::pipeline::{KeyEvent, Stage};
+use crate::remap::RemapRule;
+
+// DEFAULT_COMBO_TERM: Longest gap between the first and last key of a combo.
+pub const DEFAULT_COMBO_TERM: Duration = Duration::from_millis(50);
+
+/// Keys that type `output` when pressed together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Combo {
+    pub keys: Vec<Key>,
+    pub output: Key,
+}
+
+/// A combo that has fired and still has keys down.
+struct Active {
+    /// Its output, until the first of its keys is released.
+    output: Option<u16>,
+    keys: Vec<u16>,
+}
+
+/// Modmap stage that turns combos into their output key.
+pub struct Combos {
+    combos: Vec<Combo>,
+    term: Duration,
+    /// Presses held back while they may still become a combo.
+    pending: Vec<KeyEvent>,
+    deadline: Option<Instant>,
+    active: Vec<Active>,
+}
+
+impl Combos {
+    pub fn new(combos: Vec<Combo>, term: Duration) -> Self {
+        Combos { combos, term, pending: Vec::new(), deadline: None, active: Vec::new() }
+    }
+
+    /// The combos whose keys include all of `codes`.
+    fn candidates<'a>(&'a self, codes: &'a [u16]) -> impl Iterator<Item = &'a Combo> + 'a {
+        self.combos.iter().filter(|combo| codes.iter().all(|&code| combo.keys.iter().any(|key| key.code() == code)))
+    }
+
+    /// Lets the held-back presses through as they were.
+    fn flush(&mut self, out: &mut Vec<KeyEvent>) {
+        self.deadline = None;
+        out.append(&mut self.pending);
+    }
+}
+
+impl Stage for Combos {
+    fn process(&mut self, event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        if let Some(index) = self.active.iter().position(|active| active.keys.contains(&event.code)) {
+            let active = &mut self.active[index];
+            if event.value == 0 {
+                active.keys.retain(|&code| code != event.code);
+                if let Some(output) = active.output.take() {
+                    out.push(KeyEvent { rule: Some(RemapRule::Combo), ..KeyEvent::new(output, 0) });
+                }
+                if active.keys.is_empty() {
+                    self.active.remove(index);
+                }
+            }
+            // The combo's keys don't repeat.
+            return;
+        }
+
+        if event.value == 1 && !self.pending.iter().any(|pending| pending.code == event.code) {
+            let mut codes: Vec<u16> = self.pending.iter().map(|pending| pending.code).collect();
+            codes.push(event.code);
+            let complete =
+                self.candidates(&codes).find(|combo| combo.keys.len() == codes.len()).map(|combo| combo.output);
+            if let Some(output) = complete {
+                self.pending.clear();
+                self.deadline = None;
+                self.active.push(Active { output: Some(output.code()), keys: codes });
+                out.push(KeyEvent { rule: Some(RemapRule::Combo), ..KeyEvent::new(output.code(), 1) });
+                return;
+            }
+            if self.candidates(&codes).next().is_some() {
+                if self.pending.is_empty() {
+                    self.deadline = Some(Instant::now() + self.term);
+                }
+                self.pending.push(event);
+                return;
+            }
+        }
+
+        // Anything else means the held-back keys are not a combo. The event itself may still
+        // start one.
+        if !self.pending.is_empty() {
+            self.flush(out);
+            self.process(event, out);
+            return;
+        }
+        out.push(event);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<KeyEvent>) {
+        if self.deadline.is_some_and(|deadline| deadline <= now) {
+            self.flush(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{run, tick};
+
+    const J: u16 = Key::KEY_J.code();
+    const K: u16 = Key::KEY_K.code();
+    const ESC: u16 = Key::KEY_ESC.code();
+    const A: u16 = Key::KEY_A.code();
+
+    fn combos() -> Combos {
+        Combos::new(vec![Combo { keys: vec![Key::KEY_J, Key::KEY_K], output: Key::KEY_ESC }], DEFAULT_COMBO_TERM)
+    }
+
+    fn tick_past_deadline(stage: &mut Combos) -> Vec<(u16, i32)> {
+        let deadline = stage.deadline().expect("a press is held back");
+        tick(stage, deadline)
+    }
+
+    #[test]
+    fn keys_pressed_within_the_term_type_the_combo() {
+        let mut stage = combos();
+        assert_eq!(run(&mut stage, &[(J, 1)]), []);
+        assert_eq!(run(&mut stage, &[(K, 1)]), [(ESC, 1)]);
+        assert_eq!(stage.deadline(), None);
+        assert_eq!(run(&mut stage, &[(K, 2), (J, 0)]), [(ESC, 0)]);
+        assert_eq!(run(&mut stage, &[(K, 0)]), []);
+        assert_eq!(run(&mut stage, &[(J, 1)]), []);
+    }
+
+    #[test]
+    fn keys_pressed_after_the_term_type_themselves() {
+        let mut stage = combos();
+        assert_eq!(run(&mut stage, &[(J, 1)]), []);
+        assert_eq!(tick_past_deadline(&mut stage), [(J, 1)]);
+        assert_eq!(run(&mut stage, &[(K, 1)]), []);
+        assert_eq!(tick_past_deadline(&mut stage), [(K, 1)]);
+        assert_eq!(run(&mut stage, &[(J, 0), (K, 0)]), [(J, 0), (K, 0)]);
+    }
+
+    #[test]
+    fn a_lone_combo_key_is_let_through_before_its_release() {
+        let mut stage = combos();
+        assert_eq!(run(&mut stage, &[(J, 1), (J, 0)]), [(J, 1), (J, 0)]);
+        assert_eq!(stage.deadline(), None);
+
+        let mut stage = combos();
+        assert_eq!(run(&mut stage, &[(K, 1), (A, 1), (K, 0), (A, 0)]), [(K, 1), (A, 1), (K, 0), (A, 0)]);
+    }
+}
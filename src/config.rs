@@ -0,0 +1,917 @@
+//! Daemon settings. The binary fills these in from the config file and its command line.
+//!
+//! The config file is TOML with the same settings as the command-line flags. Tables under
+//! `host` and `env` override them conditionally, so one file can be shared between machines:
+//!
+//! ```toml
+//! layout = "dvorak"
+//! heartbeat_minutes = 60
+//! device_names = ["Keychron"]   # grab only keyboards with these in their names
+//! exclude_devices = ["1050:0407"]   # never grab these vendor:product IDs
+//!
+//! [keys]                    # physical key = key it types, overriding the layout
+//! capslock = "backspace"
+//!
+//! [modmap]                  # physical key = key it acts as, modifiers included
+//! rightalt = "rightctrl"
+//!
+//! [device_layouts]          # keyboard name or vendor:product ID = its own layout
+//! "Kinesis Advantage" = "qwerty"
+//!
+//! [host."work-laptop"]      # applies when the hostname is work-laptop
+//! typing_stats = true
+//!
+//! [env.SSH_CONNECTION]      # applies when SSH_CONNECTION is set and not empty
+//! heartbeat_minutes = 0
+//! ```
+//!
+//! Matching `host` tables are applied first, then matching `env` tables in name order; later
+//! tables win, and command-line flags win over the file.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use evdev::{BusType, Key};
+use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
+
+use crate::chord::{PauseChord, DEFAULT_CHORD_PRESSES};
+use crate::combo::{Combo, DEFAULT_COMBO_TERM};
+use crate::enumeration::{DeviceFilter, DeviceId, DeviceMatch};
+use crate::error::ConfigError;
+use crate::macros::{text_keys, Macro, Snippet, UnicodeInput};
+use crate::remap::{parse_key, SHORTCUT_MODIFIERS};
+use crate::taphold::{Overload, DEFAULT_TAPPING_TERM};
+use crate::xkb::XkbCheck;
+
+// Config file
+// CONFIG_FILE: Loaded from $XDG_CONFIG_HOME/qwertdvert/ (or ~/.config/qwertdvert/) if present.
+pub const CONFIG_FILE: &str = "config.toml";
+
+// Heartbeat
+// DEFAULT_HEARTBEAT_MINUTES: How often a summary line is logged (0 disables it).
+pub const DEFAULT_HEARTBEAT_MINUTES: u64 = 60;
+
+// Drop alerts
+// DEFAULT_DROP_ALERT_THRESHOLD: Dropped events per window that raise an alert (0 disables it).
+// DEFAULT_DROP_ALERT_WINDOW: Length of the window the threshold applies to.
+pub const DEFAULT_DROP_ALERT_THRESHOLD: u64 = 100;
+pub const DEFAULT_DROP_ALERT_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Metrics
+// DEFAULT_METRICS_PORT: Localhost port of the Prometheus endpoint (0 disables it).
+#[cfg(feature = "prometheus")]
+pub const DEFAULT_METRICS_PORT: u16 = 9477;
+
+// Key repeat
+// DEFAULT_REPEAT_DELAY, DEFAULT_REPEAT_INTERVAL: The kernel's own timing, used for whichever of
+// the two the config leaves out.
+pub const DEFAULT_REPEAT_DELAY: std::time::Duration = std::time::Duration::from_millis(250);
+pub const DEFAULT_REPEAT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(33);
+
+/// Key repeat timing for the virtual keyboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyRepeat {
+    /// How long a key is held before it starts repeating.
+    pub delay: std::time::Duration,
+    /// Time between repeats.
+    pub period: std::time::Duration,
+}
+
+impl Default for KeyRepeat {
+    fn default() -> Self {
+        KeyRepeat { delay: DEFAULT_REPEAT_DELAY, period: DEFAULT_REPEAT_INTERVAL }
+    }
+}
+
+/// How the threads every key passes through, the event loop and the uinput writer, are
+/// scheduled. The defaults leave them as any other thread.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThreadScheduling {
+    /// Run them under SCHED_FIFO at this priority (1 to 99).
+    pub realtime_priority: Option<u8>,
+    /// Keep them to these CPUs; empty for any.
+    pub cpu_affinity: Vec<usize>,
+    /// Their nice value (-20 to 19).
+    pub nice: Option<i32>,
+}
+
+// Virtual keyboard
+// BUS_TYPES: Bus types the virtual keyboard can claim, by the name used in the config file.
+const BUS_TYPES: [(&str, BusType); 4] = [
+    ("usb", BusType::BUS_USB),
+    ("bluetooth", BusType::BUS_BLUETOOTH),
+    ("i8042", BusType::BUS_I8042),
+    ("virtual", BusType::BUS_VIRTUAL),
+];
+// MAX_DEVICE_NAME_LEN: The longest name uinput accepts, in bytes (UINPUT_MAX_NAME_SIZE less
+// the terminating NUL).
+const MAX_DEVICE_NAME_LEN: usize = 79;
+
+/// The name and IDs the virtual keyboard shows to applications and libinput.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceIdentity {
+    pub name: String,
+    pub bus_type: BusType,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+}
+
+impl Default for DeviceIdentity {
+    fn default() -> Self {
+        DeviceIdentity {
+            name: crate::output::OUTPUT_DEVICE_NAME.to_string(),
+            bus_type: BusType(0),
+            vendor: 0,
+            product: 0,
+            version: 0,
+        }
+    }
+}
+
+/// Everything a [`Daemon`](crate::Daemon) needs to know before it starts.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Publish a rolling keys-per-minute/WPM figure for the tray.
+    pub typing_stats: bool,
+    /// Count presses per output key for the `histogram` control command.
+    pub key_histogram: bool,
+    /// Measure how long keys take to get through for the `latency` control command.
+    pub latency_histogram: bool,
+    /// Start with the explain trace enabled.
+    pub explain: bool,
+    /// The least severe log level written, unless `RUST_LOG` says otherwise.
+    pub log_level: LevelFilter,
+    /// Minutes between heartbeat log lines; 0 disables them.
+    pub heartbeat_minutes: u64,
+    /// Warn when more events than this are dropped within `drop_alert_window`; 0 disables it.
+    pub drop_alert_threshold: u64,
+    pub drop_alert_window: std::time::Duration,
+    /// Name of a layout in the [`layout`](crate::layout) registry.
+    pub layout: String,
+    /// Keys that type something other than the layout says, as (physical key, key it types).
+    pub keys: Vec<(evdev::Key, evdev::Key)>,
+    /// Which keyboards to grab.
+    pub devices: DeviceFilter,
+    /// Keyboards that type with a layout of their own rather than the selected one, as
+    /// (keyboard, layout name). An ID match wins over a name match.
+    pub device_layouts: Vec<(DeviceMatch, String)>,
+    /// Get keyboards from this privileged helper instead of opening them directly.
+    pub device_helper: Option<PathBuf>,
+    /// When /dev/input is not readable, ask polkit to run this helper rather than waiting for
+    /// udev to grant access.
+    pub polkit_helper: Option<PathBuf>,
+    /// Drop a key's release and press closer together than this; zero turns debouncing off.
+    pub debounce: std::time::Duration,
+    /// Keys debounced with a window other than `debounce`.
+    pub debounce_keys: Vec<(evdev::Key, std::time::Duration)>,
+    /// Have the kernel repeat held keys on the virtual keyboard with this timing, rather than
+    /// forward the keyboards' own repeats. With `daemon_repeat`, the timing for that instead.
+    pub key_repeat: Option<KeyRepeat>,
+    /// Drop the keyboards' own repeats and repeat the remapped key in the pipeline.
+    pub daemon_repeat: bool,
+    /// How the virtual keyboard identifies itself.
+    pub virtual_keyboard: DeviceIdentity,
+    /// Physical keys swapped in pairs before anything else sees them.
+    pub swaps: Vec<(evdev::Key, evdev::Key)>,
+    /// Modifiers that keep keys on the QWERTY layer while held; the layout applies under any
+    /// others.
+    pub shortcut_modifiers: Vec<evdev::Key>,
+    /// The key the system layout uses as AltGr (ISO_Level3_Shift), if any. It is never a
+    /// shortcut modifier; keys typed while it is held map through the layout's AltGr table.
+    pub altgr: Option<evdev::Key>,
+    /// Physical keys that act as another key, one way round, applied along with the swaps.
+    pub remaps: Vec<(evdev::Key, evdev::Key)>,
+    /// Keys pressed together that type another key, resolved before dual-function keys.
+    pub combos: Vec<Combo>,
+    /// Longest gap between the first and last key of a combo.
+    pub combo_term: std::time::Duration,
+    /// Dual-function keys (space-cadet style), resolved before the shortcut layer.
+    pub overloads: Vec<Overload>,
+    /// How long an overloaded key may be held and still count as a tap.
+    pub tapping_term: std::time::Duration,
+    /// Key that mirrors the keyboard while held, for one-handed typing. It shares the
+    /// tapping term.
+    pub mirror_key: Option<evdev::Key>,
+    /// Start with sticky keys on: a tapped modifier applies to the next key.
+    pub sticky_keys: bool,
+    /// Keys that type a string, applied after the layout.
+    pub macros: Vec<Macro>,
+    /// Abbreviations replaced by a text as they are typed.
+    pub snippets: Vec<Snippet>,
+    /// How macros and snippets type characters that have no key.
+    pub unicode_input: UnicodeInput,
+    /// Key combination that pauses or resumes remapping from the keyboard.
+    pub pause_chord: Option<PauseChord>,
+    /// Programs, by executable or process name, that remapping pauses for while they run.
+    pub game_apps: Vec<String>,
+    /// What to do at startup if the desktop's own layout is Dvorak already.
+    pub xkb_check: XkbCheck,
+    /// How the event loop and writer threads are scheduled.
+    pub scheduling: ThreadScheduling,
+    /// Lock the daemon's memory once it is set up, so remapping never waits on a page fault.
+    pub lock_memory: bool,
+    /// Record every event read from the keyboards to this file, for `--replay`.
+    pub record_events: Option<PathBuf>,
+    /// Capture and inject through the desktop portals instead of evdev and uinput.
+    #[cfg(feature = "portal")]
+    pub portal: bool,
+    /// Localhost port to serve Prometheus metrics on; 0 disables them.
+    #[cfg(feature = "prometheus")]
+    pub metrics_port: u16,
+    #[cfg(feature = "fault-injection")]
+    pub faults: Option<crate::faults::FaultConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            typing_stats: false,
+            key_histogram: false,
+            latency_histogram: false,
+            explain: false,
+            log_level: LevelFilter::INFO,
+            heartbeat_minutes: DEFAULT_HEARTBEAT_MINUTES,
+            drop_alert_threshold: DEFAULT_DROP_ALERT_THRESHOLD,
+            drop_alert_window: DEFAULT_DROP_ALERT_WINDOW,
+            layout: crate::layout::DVORAK.to_string(),
+            keys: Vec::new(),
+            devices: DeviceFilter::default(),
+            device_layouts: Vec::new(),
+            device_helper: None,
+            polkit_helper: None,
+            debounce: std::time::Duration::ZERO,
+            debounce_keys: Vec::new(),
+            key_repeat: None,
+            daemon_repeat: false,
+            virtual_keyboard: DeviceIdentity::default(),
+            swaps: Vec::new(),
+            shortcut_modifiers: SHORTCUT_MODIFIERS.to_vec(),
+            altgr: None,
+            remaps: Vec::new(),
+            combos: Vec::new(),
+            combo_term: DEFAULT_COMBO_TERM,
+            overloads: Vec::new(),
+            tapping_term: DEFAULT_TAPPING_TERM,
+            mirror_key: None,
+            sticky_keys: false,
+            macros: Vec::new(),
+            snippets: Vec::new(),
+            unicode_input: UnicodeInput::default(),
+            pause_chord: None,
+            game_apps: Vec::new(),
+            xkb_check: XkbCheck::default(),
+            scheduling: ThreadScheduling::default(),
+            lock_memory: false,
+            record_events: None,
+            #[cfg(feature = "portal")]
+            portal: false,
+            #[cfg(feature = "prometheus")]
+            metrics_port: DEFAULT_METRICS_PORT,
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+        }
+    }
+}
+
+/// Settings that may appear at the top level of the config file or in a conditional table.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    typing_stats: Option<bool>,
+    key_histogram: Option<bool>,
+    latency_histogram: Option<bool>,
+    explain: Option<bool>,
+    log_level: Option<String>,
+    heartbeat_minutes: Option<u64>,
+    drop_alert_threshold: Option<u64>,
+    drop_alert_window_secs: Option<u64>,
+    layout: Option<String>,
+    #[serde(default)]
+    keys: BTreeMap<String, String>,
+    device_names: Option<Vec<String>>,
+    include_devices: Option<Vec<String>>,
+    exclude_devices: Option<Vec<String>>,
+    #[serde(default)]
+    device_layouts: BTreeMap<String, String>,
+    device_helper: Option<PathBuf>,
+    polkit_helper: Option<PathBuf>,
+    debounce_ms: Option<u64>,
+    #[serde(default)]
+    debounce: BTreeMap<String, u64>,
+    repeat_delay_ms: Option<u64>,
+    repeat_interval_ms: Option<u64>,
+    daemon_repeat: Option<bool>,
+    virtual_keyboard: Option<DeviceIdentitySettings>,
+    swaps: Option<Vec<[String; 2]>>,
+    shortcut_modifiers: Option<Vec<String>>,
+    altgr: Option<String>,
+    #[serde(default)]
+    modmap: BTreeMap<String, String>,
+    caps_lock: Option<String>,
+    caps_lock_moved_to: Option<String>,
+    combo_term_ms: Option<u64>,
+    #[serde(default)]
+    combos: BTreeMap<String, String>,
+    tapping_term_ms: Option<u64>,
+    #[serde(default)]
+    overload: BTreeMap<String, OverloadSettings>,
+    mirror_key: Option<String>,
+    sticky_keys: Option<bool>,
+    #[serde(default, rename = "macro")]
+    macros: BTreeMap<String, MacroSettings>,
+    #[serde(default)]
+    snippets: BTreeMap<String, String>,
+    unicode_input: Option<String>,
+    pause_chord: Option<Vec<String>>,
+    pause_chord_presses: Option<u32>,
+    game_apps: Option<Vec<String>>,
+    xkb_check: Option<String>,
+    realtime_priority: Option<u8>,
+    cpu_affinity: Option<Vec<usize>>,
+    nice: Option<i32>,
+    lock_memory: Option<bool>,
+    #[cfg(feature = "portal")]
+    portal: Option<bool>,
+    #[cfg(feature = "prometheus")]
+    metrics_port: Option<u16>,
+}
+
+/// An `[overload.KEY]` table. `tap` defaults to the key itself.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OverloadSettings {
+    hold: String,
+    tap: Option<String>,
+    #[serde(default)]
+    tap_shifted: bool,
+}
+
+/// The `[virtual_keyboard]` table. Whatever it leaves out stays as it was.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceIdentitySettings {
+    name: Option<String>,
+    bustype: Option<String>,
+    vendor: Option<u16>,
+    product: Option<u16>,
+    version: Option<u16>,
+}
+
+/// A `[macro.KEY]` table. Delays default to none, typing the text as one burst.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MacroSettings {
+    text: String,
+    #[serde(default)]
+    key_delay_ms: u64,
+    #[serde(default)]
+    press_ms: u64,
+}
+
+impl Settings {
+    /// Overrides `config` with every setting given here. Fails on an unknown key name.
+    fn apply(self, config: &mut Config) -> Result<(), String> {
+        let Settings {
+            typing_stats,
+            key_histogram,
+            latency_histogram,
+            explain,
+            log_level,
+            heartbeat_minutes,
+            drop_alert_threshold,
+            drop_alert_window_secs,
+            layout,
+            keys,
+            device_names,
+            include_devices,
+            exclude_devices,
+            device_layouts,
+            device_helper,
+            polkit_helper,
+            debounce_ms,
+            debounce,
+            repeat_delay_ms,
+            repeat_interval_ms,
+            daemon_repeat,
+            virtual_keyboard,
+            swaps,
+            shortcut_modifiers,
+            altgr,
+            modmap,
+            caps_lock,
+            caps_lock_moved_to,
+            combo_term_ms,
+            combos,
+            tapping_term_ms,
+            overload,
+            mirror_key,
+            sticky_keys,
+            macros,
+            snippets,
+            unicode_input,
+            pause_chord,
+            pause_chord_presses,
+            game_apps,
+            xkb_check,
+            realtime_priority,
+            cpu_affinity,
+            nice,
+            lock_memory,
+            #[cfg(feature = "portal")]
+            portal,
+            #[cfg(feature = "prometheus")]
+            metrics_port,
+        } = self;
+        config.typing_stats = typing_stats.unwrap_or(config.typing_stats);
+        config.key_histogram = key_histogram.unwrap_or(config.key_histogram);
+        config.latency_histogram = latency_histogram.unwrap_or(config.latency_histogram);
+        config.explain = explain.unwrap_or(config.explain);
+        if let Some(level) = log_level {
+            config.log_level = level
+                .parse()
+                .map_err(|_| format!("log_level '{level}' is not error, warn, info, debug, trace or off"))?;
+        }
+        config.heartbeat_minutes = heartbeat_minutes.unwrap_or(config.heartbeat_minutes);
+        config.drop_alert_threshold = drop_alert_threshold.unwrap_or(config.drop_alert_threshold);
+        if let Some(secs) = drop_alert_window_secs {
+            if secs == 0 {
+                return Err("drop_alert_window_secs must be at least 1".to_string());
+            }
+            config.drop_alert_window = std::time::Duration::from_secs(secs);
+        }
+        config.layout = layout.unwrap_or(std::mem::take(&mut config.layout));
+        if let Some(names) = device_names {
+            config.devices.names = names;
+        }
+        let ids = |setting: &str, ids: Vec<String>| -> Result<Vec<DeviceId>, String> {
+            ids.iter().map(|id| id.parse::<DeviceId>().map_err(|e| format!("{setting}: {e}"))).collect()
+        };
+        if let Some(include) = include_devices {
+            config.devices.include = ids("include_devices", include)?;
+        }
+        if let Some(exclude) = exclude_devices {
+            config.devices.exclude = ids("exclude_devices", exclude)?;
+        }
+        for (device, layout) in device_layouts {
+            let device = DeviceMatch::parse(&device);
+            config.device_layouts.retain(|(existing, _)| *existing != device);
+            config.device_layouts.push((device, layout));
+        }
+        config.device_helper = device_helper.or(config.device_helper.take());
+        config.polkit_helper = polkit_helper.or(config.polkit_helper.take());
+        if let Some(ms) = tapping_term_ms {
+            config.tapping_term = std::time::Duration::from_millis(ms);
+        }
+        let key = |name: &str| {
+            parse_key(name).ok_or_else(|| {
+                format!("unknown key '{name}' (use evdev key names, e.g. 'semicolon' or 'KEY_SEMICOLON')")
+            })
+        };
+        if let Some(ms) = debounce_ms {
+            config.debounce = std::time::Duration::from_millis(ms);
+        }
+        for (name, ms) in debounce {
+            let debounced = key(&name)?;
+            config.debounce_keys.retain(|&(existing, _)| existing != debounced);
+            config.debounce_keys.push((debounced, std::time::Duration::from_millis(ms)));
+        }
+        if repeat_delay_ms.is_some() || repeat_interval_ms.is_some() {
+            let current = config.key_repeat.unwrap_or_default();
+            let repeat = KeyRepeat {
+                delay: repeat_delay_ms.map_or(current.delay, std::time::Duration::from_millis),
+                period: repeat_interval_ms.map_or(current.period, std::time::Duration::from_millis),
+            };
+            if repeat.period.is_zero() {
+                return Err("repeat_interval_ms must be at least 1".to_string());
+            }
+            config.key_repeat = Some(repeat);
+        }
+        config.daemon_repeat = daemon_repeat.unwrap_or(config.daemon_repeat);
+        if let Some(settings) = virtual_keyboard {
+            let identity = &mut config.virtual_keyboard;
+            if let Some(name) = settings.name {
+                if name.is_empty() || name.len() > MAX_DEVICE_NAME_LEN || name.contains('\0') {
+                    return Err(format!("virtual_keyboard name must be 1 to {MAX_DEVICE_NAME_LEN} bytes long"));
+                }
+                identity.name = name;
+            }
+            if let Some(bus) = settings.bustype {
+                let (_, bus_type) = BUS_TYPES.iter().find(|(name, _)| *name == bus).ok_or_else(|| {
+                    format!("virtual_keyboard bustype must be 'usb', 'bluetooth', 'i8042' or 'virtual', not '{bus}'")
+                })?;
+                identity.bus_type = *bus_type;
+            }
+            identity.vendor = settings.vendor.unwrap_or(identity.vendor);
+            identity.product = settings.product.unwrap_or(identity.product);
+            identity.version = settings.version.unwrap_or(identity.version);
+        }
+        let mut mapped = Vec::new();
+        for (from, to) in keys {
+            let (from_key, to_key) = (key(&from)?, key(&to)?);
+            if mapped.contains(&from_key) {
+                return Err(format!("'{from}' is mapped more than once in [keys]"));
+            }
+            mapped.push(from_key);
+            config.keys.retain(|&(existing, _)| existing != from_key);
+            config.keys.push((from_key, to_key));
+        }
+        if let Some(swaps) = swaps {
+            let mut seen = Vec::new();
+            config.swaps.clear();
+            for [a, b] in swaps {
+                let pair = (key(&a)?, key(&b)?);
+                for (name, key) in [(&a, pair.0), (&b, pair.1)] {
+                    if seen.contains(&key) {
+                        return Err(format!("'{name}' appears in more than one swap"));
+                    }
+                    seen.push(key);
+                }
+                config.swaps.push(pair);
+            }
+        }
+        if let Some(names) = shortcut_modifiers {
+            config.shortcut_modifiers.clear();
+            for name in names {
+                // The bare names cover both sides; `leftalt`, `rightalt` etc. just the one.
+                let keys = match name.to_ascii_lowercase().as_str() {
+                    "ctrl" => vec![Key::KEY_LEFTCTRL, Key::KEY_RIGHTCTRL],
+                    "alt" => vec![Key::KEY_LEFTALT, Key::KEY_RIGHTALT],
+                    "super" | "meta" => vec![Key::KEY_LEFTMETA, Key::KEY_RIGHTMETA],
+                    _ => vec![key(&name)?],
+                };
+                config.shortcut_modifiers.extend(keys);
+            }
+        }
+        // "off", e.g. in a host table, turns a configured AltGr key back into a plain modifier.
+        if let Some(name) = altgr {
+            config.altgr = match name.eq_ignore_ascii_case("off") {
+                true => None,
+                false => Some(key(&name)?),
+            };
+        }
+        for (from, to) in modmap {
+            let (from, to) = (key(&from)?, key(&to)?);
+            config.remaps.retain(|&(existing, _)| existing != from);
+            config.remaps.push((from, to));
+        }
+        // Caps Lock types `caps_lock`, and `caps_lock_moved_to` takes over as Caps Lock.
+        if let Some(name) = caps_lock {
+            let to = key(&name)?;
+            config.remaps.retain(|&(from, _)| from != Key::KEY_CAPSLOCK);
+            if to != Key::KEY_CAPSLOCK {
+                config.remaps.push((Key::KEY_CAPSLOCK, to));
+            }
+        }
+        if let Some(name) = caps_lock_moved_to {
+            let from = key(&name)?;
+            config.remaps.retain(|&(existing, to)| to != Key::KEY_CAPSLOCK && existing != from);
+            config.remaps.push((from, Key::KEY_CAPSLOCK));
+        }
+        if let Some(ms) = combo_term_ms {
+            config.combo_term = std::time::Duration::from_millis(ms);
+        }
+        for (names, output) in combos {
+            let mut keys = Vec::new();
+            for name in names.split('+').map(str::trim) {
+                let combo_key = key(name)?;
+                if keys.contains(&combo_key) {
+                    return Err(format!("'{name}' appears more than once in combo '{names}'"));
+                }
+                keys.push(combo_key);
+            }
+            if keys.len() < 2 {
+                return Err(format!("combo '{names}' needs at least two keys joined by '+'"));
+            }
+            let combo = Combo { keys, output: key(&output)? };
+            let same_keys = |existing: &Combo| {
+                existing.keys.len() == combo.keys.len() && existing.keys.iter().all(|k| combo.keys.contains(k))
+            };
+            config.combos.retain(|existing| !same_keys(existing));
+            config.combos.push(combo);
+        }
+        for (name, settings) in overload {
+            let overload = Overload {
+                key: key(&name)?,
+                hold: key(&settings.hold)?,
+                tap: key(settings.tap.as_deref().unwrap_or(&name))?,
+                tap_shifted: settings.tap_shifted,
+            };
+            config.overloads.retain(|existing| existing.key != overload.key);
+            config.overloads.push(overload);
+        }
+        config.sticky_keys = sticky_keys.unwrap_or(config.sticky_keys);
+        if let Some(name) = mirror_key {
+            config.mirror_key = match name.eq_ignore_ascii_case("off") {
+                true => None,
+                false => Some(key(&name)?),
+            };
+        }
+        // Set first so it applies to the macros and snippets in the same table.
+        if let Some(method) = unicode_input {
+            config.unicode_input = method.parse()?;
+        }
+        for (name, settings) in macros {
+            let keys = text_keys(&settings.text, config.unicode_input).map_err(|c| {
+                format!("macro '{name}': {c:?} has no key on a US QWERTY layout and unicode_input is off")
+            })?;
+            let text_macro = Macro {
+                trigger: key(&name)?,
+                keys,
+                key_delay: std::time::Duration::from_millis(settings.key_delay_ms),
+                press_time: std::time::Duration::from_millis(settings.press_ms),
+            };
+            config.macros.retain(|existing| existing.trigger != text_macro.trigger);
+            config.macros.push(text_macro);
+        }
+        for (abbreviation, text) in snippets {
+            if abbreviation.is_empty() {
+                return Err("snippet abbreviations can't be empty".to_string());
+            }
+            // The abbreviation is matched against keys typed, so each character needs a key.
+            text_keys(&abbreviation, UnicodeInput::Off)
+                .map_err(|c| format!("snippet '{abbreviation}': {c:?} has no key on a US QWERTY layout"))?;
+            let keys = text_keys(&text, config.unicode_input).map_err(|c| {
+                format!("snippet '{abbreviation}': {c:?} has no key on a US QWERTY layout and unicode_input is off")
+            })?;
+            config.snippets.retain(|existing| existing.abbreviation != abbreviation);
+            config.snippets.push(Snippet { abbreviation, keys });
+        }
+        if let Some(names) = pause_chord {
+            let mut keys = Vec::new();
+            for name in &names {
+                let chord_key = key(name)?;
+                if keys.contains(&chord_key) {
+                    return Err(format!("'{name}' appears more than once in pause_chord"));
+                }
+                keys.push(chord_key);
+            }
+            // An empty list turns the chord off, e.g. in a host table.
+            config.pause_chord = (!keys.is_empty()).then(|| PauseChord {
+                keys,
+                presses: config.pause_chord.as_ref().map_or(DEFAULT_CHORD_PRESSES, |chord| chord.presses),
+            });
+        }
+        if let Some(presses) = pause_chord_presses {
+            if presses == 0 {
+                return Err("pause_chord_presses must be at least 1".to_string());
+            }
+            if let Some(chord) = &mut config.pause_chord {
+                chord.presses = presses;
+            }
+        }
+        if let Some(apps) = game_apps {
+            config.game_apps = apps;
+        }
+        if let Some(check) = xkb_check {
+            config.xkb_check = check.parse()?;
+        }
+        // 0 turns it back off, e.g. in a host table.
+        if let Some(priority) = realtime_priority {
+            if priority > 99 {
+                return Err("realtime_priority must be 1 to 99, or 0 for none".to_string());
+            }
+            config.scheduling.realtime_priority = (priority > 0).then_some(priority);
+        }
+        if let Some(cpus) = cpu_affinity {
+            config.scheduling.cpu_affinity = cpus;
+        }
+        if let Some(nice) = nice {
+            if !(-20..=19).contains(&nice) {
+                return Err("nice must be -20 to 19".to_string());
+            }
+            config.scheduling.nice = Some(nice);
+        }
+        config.lock_memory = lock_memory.unwrap_or(config.lock_memory);
+        #[cfg(feature = "portal")]
+        {
+            config.portal = portal.unwrap_or(config.portal);
+        }
+        #[cfg(feature = "prometheus")]
+        {
+            config.metrics_port = metrics_port.unwrap_or(config.metrics_port);
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// The repeat timing to set on the virtual keyboard, unless the daemon repeats keys itself.
+    pub fn kernel_repeat(&self) -> Option<KeyRepeat> {
+        self.key_repeat.filter(|_| !self.daemon_repeat)
+    }
+
+    /// The layout `device_layouts` gives the keyboard called `name` with ID `id`, if any.
+    pub fn device_layout(&self, name: &str, id: Option<DeviceId>) -> Option<&str> {
+        let matching = |by_id: bool| {
+            self.device_layouts
+                .iter()
+                .find(|(device, _)| matches!(device, DeviceMatch::Id(_)) == by_id && device.matches(name, id))
+        };
+        matching(true).or_else(|| matching(false)).map(|(_, layout)| layout.as_str())
+    }
+
+    /// The user's qwertdvert config directory, `$XDG_CONFIG_HOME/qwertdvert` or
+    /// `~/.config/qwertdvert`, whether or not it exists.
+    pub fn dir() -> Option<PathBuf> {
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(dir.join("qwertdvert"))
+    }
+
+    /// The config file in the user's config directory, if there is one.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(Config::dir()?.join(CONFIG_FILE)).filter(|path| path.exists())
+    }
+
+    /// Settings that each load but can't be used together.
+    fn conflicts(&self) -> Result<(), String> {
+        let swapped = |key: Key| self.swaps.iter().any(|&(a, b)| a == key || b == key);
+        if let Some(&(from, _)) = self.remaps.iter().find(|&&(from, _)| swapped(from)) {
+            return Err(format!("{from:?} is in a swap and is also remapped by [modmap] or caps_lock"));
+        }
+        if let Some(key) = self.mirror_key.filter(|&key| self.overloads.iter().any(|o| o.key == key)) {
+            return Err(format!("{key:?} is the mirror_key and also has an [overload] table"));
+        }
+        Ok(())
+    }
+
+    /// Reads a config file, applying the `host` and `env` tables that match this machine. The
+    /// others are checked all the same.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::ReadFile { path: path.to_path_buf(), source })?;
+        let hostname = nix::unistd::gethostname().ok().and_then(|name| name.into_string().ok());
+        Config::parse(&text, path, hostname.as_deref(), |variable| {
+            std::env::var_os(variable).is_some_and(|value| !value.is_empty())
+        })
+    }
+
+    /// Parses the config file `path` holds `text`, applying the `host` table for `hostname` and
+    /// the `env` tables for which `is_set` holds.
+    fn parse(
+        text: &str,
+        path: &Path,
+        hostname: Option<&str>,
+        is_set: impl Fn(&str) -> bool,
+    ) -> Result<Config, ConfigError> {
+        let invalid = |e: toml::de::Error| ConfigError::ParseFile { path: path.to_path_buf(), reason: e.to_string() };
+        let mut table: toml::Table = text.parse().map_err(invalid)?;
+        // Pull out the conditional tables so the rest can be checked for unknown settings.
+        let mut conditional = |name: &str| -> Result<BTreeMap<String, Settings>, ConfigError> {
+            table.remove(name).map_or(Ok(BTreeMap::new()), |value| value.try_into().map_err(invalid))
+        };
+        let hosts = conditional("host")?;
+        let envs = conditional("env")?;
+        let settings: Settings = toml::Value::Table(table).try_into().map_err(invalid)?;
+
+        let rejected = |reason: String| ConfigError::ParseFile { path: path.to_path_buf(), reason };
+
+        let mut config = Config::default();
+        settings.apply(&mut config).map_err(rejected)?;
+        // Tables for other machines are applied to a copy, so a mistake in one is found on every
+        // machine the file is used on, not only once it matches.
+        let base = config.clone();
+        let mut tables = Vec::new();
+        for (host, settings) in hosts {
+            let matches = hostname == Some(host.as_str());
+            tables.push((format!("[host.\"{host}\"]"), matches, settings));
+        }
+        for (variable, settings) in envs {
+            let matches = is_set(&variable);
+            tables.push((format!("[env.{variable}]"), matches, settings));
+        }
+        for (name, matches, settings) in tables {
+            let mut scratch = base.clone();
+            let target = if matches { &mut config } else { &mut scratch };
+            settings
+                .apply(target)
+                .and_then(|()| target.conflicts())
+                .map_err(|reason| rejected(format!("{name}: {reason}")))?;
+        }
+        config.conflicts().map_err(rejected)?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads `text` on the host `laptop` with `SSH_CONNECTION` and `TMUX` set.
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        Config::parse(text, Path::new("config.toml"), Some("laptop"), |variable| {
+            ["SSH_CONNECTION", "TMUX"].contains(&variable)
+        })
+    }
+
+    fn rejection(text: &str) -> String {
+        match parse(text) {
+            Err(ConfigError::ParseFile { reason, .. }) => reason,
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("config was accepted"),
+        }
+    }
+
+    #[test]
+    fn matching_host_table_overrides_the_top_level() {
+        let config = parse(
+            r#"
+            heartbeat_minutes = 60
+            typing_stats = true
+            [host."laptop"]
+            heartbeat_minutes = 5
+            [host."desktop"]
+            heartbeat_minutes = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.heartbeat_minutes, 5);
+        assert!(config.typing_stats);
+    }
+
+    #[test]
+    fn table_that_does_not_match_is_still_checked() {
+        let reason = rejection(
+            r#"
+            [host."desktop"]
+            keys = { q = "nokey" }
+            "#,
+        );
+        assert!(reason.starts_with("[host.\"desktop\"]: unknown key 'nokey'"), "{reason}");
+        let reason = rejection(
+            r#"
+            [env.DISPLAY]
+            drop_alert_window_secs = 0
+            "#,
+        );
+        assert!(reason.starts_with("[env.DISPLAY]: drop_alert_window_secs"), "{reason}");
+    }
+
+    #[test]
+    fn env_tables_apply_after_host_tables_in_name_order() {
+        let config = parse(
+            r#"
+            [env.TMUX]
+            heartbeat_minutes = 3
+            [env.SSH_CONNECTION]
+            heartbeat_minutes = 2
+            drop_alert_threshold = 7
+            [host."laptop"]
+            heartbeat_minutes = 1
+            drop_alert_threshold = 6
+            typing_stats = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.heartbeat_minutes, 3);
+        assert_eq!(config.drop_alert_threshold, 7);
+        assert!(config.typing_stats);
+    }
+
+    #[test]
+    fn caps_lock_replaces_earlier_caps_lock_remaps() {
+        let config = parse(
+            r#"
+            caps_lock = "esc"
+            caps_lock_moved_to = "rightctrl"
+            [modmap]
+            leftctrl = "capslock"
+            [host."laptop"]
+            caps_lock = "backspace"
+            caps_lock_moved_to = "esc"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.remaps, [(Key::KEY_CAPSLOCK, Key::KEY_BACKSPACE), (Key::KEY_ESC, Key::KEY_CAPSLOCK)]);
+
+        let config = parse(
+            r#"
+            caps_lock = "esc"
+            [host."laptop"]
+            caps_lock = "capslock"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.remaps, []);
+    }
+
+    #[test]
+    fn swaps_replace_earlier_swaps_and_take_each_key_once() {
+        let config = parse(
+            r#"
+            swaps = [["a", "b"], ["leftctrl", "capslock"]]
+            [host."laptop"]
+            swaps = [["a", "c"]]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.swaps, [(Key::KEY_A, Key::KEY_C)]);
+
+        let reason = rejection(r#"swaps = [["a", "b"], ["b", "c"]]"#);
+        assert_eq!(reason, "'b' appears in more than one swap");
+    }
+}
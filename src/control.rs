@@ -0,0 +1,314 @@
+//! The control socket: one command per connection on `$XDG_RUNTIME_DIR/qwertdvert/control.sock`.
+//!
+//! A client writes a command line and reads the reply until the daemon closes the connection.
+//! The socket lives in the user's runtime directory, so only that user can connect.
+//! `qwertdvertctl` is such a client; failed commands reply with a line starting `error: `.
+//!
+//! ```text
+//! status       state (running or paused), layout and grabbed keyboards, as key=value lines
+//! pause        pass keys through unmapped until resumed; replies with the new state
+//! resume       remap again; replies with the new state
+//! toggle       pause if remapping, resume if paused; replies with the new state
+//! layout       the layout in use
+//! layout NAME  switch every keyboard to another layout
+//! devices      the grabbed keyboards, one per line
+//! sticky       whether sticky keys are on or off
+//! sticky on    turn sticky keys on (or off with `sticky off`); replies with the new state
+//! explain      whether the explain trace is on or off
+//! explain on   log the rule behind every key (or stop with `explain off`); replies with the
+//!              new state
+//! log-level    the log filter in effect, e.g. `info`
+//! log-level L  log level L and above from now on, or whatever `RUST_LOG`-style directives
+//!              say; replies with the new filter
+//! histogram    presses per output key since start, as JSON (needs key_histogram = true)
+//! latency      percentiles of the time keys take to get through, in microseconds, as
+//!              key=value lines (needs latency_histogram = true)
+//! dump-map     what every key sends with the config and layout in use, as JSON (see
+//!              [`EffectiveMap::to_json`]); `dump-map diagram` draws it as a keyboard instead
+//! ```
+
+#[cfg(not(feature = "tokio"))]
+use std::io::{BufRead, BufReader};
+use std::io::{Read, Write};
+#[cfg(not(feature = "tokio"))]
+use std::os::fd::AsFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(not(feature = "tokio"))]
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::daemon::{set_explain, set_paused, set_sticky_keys, LiveConfig};
+use crate::layout::ActiveLayout;
+use crate::logging;
+use crate::mapping::EffectiveMap;
+use crate::remap::ModifierState;
+use crate::shutdown::Shutdown;
+use crate::stats::{runtime_dir, Counters, KeyHistogram, LatencyHistogram};
+
+// CONTROL_SOCKET: File name of the socket under $XDG_RUNTIME_DIR/qwertdvert.
+pub const CONTROL_SOCKET: &str = "control.sock";
+// COMMAND_TIMEOUT: How long a client has to send its command before it is hung up on.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+// ACCEPT_RETRY: How long to wait before accepting again after a connection couldn't be.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// Daemon state the control commands act on.
+pub struct ControlState {
+    pub explain: Arc<AtomicBool>,
+    pub key_histogram: Option<Arc<Mutex<KeyHistogram>>>,
+    pub latency_histogram: Option<Arc<Mutex<LatencyHistogram>>>,
+    pub paused: Arc<AtomicBool>,
+    pub layout: Arc<ActiveLayout>,
+    pub modifiers: Arc<ModifierState>,
+    pub counters: Arc<Counters>,
+    /// The config as last reloaded, for `dump-map`.
+    pub(crate) live: Arc<LiveConfig>,
+}
+
+impl ControlState {
+    fn handle(&self, command: &str) -> String {
+        let (verb, argument) = match command.split_once(' ') {
+            Some((verb, argument)) => (verb, Some(argument.trim())),
+            None => (command, None),
+        };
+        match (verb, argument) {
+            ("status", None) => format!(
+                "state={}\nlayout={}\ndevices={}",
+                self.state(),
+                self.layout.name(),
+                self.counters.grabbed_names.lock().unwrap().len()
+            ),
+            ("pause", None) => self.set_paused(true),
+            ("resume", None) => self.set_paused(false),
+            ("toggle", None) => self.set_paused(!self.paused.load(Ordering::Relaxed)),
+            ("layout", None) => self.layout.name(),
+            ("layout", Some(name)) => match self.layout.select(name) {
+                Ok(()) => {
+                    info!("Switched to the {} layout over the control socket", name);
+                    self.layout.name()
+                }
+                Err(e) => format!("error: {e}"),
+            },
+            ("devices", None) => self.counters.grabbed_names.lock().unwrap().join("\n"),
+            ("sticky", None) => self.sticky_state().to_string(),
+            ("sticky", Some(state @ ("on" | "off"))) => {
+                set_sticky_keys(&self.modifiers, state == "on");
+                self.sticky_state().to_string()
+            }
+            ("explain", None) => self.explain_state().to_string(),
+            ("explain", Some(state @ ("on" | "off"))) => {
+                set_explain(&self.explain, state == "on");
+                self.explain_state().to_string()
+            }
+            ("log-level", None) => {
+                logging::filter().unwrap_or_else(|| "error: logging was not set up by qwertdvert".to_string())
+            }
+            ("log-level", Some(directives)) => match logging::set_filter(directives) {
+                Ok(()) => {
+                    info!("Logging {} over the control socket", directives);
+                    logging::filter().unwrap_or_default()
+                }
+                Err(e) => format!("error: {e}"),
+            },
+            ("histogram", None) => match &self.key_histogram {
+                Some(histogram) => histogram.lock().unwrap().to_json(),
+                None => "error: key histogram collection is off; set key_histogram = true in the config".to_string(),
+            },
+            ("latency", None) => match &self.latency_histogram {
+                Some(histogram) => histogram.lock().unwrap().report(),
+                None => "error: latency measurement is off; set latency_histogram = true in the config".to_string(),
+            },
+            ("dump-map", None | Some("json")) => EffectiveMap::new(&self.live.current(), &self.layout).to_json(),
+            ("dump-map", Some("diagram")) => EffectiveMap::new(&self.live.current(), &self.layout).diagram(),
+            _ => format!("error: unknown command '{command}'"),
+        }
+    }
+
+    fn state(&self) -> &'static str {
+        if self.paused.load(Ordering::Relaxed) { "paused" } else { "running" }
+    }
+
+    fn sticky_state(&self) -> &'static str {
+        if self.modifiers.sticky_keys() { "on" } else { "off" }
+    }
+
+    fn explain_state(&self) -> &'static str {
+        if self.explain.load(Ordering::Relaxed) { "on" } else { "off" }
+    }
+
+    fn set_paused(&self, paused: bool) -> String {
+        set_paused(&self.paused, paused);
+        self.state().to_string()
+    }
+}
+
+/// Sends one command to the running daemon and returns its reply.
+pub fn send_command(command: &str) -> std::io::Result<String> {
+    let dir = runtime_dir()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+    let mut stream = UnixStream::connect(dir.join(CONTROL_SOCKET))?;
+    writeln!(stream, "{command}")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+/// Where the control socket was bound, to remove it again at shutdown.
+struct SocketPath {
+    path: PathBuf,
+    /// Device and inode of the socket, so one bound since by another instance is left alone.
+    id: Option<(u64, u64)>,
+}
+
+impl SocketPath {
+    fn new(path: PathBuf) -> Self {
+        let id = file_id(&path);
+        SocketPath { path, id }
+    }
+
+    fn remove(&self) {
+        if self.id.is_some() && file_id(&self.path) == self.id {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn file_id(path: &std::path::Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::symlink_metadata(path).ok().map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+/// Creates the control socket, ready to accept without blocking. Returns None if it could not
+/// be created, or if another instance is listening on it.
+fn bind_control_socket() -> Option<(UnixListener, SocketPath)> {
+    let Some(dir) = runtime_dir() else {
+        warn!("XDG_RUNTIME_DIR is not set; the control socket is disabled");
+        return None;
+    };
+    let path = dir.join(CONTROL_SOCKET);
+    match UnixStream::connect(&path) {
+        Ok(_) => {
+            warn!("Another qwertdvert is listening on {}; the control socket is disabled", path.display());
+            return None;
+        }
+        // A socket left behind by a daemon that didn't exit cleanly would block the bind.
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            let _ = std::fs::remove_file(&path);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            warn!("Failed to check control socket {}; the control socket is disabled: {}", path.display(), e);
+            return None;
+        }
+    }
+    let listener = std::fs::create_dir_all(&dir)
+        .and_then(|()| UnixListener::bind(&path))
+        .and_then(|listener| listener.set_nonblocking(true).map(|()| listener));
+    match listener {
+        Ok(listener) => {
+            info!("Listening for control commands on {}", path.display());
+            Some((listener, SocketPath::new(path)))
+        }
+        Err(e) => {
+            warn!("Failed to create control socket {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Serves control commands until shutdown, then removes the socket. Returns None if the
+/// socket could not be created.
+#[cfg(not(feature = "tokio"))]
+pub fn spawn_control_server(state: ControlState, shutdown: Arc<Shutdown>) -> Option<JoinHandle<()>> {
+    let (listener, socket) = bind_control_socket()?;
+    Some(std::thread::spawn(move || {
+        while shutdown.wait_readable(&[listener.as_fd()], None) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = serve(&state, stream) {
+                        warn!("Control connection failed: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    warn!("Failed to accept control connection: {}", e);
+                    // The listener stays readable, e.g. while no fd is free for the connection.
+                    if shutdown.wait(ACCEPT_RETRY) {
+                        break;
+                    }
+                }
+            }
+        }
+        socket.remove();
+    }))
+}
+
+#[cfg(not(feature = "tokio"))]
+fn serve(state: &ControlState, mut stream: UnixStream) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+    writeln!(stream, "{}", state.handle(command.trim()))
+}
+
+/// Serves control commands on the event loop's runtime until shutdown, then removes the socket.
+/// Returns at once if the socket could not be created.
+#[cfg(feature = "tokio")]
+pub async fn serve_control(state: ControlState, shutdown: Arc<Shutdown>) {
+    use std::future::Future;
+    use std::task::Poll;
+
+    let Some((listener, socket)) = bind_control_socket() else {
+        return;
+    };
+    let listener = match tokio::net::UnixListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to create control socket {}: {}", socket.path.display(), e);
+            socket.remove();
+            return;
+        }
+    };
+    let mut stopped = std::pin::pin!(shutdown.wait_async(Duration::MAX));
+    loop {
+        // A connection, or None once shutdown is requested.
+        let accepted = std::future::poll_fn(|cx| match stopped.as_mut().poll(cx) {
+            Poll::Ready(_) => Poll::Ready(None),
+            Poll::Pending => listener.poll_accept(cx).map(Some),
+        });
+        match accepted.await {
+            None => break,
+            Some(Ok((stream, _))) => {
+                if let Err(e) = serve_async(&state, stream).await {
+                    warn!("Control connection failed: {}", e);
+                }
+            }
+            Some(Err(e)) => {
+                warn!("Failed to accept control connection: {}", e);
+                // The listener stays readable, e.g. while no fd is free for the connection.
+                if shutdown.wait_async(ACCEPT_RETRY).await {
+                    break;
+                }
+            }
+        }
+    }
+    socket.remove();
+}
+
+#[cfg(feature = "tokio")]
+async fn serve_async(state: &ControlState, mut stream: tokio::net::UnixStream) -> std::io::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.split();
+    let mut command = String::new();
+    tokio::time::timeout(COMMAND_TIMEOUT, BufReader::new(reader).read_line(&mut command))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    writer.write_all(format!("{}\n", state.handle(command.trim())).as_bytes()).await
+}
@@ -0,0 +1,770 @@
+//! The daemon lifecycle: wait for devices, run the event loop and the writer thread, and
+//! report how it stopped.
+
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use tracing::{debug, info, warn};
+
+use crate::capture::Capture;
+use crate::config::Config;
+use crate::control::ControlState;
+use crate::enumeration::{find_keyboards, input_access_denied, DeviceId};
+use crate::error::{handle_error, ConfigError, DaemonError, DeviceError, Recovery, STARTUP_RETRY_INTERVAL};
+use crate::event_loop::EventLoop;
+use crate::feedback::Feedback;
+use crate::game::spawn_game_watcher;
+use crate::helper::{acquire_keyboards, acquire_keyboards_via_polkit};
+use crate::hotplug::UdevMonitor;
+use crate::layout::{find, ActiveLayout, Dvorak, QWERTY};
+use crate::lint::{lint, Lint};
+use crate::logging;
+use crate::notify::{notify, watchdog_interval};
+use crate::output::{create_uinput_device, run_writer, OutputDevice, QueuedFrame, EVENT_BUFFER_SIZE};
+use crate::remap::ModifierState;
+use crate::ring::rings;
+use crate::replay::EventRecorder;
+use crate::sched::{lock_memory, schedule};
+use crate::shutdown::Shutdown;
+use crate::source::{BoxedKeyboard, KeyboardSource, PassedKeyboard};
+use crate::stats::{
+    Counters, DropAlert, Heartbeat, KeyHistogram, LatencyHistogram, TypingStats, TypingStatsPublisher,
+};
+use crate::status::{spawn_status_publisher, state_changed, StatusState};
+use crate::xkb::{dvorak_layout, XkbCheck};
+
+/// Keyboards to capture and the device to write remapped events to.
+type Devices = (Vec<BoxedKeyboard>, OutputDevice);
+
+// HANDLED_SIGNALS: The signals `Daemon::handle_signals` takes over: SIGTERM and SIGINT shut
+// down, SIGUSR1 logs a dump of the daemon's state, SIGUSR2 pauses or resumes remapping and
+// SIGHUP reloads the config.
+const HANDLED_SIGNALS: [Signal; 5] =
+    [Signal::SIGTERM, Signal::SIGINT, Signal::SIGUSR1, Signal::SIGUSR2, Signal::SIGHUP];
+
+/// A running (or ready to run) remapper. `run()` blocks; `shutdown()` and `toggle_explain()`
+/// may be called from other threads, or left to the signals with `handle_signals()`.
+pub struct Daemon {
+    config: Config,
+    shutdown_flag: AtomicBool,
+    explain: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    layout: Arc<ActiveLayout>,
+    /// Modifier state shared by every keyboard, sticky keys included.
+    modifiers: Arc<ModifierState>,
+    /// The config as last reloaded; `config` keeps the one the daemon started with.
+    live: Arc<LiveConfig>,
+    /// Stops the event loop and the other threads while `run()` runs, so `shutdown()` takes
+    /// effect at once.
+    stop: Mutex<Option<Arc<Shutdown>>>,
+    /// The signals the daemon handles itself, if `handle_signals()` was called.
+    signals: Option<Signals>,
+}
+
+/// Signals read from a signalfd by the event loop, rather than by a handler.
+struct Signals {
+    fd: SignalFd,
+    /// Called on SIGHUP.
+    reload: Box<dyn Fn(&Daemon) + Send + Sync>,
+}
+
+impl Daemon {
+    pub fn new(config: Config) -> Self {
+        let explain = Arc::new(AtomicBool::new(config.explain));
+        let modifiers = Arc::new(ModifierState::default());
+        modifiers.set_sticky_keys(config.sticky_keys);
+        Daemon {
+            shutdown_flag: AtomicBool::new(false),
+            explain,
+            paused: Arc::new(AtomicBool::new(false)),
+            counters: Arc::new(Counters::default()),
+            // Replaced by the configured layout when run() starts.
+            layout: Arc::new(ActiveLayout::new(Arc::new(Dvorak), config.keys.clone())),
+            modifiers,
+            live: Arc::new(LiveConfig::new(config.clone())),
+            stop: Mutex::new(None),
+            signals: None,
+            config,
+        }
+    }
+
+    /// Has the daemon handle SIGTERM and SIGINT (shutting down), SIGUSR1 (logging a dump of
+    /// its state), SIGUSR2 (pausing or resuming remapping) and SIGHUP (calling `reload`)
+    /// itself, from the event loop. The signals are blocked for the calling thread and any it
+    /// starts from now on, so call this before the process starts other threads, or they may
+    /// still be killed by them.
+    pub fn handle_signals(&mut self, reload: impl Fn(&Daemon) + Send + Sync + 'static) -> nix::Result<()> {
+        let mut mask = SigSet::empty();
+        for signal in HANDLED_SIGNALS {
+            mask.add(signal);
+        }
+        let fd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+        mask.thread_block()?;
+        self.signals = Some(Signals { fd, reload: Box::new(reload) });
+        Ok(())
+    }
+
+    /// Acts on the signals waiting to be read, if the daemon handles them. `event_loop` is the
+    /// running loop, if it is running yet.
+    fn dispatch_signals(&self, event_loop: Option<&EventLoop>) {
+        let Some(signals) = &self.signals else {
+            return;
+        };
+        while let Ok(Some(info)) = signals.fd.read_signal() {
+            match Signal::try_from(info.ssi_signo as i32) {
+                Ok(Signal::SIGTERM | Signal::SIGINT) => self.shutdown(),
+                Ok(Signal::SIGUSR1) => self.dump_state(event_loop),
+                Ok(Signal::SIGUSR2) => self.set_paused(!self.is_paused()),
+                Ok(Signal::SIGHUP) => (signals.reload)(self),
+                _ => {}
+            }
+        }
+    }
+
+    /// Logs what the daemon is doing, to see where a stuck key is stuck without a restart.
+    fn dump_state(&self, event_loop: Option<&EventLoop>) {
+        let state = match event_loop {
+            Some(event_loop) => event_loop.state_dump(),
+            None => "waiting for keyboards and /dev/uinput".to_string(),
+        };
+        info!(
+            "State dump:\nlayout: {}\nexplain trace: {}\n{}",
+            self.layout.name(),
+            if self.explain.load(Ordering::Relaxed) { "on" } else { "off" },
+            state
+        );
+    }
+
+    /// Waits for `timeout`, or less if a signal the daemon handles arrives first.
+    fn wait(&self, timeout: Duration) {
+        let Some(signals) = &self.signals else {
+            std::thread::sleep(timeout);
+            return;
+        };
+        let mut fds = [PollFd::new(signals.fd.as_fd(), PollFlags::POLLIN)];
+        let _ = poll(&mut fds, PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX));
+        self.dispatch_signals(None);
+    }
+
+    /// Asks `run()` to release the keyboards and return.
+    pub fn shutdown(&self) {
+        self.shutdown_flag.store(true, Ordering::Relaxed);
+        if let Some(stop) = &*self.stop.lock().unwrap() {
+            stop.request();
+        }
+    }
+
+    /// Turns the explain trace on or off.
+    pub fn toggle_explain(&self) {
+        set_explain(&self.explain, !self.explain.load(Ordering::Relaxed));
+    }
+
+    /// Pauses or resumes remapping. While paused, keys are passed through unmapped; keys held
+    /// down at the time finish as they started.
+    pub fn set_paused(&self, paused: bool) {
+        set_paused(&self.paused, paused);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Turns sticky keys on or off for every keyboard.
+    pub fn set_sticky_keys(&self, enabled: bool) {
+        set_sticky_keys(&self.modifiers, enabled);
+    }
+
+    pub fn sticky_keys(&self) -> bool {
+        self.modifiers.sticky_keys()
+    }
+
+    /// The names of the keyboards grabbed now.
+    pub fn grabbed_keyboards(&self) -> Vec<String> {
+        self.counters.grabbed_names.lock().unwrap().clone()
+    }
+
+    /// Switches every keyboard to the layout registered (or with a layout file) as `name`.
+    /// Keys held down at the time keep their output until released.
+    pub fn set_layout(&self, name: &str) -> Result<(), ConfigError> {
+        self.layout.select(name)?;
+        info!("Switched to the {} layout", name);
+        Ok(())
+    }
+
+    /// Applies a changed config without releasing the keyboards or the virtual keyboard. Each
+    /// keyboard switches to the new mappings once none of its keys are held. Settings that
+    /// decide which devices are opened, and which background threads run, are logged as
+    /// needing a restart. If the new layout can't be found, nothing changes.
+    pub fn reload(&self, config: Config) -> Result<(), ConfigError> {
+        let previous = self.live.current();
+        check_device_layouts(&config)?;
+        for lint in lint(&config) {
+            warn!("Keymap: {}", lint);
+        }
+        // Keep a layout switched to at runtime unless the config names a different one now.
+        if config.layout != previous.layout {
+            self.layout.select(&config.layout)?;
+            info!("Switched to the {} layout", config.layout);
+        }
+        if config.keys != previous.keys {
+            self.layout.set_overrides(config.keys.clone());
+        }
+        if config.explain != previous.explain && config.explain != self.explain.load(Ordering::Relaxed) {
+            self.toggle_explain();
+        }
+        if config.sticky_keys != previous.sticky_keys {
+            self.set_sticky_keys(config.sticky_keys);
+        }
+        if config.log_level != previous.log_level {
+            match logging::set_filter(&config.log_level.to_string()) {
+                Ok(()) => info!("Logging {} and above", config.log_level),
+                Err(e) => warn!("Failed to change the log level: {}", e),
+            }
+        }
+        let restart_needed = [
+            ("typing_stats", config.typing_stats != previous.typing_stats),
+            ("key_histogram", config.key_histogram != previous.key_histogram),
+            ("latency_histogram", config.latency_histogram != previous.latency_histogram),
+            ("heartbeat_minutes", config.heartbeat_minutes != previous.heartbeat_minutes),
+            (
+                "drop alert",
+                (config.drop_alert_threshold, config.drop_alert_window)
+                    != (previous.drop_alert_threshold, previous.drop_alert_window),
+            ),
+            ("device selection", config.devices != previous.devices),
+            ("device_helper", config.device_helper != previous.device_helper),
+            ("polkit_helper", config.polkit_helper != previous.polkit_helper),
+            ("key repeat", config.kernel_repeat() != previous.kernel_repeat()),
+            ("virtual_keyboard", config.virtual_keyboard != previous.virtual_keyboard),
+            ("thread scheduling", config.scheduling != previous.scheduling),
+            ("lock_memory", config.lock_memory != previous.lock_memory),
+            ("--record-events", config.record_events != previous.record_events),
+            (
+                "game_apps",
+                self.config.game_apps.is_empty() && previous.game_apps.is_empty() && !config.game_apps.is_empty(),
+            ),
+            #[cfg(feature = "portal")]
+            ("portal", config.portal != previous.portal),
+            #[cfg(feature = "prometheus")]
+            ("metrics_port", config.metrics_port != previous.metrics_port),
+        ];
+        for (setting, changed) in restart_needed {
+            if changed {
+                warn!("Changes to {} take effect when the daemon restarts", setting);
+            }
+        }
+        self.live.replace(config);
+        info!("Reloaded the config");
+        Ok(())
+    }
+
+    /// Remaps until `shutdown()` is called or an error stops the daemon. Errors are logged as
+    /// they happen; the one returned is the error that stopped the daemon.
+    pub fn run(&self) -> Result<(), DaemonError> {
+        self.layout.select(&self.config.layout).map_err(report)?;
+        check_device_layouts(&self.config).map_err(report)?;
+        info!("Using the {} layout", self.layout.name());
+        if self.config.xkb_check != XkbCheck::Off
+            && self.layout.name() != QWERTY
+            && let Some((source, layout)) = dvorak_layout()
+        {
+            warn!(
+                "{} has a Dvorak layout, {}, but the daemon expects the desktop to type QWERTY, so letters will be \
+                 remapped twice. Set the desktop to a QWERTY layout, or xkb_check = \"off\" if this is intended",
+                source, layout
+            );
+            if self.config.xkb_check == XkbCheck::Pause {
+                set_paused(&self.paused, true);
+            }
+        }
+
+        let Some((keyboards, output)) = self.wait_for_devices()? else {
+            info!("Shutdown requested before devices were ready");
+            return Ok(());
+        };
+        let keyboard_count = keyboards.len();
+        info!("Found {} keyboard devices", keyboard_count);
+        info!("Created output device");
+
+        // A ring buffer of frames for each keyboard (bounded to prevent memory issues)
+        let (tx, mut rx) = rings::<QueuedFrame>(EVENT_BUFFER_SIZE);
+
+        #[cfg(feature = "otel")]
+        let telemetry = match crate::telemetry::Telemetry::init() {
+            Ok(telemetry) => {
+                info!("Exporting pipeline spans via OTLP");
+                Some(Arc::new(telemetry))
+            }
+            Err(e) => {
+                warn!("Failed to set up OTLP span export: {e}");
+                None
+            }
+        };
+
+        // Typing statistics are opt-in; the tray picks them up from the runtime directory.
+        let typing_stats = self
+            .config
+            .typing_stats
+            .then(|| Arc::new(Mutex::new(TypingStats::default())));
+        let key_histogram = self
+            .config
+            .key_histogram
+            .then(|| Arc::new(Mutex::new(KeyHistogram::default())));
+        let latency_histogram = self
+            .config
+            .latency_histogram
+            .then(|| Arc::new(Mutex::new(LatencyHistogram::default())));
+        // Cleared while another session is active on the seat; see `logind`.
+        let session_active = Arc::new(AtomicBool::new(true));
+        // Set while a game is running; see `game`.
+        let game_running = Arc::new(AtomicBool::new(false));
+
+        // Every event read is recorded for `--replay` when asked for; see `replay`.
+        let recorder = match &self.config.record_events {
+            Some(path) => match EventRecorder::create(path) {
+                Ok(recorder) => {
+                    warn!("Recording every key typed, passwords included, to {}", path.display());
+                    Some(recorder)
+                }
+                Err(e) => {
+                    warn!("Failed to create {}; events will not be recorded: {}", path.display(), e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let shutdown = Arc::new(Shutdown::new().map_err(DeviceError::EventLoop).map_err(report)?);
+        *self.stop.lock().unwrap() = Some(shutdown.clone());
+        // `shutdown()` may have been called while the devices were opened.
+        if self.shutdown_flag.load(Ordering::Relaxed) {
+            shutdown.request();
+        }
+
+        let mut event_loop = EventLoop::new(
+            Capture {
+                tx,
+                paused: self.paused.clone(),
+                session_active: session_active.clone(),
+                game_running: game_running.clone(),
+                counters: self.counters.clone(),
+                explain: self.explain.clone(),
+                typing_stats: typing_stats.clone(),
+                key_histogram: key_histogram.clone(),
+                config: self.live.clone(),
+                layout: self.layout.clone(),
+                modifiers: self.modifiers.clone(),
+                feedback: Feedback::default(),
+                recorder,
+                #[cfg(feature = "otel")]
+                telemetry: telemetry.clone(),
+                #[cfg(feature = "fault-injection")]
+                opened: 0,
+            },
+            shutdown.clone(),
+        )
+        .map_err(report)?;
+
+        // The LED and repeat settings applications send to the virtual keyboard are copied to
+        // the keyboards.
+        match &output {
+            OutputDevice::Uinput(keyboard) => {
+                let watched = keyboard.try_clone_file().and_then(|file| Ok(event_loop.watch_feedback(file)?));
+                if let Err(e) = watched {
+                    warn!("Failed to read LED and repeat changes; keyboards will not follow them: {}", e);
+                }
+            }
+            #[cfg(feature = "portal")]
+            OutputDevice::Portal(_) => {}
+        }
+
+        let shutdown_writer = shutdown.clone();
+        let counters_writer = self.counters.clone();
+        #[cfg(feature = "otel")]
+        let telemetry_writer = telemetry.clone();
+        #[cfg(feature = "fault-injection")]
+        if let Some(config) = &self.config.faults {
+            warn!("Fault injection enabled: {config:?}");
+        }
+        #[cfg(feature = "fault-injection")]
+        let output = crate::faults::FaultyWriter::new(output, self.config.faults.clone());
+        let scheduling = self.config.scheduling.clone();
+        let latency_writer = latency_histogram.clone();
+        let writer_handle = std::thread::spawn(move || {
+            schedule("uinput writer", &scheduling);
+            let mut writer = output;
+            match run_writer(
+                &mut writer,
+                &mut rx,
+                &counters_writer,
+                latency_writer.as_deref(),
+                #[cfg(feature = "otel")]
+                telemetry_writer.as_deref(),
+                std::thread::sleep,
+            ) {
+                Ok(()) => None,
+                Err(e) => {
+                    // Nothing can be remapped without the writer, so stop the event loop too.
+                    shutdown_writer.request();
+                    Some(report(e))
+                }
+            }
+        });
+
+        if let Some(publisher) = typing_stats.clone().and_then(TypingStatsPublisher::new) {
+            event_loop.every(publisher);
+        }
+        let control = ControlState {
+            explain: self.explain.clone(),
+            key_histogram: key_histogram.clone(),
+            latency_histogram: latency_histogram.clone(),
+            paused: self.paused.clone(),
+            layout: self.layout.clone(),
+            modifiers: self.modifiers.clone(),
+            counters: self.counters.clone(),
+            live: self.live.clone(),
+        };
+        #[cfg(not(feature = "tokio"))]
+        let control_handle = crate::control::spawn_control_server(control, shutdown.clone());
+        #[cfg(feature = "tokio")]
+        event_loop.spawn(crate::control::serve_control(control, shutdown.clone()));
+
+        let status_handle = spawn_status_publisher(
+            StatusState {
+                layout: self.layout.clone(),
+                paused: self.paused.clone(),
+                counters: self.counters.clone(),
+            },
+            shutdown.clone(),
+        );
+        #[cfg(feature = "prometheus")]
+        let metrics_handle = (self.config.metrics_port > 0)
+            .then(|| {
+                crate::metrics::spawn_metrics_server(
+                    self.config.metrics_port,
+                    self.counters.clone(),
+                    latency_histogram.clone(),
+                    shutdown.clone(),
+                )
+            })
+            .flatten();
+
+        #[cfg(feature = "dbus")]
+        let bus_handle = crate::bus::spawn_bus_service(
+            crate::bus::BusState {
+                layout: self.layout.clone(),
+                paused: self.paused.clone(),
+                counters: self.counters.clone(),
+            },
+            shutdown.clone(),
+        );
+        #[cfg(feature = "dbus")]
+        let session_handle = crate::logind::spawn_session_watcher(session_active, shutdown.clone());
+
+        if self.explain.load(Ordering::Relaxed) {
+            warn!("Explain trace enabled: every key event is written to the log");
+        }
+
+        for device in keyboards {
+            event_loop.add(device);
+        }
+        // Keyboards opened directly can be joined by ones plugged in later; those handed over
+        // by a helper or the portal can't.
+        if self.opens_keyboards_directly()
+            && let Err(e) = UdevMonitor::new()
+                .and_then(|monitor| event_loop.watch_hotplug(monitor, self.config.devices.clone()))
+        {
+            warn!("Failed to watch for new keyboards; only those present now are remapped: {}", e);
+        }
+
+        if self.config.heartbeat_minutes > 0 {
+            event_loop.every(Heartbeat {
+                interval: Duration::from_secs(self.config.heartbeat_minutes * 60),
+                counters: self.counters.clone(),
+            });
+        }
+
+        let game_handle = (!self.config.game_apps.is_empty())
+            .then(|| spawn_game_watcher(self.live.clone(), game_running, shutdown.clone()));
+
+        if self.config.drop_alert_threshold > 0 {
+            event_loop.every(DropAlert::new(
+                self.config.drop_alert_threshold,
+                self.config.drop_alert_window,
+                self.counters.clone(),
+            ));
+        }
+
+        notify(&format!("READY=1\nSTATUS=Remapping {} keyboards", keyboard_count));
+
+        if let Some(signals) = &self.signals
+            && let Err(e) = event_loop.watch_signals(&signals.fd)
+        {
+            warn!("Failed to watch for signals; SIGTERM, SIGUSR1, SIGUSR2 and SIGHUP are ignored: {}", e);
+        }
+        schedule("event loop", &self.config.scheduling);
+        // Everything the loop and the writer need is in place by now.
+        if self.config.lock_memory {
+            lock_memory();
+        }
+        let fatal = event_loop.run(watchdog_interval(), |event_loop| {
+            self.dispatch_signals(Some(event_loop))
+        });
+        // Stop the other threads too, whatever ended the loop.
+        let requested = self.shutdown_flag.swap(true, Ordering::Relaxed);
+        shutdown.request();
+        *self.stop.lock().unwrap() = None;
+        notify("STOPPING=1");
+
+        // The event loop has released the keyboards and dropped its sender, so the writer
+        // drains what is queued and exits.
+        let writer_error = writer_handle.join().ok().flatten();
+        #[cfg(not(feature = "tokio"))]
+        if let Some(handle) = control_handle {
+            let _ = handle.join();
+        }
+        if let Some(handle) = status_handle {
+            let _ = handle.join();
+        }
+        #[cfg(feature = "prometheus")]
+        if let Some(handle) = metrics_handle {
+            let _ = handle.join();
+        }
+        #[cfg(feature = "dbus")]
+        let _ = bus_handle.join();
+        #[cfg(feature = "dbus")]
+        let _ = session_handle.join();
+        if let Some(handle) = game_handle {
+            let _ = handle.join();
+        }
+        #[cfg(feature = "otel")]
+        if let Some(telemetry) = &telemetry {
+            telemetry.shutdown();
+        }
+
+        if let Some(error) = fatal.or(writer_error) {
+            return Err(error);
+        }
+        // If we weren't asked to shut down but we got here, every keyboard is gone.
+        if !requested {
+            return Err(report(DeviceError::AllDevicesLost));
+        }
+        Ok(())
+    }
+
+    /// Whether the keyboards are opened from /dev/input by this process.
+    fn opens_keyboards_directly(&self) -> bool {
+        #[cfg(feature = "portal")]
+        if self.config.portal {
+            return false;
+        }
+        self.config.device_helper.is_none()
+    }
+
+    /// Waits for keyboard devices + uinput to become available. Returns None if shutdown was
+    /// requested first.
+    fn wait_for_devices(&self) -> Result<Option<Devices>, DaemonError> {
+        notify("STATUS=Waiting for keyboards and /dev/uinput");
+        // Cleared once the user declines, so they are not asked again on every retry.
+        let mut polkit_helper = self.config.polkit_helper.as_deref();
+        loop {
+            if self.shutdown_flag.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+
+            let error = match self.open_devices(&mut polkit_helper) {
+                Ok(devices) => {
+                    logging::flush();
+                    return Ok(Some(devices));
+                }
+                Err(e) => e,
+            };
+            match handle_error(&error) {
+                Recovery::Exit(_) => return Err(error),
+                Recovery::Retry | Recovery::DropDevice => self.wait(STARTUP_RETRY_INTERVAL),
+            }
+        }
+    }
+
+    /// Opens the keyboards and the output device. Forgets `polkit_helper` if the user declines
+    /// to run it.
+    fn open_devices(&self, polkit_helper: &mut Option<&Path>) -> Result<Devices, DaemonError> {
+        #[cfg(feature = "portal")]
+        if self.config.portal {
+            let output = crate::portal::RemoteDesktop::connect()?;
+            let keyboard = crate::portal::capture_keyboard()?;
+            return Ok((vec![Box::new(keyboard)], OutputDevice::Portal(output)));
+        }
+        let found = self.find_keyboards(*polkit_helper);
+        if matches!(found, Err(DeviceError::PolkitDenied)) {
+            *polkit_helper = None;
+        }
+        let keyboards = found?;
+        let output = create_uinput_device(&self.config.virtual_keyboard, self.config.kernel_repeat())?;
+        Ok((keyboards, OutputDevice::Uinput(output)))
+    }
+
+    /// Opens the keyboards directly, through the device helper if one is configured, or
+    /// through `polkit_helper` if the keyboards exist but may not be opened.
+    fn find_keyboards(&self, polkit_helper: Option<&Path>) -> Result<Vec<BoxedKeyboard>, DeviceError> {
+        fn boxed<K: KeyboardSource + 'static>(keyboards: Vec<K>) -> Vec<BoxedKeyboard> {
+            keyboards.into_iter().map(|k| Box::new(k) as BoxedKeyboard).collect()
+        }
+        // The helpers pass every keyboard; dropping the unwanted ones releases their grab.
+        let filter = |keyboards: Vec<PassedKeyboard>| {
+            let keyboards: Vec<_> = keyboards
+                .into_iter()
+                .filter(|keyboard| {
+                    let name = keyboard.name();
+                    let rejected = match keyboard.id() {
+                        Ok(id) => self.config.devices.rejects(&name, id),
+                        Err(_) => Some("its ID could not be read"),
+                    };
+                    if let Some(reason) = rejected {
+                        debug!("Not grabbing {}: {}", name, reason);
+                    }
+                    rejected.is_none()
+                })
+                .collect();
+            if keyboards.is_empty() {
+                return Err(DeviceError::NoKeyboards);
+            }
+            Ok(boxed(keyboards))
+        };
+        if let Some(path) = &self.config.device_helper {
+            return acquire_keyboards(path).and_then(filter);
+        }
+        match (find_keyboards(&self.config.devices), polkit_helper) {
+            (Err(DeviceError::NoKeyboards), Some(path)) if input_access_denied() => {
+                info!("Keyboards are not readable; asking polkit to run {}", path.display());
+                acquire_keyboards_via_polkit(path).and_then(filter)
+            }
+            (found, _) => found.map(boxed),
+        }
+    }
+}
+
+/// The config the keyboards build their pipelines from, replaced by
+/// [`Daemon::reload`]. The generation counts replacements, so a thread can tell cheaply
+/// whether its pipeline is out of date.
+pub(crate) struct LiveConfig {
+    generation: AtomicU64,
+    config: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    fn new(config: Config) -> Self {
+        LiveConfig { generation: AtomicU64::new(0), config: RwLock::new(Arc::new(config)) }
+    }
+
+    pub(crate) fn current(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub(crate) fn current_generation(&self) -> (u64, Arc<Config>) {
+        let config = self.config.read().unwrap();
+        (self.generation.load(Ordering::Relaxed), config.clone())
+    }
+
+    /// The current config and its generation, if it has been replaced since `generation`.
+    pub(crate) fn newer_than(&self, generation: u64) -> Option<(u64, Arc<Config>)> {
+        if self.generation.load(Ordering::Relaxed) == generation {
+            return None;
+        }
+        Some(self.current_generation())
+    }
+
+    fn replace(&self, config: Config) {
+        let mut current = self.config.write().unwrap();
+        *current = Arc::new(config);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Checks what loading `config` alone doesn't: that its layout and every layout in
+/// `device_layouts` can be found and loaded, as `--check-config` reports before starting.
+/// Returns what the keymap linter finds, which is worth a warning but no reason not to start.
+pub fn check_config(config: &Config) -> Result<Vec<Lint>, ConfigError> {
+    find(&config.layout)?;
+    check_device_layouts(config)?;
+    Ok(lint(config))
+}
+
+/// Checks that every layout in `device_layouts` can be found.
+fn check_device_layouts(config: &Config) -> Result<(), ConfigError> {
+    for (_, name) in &config.device_layouts {
+        find(name)?;
+    }
+    Ok(())
+}
+
+/// The layout the keyboard called `name` types with: its own if `device_layouts` gives it
+/// one, otherwise `shared`, which follows layout switches.
+pub(crate) fn keyboard_layout(
+    config: &Config,
+    shared: &Arc<ActiveLayout>,
+    name: &str,
+    id: Option<DeviceId>,
+) -> Arc<ActiveLayout> {
+    let Some(layout_name) = config.device_layout(name, id) else {
+        return shared.clone();
+    };
+    match find(layout_name) {
+        Ok(layout) => {
+            info!("{} types with its own layout, {}", name, layout_name);
+            Arc::new(ActiveLayout::new(layout, config.keys.clone()))
+        }
+        Err(e) => {
+            warn!("{}: {}; using the {} layout", name, e, shared.name());
+            shared.clone()
+        }
+    }
+}
+
+/// Sets the pause flag shared with the event loop, logging only actual changes.
+pub(crate) fn set_paused(flag: &AtomicBool, paused: bool) {
+    if flag.swap(paused, Ordering::Relaxed) != paused {
+        if paused {
+            info!("Remapping paused; keys are passed through unmapped");
+        } else {
+            info!("Remapping resumed");
+        }
+        state_changed();
+    }
+}
+
+/// Turns the explain trace on or off, logging only actual changes.
+pub(crate) fn set_explain(flag: &AtomicBool, enabled: bool) {
+    if flag.swap(enabled, Ordering::Relaxed) != enabled {
+        if enabled {
+            warn!("Explain trace enabled: every key event is written to the log");
+        } else {
+            info!("Explain trace disabled");
+        }
+    }
+}
+
+/// Turns sticky keys on or off, logging only actual changes.
+pub(crate) fn set_sticky_keys(modifiers: &ModifierState, enabled: bool) {
+    if modifiers.set_sticky_keys(enabled) != enabled {
+        if enabled {
+            info!("Sticky keys on; a tapped modifier applies to the next key");
+        } else {
+            info!("Sticky keys off");
+        }
+    }
+}
+
+/// Logs an error that stops the daemon and hands it back for `run()` to return.
+fn report(error: impl Into<DaemonError>) -> DaemonError {
+    let error = error.into();
+    handle_error(&error);
+    error
+}
@@ -0,0 +1,104 @@
+//! Debouncing for worn key switches that chatter: a key released and pressed again within a
+//! few milliseconds is taken to have stayed down.
+//!
+//! A release is held back for the key's debounce window. If the same key is pressed again in
+//! that time, both are dropped; otherwise the release goes out when the window ends, or as
+//! soon as another key's event arrives, so events keep their order.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use evdev::Key;
+
+use crate::pipeline::{KeyEvent, Stage};
+
+/// First stage: drops release and press pairs closer together than the debounce window.
+pub struct Debounce {
+    window: Duration,
+    /// Windows that differ from `window`, by code; zero turns debouncing off for the key.
+    per_key: HashMap<u16, Duration>,
+    /// A release held back, and when its window ends.
+    pending: Option<(KeyEvent, Instant)>,
+}
+
+impl Debounce {
+    pub fn new(window: Duration, per_key: &[(Key, Duration)]) -> Self {
+        let per_key = per_key.iter().map(|&(key, window)| (key.code(), window)).collect();
+        Debounce { window, per_key, pending: None }
+    }
+
+    fn window(&self, code: u16) -> Duration {
+        self.per_key.get(&code).copied().unwrap_or(self.window)
+    }
+}
+
+impl Stage for Debounce {
+    fn process(&mut self, event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        if let Some((release, deadline)) = self.pending.take() {
+            if release.code == event.code && event.value == 1 && Instant::now() < deadline {
+                // The key chattered: it never really went up.
+                return;
+            }
+            out.push(release);
+        }
+        let window = self.window(event.code);
+        if event.value == 0 && !window.is_zero() {
+            self.pending = Some((event, Instant::now() + window));
+            return;
+        }
+        out.push(event);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|(_, deadline)| deadline)
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<KeyEvent>) {
+        if self.pending.is_some_and(|(_, deadline)| deadline <= now) {
+            out.extend(self.pending.take().map(|(release, _)| release));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{run, tick};
+
+    const A: u16 = Key::KEY_A.code();
+    const B: u16 = Key::KEY_B.code();
+    const SPACE: u16 = Key::KEY_SPACE.code();
+
+    // A window far longer than the test takes, so a press sent straight after a release always
+    // falls inside it.
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn a_release_and_press_within_the_window_are_dropped() {
+        let mut stage = Debounce::new(WINDOW, &[]);
+        assert_eq!(run(&mut stage, &[(A, 1), (A, 0), (A, 1)]), [(A, 1)]);
+        assert_eq!(stage.deadline(), None);
+        assert_eq!(run(&mut stage, &[(A, 0)]), []);
+        let deadline = stage.deadline().expect("the release is held back");
+        assert_eq!(tick(&mut stage, deadline - Duration::from_millis(1)), []);
+        assert_eq!(tick(&mut stage, deadline), [(A, 0)]);
+        assert_eq!(stage.deadline(), None);
+    }
+
+    #[test]
+    fn another_key_lets_a_held_back_release_through_in_order() {
+        let mut stage = Debounce::new(WINDOW, &[]);
+        assert_eq!(run(&mut stage, &[(A, 1), (A, 0), (B, 1)]), [(A, 1), (A, 0), (B, 1)]);
+    }
+
+    #[test]
+    fn debounce_keys_override_the_window_per_key() {
+        let mut stage = Debounce::new(Duration::ZERO, &[(Key::KEY_A, WINDOW), (Key::KEY_SPACE, Duration::ZERO)]);
+        assert_eq!(run(&mut stage, &[(B, 1), (B, 0), (B, 1)]), [(B, 1), (B, 0), (B, 1)]);
+        assert_eq!(run(&mut stage, &[(A, 1), (A, 0), (A, 1)]), [(A, 1)]);
+
+        let mut stage = Debounce::new(WINDOW, &[(Key::KEY_SPACE, Duration::ZERO)]);
+        assert_eq!(run(&mut stage, &[(SPACE, 1), (SPACE, 0), (SPACE, 1)]), [(SPACE, 1), (SPACE, 0), (SPACE, 1)]);
+        assert_eq!(stage.deadline(), None);
+    }
+}
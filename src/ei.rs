@@ -0,0 +1,249 @@
+//! A minimal receiver for the libei protocol, which the InputCapture portal uses to deliver
+//! captured input.
+//!
+//! Only what a keyboard receiver needs is implemented: the handshake, binding the keyboard
+//! capability of each seat, answering pings, and turning `ei_keyboard.key` and
+//! `ei_device.frame` into key and SYN_REPORT events. Opcodes follow version 1 of `ei.xml`.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+
+use evdev::{EventType, InputEvent};
+use tracing::{debug, warn};
+
+use crate::source::KeyboardSource;
+
+// Message header: object id (u64), message length including the header (u32), opcode (u32).
+const HEADER_LEN: usize = 16;
+// The handshake object always has id 0.
+const HANDSHAKE_ID: u64 = 0;
+// ei_handshake.context_type: we receive events rather than emulate them.
+const CONTEXT_RECEIVER: u32 = 1;
+// Interfaces announced in the handshake, all at version 1.
+const INTERFACES: &[&str] = &["ei_connection", "ei_callback", "ei_pingpong", "ei_seat", "ei_device", "ei_keyboard"];
+
+/// What a server-created object id refers to.
+enum Object {
+    Handshake,
+    Connection,
+    Seat { keyboard_mask: u64 },
+    Device,
+    Keyboard,
+    Other,
+}
+
+/// The receiving end of an EIS connection, read as a keyboard by the event loop.
+pub struct EiKeyboard {
+    name: String,
+    fd: OwnedFd,
+    buffer: Vec<u8>,
+    objects: HashMap<u64, Object>,
+}
+
+impl EiKeyboard {
+    pub fn new(name: String, fd: OwnedFd) -> Self {
+        EiKeyboard {
+            name,
+            fd,
+            buffer: Vec::new(),
+            objects: HashMap::from([(HANDSHAKE_ID, Object::Handshake)]),
+        }
+    }
+
+    fn send(&self, object: u64, opcode: u32, args: &[Arg]) -> io::Result<()> {
+        let mut message = Vec::with_capacity(HEADER_LEN);
+        message.extend_from_slice(&object.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&opcode.to_ne_bytes());
+        for arg in args {
+            match arg {
+                Arg::U32(value) => message.extend_from_slice(&value.to_ne_bytes()),
+                Arg::U64(value) => message.extend_from_slice(&value.to_ne_bytes()),
+                Arg::Str(value) => {
+                    message.extend_from_slice(&(value.len() as u32 + 1).to_ne_bytes());
+                    message.extend_from_slice(value.as_bytes());
+                    message.push(0);
+                    message.resize(message.len().next_multiple_of(4), 0);
+                }
+            }
+        }
+        let len = message.len() as u32;
+        message[8..12].copy_from_slice(&len.to_ne_bytes());
+        let written = nix::unistd::write(&self.fd, &message).map_err(io::Error::from)?;
+        if written != message.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "short write to EIS"));
+        }
+        Ok(())
+    }
+
+    /// Handles one event from the server, appending any key events it carries to `out`.
+    fn dispatch(&mut self, object: u64, opcode: u32, mut args: Args, out: &mut Vec<InputEvent>) -> io::Result<()> {
+        let Some(kind) = self.objects.get_mut(&object) else {
+            // Events can still arrive for objects we have released.
+            return Ok(());
+        };
+        match (kind, opcode) {
+            // ei_handshake.handshake_version: reply with ours and describe this client.
+            (Object::Handshake, 0) => {
+                self.send(HANDSHAKE_ID, 0, &[Arg::U32(1)])?;
+                self.send(HANDSHAKE_ID, 2, &[Arg::U32(CONTEXT_RECEIVER)])?;
+                self.send(HANDSHAKE_ID, 3, &[Arg::Str("qwertdvert")])?;
+                for interface in INTERFACES {
+                    self.send(HANDSHAKE_ID, 4, &[Arg::Str(interface), Arg::U32(1)])?;
+                }
+                self.send(HANDSHAKE_ID, 1, &[])?;
+            }
+            // ei_handshake.connection(serial, connection, version)
+            (Object::Handshake, 2) => {
+                args.u32()?;
+                let connection = args.u64()?;
+                self.objects.insert(connection, Object::Connection);
+            }
+            // ei_connection.disconnected(last_serial, reason, explanation)
+            (Object::Connection, 0) => {
+                args.u32()?;
+                let reason = args.u32()?;
+                let explanation = args.string()?;
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("EIS disconnected (reason {reason}): {explanation}"),
+                ));
+            }
+            // ei_connection.seat(seat, version)
+            (Object::Connection, 1) => {
+                let seat = args.u64()?;
+                self.objects.insert(seat, Object::Seat { keyboard_mask: 0 });
+            }
+            // ei_connection.invalid_object(last_serial, id)
+            (Object::Connection, 2) => {
+                args.u32()?;
+                warn!("EIS reported an invalid object {}", args.u64()?);
+            }
+            // ei_connection.ping(ping, version): answer with ei_pingpong.done.
+            (Object::Connection, 3) => {
+                let ping = args.u64()?;
+                self.send(ping, 0, &[Arg::U64(0)])?;
+            }
+            // ei_seat.capability(mask, interface)
+            (Object::Seat { keyboard_mask }, 2) => {
+                let mask = args.u64()?;
+                if args.string()? == "ei_keyboard" {
+                    *keyboard_mask |= mask;
+                }
+            }
+            // ei_seat.done: bind the keyboard capability.
+            (Object::Seat { keyboard_mask }, 3) => {
+                let mask = *keyboard_mask;
+                self.send(object, 1, &[Arg::U64(mask)])?;
+            }
+            // ei_seat.device(device, version)
+            (Object::Seat { .. }, 4) => {
+                let device = args.u64()?;
+                self.objects.insert(device, Object::Device);
+            }
+            // ei_device.interface(object, interface_name, version)
+            (Object::Device, 5) => {
+                let id = args.u64()?;
+                let interface = args.string()?;
+                let object = if interface == "ei_keyboard" { Object::Keyboard } else { Object::Other };
+                self.objects.insert(id, object);
+            }
+            // ei_device.frame(serial, timestamp)
+            (Object::Device, 11) => out.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0)),
+            // ei_keyboard.key(key, state)
+            (Object::Keyboard, 2) => {
+                let key = args.u32()?;
+                let state = args.u32()?;
+                out.push(InputEvent::new(EventType::KEY, key as u16, state as i32));
+            }
+            // destroyed is opcode 0 on every interface except the handshake and connection.
+            (Object::Seat { .. } | Object::Device | Object::Keyboard | Object::Other, 0) => {
+                self.objects.remove(&object);
+            }
+            _ => debug!("Ignoring EIS event {opcode} on object {object}"),
+        }
+        Ok(())
+    }
+}
+
+enum Arg<'a> {
+    U32(u32),
+    U64(u64),
+    Str(&'a str),
+}
+
+/// Reads the arguments of one event in order.
+struct Args {
+    bytes: Vec<u8>,
+    offset: usize,
+}
+
+impl Args {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        let end = self.offset + len;
+        let slice = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated EIS message"))?;
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_ne_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len.next_multiple_of(4))?;
+        let text = &bytes[..len.saturating_sub(1)];
+        Ok(String::from_utf8_lossy(text).into_owned())
+    }
+}
+
+impl AsRawFd for EiKeyboard {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl KeyboardSource for EiKeyboard {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn grab(&mut self) -> io::Result<()> {
+        // While capture is active the compositor sends input only to us.
+        Ok(())
+    }
+
+    fn fetch_events(&mut self, out: &mut Vec<InputEvent>) -> io::Result<()> {
+        let mut chunk = [0u8; 4096];
+        let read = nix::unistd::read(self.fd.as_raw_fd(), &mut chunk).map_err(io::Error::from)?;
+        if read == 0 {
+            return Err(io::Error::from_raw_os_error(nix::libc::ENODEV));
+        }
+        self.buffer.extend_from_slice(&chunk[..read]);
+
+        while self.buffer.len() >= HEADER_LEN {
+            let object = u64::from_ne_bytes(self.buffer[0..8].try_into().unwrap());
+            let len = u32::from_ne_bytes(self.buffer[8..12].try_into().unwrap()) as usize;
+            let opcode = u32::from_ne_bytes(self.buffer[12..16].try_into().unwrap());
+            if len < HEADER_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid EIS message length"));
+            }
+            if self.buffer.len() < len {
+                break;
+            }
+            let message: Vec<u8> = self.buffer.drain(..len).collect();
+            let args = Args { bytes: message, offset: HEADER_LEN };
+            self.dispatch(object, opcode, args, out)?;
+        }
+        Ok(())
+    }
+}
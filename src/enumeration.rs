@@ -0,0 +1,223 @@
+//! Finding the keyboards to grab.
+//!
+//! A device is a keyboard if it has the letter keys A to Z. That covers laptop (AT) keyboards,
+//! USB and Bluetooth HID keyboards, and the extra key interfaces some keyboards expose, but
+//! not the media-key-only interfaces next to them. Devices that also move a pointer (mice with
+//! macro keys, touchpads, combined receivers) are skipped, as are virtual devices, which
+//! include QwertDvert's own output keyboard and those of other remappers. The rest can be
+//! narrowed down by name and by USB vendor:product ID.
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use evdev::{enumerate, AbsoluteAxisType, BusType, Device, Key, RelativeAxisType};
+use tracing::debug;
+
+use crate::error::DeviceError;
+use crate::output::{OUTPUT_DEVICE_NAME, OUTPUT_DEVICE_PHYS};
+
+/// A USB vendor:product ID, as shown by `lsusb`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceId {
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl FromStr for DeviceId {
+    type Err = String;
+
+    /// Parses `vvvv:pppp` in hex, e.g. `046d:c52b`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{s}' is not a vendor:product ID such as '046d:c52b'");
+        let (vendor, product) = s.split_once(':').ok_or_else(invalid)?;
+        let hex = |part: &str| u16::from_str_radix(part, 16).map_err(|_| invalid());
+        Ok(DeviceId { vendor: hex(vendor)?, product: hex(product)? })
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor, self.product)
+    }
+}
+
+/// One keyboard, or a group of them, picked out by vendor:product ID or by part of the name.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceMatch {
+    Id(DeviceId),
+    Name(String),
+}
+
+impl DeviceMatch {
+    /// An ID if `text` reads as one, otherwise part of a name.
+    pub fn parse(text: &str) -> Self {
+        match text.parse() {
+            Ok(id) => DeviceMatch::Id(id),
+            Err(_) => DeviceMatch::Name(text.to_string()),
+        }
+    }
+
+    pub fn matches(&self, name: &str, id: Option<DeviceId>) -> bool {
+        match self {
+            DeviceMatch::Id(wanted) => id == Some(*wanted),
+            DeviceMatch::Name(part) => name.contains(part.as_str()),
+        }
+    }
+}
+
+/// Which keyboards to grab.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceFilter {
+    /// Grab keyboards whose name contains one of these; an empty list grabs every keyboard.
+    pub names: Vec<String>,
+    /// Grab only keyboards with one of these IDs; an empty list allows any ID.
+    pub include: Vec<DeviceId>,
+    /// Never grab keyboards with one of these IDs.
+    pub exclude: Vec<DeviceId>,
+}
+
+impl DeviceFilter {
+    /// A filter that grabs every keyboard.
+    pub fn all() -> Self {
+        DeviceFilter::default()
+    }
+
+    /// Why a keyboard called `name` with ID `id` should not be grabbed, or None if it should.
+    pub fn rejects(&self, name: &str, id: DeviceId) -> Option<&'static str> {
+        if self.exclude.contains(&id) {
+            Some("its ID is in exclude_devices")
+        } else if !self.include.is_empty() && !self.include.contains(&id) {
+            Some("its ID is not in include_devices")
+        } else if !self.names.is_empty() && !self.names.iter().any(|filter| name.contains(filter.as_str())) {
+            Some("its name does not match device_names")
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `device` is a keyboard `filter` selects.
+pub fn is_keyboard(device: &Device, filter: &DeviceFilter) -> bool {
+    if !has_letters(device) {
+        return false;
+    }
+    let Some(skipped) = skip_reason(device, filter) else {
+        return true;
+    };
+    debug!("Not grabbing {} ({}): {}", device.name().unwrap_or(""), device_id(device), skipped);
+    false
+}
+
+/// Why `device` would not be grabbed with `filter`, or None if it would.
+fn skip_reason(device: &Device, filter: &DeviceFilter) -> Option<&'static str> {
+    if !has_letters(device) {
+        Some("it has no letter keys, so it is not a keyboard")
+    } else if is_virtual(device) {
+        Some("it is a virtual device")
+    } else if moves_pointer(device) {
+        Some("it moves a pointer")
+    } else {
+        filter.rejects(device.name().unwrap_or(""), device_id(device))
+    }
+}
+
+fn device_id(device: &Device) -> DeviceId {
+    DeviceId { vendor: device.input_id().vendor(), product: device.input_id().product() }
+}
+
+/// An input device, and whether it would be grabbed.
+pub struct ListedDevice {
+    pub path: PathBuf,
+    pub name: String,
+    pub id: DeviceId,
+    pub bus_type: BusType,
+    /// Why it would not be grabbed, or None if it would.
+    pub skipped: Option<&'static str>,
+}
+
+/// Every input device this process can open, in /dev/input order, and whether `filter` would
+/// grab it.
+pub fn list_devices(filter: &DeviceFilter) -> Vec<ListedDevice> {
+    let mut devices: Vec<ListedDevice> = enumerate()
+        .map(|(path, device)| ListedDevice {
+            name: device.name().unwrap_or("Unknown").to_string(),
+            id: device_id(&device),
+            bus_type: device.input_id().bus_type(),
+            skipped: skip_reason(&device, filter),
+            path,
+        })
+        .collect();
+    // By event number, so event10 comes after event9.
+    let number = |path: &PathBuf| {
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        name.trim_start_matches(|c: char| !c.is_ascii_digit()).parse::<u32>().unwrap_or(u32::MAX)
+    };
+    devices.sort_by_key(|device| number(&device.path));
+    devices
+}
+
+fn has_letters(device: &Device) -> bool {
+    device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(Key::KEY_A) && keys.contains(Key::KEY_Z))
+}
+
+/// Whether `device` was created by software rather than plugged in.
+fn is_virtual(device: &Device) -> bool {
+    device.input_id().bus_type() == BusType::BUS_VIRTUAL || is_own(device)
+}
+
+/// Whether `device` is QwertDvert's own output keyboard.
+fn is_own(device: &Device) -> bool {
+    device.name() == Some(OUTPUT_DEVICE_NAME) || device.physical_path() == Some(OUTPUT_DEVICE_PHYS)
+}
+
+/// Names of the virtual keyboards other programs have created. A remapper that grabs keyboards
+/// (keyd, kanata, interception tools) types through one, so these name the likely culprits
+/// when a keyboard is already grabbed.
+pub fn other_virtual_keyboards() -> Vec<String> {
+    enumerate()
+        .map(|(_path, device)| device)
+        .filter(|device| has_letters(device) && is_virtual(device) && !is_own(device))
+        .map(|device| device.name().unwrap_or("Unknown").to_string())
+        .collect()
+}
+
+/// Whether `device` is (or includes) a mouse, touchpad or other pointing device.
+fn moves_pointer(device: &Device) -> bool {
+    device
+        .supported_relative_axes()
+        .is_some_and(|axes| axes.contains(RelativeAxisType::REL_X))
+        || device
+            .supported_absolute_axes()
+            .is_some_and(|axes| axes.contains(AbsoluteAxisType::ABS_X))
+        || device
+            .supported_keys()
+            .is_some_and(|keys| keys.contains(Key::BTN_LEFT) || keys.contains(Key::BTN_TOUCH))
+}
+
+/// Returns the keyboards `filter` selects, or an error if none are available (yet).
+pub fn find_keyboards(filter: &DeviceFilter) -> Result<Vec<Device>, DeviceError> {
+    let keyboards: Vec<Device> = enumerate()
+        .map(|(_path, device)| device)
+        .filter(|device| is_keyboard(device, filter))
+        .collect();
+    if keyboards.is_empty() {
+        return Err(DeviceError::NoKeyboards);
+    }
+    Ok(keyboards)
+}
+
+/// Whether some input device exists but this process may not open it, i.e. finding no
+/// keyboards is a permissions problem rather than no keyboards being plugged in.
+pub fn input_access_denied() -> bool {
+    let Ok(entries) = std::fs::read_dir("/dev/input") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        entry.file_name().to_string_lossy().starts_with("event")
+            && std::fs::File::open(entry.path()).is_err_and(|e| e.kind() == ErrorKind::PermissionDenied)
+    })
+}
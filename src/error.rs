@@ -0,0 +1,259 @@
+//! Error types and the recovery policy that decides what happens after each of them.
+
+use tracing::error;
+
+// Exit codes
+// Any of them but EXIT_USAGE makes systemd restart the daemon: the unit has Restart=on-failure,
+// which covers every non-zero code, and RestartPreventExitStatus=2. They tell an OnFailure=
+// unit, or whoever reads `systemctl status`, which part failed.
+// EXIT_FAILURE: the event loop itself, or anything not covered below.
+// EXIT_USAGE: bad arguments or config, where a restart would fail the same way.
+// EXIT_NO_KEYBOARDS: no keyboard could be found, or the device helper failed.
+// EXIT_GRAB: a keyboard could not be grabbed, e.g. because another remapper has it.
+// EXIT_UINPUT: the virtual keyboard could not be created.
+// EXIT_DEVICES_LOST: every keyboard went away or stopped being readable.
+// EXIT_WRITE_FAILED: writes to the virtual keyboard kept failing.
+// EXIT_PORTAL: the desktop portals refused or failed (`--portal`).
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_NO_KEYBOARDS: i32 = 3;
+pub const EXIT_GRAB: i32 = 4;
+pub const EXIT_UINPUT: i32 = 5;
+pub const EXIT_DEVICES_LOST: i32 = 6;
+pub const EXIT_WRITE_FAILED: i32 = 7;
+pub const EXIT_PORTAL: i32 = 8;
+
+// Startup robustness
+// On some desktops, uaccess ACLs for /dev/input and /dev/uinput may be applied shortly
+// after the user session starts. If we enumerate devices too early, we can see zero
+// devices and would otherwise exit successfully, leaving only the tray running.
+pub(crate) const STARTUP_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Invalid command-line arguments or settings.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{flag}: {reason}")]
+    InvalidValue { flag: &'static str, reason: String },
+    #[error("Unknown layout '{name}' (registered: {registered})")]
+    UnknownLayout { name: String, registered: String },
+    #[error("Failed to read config file {}: {source}", path.display())]
+    ReadFile { path: std::path::PathBuf, source: std::io::Error },
+    #[error("Invalid config file {}: {reason}", path.display())]
+    ParseFile { path: std::path::PathBuf, reason: String },
+    #[error("Failed to write {}: {source}", path.display())]
+    WriteFile { path: std::path::PathBuf, source: std::io::Error },
+}
+
+/// Failures finding, grabbing, or reading a source keyboard.
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceError {
+    #[error("No compatible keyboard devices available yet")]
+    NoKeyboards,
+    #[error("Failed to grab keyboard device {device}: {source}")]
+    Grab { device: String, source: std::io::Error },
+    #[error("Keyboard device {device} is grabbed by another program{}", grabbed_by(.virtual_keyboards))]
+    Busy { device: String, virtual_keyboards: Vec<String> },
+    #[error("Failed to set up epoll for {device}: {source}")]
+    Epoll { device: String, source: nix::Error },
+    #[error("Failed to fetch events from device {device}: {source}")]
+    Read { device: String, source: std::io::Error },
+    #[error("Uinput writer stopped accepting events from {device}")]
+    WriterGone { device: String },
+    #[error("Failed to set up the event loop: {0}")]
+    EventLoop(nix::Error),
+    #[error("All keyboards are gone; exiting so systemd can restart")]
+    AllDevicesLost,
+    #[error("Device helper failed: {0}")]
+    Helper(String),
+    #[error("Polkit did not authorize the device helper; waiting for /dev/input access instead")]
+    PolkitDenied,
+}
+
+/// Who the other virtual keyboards suggest has grabbed a keyboard, for [`DeviceError::Busy`].
+fn grabbed_by(virtual_keyboards: &[String]) -> String {
+    match virtual_keyboards {
+        [] => String::new(),
+        names => format!(", probably the one that created {}", names.join(", ")),
+    }
+}
+
+impl DeviceError {
+    fn device(&self) -> Option<&str> {
+        match self {
+            DeviceError::NoKeyboards
+            | DeviceError::AllDevicesLost
+            | DeviceError::EventLoop(_)
+            | DeviceError::Helper(_)
+            | DeviceError::PolkitDenied => None,
+            DeviceError::Grab { device, .. }
+            | DeviceError::Busy { device, .. }
+            | DeviceError::Epoll { device, .. }
+            | DeviceError::Read { device, .. }
+            | DeviceError::WriterGone { device } => Some(device),
+        }
+    }
+}
+
+/// Failures creating or writing to the virtual uinput keyboard.
+#[derive(Debug, thiserror::Error)]
+pub enum OutputError {
+    #[error("Failed to open /dev/uinput: {0}")]
+    Open(std::io::Error),
+    #[error("Failed to configure uinput keyboard events: {0}")]
+    Configure(nix::Error),
+    #[error("Failed to create uinput device: {0}")]
+    Create(nix::Error),
+    #[error("Failed to set the key repeat timing: {0}")]
+    Repeat(std::io::Error),
+    #[error("Too many consecutive uinput write failures ({0})")]
+    TooManyFailures(u32),
+    #[error("Failed to create the self-test keyboard: {0}")]
+    TestKeyboard(std::io::Error),
+}
+
+/// Failures setting up input capture and injection through the desktop portals (`--portal`).
+#[cfg(feature = "portal")]
+#[derive(Debug, thiserror::Error)]
+pub enum PortalError {
+    #[error("Failed to talk to the desktop portal: {0}")]
+    Bus(String),
+    #[error("{0} was cancelled in the portal dialog")]
+    Cancelled(&'static str),
+    #[error("{method} failed with portal response {code}")]
+    Failed { method: &'static str, code: u32 },
+    #[error("{method} did not return {field}")]
+    Missing { method: &'static str, field: &'static str },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DaemonError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+    #[error(transparent)]
+    Output(#[from] OutputError),
+    #[cfg(feature = "portal")]
+    #[error(transparent)]
+    Portal(#[from] PortalError),
+}
+
+/// What the daemon does after an error.
+#[derive(Debug, PartialEq)]
+pub enum Recovery {
+    /// Wait and try again; used while devices and permissions settle at login.
+    Retry,
+    /// Stop using the affected device and carry on with the others.
+    DropDevice,
+    /// Stop the daemon with the given exit code (see [`DaemonError::exit_code`]).
+    Exit(i32),
+}
+
+impl DaemonError {
+    /// The recovery policy for every error the daemon can hit.
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            DaemonError::Config(_) => Recovery::Exit(self.exit_code()),
+            DaemonError::Device(DeviceError::NoKeyboards | DeviceError::Helper(_) | DeviceError::PolkitDenied) => {
+                Recovery::Retry
+            }
+            DaemonError::Device(DeviceError::AllDevicesLost | DeviceError::EventLoop(_)) => {
+                Recovery::Exit(self.exit_code())
+            }
+            DaemonError::Device(_) => Recovery::DropDevice,
+            DaemonError::Output(OutputError::TooManyFailures(_)) => Recovery::Exit(self.exit_code()),
+            DaemonError::Output(_) => Recovery::Retry,
+            #[cfg(feature = "portal")]
+            DaemonError::Portal(PortalError::Cancelled(_)) => Recovery::Exit(self.exit_code()),
+            #[cfg(feature = "portal")]
+            DaemonError::Portal(_) => Recovery::Retry,
+        }
+    }
+
+    /// Process exit code for an error that stopped the daemon, or a one-off command such as
+    /// `record-layout`, by which part failed. Errors the daemon recovers from have one too, for
+    /// the commands that give up on them.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DaemonError::Config(_) => EXIT_USAGE,
+            DaemonError::Device(DeviceError::NoKeyboards | DeviceError::Helper(_) | DeviceError::PolkitDenied) => {
+                EXIT_NO_KEYBOARDS
+            }
+            DaemonError::Device(DeviceError::Grab { .. } | DeviceError::Busy { .. }) => EXIT_GRAB,
+            DaemonError::Device(DeviceError::AllDevicesLost | DeviceError::Read { .. } | DeviceError::Epoll { .. }) => {
+                EXIT_DEVICES_LOST
+            }
+            DaemonError::Device(DeviceError::EventLoop(_)) => EXIT_FAILURE,
+            DaemonError::Device(DeviceError::WriterGone { .. })
+            | DaemonError::Output(OutputError::TooManyFailures(_)) => EXIT_WRITE_FAILED,
+            DaemonError::Output(_) => EXIT_UINPUT,
+            #[cfg(feature = "portal")]
+            DaemonError::Portal(_) => EXIT_PORTAL,
+        }
+    }
+
+    /// Which part of the daemon failed, as the `kind` field of its log line.
+    fn kind(&self) -> &'static str {
+        match self {
+            DaemonError::Config(_) => "config",
+            DaemonError::Device(_) => "device",
+            DaemonError::Output(_) => "output",
+            #[cfg(feature = "portal")]
+            DaemonError::Portal(_) => "portal",
+        }
+    }
+
+    /// A pointer at the usual cause, for errors a user can fix.
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            DaemonError::Device(DeviceError::NoKeyboards) => Some(
+                "If this persists, check udev uaccess rules for /dev/input/event* (ID_INPUT_KEYBOARD==1).",
+            ),
+            DaemonError::Device(DeviceError::Helper(_)) => Some(
+                "If this persists, check that --device-helper points at qwertdvert-device-helper and that it can read /dev/input.",
+            ),
+            DaemonError::Device(DeviceError::Busy { .. }) => Some(
+                "Another remapper such as keyd or kanata is probably running; stop it, or exclude this keyboard from one of them.",
+            ),
+            DaemonError::Device(DeviceError::PolkitDenied) => {
+                Some("Restart the daemon to be asked again, or set up the udev rule from the README.")
+            }
+            DaemonError::Output(OutputError::Open(_)) => Some(
+                "If this persists, check that the uinput kernel module is available and udev uaccess rules for /dev/uinput.",
+            ),
+            DaemonError::Output(OutputError::Create(_)) => {
+                Some("If this persists, check udev uaccess rules for /dev/uinput.")
+            }
+            #[cfg(feature = "portal")]
+            DaemonError::Portal(PortalError::Cancelled(_)) => {
+                Some("Allow QwertDvert in the dialog when it starts again, or run it without --portal.")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Logs an error and returns what to do about it. Startup and runtime errors both end up here
+/// so failure sites only describe what went wrong.
+pub(crate) fn handle_error(error: &DaemonError) -> Recovery {
+    let recovery = error.recovery();
+    let mut message = error.to_string();
+    if recovery == Recovery::Retry {
+        message.push_str(&format!("; retrying every {:?}…", STARTUP_RETRY_INTERVAL));
+    }
+    if let Some(hint) = error.hint() {
+        message.push('\n');
+        message.push_str(hint);
+    }
+    let device = match error {
+        DaemonError::Device(device_error) => device_error.device(),
+        _ => None,
+    };
+    let kind = error.kind();
+    // Repeats, e.g. while waiting for keyboards at startup, are held back by the logging layer.
+    match device {
+        Some(device) => error!(device, kind, "{message}"),
+        None => error!(kind, "{message}"),
+    }
+    recovery
+}
@@ -0,0 +1,482 @@
+//! The event loop that reads every keyboard on one thread.
+//!
+//! One epoll instance watches the grabbed keyboards, udev's announcements of new ones, the LED
+//! and repeat changes sent back to the virtual keyboard, the signals the daemon handles (via a
+//! signalfd), and the shutdown request's eventfd, so a request stops the loop at once. Between
+//! events the loop sleeps until the next timer is due (a pipeline timer, a retried grab or a
+//! watchdog ping) instead of waking at an interval. Every fd is read until it would block
+//! each time it is reported readable.
+//!
+//! With the `tokio` feature the loop is a task on a single-threaded tokio runtime, waiting on an
+//! `AsyncFd` for each fd in place of the epoll instance, and the control socket and the
+//! [`Periodic`] timers run as tasks beside it. Without it, each of those has a thread.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use tracing::warn;
+
+use crate::capture::{Capture, Keyboard};
+use crate::enumeration::DeviceFilter;
+use crate::error::{handle_error, DaemonError, DeviceError, Recovery};
+use crate::hotplug::UdevMonitor;
+use crate::logging;
+use crate::notify::notify;
+use crate::shutdown::Shutdown;
+use crate::source::BoxedKeyboard;
+
+// Tokens: the fixed sources, then one per keyboard from FIRST_KEYBOARD up.
+const SHUTDOWN: u64 = 0;
+const HOTPLUG: u64 = 1;
+const FEEDBACK: u64 = 2;
+const SIGNALS: u64 = 3;
+const FIRST_KEYBOARD: u64 = 4;
+// WATCHDOG_RETRY: How soon to check again when a watchdog ping is due but the writer is stuck
+// on the same event as at the last one.
+const WATCHDOG_RETRY: Duration = Duration::from_millis(100);
+
+/// Work done at an interval until shutdown, started with [`EventLoop::every`].
+pub(crate) trait Periodic: Send + 'static {
+    /// How long to wait before each [`tick`](Periodic::tick).
+    fn interval(&self) -> Duration;
+
+    fn tick(&mut self);
+
+    /// Called once shutdown is requested, e.g. to remove a published file.
+    fn finish(&mut self) {}
+}
+
+/// Waits for the loop's fds to become readable, each known by its token.
+#[cfg(not(feature = "tokio"))]
+struct Poller {
+    epoll: nix::sys::epoll::Epoll,
+    events: [nix::sys::epoll::EpollEvent; 16],
+}
+
+#[cfg(not(feature = "tokio"))]
+impl Poller {
+    fn new() -> nix::Result<Self> {
+        use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent};
+
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        Ok(Poller { epoll, events: [EpollEvent::empty(); 16] })
+    }
+
+    fn add(&mut self, fd: impl AsFd, token: u64) -> nix::Result<()> {
+        use nix::sys::epoll::{EpollEvent, EpollFlags};
+
+        self.epoll.add(fd, EpollEvent::new(EpollFlags::EPOLLIN, token))
+    }
+
+    /// Stops watching `fd`. A closed fd is only forgotten by epoll once every duplicate of it
+    /// is closed too, so this comes first.
+    fn delete(&mut self, fd: impl AsFd) {
+        let _ = self.epoll.delete(fd);
+    }
+
+    /// Adds the tokens of the readable fds to `ready`, waiting until there are some or
+    /// `deadline` passes. This blocks the thread rather than awaiting, so the loop never
+    /// yields.
+    async fn wait(&mut self, ready: &mut Vec<u64>, deadline: Option<Instant>) -> nix::Result<()> {
+        use nix::sys::epoll::EpollTimeout;
+
+        let timeout = match deadline {
+            Some(deadline) => {
+                let millis = deadline.saturating_duration_since(Instant::now()).as_micros().div_ceil(1000);
+                EpollTimeout::try_from(millis).unwrap_or(EpollTimeout::MAX)
+            }
+            None => EpollTimeout::NONE,
+        };
+        let count = match self.epoll.wait(&mut self.events, timeout) {
+            Ok(count) => count,
+            Err(Errno::EINTR) => 0,
+            Err(e) => return Err(e),
+        };
+        ready.extend(self.events[..count].iter().map(|event| event.data()));
+        Ok(())
+    }
+
+    /// Nothing to forget: epoll reports an fd for as long as it is readable.
+    fn drained(&mut self, _token: u64) {}
+}
+
+/// Waits for the loop's fds to become readable, each known by its token.
+#[cfg(feature = "tokio")]
+struct Poller {
+    runtime: Arc<tokio::runtime::Runtime>,
+    fds: BTreeMap<u64, tokio::io::unix::AsyncFd<std::os::fd::RawFd>>,
+}
+
+#[cfg(feature = "tokio")]
+impl Poller {
+    fn new() -> nix::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()
+            .map_err(errno)?;
+        Ok(Poller { runtime: Arc::new(runtime), fds: BTreeMap::new() })
+    }
+
+    fn add(&mut self, fd: impl AsFd, token: u64) -> nix::Result<()> {
+        let _runtime = self.runtime.enter();
+        let fd = tokio::io::unix::AsyncFd::new(fd.as_fd().as_raw_fd()).map_err(errno)?;
+        self.fds.insert(token, fd);
+        Ok(())
+    }
+
+    /// Stops watching `fd`, before it is closed.
+    fn delete(&mut self, fd: impl AsFd) {
+        let fd = fd.as_fd().as_raw_fd();
+        self.fds.retain(|_, watched| *watched.get_ref() != fd);
+    }
+
+    /// Adds the tokens of the readable fds to `ready`, waiting until there are some or
+    /// `deadline` passes.
+    async fn wait(&mut self, ready: &mut Vec<u64>, deadline: Option<Instant>) -> nix::Result<()> {
+        let fds = &self.fds;
+        let readable = std::future::poll_fn(|cx| {
+            for (&token, fd) in fds {
+                if let Poll::Ready(guard) = fd.poll_read_ready(cx) {
+                    // Readable until the loop has read it all; see drained.
+                    guard.map_err(errno)?.retain_ready();
+                    ready.push(token);
+                }
+            }
+            if ready.is_empty() { Poll::Pending } else { Poll::Ready(Ok(())) }
+        });
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), readable).await.unwrap_or(Ok(())),
+            None => readable.await,
+        }
+    }
+
+    /// Forgets that the fd for `token` was readable, once it has been read until it would
+    /// block; tokio only hears when an fd becomes readable, so it reports it until then. The
+    /// runtime only polls epoll while the loop awaits, so nothing that arrived since the read
+    /// is forgotten with it.
+    fn drained(&mut self, token: u64) {
+        if let Some(fd) = self.fds.get(&token)
+            && let Poll::Ready(Ok(mut guard)) = fd.poll_read_ready(&mut Context::from_waker(Waker::noop()))
+        {
+            guard.clear_ready();
+        }
+    }
+}
+
+/// The errno behind an error from tokio's epoll calls.
+#[cfg(feature = "tokio")]
+fn errno(error: std::io::Error) -> Errno {
+    error.raw_os_error().map_or(Errno::UnknownErrno, Errno::from_raw)
+}
+
+/// A timer or server started beside the loop, finished once the loop is.
+#[cfg(not(feature = "tokio"))]
+type Task = std::thread::JoinHandle<()>;
+#[cfg(feature = "tokio")]
+type Task = tokio::task::JoinHandle<()>;
+
+/// Every keyboard, and the fds that add to or act on them, read on one thread.
+pub(crate) struct EventLoop {
+    poller: Poller,
+    shutdown: Arc<Shutdown>,
+    capture: Capture,
+    /// The keyboards by token, grabbed or waiting to be.
+    keyboards: BTreeMap<u64, Keyboard>,
+    next_token: u64,
+    hotplug: Option<(UdevMonitor, DeviceFilter)>,
+    /// A handle on the virtual keyboard, to read the LED and repeat changes sent to it.
+    feedback: Option<File>,
+    /// The first error that requires the daemon to stop.
+    fatal: Option<DaemonError>,
+    /// Timers and servers started beside the loop.
+    tasks: Vec<Task>,
+}
+
+impl EventLoop {
+    /// A loop that runs until `shutdown` is requested.
+    pub(crate) fn new(capture: Capture, shutdown: Arc<Shutdown>) -> Result<Self, DeviceError> {
+        let mut poller = Poller::new().map_err(DeviceError::EventLoop)?;
+        poller.add(&*shutdown, SHUTDOWN).map_err(DeviceError::EventLoop)?;
+        Ok(EventLoop {
+            poller,
+            shutdown,
+            capture,
+            keyboards: BTreeMap::new(),
+            next_token: FIRST_KEYBOARD,
+            hotplug: None,
+            feedback: None,
+            fatal: None,
+            tasks: Vec::new(),
+        })
+    }
+
+    /// Ticks `periodic` at its interval until shutdown, beside the loop.
+    pub(crate) fn every(&mut self, mut periodic: impl Periodic) {
+        let shutdown = self.shutdown.clone();
+        #[cfg(not(feature = "tokio"))]
+        let task = std::thread::spawn(move || {
+            while !shutdown.wait(periodic.interval()) {
+                periodic.tick();
+            }
+            periodic.finish();
+        });
+        #[cfg(feature = "tokio")]
+        let task = self.poller.runtime.spawn(async move {
+            while !shutdown.wait_async(periodic.interval()).await {
+                periodic.tick();
+            }
+            periodic.finish();
+        });
+        self.tasks.push(task);
+    }
+
+    /// Runs `task` on the loop's runtime until it finishes or, once the loop has stopped,
+    /// shutdown is requested.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn spawn(&mut self, task: impl std::future::Future<Output = ()> + Send + 'static) {
+        let task = self.poller.runtime.spawn(task);
+        self.tasks.push(task);
+    }
+
+    /// Starts capturing `device`, which is grabbed straight away or, if another program has
+    /// it, retried from the loop.
+    pub(crate) fn add(&mut self, device: BoxedKeyboard) {
+        let token = self.next_token;
+        self.next_token += 1;
+        self.keyboards.insert(token, self.capture.keyboard(device));
+        self.drive(token);
+    }
+
+    /// Captures the keyboards `monitor` announces from now on, if `filter` selects them.
+    pub(crate) fn watch_hotplug(&mut self, monitor: UdevMonitor, filter: DeviceFilter) -> nix::Result<()> {
+        self.poller.add(&monitor, HOTPLUG)?;
+        self.hotplug = Some((monitor, filter));
+        Ok(())
+    }
+
+    /// Copies the LED and repeat changes read from `file`, a handle on the virtual keyboard, to
+    /// every keyboard.
+    pub(crate) fn watch_feedback(&mut self, file: File) -> nix::Result<()> {
+        self.poller.add(&file, FEEDBACK)?;
+        self.feedback = Some(file);
+        Ok(())
+    }
+
+    /// Calls `run`'s `on_signal`, with the loop, whenever `signals`, a signalfd, has signals
+    /// to read.
+    pub(crate) fn watch_signals(&mut self, signals: impl AsFd) -> nix::Result<()> {
+        self.poller.add(signals, SIGNALS)
+    }
+
+    /// What the loop and its keyboards are doing, as lines for the log, to tell where a stuck
+    /// key is stuck.
+    pub(crate) fn state_dump(&self) -> String {
+        let capture = &self.capture;
+        let counters = &capture.counters;
+        let mut lines = vec![
+            format!(
+                "remapping: {}{}{}",
+                if capture.paused.load(Ordering::Relaxed) { "paused" } else { "running" },
+                if capture.game_running.load(Ordering::Relaxed) { ", game running" } else { "" },
+                if capture.session_active.load(Ordering::Relaxed) { "" } else { ", session switched away from" }
+            ),
+            format!("modifiers: {}", capture.modifiers),
+            format!(
+                "ring buffers: {} frames each, writer {}",
+                capture.tx.capacity(),
+                if counters.writer_waiting.load(Ordering::Relaxed) { "waiting" } else { "writing" }
+            ),
+            format!(
+                "drops: {} events since start, {} since the last heartbeat; {} failures since the last heartbeat",
+                counters.dropped_total.load(Ordering::Relaxed),
+                counters.events_dropped.load(Ordering::Relaxed),
+                counters.failures.load(Ordering::Relaxed)
+            ),
+        ];
+        for ((device, class), count) in counters.drops_by_source.lock().unwrap().iter() {
+            lines.push(format!("dropped from {device} since the last heartbeat: {count} {class}"));
+        }
+        lines.extend(self.keyboards.values().map(|keyboard| format!("keyboard {}", keyboard.describe())));
+        if self.keyboards.is_empty() {
+            lines.push("no keyboards".to_string());
+        }
+        lines.join("\n")
+    }
+
+    /// Runs until shutdown is requested, an error requires the daemon to stop, or every
+    /// keyboard is gone and none can be plugged in again. Pings the systemd watchdog every
+    /// half `watchdog` while the writer thread isn't stuck. Returns the error that stopped
+    /// the loop, if one did.
+    pub(crate) fn run(mut self, watchdog: Option<Duration>, on_signal: impl FnMut(&EventLoop)) -> Option<DaemonError> {
+        #[cfg(not(feature = "tokio"))]
+        let fatal = {
+            let run = std::pin::pin!(self.run_until_stopped(watchdog, on_signal));
+            match run.poll(&mut Context::from_waker(Waker::noop())) {
+                Poll::Ready(fatal) => fatal,
+                Poll::Pending => unreachable!("the epoll poller never yields"),
+            }
+        };
+        #[cfg(feature = "tokio")]
+        let runtime = self.poller.runtime.clone();
+        #[cfg(feature = "tokio")]
+        let fatal = runtime.block_on(self.run_until_stopped(watchdog, on_signal));
+
+        // Stop the timers and servers beside the loop too, whatever ended it.
+        self.shutdown.request();
+        let tasks = std::mem::take(&mut self.tasks);
+        #[cfg(not(feature = "tokio"))]
+        for task in tasks {
+            let _ = task.join();
+        }
+        #[cfg(feature = "tokio")]
+        runtime.block_on(async {
+            for task in tasks {
+                let _ = task.await;
+            }
+        });
+        fatal
+    }
+
+    async fn run_until_stopped(
+        &mut self,
+        watchdog: Option<Duration>,
+        mut on_signal: impl FnMut(&EventLoop),
+    ) -> Option<DaemonError> {
+        let counters = self.capture.counters.clone();
+        let mut last_ping = (Instant::now(), counters.writer_wakeups.load(Ordering::Relaxed));
+        let mut ping_at = watchdog.map(|interval| last_ping.0 + interval / 2);
+        let mut ready = Vec::new();
+        while self.fatal.is_none() && !self.shutdown.is_requested() {
+            if self.keyboards.is_empty() && self.hotplug.is_none() {
+                break;
+            }
+            // Only vouch for the daemon while the writer thread is waiting for events or getting
+            // through them.
+            if let (Some(interval), Some(at)) = (watchdog, ping_at)
+                && at <= Instant::now()
+            {
+                let wakeups = counters.writer_wakeups.load(Ordering::Relaxed);
+                if counters.writer_waiting.load(Ordering::Relaxed) || wakeups != last_ping.1 {
+                    notify("WATCHDOG=1");
+                    last_ping = (Instant::now(), wakeups);
+                    ping_at = Some(last_ping.0 + interval / 2);
+                } else {
+                    ping_at = Some(Instant::now() + WATCHDOG_RETRY);
+                }
+            }
+
+            let deadline = self.keyboards.values().filter_map(Keyboard::deadline).chain(ping_at).min();
+            ready.clear();
+            if let Err(source) = self.poller.wait(&mut ready, deadline).await {
+                self.fail(DeviceError::EventLoop(source).into());
+                break;
+            }
+            for &token in &ready {
+                match token {
+                    // The loop's condition sees the request.
+                    SHUTDOWN => {}
+                    HOTPLUG => self.plugged_in(),
+                    FEEDBACK => self.feedback_changed(),
+                    SIGNALS => on_signal(self),
+                    token => self.drive(token),
+                }
+                self.poller.drained(token);
+            }
+            // Keyboards with a timer due, whether or not they had anything to read.
+            let now = Instant::now();
+            let due: Vec<u64> = self
+                .keyboards
+                .iter()
+                .filter(|(_, keyboard)| keyboard.deadline().is_some_and(|deadline| deadline <= now))
+                .map(|(&token, _)| token)
+                .collect();
+            for token in due {
+                self.drive(token);
+            }
+        }
+
+        for keyboard in self.keyboards.values_mut() {
+            keyboard.release_held(&self.capture);
+        }
+        logging::flush();
+        self.fatal.take()
+    }
+
+    /// Grabs the keyboard for `token` if it isn't yet, or services it if it is. If that
+    /// fails, the keyboard is dropped.
+    fn drive(&mut self, token: u64) {
+        let Some(keyboard) = self.keyboards.get_mut(&token) else {
+            return;
+        };
+        let result = if keyboard.is_grabbed() {
+            keyboard.service(&self.capture)
+        } else {
+            keyboard.grab(&self.capture.counters).and_then(|grabbed| {
+                if grabbed {
+                    // SAFETY: The keyboard owns the fd and stays in `keyboards` while it is watched.
+                    let fd = unsafe { BorrowedFd::borrow_raw(keyboard.as_raw_fd()) };
+                    self.poller
+                        .add(fd, token)
+                        .map_err(|source| DeviceError::Epoll { device: keyboard.name().to_string(), source })?;
+                    keyboard.sync_feedback(&self.capture.feedback);
+                }
+                Ok(())
+            })
+        };
+        if let Err(error) = result
+            && let Some(mut keyboard) = self.keyboards.remove(&token)
+        {
+            keyboard.release_held(&self.capture);
+            // SAFETY: The keyboard still owns the fd.
+            self.poller.delete(unsafe { BorrowedFd::borrow_raw(keyboard.as_raw_fd()) });
+            self.fail(error.into());
+        }
+    }
+
+    /// Reads the announcements from udev, capturing the keyboards they announce, if any.
+    fn plugged_in(&mut self) {
+        while let Some((monitor, filter)) = &self.hotplug {
+            match monitor.added_keyboard(filter) {
+                Ok(Some(device)) => self.add(Box::new(device)),
+                Ok(None) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Failed to read udev events; keyboards plugged in from now on will be ignored: {}", e);
+                    self.poller.delete(monitor);
+                    self.hotplug = None;
+                }
+            }
+        }
+    }
+
+    /// Reads the changes sent to the virtual keyboard and copies them to every keyboard.
+    fn feedback_changed(&mut self) {
+        let Some(file) = &self.feedback else {
+            return;
+        };
+        if let Err(e) = self.capture.feedback.read_from(file) {
+            warn!("Failed to read LED and repeat changes; keyboards will no longer follow them: {}", e);
+            self.poller.delete(file);
+            self.feedback = None;
+            return;
+        }
+        for keyboard in self.keyboards.values_mut().filter(|keyboard| keyboard.is_grabbed()) {
+            keyboard.sync_feedback(&self.capture.feedback);
+        }
+    }
+
+    /// Logs an error, keeping it to return if it requires the daemon to stop.
+    fn fail(&mut self, error: DaemonError) {
+        self.capture.counters.record_error(&error);
+        if let Recovery::Exit(_) = handle_error(&error) {
+            self.fatal.get_or_insert(error);
+        }
+    }
+}
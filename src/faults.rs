@@ -0,0 +1,145 @@
+//! Test-only fault injection for exercising the daemon's recovery paths.
+//!
+//! Enabled with the `fault-injection` cargo feature and `--inject-faults`. Each rate is the
+//! probability (0.0-1.0) that a given operation fails: `write` for uinput writes, `fetch` for
+//! evdev reads, and `stall` for the writer pausing for `stall-ms` so the ring buffers to it back up.
+
+use std::str::FromStr;
+
+use crate::output::{EventWriter, OutputEvent};
+
+const DEFAULT_STALL: std::time::Duration = std::time::Duration::from_millis(250);
+
+#[derive(Clone, Debug)]
+pub struct FaultConfig {
+    pub write_error_rate: f64,
+    pub fetch_error_rate: f64,
+    pub stall_rate: f64,
+    pub stall: std::time::Duration,
+}
+
+impl FromStr for FaultConfig {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut config = FaultConfig {
+            write_error_rate: 0.0,
+            fetch_error_rate: 0.0,
+            stall_rate: 0.0,
+            stall: DEFAULT_STALL,
+        };
+        for part in spec.split(',').filter(|p| !p.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=value, got '{part}'"))?;
+            if name == "stall-ms" {
+                let ms = value.parse().map_err(|_| format!("invalid stall-ms '{value}'"))?;
+                config.stall = std::time::Duration::from_millis(ms);
+                continue;
+            }
+            let rate: f64 = value.parse().map_err(|_| format!("invalid rate '{value}' for {name}"))?;
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("rate for {name} must be between 0 and 1"));
+            }
+            match name {
+                "write" => config.write_error_rate = rate,
+                "fetch" => config.fetch_error_rate = rate,
+                "stall" => config.stall_rate = rate,
+                _ => return Err(format!("unknown fault '{name}' (expected write, fetch, stall, stall-ms)")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Decides when to inject a fault. Each thread gets its own injector.
+pub struct FaultInjector {
+    config: FaultConfig,
+    state: u64,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig, salt: u64) -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        FaultInjector {
+            config,
+            // xorshift must not start at zero.
+            state: (nanos ^ salt.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1,
+        }
+    }
+
+    /// xorshift64*; plenty for picking which operations fail.
+    fn chance(&mut self, rate: f64) -> bool {
+        if rate <= 0.0 {
+            return false;
+        }
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let sample = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        (sample as f64 / (1u64 << 53) as f64) < rate
+    }
+
+    pub fn fetch_error(&mut self) -> bool {
+        self.chance(self.config.fetch_error_rate)
+    }
+}
+
+pub enum FaultyWriteError<E> {
+    Injected,
+    Inner(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for FaultyWriteError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaultyWriteError::Injected => f.write_str("injected write fault"),
+            FaultyWriteError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Wraps the real writer, failing or stalling writes at the configured rates.
+pub struct FaultyWriter<W> {
+    inner: W,
+    faults: Option<FaultInjector>,
+}
+
+impl<W> FaultyWriter<W> {
+    pub fn new(inner: W, config: Option<FaultConfig>) -> Self {
+        FaultyWriter {
+            inner,
+            faults: config.map(|config| FaultInjector::new(config, 0)),
+        }
+    }
+
+    /// Stalls or fails a write, at the configured rates.
+    fn inject<E>(&mut self) -> Result<(), FaultyWriteError<E>> {
+        if let Some(faults) = &mut self.faults {
+            if faults.chance(faults.config.stall_rate) {
+                std::thread::sleep(faults.config.stall);
+            }
+            if faults.chance(faults.config.write_error_rate) {
+                return Err(FaultyWriteError::Injected);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: EventWriter> EventWriter for FaultyWriter<W> {
+    type Error = FaultyWriteError<W::Error>;
+
+    fn write_event(&mut self, event: OutputEvent) -> Result<(), Self::Error> {
+        self.inject()?;
+        self.inner.write_event(event).map_err(FaultyWriteError::Inner)
+    }
+
+    fn write_frame(&mut self, frame: &[OutputEvent]) -> Result<(), Self::Error> {
+        self.inject()?;
+        self.inner.write_frame(frame).map_err(FaultyWriteError::Inner)
+    }
+}
@@ -0,0 +1,92 @@
+//! LED and key repeat settings sent to the virtual keyboard, relayed to the real keyboards.
+//!
+//! The desktop lights Caps Lock and Num Lock on the keyboard it reads from, which is the
+//! virtual one, and tools such as `kbdrate` set the repeat timing there too. The real
+//! keyboards are grabbed, so they never hear about either and stay dark. The event loop reads
+//! the events the kernel passes back to the virtual keyboard, keeps the latest state here, and
+//! writes any change to every keyboard.
+
+use std::fs::File;
+use std::io::Read;
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+
+use evdev::{EventType, InputEvent, LedType};
+use nix::libc;
+
+use crate::output::{REP_DELAY, REP_PERIOD};
+
+// LEDS: The keyboard LEDs relayed; the virtual keyboard has these and no others.
+pub(crate) const LEDS: [LedType; 5] =
+    [LedType::LED_NUML, LedType::LED_CAPSL, LedType::LED_SCROLLL, LedType::LED_COMPOSE, LedType::LED_KANA];
+
+/// The LED and repeat settings last sent to the virtual keyboard.
+pub struct Feedback {
+    /// One bit per LED, by code.
+    leds: AtomicU32,
+    /// The repeat delay and period in milliseconds, by code; negative until one is set.
+    repeat: [AtomicI32; 2],
+    /// Counts changes, so each keyboard can be told cheaply whether it is out of date.
+    generation: AtomicU64,
+}
+
+impl Default for Feedback {
+    fn default() -> Self {
+        Feedback {
+            leds: AtomicU32::new(0),
+            repeat: [AtomicI32::new(-1), AtomicI32::new(-1)],
+            generation: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Feedback {
+    /// Records one event sent to the virtual keyboard.
+    fn record(&self, kind: u16, code: u16, value: i32) {
+        if kind == EventType::LED.0 && LEDS.iter().any(|led| led.0 == code) {
+            match value != 0 {
+                true => self.leds.fetch_or(1 << code, Ordering::Relaxed),
+                false => self.leds.fetch_and(!(1 << code), Ordering::Relaxed),
+            };
+        } else if kind == EventType::REPEAT.0 && (code == REP_DELAY || code == REP_PERIOD) && value >= 0 {
+            self.repeat[code as usize].store(value, Ordering::Relaxed);
+        } else {
+            return;
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Reads the events sent to the virtual keyboard from `file`, a non-blocking handle on it,
+    /// until there are none left.
+    pub fn read_from(&self, mut file: &File) -> std::io::Result<()> {
+        // uinput hands out whole events, one per read of this size.
+        let mut bytes = [0u8; size_of::<libc::input_event>()];
+        loop {
+            match file.read(&mut bytes) {
+                Ok(len) if len == bytes.len() => {
+                    // SAFETY: input_event is plain old data, and every byte of it was read.
+                    let event: libc::input_event = unsafe { std::ptr::read_unaligned(bytes.as_ptr().cast()) };
+                    self.record(event.type_, event.code, event.value);
+                }
+                Ok(_) => return Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// The events that bring a keyboard's LEDs, and its repeat timing if any has been set, to
+    /// the current state.
+    pub fn events(&self) -> Vec<InputEvent> {
+        let leds = self.leds.load(Ordering::Relaxed);
+        let leds = LEDS.iter().map(|led| InputEvent::new(EventType::LED, led.0, (leds >> led.0 & 1) as i32));
+        let repeat = [REP_DELAY, REP_PERIOD].into_iter().filter_map(|code| {
+            let value = self.repeat[code as usize].load(Ordering::Relaxed);
+            (value >= 0).then(|| InputEvent::new(EventType::REPEAT, code, value))
+        });
+        leds.chain(repeat).chain([InputEvent::new(EventType::SYNCHRONIZATION, 0, 0)]).collect()
+    }
+}
@@ -0,0 +1,178 @@
+//! C API for the remapping core, declared in `include/qwertdvert.h`.
+//!
+//! Runs the same pipeline as the daemon on events the caller supplies, without grabbing
+//! devices or creating a uinput keyboard, so it can be embedded in other input stacks or
+//! wrapped by other languages. A remapper handle is not thread-safe; use one per keyboard.
+
+use std::ffi::{c_char, CStr};
+use std::time::Instant;
+
+use evdev::{EventType, InputEvent};
+
+use crate::remapper::{Outputs, Remapper};
+
+/// An input event as (type, code, value), matching `struct input_event` minus the timestamp.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct QdEvent {
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// Opaque remapper handle.
+pub struct QdRemapper {
+    remapper: Remapper,
+    outputs: Vec<QdEvent>,
+}
+
+impl QdRemapper {
+    /// Keeps `outputs` for `qd_remapper_outputs` and returns how many there are.
+    fn set_outputs(&mut self, outputs: Outputs) -> usize {
+        self.outputs.clear();
+        self.outputs.extend(outputs.iter().map(|output| QdEvent {
+            kind: output.event_type.0,
+            code: output.code,
+            value: output.value,
+        }));
+        self.outputs.len()
+    }
+}
+
+/// Creates a remapper for the named layout, or for the default layout if `layout` is NULL.
+/// Returns NULL if no layout is registered under that name.
+///
+/// # Safety
+///
+/// `layout` must be NULL or a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qd_remapper_new(layout: *const c_char) -> *mut QdRemapper {
+    let name = if layout.is_null() {
+        crate::layout::DVORAK
+    } else {
+        match unsafe { CStr::from_ptr(layout) }.to_str() {
+            Ok(name) => name,
+            Err(_) => return std::ptr::null_mut(),
+        }
+    };
+    match crate::layout::lookup(name) {
+        Some(layout) => Box::into_raw(Box::new(QdRemapper {
+            remapper: Remapper::with_layout(layout),
+            outputs: Vec::new(),
+        })),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Frees a remapper. NULL is ignored.
+///
+/// # Safety
+///
+/// `remapper` must be NULL or a pointer from `qd_remapper_new` that has not been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qd_remapper_free(remapper: *mut QdRemapper) {
+    if !remapper.is_null() {
+        drop(unsafe { Box::from_raw(remapper) });
+    }
+}
+
+/// Feeds one input event and returns how many events it produced. The outputs of the
+/// previous call are discarded. Key events go through the pipeline; everything else
+/// (including SYN_REPORT) is passed through unchanged.
+///
+/// # Safety
+///
+/// `remapper` must be a live pointer from `qd_remapper_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qd_remapper_feed(remapper: *mut QdRemapper, event: QdEvent) -> usize {
+    let remapper = unsafe { &mut *remapper };
+    let outputs = remapper.remapper.process(InputEvent::new(EventType(event.kind), event.code, event.value));
+    remapper.set_outputs(outputs)
+}
+
+/// Milliseconds until `qd_remapper_tick` should next be called, rounded up; 0 if a timer is
+/// already due, or -1 if none is pending (no tap-hold key undecided, no debounced key, no
+/// autorepeat).
+///
+/// # Safety
+///
+/// `remapper` must be a live pointer from `qd_remapper_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qd_remapper_deadline_ms(remapper: *const QdRemapper) -> i64 {
+    let remapper = unsafe { &*remapper };
+    match remapper.remapper.deadline() {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            remaining.as_micros().div_ceil(1000).try_into().unwrap_or(i64::MAX)
+        }
+        None => -1,
+    }
+}
+
+/// Fires the timers that are due and returns how many events they produced, to be read with
+/// `qd_remapper_outputs`. The outputs of the previous call are discarded.
+///
+/// # Safety
+///
+/// `remapper` must be a live pointer from `qd_remapper_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qd_remapper_tick(remapper: *mut QdRemapper) -> usize {
+    let remapper = unsafe { &mut *remapper };
+    let outputs = remapper.remapper.tick(Instant::now());
+    remapper.set_outputs(outputs)
+}
+
+/// Copies up to `capacity` events produced by the last `qd_remapper_feed` or
+/// `qd_remapper_tick` into `out` and returns how many were copied.
+///
+/// # Safety
+///
+/// `remapper` must be a live pointer from `qd_remapper_new`, and `out` must point to at least
+/// `capacity` writable events (it may be NULL if `capacity` is 0).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qd_remapper_outputs(remapper: *const QdRemapper, out: *mut QdEvent, capacity: usize) -> usize {
+    let remapper = unsafe { &*remapper };
+    let count = remapper.outputs.len().min(capacity);
+    if count > 0 {
+        unsafe { std::ptr::copy_nonoverlapping(remapper.outputs.as_ptr(), out, count) };
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use evdev::Key;
+
+    use super::*;
+
+    fn key(code: Key, value: i32) -> QdEvent {
+        QdEvent { kind: EventType::KEY.0, code: code.code(), value }
+    }
+
+    /// The outputs of the last call, as (code, value) pairs.
+    fn outputs(remapper: *const QdRemapper, count: usize) -> Vec<(u16, i32)> {
+        let mut out = vec![QdEvent { kind: 0, code: 0, value: 0 }; count + 1];
+        let copied = unsafe { qd_remapper_outputs(remapper, out.as_mut_ptr(), out.len()) };
+        assert_eq!(copied, count);
+        out[..copied].iter().map(|event| (event.code, event.value)).collect()
+    }
+
+    #[test]
+    fn default_layout_remaps_fed_keys() {
+        let remapper = unsafe { qd_remapper_new(ptr::null()) };
+        assert!(!remapper.is_null());
+        let count = unsafe { qd_remapper_feed(remapper, key(Key::KEY_Q, 1)) };
+        assert_eq!(outputs(remapper, count), [(Key::KEY_APOSTROPHE.code(), 1)]);
+        let count = unsafe { qd_remapper_feed(remapper, key(Key::KEY_Q, 0)) };
+        assert_eq!(outputs(remapper, count), [(Key::KEY_APOSTROPHE.code(), 0)]);
+        assert_eq!(unsafe { qd_remapper_deadline_ms(remapper) }, -1);
+        unsafe { qd_remapper_free(remapper) };
+    }
+
+    #[test]
+    fn unknown_layout_gives_null() {
+        assert!(unsafe { qd_remapper_new(c"no-such-layout".as_ptr()) }.is_null());
+    }
+}
@@ -0,0 +1,76 @@
+//! Game mode: remapping pauses while a game from the config's list is running.
+//!
+//! Games bind actions to physical keys, so WASD should stay WASD. The daemon can't see which
+//! window is focused or fullscreen (Wayland keeps that from other programs), so it goes by the
+//! processes running instead: every few seconds it looks through /proc for one named in
+//! `game_apps`, passing keys through unmapped from when one starts until the last one exits.
+//! This is a pause of its own, beside the one the user controls, so a pause made by hand
+//! outlasts the game and a resume by hand doesn't remap the game's keys.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::daemon::LiveConfig;
+use crate::shutdown::Shutdown;
+
+// SCAN_INTERVAL: How often running processes are checked for games.
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+// COMM_LEN: The kernel truncates process names in /proc/PID/comm to this many bytes.
+const COMM_LEN: usize = 15;
+
+/// The names in `apps` of the processes running now. A process matches by its executable's
+/// file name or by its process name, which the kernel truncates to 15 bytes.
+fn running_games(apps: &[String]) -> BTreeSet<String> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return BTreeSet::new();
+    };
+    let mut running = BTreeSet::new();
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let dir = entry.path();
+        let comm = std::fs::read_to_string(dir.join("comm")).unwrap_or_default();
+        let comm = comm.trim_end_matches('\n');
+        let exe = std::fs::read_link(dir.join("exe")).ok();
+        let exe = exe.as_deref().and_then(Path::file_name).map(|name| name.to_string_lossy());
+        let matches = |app: &&String| {
+            exe.as_deref() == Some(app.as_str())
+                || (!comm.is_empty() && &app.as_bytes()[..COMM_LEN.min(app.len())] == comm.as_bytes())
+        };
+        running.extend(apps.iter().find(matches).cloned());
+    }
+    running
+}
+
+/// Sets `game_running` while an app in the live config's `game_apps` runs, until shutdown.
+pub(crate) fn spawn_game_watcher(
+    config: Arc<LiveConfig>,
+    game_running: Arc<AtomicBool>,
+    shutdown: Arc<Shutdown>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut running = BTreeSet::new();
+        loop {
+            let apps = config.current().game_apps.clone();
+            let now_running = if apps.is_empty() { BTreeSet::new() } else { running_games(&apps) };
+            if running.is_empty() && !now_running.is_empty() {
+                info!("Game mode: {} is running; keys are passed through unmapped", now_running.iter().cloned().collect::<Vec<_>>().join(", "));
+                game_running.store(true, Ordering::Relaxed);
+            } else if !running.is_empty() && now_running.is_empty() {
+                info!("Game mode: no games running; remapping unless paused");
+                game_running.store(false, Ordering::Relaxed);
+            }
+            running = now_running;
+            if shutdown.wait(SCAN_INTERVAL) {
+                break;
+            }
+        }
+    })
+}
@@ -0,0 +1,128 @@
+//! The privileged device helper and the daemon's side of talking to it.
+//!
+//! With `--device-helper PATH`, the daemon does not open keyboards itself. Each time it looks
+//! for keyboards it starts the helper with one end of a `SOCK_SEQPACKET` socket pair as stdin.
+//! The helper opens and grabs the keyboards, sends one message per keyboard (its name as the
+//! payload and the open fd as `SCM_RIGHTS`), and exits; the daemon sees EOF once all are sent.
+//!
+//! With `--polkit-helper PATH`, the daemon opens keyboards itself while it can, and only when
+//! /dev/input is not readable runs the helper through `pkexec` so the user can authorize it.
+//!
+//! The helper takes no arguments and reads no configuration, so it is the only code that needs
+//! access to /dev/input and there is little in it to attack. Everything else - parsing,
+//! remapping, IPC - runs in the unprivileged daemon. It therefore passes every keyboard, and the
+//! daemon closes those its device filter rejects, which releases their grab.
+
+use std::io::{self, IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use nix::sys::socket::{
+    recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag,
+    SockType,
+};
+
+use crate::enumeration::{find_keyboards, DeviceFilter};
+use crate::error::DeviceError;
+use crate::source::PassedKeyboard;
+
+// pkexec
+// PKEXEC: Runs the helper as root once polkit has authorized it.
+// PKEXEC_DISMISSED / PKEXEC_NOT_AUTHORIZED: pkexec's exit codes when the user cancels the
+// authentication dialog or is not allowed to run the helper.
+const PKEXEC: &str = "pkexec";
+const PKEXEC_DISMISSED: i32 = 126;
+const PKEXEC_NOT_AUTHORIZED: i32 = 127;
+
+// Longest device name accepted from the helper; the kernel limits names to 256 bytes.
+const MAX_NAME_LEN: usize = 256;
+
+/// Helper side: grabs every keyboard and passes it over `socket`. Finding none is not an
+/// error; the daemon retries.
+pub fn serve(socket: RawFd) -> Result<(), DeviceError> {
+    for mut device in find_keyboards(&DeviceFilter::all()).unwrap_or_default() {
+        let name = device.name().filter(|n| !n.is_empty()).unwrap_or("Unknown").to_string();
+        device.grab().map_err(|source| DeviceError::Grab { device: name.clone(), source })?;
+        let fds = [device.as_raw_fd()];
+        sendmsg::<()>(
+            socket,
+            &[IoSlice::new(name.as_bytes())],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .map_err(|e| DeviceError::Helper(format!("failed to pass {name}: {e}")))?;
+    }
+    Ok(())
+}
+
+/// Daemon side: runs the helper at `path` and collects the keyboards it passes back.
+pub fn acquire_keyboards(path: &Path) -> Result<Vec<PassedKeyboard>, DeviceError> {
+    run_helper(Command::new(path), path)
+}
+
+/// Like [`acquire_keyboards`], but runs the helper as root through `pkexec`, so the session's
+/// polkit agent asks the user to authorize it. `path` must be absolute and match the
+/// `exec.path` of the installed polkit action.
+pub fn acquire_keyboards_via_polkit(path: &Path) -> Result<Vec<PassedKeyboard>, DeviceError> {
+    let mut command = Command::new(PKEXEC);
+    command.arg(path);
+    run_helper(command, path)
+}
+
+fn run_helper(mut command: Command, path: &Path) -> Result<Vec<PassedKeyboard>, DeviceError> {
+    let helper_error = |what: &str, e: &dyn std::fmt::Display| DeviceError::Helper(format!("{what}: {e}"));
+    let (ours, theirs) = socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::SOCK_CLOEXEC)
+        .map_err(|e| helper_error("failed to create socket pair", &e))?;
+    let mut child = command
+        .stdin(Stdio::from(theirs))
+        .spawn()
+        .map_err(|e| helper_error(&format!("failed to start {}", path.display()), &e))?;
+
+    let mut keyboards = Vec::new();
+    let result = loop {
+        match receive_keyboard(&ours) {
+            Ok(Some(keyboard)) => keyboards.push(keyboard),
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(helper_error("failed to receive keyboard", &e)),
+        }
+    };
+    let status = child.wait().map_err(|e| helper_error("failed to wait for helper", &e))?;
+    result?;
+    if matches!(status.code(), Some(PKEXEC_DISMISSED | PKEXEC_NOT_AUTHORIZED)) {
+        return Err(DeviceError::PolkitDenied);
+    }
+    if !status.success() {
+        return Err(DeviceError::Helper(format!("{} exited with {status}", path.display())));
+    }
+    if keyboards.is_empty() {
+        return Err(DeviceError::NoKeyboards);
+    }
+    Ok(keyboards)
+}
+
+/// Receives one keyboard, or None once the helper has closed its end.
+fn receive_keyboard(socket: &OwnedFd) -> io::Result<Option<PassedKeyboard>> {
+    let mut name = [0u8; MAX_NAME_LEN];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+    let mut iov = [IoSliceMut::new(&mut name)];
+    let message = recvmsg::<()>(socket.as_raw_fd(), &mut iov, Some(&mut cmsg_buffer), MsgFlags::MSG_CMSG_CLOEXEC)?;
+    if message.bytes == 0 {
+        return Ok(None);
+    }
+    let bytes = message.bytes;
+    let mut fd = None;
+    for cmsg in message.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            for raw in fds {
+                // Take ownership of every fd received so none leak, keeping the first.
+                let owned = unsafe { OwnedFd::from_raw_fd(raw) };
+                fd.get_or_insert(owned);
+            }
+        }
+    }
+    let fd = fd.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "message carried no fd"))?;
+    let name = String::from_utf8_lossy(&name[..bytes]).into_owned();
+    Ok(Some(PassedKeyboard::new(name, fd)))
+}
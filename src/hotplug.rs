@@ -0,0 +1,147 @@
+//! Noticing keyboards plugged in while the daemon runs.
+//!
+//! udev announces every device it has finished setting up, permissions included, on a netlink
+//! multicast group. Each message is a `libudev` header followed by the device's properties as
+//! NUL-separated `KEY=VALUE` pairs; an added evdev node has `ACTION=add`, `SUBSYSTEM=input`
+//! and a `DEVNAME` under /dev/input.
+
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::path::PathBuf;
+
+use evdev::Device;
+use nix::sys::socket::{bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType};
+use tracing::{info, warn};
+
+use crate::enumeration::{is_keyboard, DeviceFilter};
+
+// udev's netlink multicast group (group 1 is the kernel's, sent before udev has run).
+const UDEV_GROUP: u32 = 2;
+// Header of a udev netlink message: "libudev\0", then the magic number (big-endian), the header
+// size, and the offset and length of the properties (native-endian), each a u32.
+const HEADER_PREFIX: &[u8] = b"libudev\0";
+const UDEV_MAGIC: u32 = 0xfeedcafe;
+const HEADER_MIN_LEN: usize = 24;
+// Largest message udev sends.
+const MAX_MESSAGE_LEN: usize = 8192;
+
+/// A subscription to udev's device announcements.
+pub struct UdevMonitor {
+    socket: OwnedFd,
+}
+
+impl UdevMonitor {
+    pub fn new() -> nix::Result<Self> {
+        let socket = socket(
+            AddressFamily::Netlink,
+            SockType::Datagram,
+            SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkKObjectUEvent,
+        )?;
+        bind(socket.as_raw_fd(), &NetlinkAddr::new(0, UDEV_GROUP))?;
+        Ok(UdevMonitor { socket })
+    }
+
+    /// Reads one announcement, once the monitor is readable, and opens the keyboard it
+    /// announces if `filter` selects it. Fails with `WouldBlock` once there are none left.
+    pub fn added_keyboard(&self, filter: &DeviceFilter) -> io::Result<Option<Device>> {
+        let mut message = [0u8; MAX_MESSAGE_LEN];
+        let len = recv(self.socket.as_raw_fd(), &mut message, MsgFlags::MSG_DONTWAIT)?;
+        let Some(path) = added_input_node(&message[..len]) else {
+            return Ok(None);
+        };
+        match Device::open(&path) {
+            Ok(device) if is_keyboard(&device, filter) => {
+                info!("Keyboard plugged in: {}", device.name().unwrap_or("Unknown"));
+                Ok(Some(device))
+            }
+            Ok(_) => Ok(None),
+            Err(e) => {
+                warn!("Failed to open new input device {}: {}", path.display(), e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl AsFd for UdevMonitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+/// The /dev/input/event* path in a udev "add" message, if that is what it announces.
+fn added_input_node(message: &[u8]) -> Option<PathBuf> {
+    if message.len() < HEADER_MIN_LEN || !message.starts_with(HEADER_PREFIX) {
+        return None;
+    }
+    let field = |offset: usize| <[u8; 4]>::try_from(&message[offset..offset + 4]).ok();
+    if u32::from_be_bytes(field(8)?) != UDEV_MAGIC {
+        return None;
+    }
+    let properties_offset = u32::from_ne_bytes(field(16)?) as usize;
+    let properties_len = u32::from_ne_bytes(field(20)?) as usize;
+    let properties = message.get(properties_offset..properties_offset.checked_add(properties_len)?)?;
+
+    let (mut added, mut input, mut devname) = (false, false, None);
+    for property in properties.split(|&b| b == 0) {
+        // Some properties, e.g. a vendor string, needn't be UTF-8; none of the ones wanted is.
+        let Ok(property) = std::str::from_utf8(property) else {
+            continue;
+        };
+        match property.split_once('=') {
+            Some(("ACTION", action)) => added = action == "add",
+            Some(("SUBSYSTEM", subsystem)) => input = subsystem == "input",
+            Some(("DEVNAME", name)) if name.starts_with("/dev/input/event") => devname = Some(PathBuf::from(name)),
+            _ => {}
+        }
+    }
+    devname.filter(|_| added && input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A udev message with a 40-byte header and `properties` after it.
+    fn message(properties: &[&[u8]]) -> Vec<u8> {
+        let properties: Vec<u8> = properties.iter().flat_map(|property| property.iter().chain(b"\0")).copied().collect();
+        let mut message = HEADER_PREFIX.to_vec();
+        message.extend(UDEV_MAGIC.to_be_bytes());
+        message.extend(40u32.to_ne_bytes());
+        message.extend(40u32.to_ne_bytes());
+        message.extend((properties.len() as u32).to_ne_bytes());
+        message.resize(40, 0);
+        message.extend(properties);
+        message
+    }
+
+    #[test]
+    fn finds_added_event_nodes() {
+        let added = message(&[b"ACTION=add", b"SUBSYSTEM=input", b"DEVNAME=/dev/input/event7"]);
+        assert_eq!(added_input_node(&added), Some(PathBuf::from("/dev/input/event7")));
+        let removed = message(&[b"ACTION=remove", b"SUBSYSTEM=input", b"DEVNAME=/dev/input/event7"]);
+        assert_eq!(added_input_node(&removed), None);
+        let mouse = message(&[b"ACTION=add", b"SUBSYSTEM=input", b"DEVNAME=/dev/input/mouse0"]);
+        assert_eq!(added_input_node(&mouse), None);
+    }
+
+    #[test]
+    fn skips_properties_that_are_not_utf8() {
+        let odd_vendor = message(&[
+            b"ACTION=add",
+            b"ID_VENDOR_ENC=Cherry\xff\xfe",
+            b"SUBSYSTEM=input",
+            b"DEVNAME=/dev/input/event3",
+        ]);
+        assert_eq!(added_input_node(&odd_vendor), Some(PathBuf::from("/dev/input/event3")));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        let mut kernel = message(&[b"ACTION=add", b"SUBSYSTEM=input", b"DEVNAME=/dev/input/event7"]);
+        kernel[..8].copy_from_slice(b"add@/dev");
+        assert_eq!(added_input_node(&kernel), None);
+        assert_eq!(added_input_node(&kernel[..HEADER_MIN_LEN - 1]), None);
+    }
+}
@@ -0,0 +1,488 @@
+//! `qwertdvert import-layout`: translates a keyd or kanata config, or an XKB symbols file,
+//! into a layout file. XKB symbols are translated by [`crate::xkb_symbols`].
+//!
+//! Only what a layout file can express is imported: keys remapped to other keys, with Shift
+//! held or not, on the base layer, plus keyd's `[shift]` and `[altgr]` layers. Everything else
+//! (other layers, tap-hold, macros, one-shot keys, device lists) is left out and listed, so it
+//! can be set up with the config file's own settings instead. Keys remapped to or from a
+//! modifier are left out too: a layout only applies while no shortcut modifier is held, so
+//! those belong under `[modmap]` in the config file, which the list suggests.
+//!
+//! keyd's config is INI-like, one `[section]` per layer:
+//!
+//! ```ini
+//! [main]
+//! capslock = esc
+//! q = '
+//! 1 = S-7
+//! ```
+//!
+//! kanata's is a list of S-expressions, with each `deflayer` giving the keys of `defsrc`, in
+//! order; the first layer is the one that applies without any layer key held:
+//!
+//! ```text
+//! (defsrc q w e)
+//! (deflayer dvorak ' , .)
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use evdev::Key;
+
+use crate::error::ConfigError;
+use crate::layout::{key_name, save_file, Action};
+use crate::remap::{is_modifier, parse_key};
+use crate::xkb_symbols::translate_xkb;
+
+/// The tool a config file was written for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+    Keyd,
+    Kanata,
+    Xkb,
+}
+
+// Where X11 and Wayland desktops keep their XKB layouts, for symbols files given by name.
+const XKB_SYMBOLS_DIR: &str = "/usr/share/X11/xkb/symbols";
+
+impl ImportFormat {
+    /// Guesses the format from the file's extension (`.kbd` is kanata's, `.conf` keyd's), or
+    /// failing that from whether it has a `defsrc` or `xkb_symbols`.
+    pub fn guess(path: &Path, text: &str) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("kbd") => ImportFormat::Kanata,
+            Some("conf") => ImportFormat::Keyd,
+            _ if text.contains("(defsrc") => ImportFormat::Kanata,
+            _ if text.contains("xkb_symbols") => ImportFormat::Xkb,
+            _ => ImportFormat::Keyd,
+        }
+    }
+}
+
+/// A layout translated from another tool's config, and what could not be translated.
+#[derive(Debug, Default, PartialEq)]
+pub struct Translation {
+    /// Physical key = what it types, as in a layout file's `[keys]`.
+    pub keys: BTreeMap<Key, Action>,
+    /// The same while Shift is held, as in `[shifted]`.
+    pub shifted: BTreeMap<Key, Action>,
+    /// The same while AltGr is held, as in `[altgr]`.
+    pub altgr: BTreeMap<Key, Key>,
+    /// What was left out, one line each, saying where it was and why.
+    pub skipped: Vec<String>,
+}
+
+impl Translation {
+    /// The translation as a layout file, headed by `header` as a comment.
+    pub fn to_layout_file(&self, header: &str) -> String {
+        let action = |action: &Action| match *action {
+            Action::Shifted(key) => format!("shift+{}", key_name(key)),
+            Action::Key(key) | Action::Unshifted(key) => key_name(key),
+            Action::Passthrough => unreachable!("passthrough keys are not listed"),
+        };
+        let mut text = String::new();
+        for line in header.lines() {
+            text.push_str(&format!("# {line}\n"));
+        }
+        text.push_str("[keys]\n");
+        for (from, to) in &self.keys {
+            text.push_str(&format!("{} = \"{}\"\n", key_name(*from), action(to)));
+        }
+        if !self.shifted.is_empty() {
+            text.push_str("\n[shifted]\n");
+            for (from, to) in &self.shifted {
+                text.push_str(&format!("{} = \"{}\"\n", key_name(*from), action(to)));
+            }
+        }
+        if !self.altgr.is_empty() {
+            text.push_str("\n[altgr]\n");
+            for (from, to) in &self.altgr {
+                text.push_str(&format!("{} = \"{}\"\n", key_name(*from), key_name(*to)));
+            }
+        }
+        text
+    }
+
+    /// Adds `from` typing `to` to `table`, unless either is a modifier. `place` says where in
+    /// the source it came from, for the skipped list.
+    pub(crate) fn remap(&mut self, table: Table, from: Key, to: Action, place: &str) {
+        let (Action::Key(key) | Action::Shifted(key) | Action::Unshifted(key)) = to else {
+            return;
+        };
+        if is_modifier(from) || is_modifier(key) {
+            self.skipped.push(format!(
+                "{place}: {} → {} involves a modifier; add {} = \"{}\" under [modmap] in config.toml instead",
+                key_name(from),
+                key_name(key),
+                key_name(from),
+                key_name(key)
+            ));
+            return;
+        }
+        match table {
+            Table::Keys if to != Action::Key(from) => {
+                self.keys.insert(from, to);
+            }
+            Table::Shifted => {
+                self.shifted.insert(from, to);
+            }
+            Table::Altgr if key != from => {
+                self.altgr.insert(from, key);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Which table of the layout file a layer goes into.
+#[derive(Clone, Copy)]
+pub(crate) enum Table {
+    Keys,
+    Shifted,
+    Altgr,
+}
+
+/// Parses a key as keyd and kanata write it: an evdev name (`semicolon`), one of kanata's
+/// short names (`scln`, `lsft`), or the character on the key (`;`).
+fn foreign_key(name: &str) -> Option<Key> {
+    let key = match name.to_ascii_lowercase().as_str() {
+        ";" | "scln" => Key::KEY_SEMICOLON,
+        "'" | "apos" | "quot" => Key::KEY_APOSTROPHE,
+        "," | "comm" => Key::KEY_COMMA,
+        "." => Key::KEY_DOT,
+        "/" => Key::KEY_SLASH,
+        "-" | "min" => Key::KEY_MINUS,
+        "=" | "eql" => Key::KEY_EQUAL,
+        "[" | "lbrc" => Key::KEY_LEFTBRACE,
+        "]" | "rbrc" => Key::KEY_RIGHTBRACE,
+        "\\" | "bksl" | "bslh" => Key::KEY_BACKSLASH,
+        "`" | "grv" => Key::KEY_GRAVE,
+        "spc" => Key::KEY_SPACE,
+        "ret" => Key::KEY_ENTER,
+        "bspc" => Key::KEY_BACKSPACE,
+        "del" => Key::KEY_DELETE,
+        "control" | "lctl" => Key::KEY_LEFTCTRL,
+        "rctl" => Key::KEY_RIGHTCTRL,
+        "lsft" => Key::KEY_LEFTSHIFT,
+        "rsft" => Key::KEY_RIGHTSHIFT,
+        "lalt" => Key::KEY_LEFTALT,
+        "ralt" => Key::KEY_RIGHTALT,
+        "lmet" => Key::KEY_LEFTMETA,
+        "rmet" => Key::KEY_RIGHTMETA,
+        name => return parse_key(name),
+    };
+    Some(key)
+}
+
+/// Parses what a key is mapped to: a key, or `S-` and a key for the key with Shift held.
+/// Anything else (other modifiers, actions) is `None`.
+fn foreign_action(value: &str) -> Option<Action> {
+    match value.strip_prefix("S-") {
+        Some(key) => foreign_key(key).map(Action::Shifted),
+        None => foreign_key(value).map(Action::Key),
+    }
+}
+
+/// Reads the keyd or kanata config or XKB symbols file at `source`, in `format` or the one it
+/// looks like, and saves what can be translated as the layout file for `name`. `variant` picks
+/// the block of a symbols file; a symbols file can also be given by name alone, such as `fr`.
+/// Returns where the layout was saved and what was left out.
+pub fn import_layout(
+    source: &Path,
+    format: Option<ImportFormat>,
+    variant: Option<&str>,
+    name: &str,
+) -> Result<(PathBuf, Vec<String>), ConfigError> {
+    let by_name = Path::new(XKB_SYMBOLS_DIR).join(source);
+    let source = match format {
+        Some(ImportFormat::Xkb) | None if !source.exists() && source.components().count() == 1 && by_name.exists() => {
+            by_name.as_path()
+        }
+        _ => source,
+    };
+    let text = std::fs::read_to_string(source)
+        .map_err(|source_error| ConfigError::ReadFile { path: source.to_path_buf(), source: source_error })?;
+    let format = format.unwrap_or_else(|| ImportFormat::guess(source, &text));
+    let translation = match format {
+        ImportFormat::Keyd => translate_keyd(&text),
+        ImportFormat::Kanata => translate_kanata(&text),
+        ImportFormat::Xkb => {
+            // Included files are looked for next to this one.
+            let directory = source.parent().unwrap_or(Path::new("."));
+            let include = |file: &str| std::fs::read_to_string(directory.join(file)).ok();
+            translate_xkb(&text, variant, &include)
+        }
+    }
+    .map_err(|reason| ConfigError::ParseFile { path: source.to_path_buf(), reason })?;
+    let header = format!(
+        "Imported from {} by `qwertdvert import-layout`. Each physical key = the key it types,\n\
+         as on a US QWERTY layout. Keys not listed are left alone.",
+        source.display()
+    );
+    let path = save_file(name, &translation.to_layout_file(&header), "import-layout")?;
+    Ok((path, translation.skipped))
+}
+
+/// Translates a keyd config: `[main]` into `[keys]`, `[shift]` into `[shifted]` and `[altgr]`
+/// into `[altgr]`.
+pub fn translate_keyd(text: &str) -> Result<Translation, String> {
+    let mut translation = Translation::default();
+    let mut section: Option<(String, Option<Table>)> = None;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            let table = match name {
+                "main" => Some(Table::Keys),
+                "shift" => Some(Table::Shifted),
+                "altgr" => Some(Table::Altgr),
+                "ids" => {
+                    translation.skipped.push(
+                        "[ids]: the keyboards to remap are chosen with device_names and exclude_devices instead"
+                            .to_string(),
+                    );
+                    None
+                }
+                _ => {
+                    translation.skipped.push(format!("[{name}]: only the main, shift and altgr layers are imported"));
+                    None
+                }
+            };
+            section = Some((name.to_string(), table));
+            continue;
+        }
+        let place = format!("line {}", number + 1);
+        let Some((name, table)) = &section else {
+            if line.starts_with("include ") {
+                translation.skipped.push(format!("{place}: includes are not followed"));
+                continue;
+            }
+            return Err(format!("{place}: '{line}' is outside any [section]"));
+        };
+        let Some(table) = *table else {
+            continue;
+        };
+        let Some((from, to)) = line.split_once('=') else {
+            return Err(format!("{place}: expected 'key = action' in [{name}], found '{line}'"));
+        };
+        let (from, to) = (from.trim(), to.trim());
+        match (foreign_key(from), foreign_action(to)) {
+            // The shift layer keeps Shift held, so what it maps to is typed shifted.
+            (Some(from), Some(Action::Key(to))) if matches!(table, Table::Shifted) => {
+                translation.remap(table, from, Action::Shifted(to), &place)
+            }
+            (Some(from), Some(Action::Shifted(_))) if matches!(table, Table::Altgr) => {
+                translation.skipped.push(format!("{place}: {} = {to} (AltGr keys cannot add Shift)", key_name(from)))
+            }
+            (Some(from), Some(to)) => translation.remap(table, from, to, &place),
+            _ => translation.skipped.push(format!("{place}: {from} = {to} is not a plain key remap")),
+        }
+    }
+    Ok(translation)
+}
+
+/// An S-expression: an atom, or a parenthesised list of them.
+#[derive(Debug)]
+enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+}
+
+/// Splits kanata's config into its top-level forms, dropping `;;` and `#| |#` comments.
+fn parse_sexps(text: &str) -> Result<Vec<Sexp>, String> {
+    let mut stack: Vec<Vec<Sexp>> = vec![Vec::new()];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' if chars.peek() == Some(&';') => {
+                chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+            }
+            '#' if chars.peek() == Some(&'|') => {
+                chars.next();
+                let mut previous = ' ';
+                loop {
+                    match chars.next() {
+                        Some('#') if previous == '|' => break,
+                        Some(c) => previous = c,
+                        None => return Err("a #| comment is never closed".to_string()),
+                    }
+                }
+            }
+            '(' => stack.push(Vec::new()),
+            ')' => {
+                let list = stack.pop().filter(|_| !stack.is_empty()).ok_or("unbalanced ')'")?;
+                stack.last_mut().unwrap().push(Sexp::List(list));
+            }
+            '"' => {
+                let atom: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                stack.last_mut().unwrap().push(Sexp::Atom(atom));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut atom = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == '(' || next == ')' {
+                        break;
+                    }
+                    atom.push(next);
+                    chars.next();
+                }
+                stack.last_mut().unwrap().push(Sexp::Atom(atom));
+            }
+        }
+    }
+    match stack.len() {
+        1 => Ok(stack.pop().unwrap()),
+        _ => Err("a '(' is never closed".to_string()),
+    }
+}
+
+/// Translates a kanata config: its first layer, `deflayer` or `deflayermap`, into `[keys]`.
+pub fn translate_kanata(text: &str) -> Result<Translation, String> {
+    let mut translation = Translation::default();
+    let mut source: Option<Vec<Key>> = None;
+    let mut aliases: BTreeMap<String, String> = BTreeMap::new();
+    // The first layer's (physical key, action) pairs, once found.
+    let mut base: Option<(String, Vec<(Key, &Sexp)>)> = None;
+    let forms = parse_sexps(text)?;
+    for form in &forms {
+        let Sexp::List(items) = form else {
+            return Err("expected only (...) forms at the top level".to_string());
+        };
+        let (Some(Sexp::Atom(kind)), rest) = (items.first(), items.get(1..).unwrap_or_default()) else {
+            continue;
+        };
+        match kind.as_str() {
+            "defsrc" => {
+                let keys = rest
+                    .iter()
+                    .map(|item| match item {
+                        Sexp::Atom(name) => foreign_key(name).ok_or(format!("defsrc: unknown key '{name}'")),
+                        Sexp::List(_) => Err("defsrc: expected key names".to_string()),
+                    })
+                    .collect::<Result<_, _>>()?;
+                source = Some(keys);
+            }
+            "defalias" => {
+                for pair in rest.chunks(2) {
+                    if let [Sexp::Atom(name), Sexp::Atom(action)] = pair {
+                        aliases.insert(name.clone(), action.clone());
+                    }
+                }
+            }
+            "deflayer" | "deflayermap" => {
+                let name = match rest.first() {
+                    Some(Sexp::Atom(name)) => name.clone(),
+                    Some(Sexp::List(name)) => match name.first() {
+                        Some(Sexp::Atom(name)) => name.clone(),
+                        _ => String::new(),
+                    },
+                    None => return Err(format!("{kind} without a name")),
+                };
+                if base.is_some() {
+                    translation.skipped.push(format!("layer {name}: only the first layer is imported"));
+                    continue;
+                }
+                let actions = &rest[1..];
+                let pairs = if kind == "deflayer" {
+                    let keys = source.as_ref().ok_or("deflayer comes before defsrc")?;
+                    if keys.len() != actions.len() {
+                        return Err(format!("layer {name} has {} keys but defsrc has {}", actions.len(), keys.len()));
+                    }
+                    keys.iter().copied().zip(actions).collect()
+                } else {
+                    let mut pairs = Vec::new();
+                    for pair in actions.chunks(2) {
+                        match pair {
+                            [Sexp::Atom(from), action] => match foreign_key(from) {
+                                Some(from) => pairs.push((from, action)),
+                                None => translation.skipped.push(format!("layer {name}: {from} is not a key")),
+                            },
+                            _ => return Err(format!("layer {name}: expected key and action pairs")),
+                        }
+                    }
+                    pairs
+                };
+                base = Some((name, pairs));
+            }
+            _ => {}
+        }
+    }
+    let (name, pairs) = base.ok_or("no deflayer")?;
+    let place = format!("layer {name}");
+    for (from, action) in pairs {
+        let atom = match action {
+            Sexp::Atom(atom) => match atom.strip_prefix('@') {
+                Some(alias) => aliases.get(alias).map(String::as_str).unwrap_or(atom),
+                None => atom,
+            },
+            Sexp::List(_) => {
+                translation.skipped.push(format!("{place}: {} has an action, not a plain key", key_name(from)));
+                continue;
+            }
+        };
+        match atom {
+            "_" => {}
+            "XX" | "✗" | "∅" | "•" => {
+                translation.skipped.push(format!("{place}: {} is disabled", key_name(from)))
+            }
+            atom => match foreign_action(atom) {
+                Some(to) => translation.remap(Table::Keys, from, to, &place),
+                None => translation.skipped.push(format!("{place}: {} = {atom} is not a plain key", key_name(from))),
+            },
+        }
+    }
+    Ok(translation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_keyd_layers() {
+        let translation = translate_keyd(
+            "[ids]\n*\n\n[main]\n# Dvorak's top row\nq = '\nw = comma\n1 = S-7\ncapslock = overload(control, esc)\n\
+             rightalt = leftctrl\n\n[shift]\n1 = 5\n\n[altgr]\ne = S-e\n\n[nav]\nh = left\n",
+        )
+        .unwrap();
+        let keys = [
+            (Key::KEY_1, Action::Shifted(Key::KEY_7)),
+            (Key::KEY_Q, Action::Key(Key::KEY_APOSTROPHE)),
+            (Key::KEY_W, Action::Key(Key::KEY_COMMA)),
+        ];
+        assert_eq!(translation.keys, BTreeMap::from(keys));
+        assert_eq!(translation.shifted, BTreeMap::from([(Key::KEY_1, Action::Shifted(Key::KEY_5))]));
+        assert_eq!(translation.skipped.len(), 5, "{:?}", translation.skipped);
+        assert!(translation.skipped[2].contains("add rightalt = \"leftctrl\" under [modmap]"));
+        assert_eq!(
+            translation.to_layout_file("Test"),
+            "# Test\n[keys]\n1 = \"shift+7\"\nq = \"apostrophe\"\nw = \"comma\"\n\n[shifted]\n1 = \"shift+5\"\n"
+        );
+    }
+
+    #[test]
+    fn translates_the_first_kanata_layer() {
+        let translation = translate_kanata(
+            ";; Dvorak\n(defcfg process-unmapped-keys yes)\n(defalias cap (tap-hold 200 200 esc lctl) dot .)\n\
+             (defsrc q w e caps 1)\n#| the base layer |#\n(deflayer dvorak ' , @dot @cap S-7)\n\
+             (deflayer nav _ _ _ _ _)\n",
+        )
+        .unwrap();
+        let keys = [
+            (Key::KEY_1, Action::Shifted(Key::KEY_7)),
+            (Key::KEY_E, Action::Key(Key::KEY_DOT)),
+            (Key::KEY_Q, Action::Key(Key::KEY_APOSTROPHE)),
+            (Key::KEY_W, Action::Key(Key::KEY_COMMA)),
+        ];
+        assert_eq!(translation.keys, BTreeMap::from(keys));
+        assert_eq!(
+            translation.skipped,
+            ["layer nav: only the first layer is imported", "layer dvorak: capslock = @cap is not a plain key"]
+        );
+        assert!(translate_kanata("(defsrc q w)\n(deflayer base a)").is_err());
+    }
+}
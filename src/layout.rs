@@ -0,0 +1,648 @@
+//! Layouts: what each physical key produces.
+//!
+//! A [`Layout`] maps a key to an [`Action`]. Layouts are looked up by name in a process-wide
+//! registry, which starts out with the built-in layouts (Dvorak, its one-handed and Programmer
+//! variants, Colemak, Workman, and QWERTY, which changes nothing); other crates and binaries can
+//! [`register`] their own before starting a [`Daemon`](crate::Daemon) and select them with
+//! [`Config::layout`](crate::Config::layout).
+//!
+//! A name that is not registered is looked for as a layout file, `NAME.toml` in the
+//! `layouts` directory next to the config file (as written by `qwertdvert record-layout`):
+//!
+//! ```toml
+//! [keys]          # physical key = key it types, as on a US QWERTY layout
+//! q = "apostrophe"
+//! w = "comma"
+//! 1 = "shift+7"   # with Shift held whether or not it is: 1 types &
+//!
+//! [shifted]       # the same while Shift is held, "shift+" included; optional
+//! 1 = "shift+5"   # Shift+1 types %
+//! 2 = "7"         # Shift+2 types 7, without Shift
+//!
+//! [altgr]         # the same while AltGr is held; optional
+//! q = "q"
+//! ```
+//!
+//! Keys not in `[shifted]` type what `[keys]` says, shifted.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+
+use evdev::Key;
+use serde::Deserialize;
+
+use crate::error::ConfigError;
+use crate::remap::parse_key;
+use crate::status::state_changed;
+
+// Names of the built-in layouts. Dvorak is the default.
+pub const DVORAK: &str = "dvorak";
+pub const DVORAK_LEFT: &str = "dvorak-left";
+pub const DVORAK_RIGHT: &str = "dvorak-right";
+pub const PROGRAMMER_DVORAK: &str = "programmer-dvorak";
+pub const COLEMAK: &str = "colemak";
+pub const WORKMAN: &str = "workman";
+pub const QWERTY: &str = "qwerty";
+
+// LAYOUTS_DIR: Directory of layout files, under the config directory.
+pub const LAYOUTS_DIR: &str = "layouts";
+
+/// What a layout does with a key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Action {
+    /// Emit this key instead.
+    Key(Key),
+    /// Emit this key with Shift held, whether or not it is.
+    Shifted(Key),
+    /// Emit this key with Shift released, whether or not it is held.
+    Unshifted(Key),
+    /// Leave the key unchanged.
+    Passthrough,
+}
+
+/// A keyboard layout, applied while no shortcut modifier is held.
+pub trait Layout: Send + Sync {
+    /// Name the layout is registered and selected under.
+    fn name(&self) -> &str;
+
+    /// What pressing `key` produces. Presses, releases and repeats of a key all map the same way.
+    fn map(&self, key: Key) -> Action;
+
+    /// What pressing `key` produces while Shift is held, for layouts whose shifted symbols
+    /// aren't simply the shifted [`map`](Layout::map). [`Action::Key`] keeps Shift held.
+    fn map_shifted(&self, key: Key) -> Action {
+        self.map(key)
+    }
+
+    /// What pressing `key` produces while AltGr is held, if an AltGr key is configured. The
+    /// system layout's third level then types the symbol. By default every key passes through,
+    /// so AltGr symbols stay where the keyboard's legends show them.
+    fn map_altgr(&self, _key: Key) -> Action {
+        Action::Passthrough
+    }
+
+    /// Whether `key` is mapped by an override set in the config's `[keys]` rather than by the
+    /// layout itself, for the explain trace.
+    fn is_overridden(&self, _key: Key) -> bool {
+        false
+    }
+}
+
+/// QWERTY to Dvorak, assuming the system layout is QWERTY.
+pub struct Dvorak;
+
+impl Layout for Dvorak {
+    fn name(&self) -> &str {
+        DVORAK
+    }
+
+    fn map(&self, key: Key) -> Action {
+        Action::Key(match key {
+            Key::KEY_MINUS => Key::KEY_LEFTBRACE,
+            Key::KEY_EQUAL => Key::KEY_RIGHTBRACE,
+            Key::KEY_Q => Key::KEY_APOSTROPHE,
+            Key::KEY_W => Key::KEY_COMMA,
+            Key::KEY_E => Key::KEY_DOT,
+            Key::KEY_R => Key::KEY_P,
+            Key::KEY_T => Key::KEY_Y,
+            Key::KEY_Y => Key::KEY_F,
+            Key::KEY_U => Key::KEY_G,
+            Key::KEY_I => Key::KEY_C,
+            Key::KEY_O => Key::KEY_R,
+            Key::KEY_P => Key::KEY_L,
+            Key::KEY_LEFTBRACE => Key::KEY_SLASH,
+            Key::KEY_RIGHTBRACE => Key::KEY_EQUAL,
+            Key::KEY_S => Key::KEY_O,
+            Key::KEY_D => Key::KEY_E,
+            Key::KEY_F => Key::KEY_U,
+            Key::KEY_G => Key::KEY_I,
+            Key::KEY_H => Key::KEY_D,
+            Key::KEY_J => Key::KEY_H,
+            Key::KEY_K => Key::KEY_T,
+            Key::KEY_L => Key::KEY_N,
+            Key::KEY_SEMICOLON => Key::KEY_S,
+            Key::KEY_APOSTROPHE => Key::KEY_MINUS,
+            Key::KEY_Z => Key::KEY_SEMICOLON,
+            Key::KEY_X => Key::KEY_Q,
+            Key::KEY_C => Key::KEY_J,
+            Key::KEY_V => Key::KEY_K,
+            Key::KEY_B => Key::KEY_X,
+            Key::KEY_N => Key::KEY_B,
+            Key::KEY_COMMA => Key::KEY_W,
+            Key::KEY_DOT => Key::KEY_V,
+            Key::KEY_SLASH => Key::KEY_Z,
+            _ => return Action::Passthrough,
+        })
+    }
+}
+
+/// One-handed Dvorak for the left hand, assuming the system layout is QWERTY.
+pub struct DvorakLeft;
+
+impl Layout for DvorakLeft {
+    fn name(&self) -> &str {
+        DVORAK_LEFT
+    }
+
+    fn map(&self, key: Key) -> Action {
+        Action::Key(match key {
+            Key::KEY_1 => Key::KEY_LEFTBRACE,
+            Key::KEY_2 => Key::KEY_RIGHTBRACE,
+            Key::KEY_3 => Key::KEY_SLASH,
+            Key::KEY_4 => Key::KEY_P,
+            Key::KEY_5 => Key::KEY_F,
+            Key::KEY_6 => Key::KEY_M,
+            Key::KEY_7 => Key::KEY_L,
+            Key::KEY_8 => Key::KEY_J,
+            Key::KEY_9 => Key::KEY_4,
+            Key::KEY_0 => Key::KEY_3,
+            Key::KEY_MINUS => Key::KEY_2,
+            Key::KEY_EQUAL => Key::KEY_1,
+            Key::KEY_Q => Key::KEY_SEMICOLON,
+            Key::KEY_W => Key::KEY_Q,
+            Key::KEY_E => Key::KEY_B,
+            Key::KEY_R => Key::KEY_Y,
+            Key::KEY_T => Key::KEY_U,
+            Key::KEY_Y => Key::KEY_R,
+            Key::KEY_U => Key::KEY_S,
+            Key::KEY_I => Key::KEY_O,
+            Key::KEY_O => Key::KEY_DOT,
+            Key::KEY_P => Key::KEY_6,
+            Key::KEY_LEFTBRACE => Key::KEY_5,
+            Key::KEY_RIGHTBRACE => Key::KEY_EQUAL,
+            Key::KEY_A => Key::KEY_MINUS,
+            Key::KEY_S => Key::KEY_K,
+            Key::KEY_D => Key::KEY_C,
+            Key::KEY_F => Key::KEY_D,
+            Key::KEY_G => Key::KEY_T,
+            Key::KEY_J => Key::KEY_E,
+            Key::KEY_K => Key::KEY_A,
+            Key::KEY_L => Key::KEY_Z,
+            Key::KEY_SEMICOLON => Key::KEY_8,
+            Key::KEY_APOSTROPHE => Key::KEY_7,
+            Key::KEY_Z => Key::KEY_APOSTROPHE,
+            Key::KEY_C => Key::KEY_G,
+            Key::KEY_B => Key::KEY_W,
+            Key::KEY_M => Key::KEY_I,
+            Key::KEY_DOT => Key::KEY_0,
+            Key::KEY_SLASH => Key::KEY_9,
+            _ => return Action::Passthrough,
+        })
+    }
+}
+
+/// One-handed Dvorak for the right hand, assuming the system layout is QWERTY.
+pub struct DvorakRight;
+
+impl Layout for DvorakRight {
+    fn name(&self) -> &str {
+        DVORAK_RIGHT
+    }
+
+    fn map(&self, key: Key) -> Action {
+        Action::Key(match key {
+            Key::KEY_5 => Key::KEY_J,
+            Key::KEY_6 => Key::KEY_L,
+            Key::KEY_7 => Key::KEY_M,
+            Key::KEY_8 => Key::KEY_F,
+            Key::KEY_9 => Key::KEY_P,
+            Key::KEY_0 => Key::KEY_SLASH,
+            Key::KEY_MINUS => Key::KEY_LEFTBRACE,
+            Key::KEY_EQUAL => Key::KEY_RIGHTBRACE,
+            Key::KEY_Q => Key::KEY_5,
+            Key::KEY_W => Key::KEY_6,
+            Key::KEY_E => Key::KEY_Q,
+            Key::KEY_R => Key::KEY_DOT,
+            Key::KEY_T => Key::KEY_O,
+            Key::KEY_Y => Key::KEY_R,
+            Key::KEY_U => Key::KEY_S,
+            Key::KEY_I => Key::KEY_U,
+            Key::KEY_O => Key::KEY_Y,
+            Key::KEY_P => Key::KEY_B,
+            Key::KEY_LEFTBRACE => Key::KEY_SEMICOLON,
+            Key::KEY_RIGHTBRACE => Key::KEY_EQUAL,
+            Key::KEY_A => Key::KEY_7,
+            Key::KEY_S => Key::KEY_8,
+            Key::KEY_D => Key::KEY_Z,
+            Key::KEY_F => Key::KEY_A,
+            Key::KEY_G => Key::KEY_E,
+            Key::KEY_J => Key::KEY_T,
+            Key::KEY_K => Key::KEY_D,
+            Key::KEY_L => Key::KEY_C,
+            Key::KEY_SEMICOLON => Key::KEY_K,
+            Key::KEY_APOSTROPHE => Key::KEY_MINUS,
+            Key::KEY_Z => Key::KEY_9,
+            Key::KEY_X => Key::KEY_0,
+            Key::KEY_C => Key::KEY_X,
+            Key::KEY_V => Key::KEY_COMMA,
+            Key::KEY_B => Key::KEY_I,
+            Key::KEY_M => Key::KEY_W,
+            Key::KEY_COMMA => Key::KEY_V,
+            Key::KEY_DOT => Key::KEY_G,
+            Key::KEY_SLASH => Key::KEY_APOSTROPHE,
+            _ => return Action::Passthrough,
+        })
+    }
+}
+
+/// QWERTY to Programmer Dvorak, assuming the system layout is QWERTY: Dvorak letters, with
+/// brackets and symbols on the number row and the digits on it shifted, in the order 7531902468.
+pub struct ProgrammerDvorak;
+
+impl Layout for ProgrammerDvorak {
+    fn name(&self) -> &str {
+        PROGRAMMER_DVORAK
+    }
+
+    fn map(&self, key: Key) -> Action {
+        match key {
+            Key::KEY_GRAVE => Action::Shifted(Key::KEY_4),
+            Key::KEY_1 => Action::Shifted(Key::KEY_7),
+            Key::KEY_2 => Action::Key(Key::KEY_LEFTBRACE),
+            Key::KEY_3 => Action::Shifted(Key::KEY_LEFTBRACE),
+            Key::KEY_4 => Action::Shifted(Key::KEY_RIGHTBRACE),
+            Key::KEY_5 => Action::Shifted(Key::KEY_9),
+            Key::KEY_6 => Action::Key(Key::KEY_EQUAL),
+            Key::KEY_7 => Action::Shifted(Key::KEY_8),
+            Key::KEY_8 => Action::Shifted(Key::KEY_0),
+            Key::KEY_9 => Action::Shifted(Key::KEY_EQUAL),
+            Key::KEY_0 => Action::Key(Key::KEY_RIGHTBRACE),
+            Key::KEY_MINUS => Action::Shifted(Key::KEY_1),
+            Key::KEY_EQUAL => Action::Shifted(Key::KEY_3),
+            Key::KEY_Q => Action::Key(Key::KEY_SEMICOLON),
+            Key::KEY_RIGHTBRACE => Action::Shifted(Key::KEY_2),
+            Key::KEY_Z => Action::Key(Key::KEY_APOSTROPHE),
+            // The rest of the letters and punctuation are where Dvorak has them.
+            _ => Dvorak.map(key),
+        }
+    }
+
+    fn map_shifted(&self, key: Key) -> Action {
+        match key {
+            Key::KEY_GRAVE => Action::Key(Key::KEY_GRAVE),
+            Key::KEY_1 => Action::Key(Key::KEY_5),
+            Key::KEY_2 => Action::Unshifted(Key::KEY_7),
+            Key::KEY_3 => Action::Unshifted(Key::KEY_5),
+            Key::KEY_4 => Action::Unshifted(Key::KEY_3),
+            Key::KEY_5 => Action::Unshifted(Key::KEY_1),
+            Key::KEY_6 => Action::Unshifted(Key::KEY_9),
+            Key::KEY_7 => Action::Unshifted(Key::KEY_0),
+            Key::KEY_8 => Action::Unshifted(Key::KEY_2),
+            Key::KEY_9 => Action::Unshifted(Key::KEY_4),
+            Key::KEY_0 => Action::Unshifted(Key::KEY_6),
+            Key::KEY_MINUS => Action::Unshifted(Key::KEY_8),
+            Key::KEY_EQUAL => Action::Unshifted(Key::KEY_GRAVE),
+            Key::KEY_RIGHTBRACE => Action::Key(Key::KEY_6),
+            _ => self.map(key),
+        }
+    }
+}
+
+/// QWERTY to Colemak, assuming the system layout is QWERTY.
+pub struct Colemak;
+
+impl Layout for Colemak {
+    fn name(&self) -> &str {
+        COLEMAK
+    }
+
+    fn map(&self, key: Key) -> Action {
+        Action::Key(match key {
+            Key::KEY_E => Key::KEY_F,
+            Key::KEY_R => Key::KEY_P,
+            Key::KEY_T => Key::KEY_G,
+            Key::KEY_Y => Key::KEY_J,
+            Key::KEY_U => Key::KEY_L,
+            Key::KEY_I => Key::KEY_U,
+            Key::KEY_O => Key::KEY_Y,
+            Key::KEY_P => Key::KEY_SEMICOLON,
+            Key::KEY_S => Key::KEY_R,
+            Key::KEY_D => Key::KEY_S,
+            Key::KEY_F => Key::KEY_T,
+            Key::KEY_G => Key::KEY_D,
+            Key::KEY_J => Key::KEY_N,
+            Key::KEY_K => Key::KEY_E,
+            Key::KEY_L => Key::KEY_I,
+            Key::KEY_SEMICOLON => Key::KEY_O,
+            Key::KEY_N => Key::KEY_K,
+            _ => return Action::Passthrough,
+        })
+    }
+}
+
+/// QWERTY to Workman, assuming the system layout is QWERTY.
+pub struct Workman;
+
+impl Layout for Workman {
+    fn name(&self) -> &str {
+        WORKMAN
+    }
+
+    fn map(&self, key: Key) -> Action {
+        Action::Key(match key {
+            Key::KEY_W => Key::KEY_D,
+            Key::KEY_E => Key::KEY_R,
+            Key::KEY_R => Key::KEY_W,
+            Key::KEY_T => Key::KEY_B,
+            Key::KEY_Y => Key::KEY_J,
+            Key::KEY_U => Key::KEY_F,
+            Key::KEY_I => Key::KEY_U,
+            Key::KEY_O => Key::KEY_P,
+            Key::KEY_P => Key::KEY_SEMICOLON,
+            Key::KEY_D => Key::KEY_H,
+            Key::KEY_F => Key::KEY_T,
+            Key::KEY_H => Key::KEY_Y,
+            Key::KEY_J => Key::KEY_N,
+            Key::KEY_K => Key::KEY_E,
+            Key::KEY_L => Key::KEY_O,
+            Key::KEY_SEMICOLON => Key::KEY_I,
+            Key::KEY_C => Key::KEY_M,
+            Key::KEY_V => Key::KEY_C,
+            Key::KEY_B => Key::KEY_V,
+            Key::KEY_N => Key::KEY_K,
+            Key::KEY_M => Key::KEY_L,
+            _ => return Action::Passthrough,
+        })
+    }
+}
+
+/// Every key as it is, for keyboards that already have the layout wanted, e.g. one with
+/// Dvorak keycaps and firmware.
+pub struct Qwerty;
+
+impl Layout for Qwerty {
+    fn name(&self) -> &str {
+        QWERTY
+    }
+
+    fn map(&self, _key: Key) -> Action {
+        Action::Passthrough
+    }
+}
+
+/// A layout read from a file: each listed key types the given key, and the rest pass through.
+pub struct Keymap {
+    name: String,
+    keys: HashMap<Key, Action>,
+    shifted: HashMap<Key, Action>,
+    altgr: HashMap<Key, Key>,
+}
+
+impl Keymap {
+    pub fn new(name: String, keys: HashMap<Key, Key>) -> Self {
+        let keys = keys.into_iter().map(|(from, to)| (from, Action::Key(to))).collect();
+        Keymap { name, keys, shifted: HashMap::new(), altgr: HashMap::new() }
+    }
+
+    /// Also maps keys typed while Shift is held; those not listed map as without Shift.
+    pub fn with_shifted(mut self, shifted: HashMap<Key, Action>) -> Self {
+        self.shifted = shifted;
+        self
+    }
+
+    /// Also maps keys typed while AltGr is held; those not listed pass through.
+    pub fn with_altgr(mut self, altgr: HashMap<Key, Key>) -> Self {
+        self.altgr = altgr;
+        self
+    }
+
+    /// Reads a layout file. The layout is named after the file, without `.toml`.
+    pub fn load(path: &Path) -> Result<Keymap, ConfigError> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct KeymapFile {
+            keys: BTreeMap<String, String>,
+            #[serde(default)]
+            shifted: BTreeMap<String, String>,
+            #[serde(default)]
+            altgr: BTreeMap<String, String>,
+        }
+
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::ReadFile { path: path.to_path_buf(), source })?;
+        let invalid = |reason: String| ConfigError::ParseFile { path: path.to_path_buf(), reason };
+        let file: KeymapFile = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        let key = |name: &str| {
+            parse_key(name).ok_or_else(|| {
+                invalid(format!("unknown key '{name}' (use evdev key names, e.g. 'semicolon' or 'KEY_SEMICOLON')"))
+            })
+        };
+        // "shift+KEY" types KEY with Shift held. Otherwise `[keys]` leaves Shift as it is, and
+        // `[shifted]` releases it.
+        let action = |name: &str, unshifted: fn(Key) -> Action| match name.strip_prefix("shift+") {
+            Some(name) => key(name).map(Action::Shifted),
+            None => key(name).map(unshifted),
+        };
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let mut keymap = Keymap::new(name, HashMap::new());
+        for (from, to) in &file.keys {
+            keymap.keys.insert(key(from)?, action(to, Action::Key)?);
+        }
+        for (from, to) in &file.shifted {
+            keymap.shifted.insert(key(from)?, action(to, Action::Unshifted)?);
+        }
+        for (from, to) in &file.altgr {
+            keymap.altgr.insert(key(from)?, key(to)?);
+        }
+        Ok(keymap)
+    }
+}
+
+impl Layout for Keymap {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn map(&self, key: Key) -> Action {
+        self.keys.get(&key).copied().unwrap_or(Action::Passthrough)
+    }
+
+    fn map_shifted(&self, key: Key) -> Action {
+        self.shifted.get(&key).copied().unwrap_or_else(|| self.map(key))
+    }
+
+    fn map_altgr(&self, key: Key) -> Action {
+        self.altgr.get(&key).map_or(Action::Passthrough, |&to| Action::Key(to))
+    }
+}
+
+/// Another layout with some keys changed, as set by `[keys]` in the config file.
+pub struct Overridden {
+    base: Arc<dyn Layout>,
+    keys: HashMap<Key, Key>,
+}
+
+impl Overridden {
+    pub fn new(base: Arc<dyn Layout>, keys: &[(Key, Key)]) -> Self {
+        Overridden { base, keys: keys.iter().copied().collect() }
+    }
+}
+
+impl Layout for Overridden {
+    fn name(&self) -> &str {
+        self.base.name()
+    }
+
+    fn map(&self, key: Key) -> Action {
+        self.keys.get(&key).map_or_else(|| self.base.map(key), |&to| Action::Key(to))
+    }
+
+    fn map_shifted(&self, key: Key) -> Action {
+        self.keys.get(&key).map_or_else(|| self.base.map_shifted(key), |&to| Action::Key(to))
+    }
+
+    fn map_altgr(&self, key: Key) -> Action {
+        self.base.map_altgr(key)
+    }
+
+    fn is_overridden(&self, key: Key) -> bool {
+        self.keys.contains_key(&key)
+    }
+}
+
+/// The layout the keyboards type with. It can be switched while they are captured; every
+/// keyboard picks up the new layout with its next key press.
+pub struct ActiveLayout {
+    current: RwLock<Arc<dyn Layout>>,
+    selected: RwLock<Selection>,
+}
+
+/// The selected layout, and the config file's `[keys]` applied on top of it.
+struct Selection {
+    layout: Arc<dyn Layout>,
+    overrides: Vec<(Key, Key)>,
+}
+
+impl ActiveLayout {
+    pub fn new(layout: Arc<dyn Layout>, overrides: Vec<(Key, Key)>) -> Self {
+        let active = ActiveLayout {
+            current: RwLock::new(layout.clone()),
+            selected: RwLock::new(Selection { layout, overrides }),
+        };
+        active.update(|_| {});
+        active
+    }
+
+    /// The layout to map the next key with.
+    pub fn get(&self) -> Arc<dyn Layout> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn name(&self) -> String {
+        self.get().name().to_string()
+    }
+
+    /// Switches to `layout`.
+    pub fn set(&self, layout: Arc<dyn Layout>) {
+        self.update(|selected| selected.layout = layout);
+    }
+
+    /// Replaces the `[keys]` overrides, keeping the selected layout.
+    pub fn set_overrides(&self, overrides: Vec<(Key, Key)>) {
+        self.update(|selected| selected.overrides = overrides);
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Selection)) {
+        let mut selected = self.selected.write().unwrap();
+        change(&mut selected);
+        let layout: Arc<dyn Layout> = match selected.overrides.is_empty() {
+            true => selected.layout.clone(),
+            false => Arc::new(Overridden::new(selected.layout.clone(), &selected.overrides)),
+        };
+        *self.current.write().unwrap() = layout;
+        state_changed();
+    }
+
+    /// Switches to the layout registered (or with a layout file) as `name`.
+    pub fn select(&self, name: &str) -> Result<(), ConfigError> {
+        self.set(find(name)?);
+        Ok(())
+    }
+}
+
+static REGISTRY: LazyLock<RwLock<BTreeMap<String, Arc<dyn Layout>>>> = LazyLock::new(|| {
+    let mut layouts: BTreeMap<String, Arc<dyn Layout>> = BTreeMap::new();
+    let builtin: [Arc<dyn Layout>; 7] = [
+        Arc::new(Dvorak),
+        Arc::new(DvorakLeft),
+        Arc::new(DvorakRight),
+        Arc::new(ProgrammerDvorak),
+        Arc::new(Colemak),
+        Arc::new(Workman),
+        Arc::new(Qwerty),
+    ];
+    for layout in builtin {
+        layouts.insert(layout.name().to_string(), layout);
+    }
+    RwLock::new(layouts)
+});
+
+/// Makes `layout` available under its name, replacing any layout already registered with it.
+pub fn register(layout: impl Layout + 'static) {
+    let layout: Arc<dyn Layout> = Arc::new(layout);
+    REGISTRY.write().unwrap().insert(layout.name().to_string(), layout);
+}
+
+/// The layout registered as `name`, if any.
+pub fn lookup(name: &str) -> Option<Arc<dyn Layout>> {
+    REGISTRY.read().unwrap().get(name).cloned()
+}
+
+/// Where the layout file for `name` is looked for.
+pub fn file_path(name: &str) -> Option<PathBuf> {
+    Some(crate::Config::dir()?.join(LAYOUTS_DIR).join(format!("{name}.toml")))
+}
+
+/// Writes `text` as the layout file for `name`, creating the layouts directory if need be, and
+/// returns where it was written. `command` names what is writing it, for the error if there is
+/// no config directory.
+pub(crate) fn save_file(name: &str, text: &str, command: &'static str) -> Result<PathBuf, ConfigError> {
+    let path = file_path(name).ok_or(ConfigError::InvalidValue {
+        flag: command,
+        reason: "neither XDG_CONFIG_HOME nor HOME is set".to_string(),
+    })?;
+    let write = |path: &PathBuf| -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, text)
+    };
+    write(&path).map_err(|source| ConfigError::WriteFile { path: path.clone(), source })?;
+    Ok(path)
+}
+
+/// How a key is written in layout files: its evdev name, lower case, without `KEY_`.
+pub(crate) fn key_name(key: Key) -> String {
+    let name = format!("{key:?}");
+    name.strip_prefix("KEY_").unwrap_or(&name).to_ascii_lowercase()
+}
+
+/// Like [`lookup`], but if nothing is registered as `name`, loads and registers its layout
+/// file if there is one.
+pub fn lookup_or_load(name: &str) -> Result<Option<Arc<dyn Layout>>, ConfigError> {
+    if let Some(layout) = lookup(name) {
+        return Ok(Some(layout));
+    }
+    match file_path(name).filter(|path| path.exists()) {
+        Some(path) => {
+            register(Keymap::load(&path)?);
+            Ok(lookup(name))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Like [`lookup_or_load`], but a layout that can't be found is an error.
+pub fn find(name: &str) -> Result<Arc<dyn Layout>, ConfigError> {
+    lookup_or_load(name)?.ok_or_else(|| ConfigError::UnknownLayout {
+        name: name.to_string(),
+        registered: registered().join(", "),
+    })
+}
+
+/// Names of all registered layouts, sorted.
+pub fn registered() -> Vec<String> {
+    REGISTRY.read().unwrap().keys().cloned().collect()
+}
@@ -0,0 +1,78 @@
+//! QWERTY to Dvorak keyboard remapper.
+//!
+//! Monitors keyboard input devices via evdev, applies Dvorak remapping with
+//! modifier-aware passthrough (Ctrl/Alt/Super shortcuts remain QWERTY),
+//! and emits remapped events via uinput.
+//!
+//! The `qwertdvert` binary is a thin wrapper around [`Daemon`]: it parses arguments into a
+//! [`Config`], sets up logging and signal handling, and calls [`Daemon::run`]. The tray reads
+//! what the daemon publishes through [`stats`], and the device helper is [`helper::serve`].
+//!
+//! The remapping itself is pure and needs no devices: a [`remapper::Remapper`] takes a
+//! keyboard's events and returns the events to emit, running them through a
+//! [`pipeline::Pipeline`], with the modifier tracking and layout decisions in [`remap`] and the
+//! layouts in [`layout`].
+
+#[cfg(feature = "dbus")]
+mod bus;
+mod capture;
+pub mod chord;
+pub mod combo;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod debounce;
+#[cfg(feature = "portal")]
+mod ei;
+pub mod enumeration;
+pub mod error;
+mod event_loop;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+mod feedback;
+pub mod ffi;
+mod game;
+pub mod helper;
+mod hotplug;
+pub mod import;
+pub mod layout;
+pub mod lint;
+pub mod logging;
+#[cfg(feature = "dbus")]
+mod logind;
+pub mod macros;
+pub mod mapping;
+#[cfg(feature = "prometheus")]
+mod metrics;
+pub mod mirror;
+mod notify;
+pub mod observe;
+mod output;
+pub mod pipeline;
+#[cfg(feature = "portal")]
+mod portal;
+pub mod record;
+pub mod remap;
+pub mod remapper;
+pub mod replay;
+pub mod repeat;
+mod ring;
+mod scancode;
+mod sched;
+pub mod selftest;
+pub mod shutdown;
+pub mod source;
+pub mod stats;
+pub mod status;
+pub mod sticky;
+pub mod taphold;
+#[cfg(feature = "otel")]
+mod telemetry;
+pub mod watch;
+pub mod xkb;
+pub mod xkb_symbols;
+
+pub use config::Config;
+pub use daemon::Daemon;
+pub use error::DaemonError;
+pub use layout::{Action, Layout};
@@ -0,0 +1,238 @@
+//! Keymap linter: finds mappings that load fine but are probably mistakes.
+//!
+//! The config file can only say so much about itself when it is parsed: `[keys] q = "a"` is
+//! valid, but leaves two keys typing `a` and none typing what Q typed before. [`lint`] looks at
+//! the mapping the config adds up to, the layout with `[keys]` and `[modmap]` applied, and
+//! reports
+//!
+//! - two physical keys that type the same character,
+//! - characters no key types any more,
+//! - `[keys]` or `[modmap]` entries that map keys round in a loop of three or more (two keys
+//!   mapped to each other are a swap, which is fine), since each key is only mapped once,
+//! - keys mapped to codes the virtual keyboard doesn't advertise, which never reach the desktop.
+//!
+//! Only letters, digits and punctuation are checked for being typed twice or not at all, so
+//! turning Caps Lock into a second Backspace or Escape isn't reported.
+//! `--check-config` prints what it finds and a reload logs it; none of it stops the daemon.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+use evdev::Key;
+
+use crate::config::Config;
+use crate::layout::{find, Action, ActiveLayout, Layout};
+use crate::output::advertised;
+
+/// A mapping that is probably a mistake.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lint {
+    /// Several physical keys type `target` with `layout`.
+    DuplicateTarget { layout: String, target: String, keys: Vec<Key> },
+    /// No physical key types `key` with `layout`, though the layout places it.
+    Unreachable { layout: String, key: Key },
+    /// A config table maps `keys` round in a loop, each to the next and the last to the first.
+    Cycle { table: &'static str, keys: Vec<Key> },
+    /// `rule` sends `key`, which the virtual keyboard does not advertise.
+    NotAdvertised { rule: String, key: Key },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lint::DuplicateTarget { layout, target, keys } => {
+                let both = if keys.len() == 2 { "both" } else { "all" };
+                write!(f, "{layout} layout: {} {both} type {target}", names(keys, ", "))
+            }
+            Lint::Unreachable { layout, key } => write!(f, "{layout} layout: no key types {key:?} any more"),
+            Lint::Cycle { table, keys } => write!(
+                f,
+                "{table} maps {} → {:?} in a loop; each key is mapped once, so these rotate rather than chain",
+                names(keys, " → "),
+                keys[0]
+            ),
+            Lint::NotAdvertised { rule, key } => {
+                write!(f, "{rule} sends {key:?}, which the virtual keyboard cannot send")
+            }
+        }
+    }
+}
+
+fn names(keys: &[Key], separator: &str) -> String {
+    keys.iter().map(|key| format!("{key:?}")).collect::<Vec<_>>().join(separator)
+}
+
+/// The letter, digit and punctuation keys of the main block.
+pub(crate) fn typing_keys() -> impl Iterator<Item = Key> {
+    let codes = [
+        Key::KEY_1.code()..=Key::KEY_EQUAL.code(),
+        Key::KEY_Q.code()..=Key::KEY_RIGHTBRACE.code(),
+        Key::KEY_A.code()..=Key::KEY_GRAVE.code(),
+        Key::KEY_BACKSLASH.code()..=Key::KEY_SLASH.code(),
+        Key::KEY_102ND.code()..=Key::KEY_102ND.code(),
+    ];
+    codes.into_iter().flatten().map(Key::new)
+}
+
+/// What `config` maps that is probably a mistake, for its layout and every layout in
+/// `device_layouts`. Layouts that cannot be loaded are skipped; `check_config` reports those.
+pub fn lint(config: &Config) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    let names: BTreeSet<&str> = std::iter::once(config.layout.as_str())
+        .chain(config.device_layouts.iter().map(|(_, name)| name.as_str()))
+        .collect();
+    for name in names {
+        if let Ok(layout) = find(name) {
+            lint_layout(config, name, layout, &mut lints);
+        }
+    }
+    lints.extend(cycles("[keys]", &config.keys));
+    lints.extend(cycles("[modmap]", &config.remaps));
+    lints.extend(not_advertised(config));
+    lints
+}
+
+/// Duplicate and unreachable characters in `layout` with the config's `[keys]` and `[modmap]`.
+fn lint_layout(config: &Config, name: &str, layout: Arc<dyn Layout>, lints: &mut Vec<Lint>) {
+    let modmap: HashMap<Key, Key> =
+        config.swaps.iter().flat_map(|&(a, b)| [(a, b), (b, a)]).chain(config.remaps.iter().copied()).collect();
+    let overridden = ActiveLayout::new(layout.clone(), config.keys.clone()).get();
+    let acts_as = |key: Key| modmap.get(&key).copied().unwrap_or(key);
+
+    let mut typed: BTreeMap<(u16, &str), Vec<Key>> = BTreeMap::new();
+    let mut reachable = BTreeSet::new();
+    // Any key can be turned into a typing key, so every key on a keyboard is checked.
+    for key in (1..=0xff).map(Key::new) {
+        let logical = acts_as(key);
+        let (to, shift) = target(logical, overridden.map(logical));
+        typed.entry((to.code(), shift)).or_default().push(key);
+        reachable.insert(to);
+        reachable.insert(target(logical, overridden.map_shifted(logical)).0);
+    }
+    for ((code, shift), keys) in typed {
+        let target = Key::new(code);
+        if keys.len() > 1 && typing_keys().any(|key| key == target) {
+            lints.push(Lint::DuplicateTarget { layout: name.to_string(), target: format!("{shift}{target:?}"), keys });
+        }
+    }
+    // What the layout itself types, before the config changed anything.
+    let placed: BTreeSet<Key> = typing_keys().map(|key| target(key, layout.map(key)).0).collect();
+    for key in placed.into_iter().filter(|key| typing_keys().any(|typing| typing == *key)) {
+        if !reachable.contains(&key) {
+            lints.push(Lint::Unreachable { layout: name.to_string(), key });
+        }
+    }
+}
+
+/// The key `action` sends for `key`, and the Shift it forces, as a prefix for messages.
+fn target(key: Key, action: Action) -> (Key, &'static str) {
+    match action {
+        Action::Key(to) => (to, ""),
+        Action::Shifted(to) => (to, "Shift+"),
+        Action::Unshifted(to) => (to, "unshifted "),
+        Action::Passthrough => (key, ""),
+    }
+}
+
+/// Loops of three or more keys in `table`'s `from = to` entries.
+fn cycles(table: &'static str, entries: &[(Key, Key)]) -> Vec<Lint> {
+    let next: BTreeMap<Key, Key> = entries.iter().copied().collect();
+    let mut seen = BTreeSet::new();
+    let mut lints = Vec::new();
+    for &start in next.keys() {
+        let mut path = Vec::new();
+        let mut key = start;
+        while !seen.contains(&key) && !path.contains(&key) {
+            path.push(key);
+            match next.get(&key) {
+                Some(&to) => key = to,
+                None => break,
+            }
+        }
+        // A loop closes when the walk comes back to a key on this path.
+        if let Some(position) = path.iter().position(|&on_path| on_path == key)
+            && next.contains_key(path.last().unwrap())
+            && path.len() - position > 2
+        {
+            lints.push(Lint::Cycle { table, keys: path[position..].to_vec() });
+        }
+        seen.extend(path);
+    }
+    lints
+}
+
+/// Every key the config maps to that the virtual keyboard does not advertise.
+fn not_advertised(config: &Config) -> Vec<Lint> {
+    let mut sent: Vec<(String, Key)> = Vec::new();
+    for &(from, to) in &config.keys {
+        sent.push((format!("[keys] {from:?}"), to));
+    }
+    for &(from, to) in &config.remaps {
+        sent.push((format!("[modmap] {from:?}"), to));
+    }
+    for &(a, b) in &config.swaps {
+        sent.push((format!("the swap of {a:?} and {b:?}"), a));
+        sent.push((format!("the swap of {a:?} and {b:?}"), b));
+    }
+    for overload in &config.overloads {
+        sent.push((format!("the overload on {:?}", overload.key), overload.tap));
+        sent.push((format!("the overload on {:?}", overload.key), overload.hold));
+    }
+    for combo in &config.combos {
+        sent.push((format!("the combo {}", names(&combo.keys, "+")), combo.output));
+    }
+    sent.into_iter().filter(|(_, key)| !advertised(*key)).map(|(rule, key)| Lint::NotAdvertised { rule, key }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::registered;
+
+    #[test]
+    fn builtin_layouts_are_clean() {
+        for name in registered() {
+            let config = Config { layout: name.clone(), ..Config::default() };
+            assert_eq!(lint(&config), [], "{name}");
+        }
+    }
+
+    #[test]
+    fn finds_duplicates_unreachable_keys_and_loops() {
+        let config = Config {
+            keys: vec![(Key::KEY_Q, Key::KEY_A)],
+            remaps: vec![
+                (Key::KEY_F1, Key::KEY_F2),
+                (Key::KEY_F2, Key::KEY_F3),
+                (Key::KEY_F3, Key::KEY_F1),
+                (Key::KEY_CAPSLOCK, Key::BTN_LEFT),
+            ],
+            ..Config::default()
+        };
+        assert_eq!(
+            lint(&config),
+            [
+                Lint::DuplicateTarget {
+                    layout: "dvorak".to_string(),
+                    target: "KEY_A".to_string(),
+                    keys: vec![Key::KEY_Q, Key::KEY_A]
+                },
+                Lint::Unreachable { layout: "dvorak".to_string(), key: Key::KEY_APOSTROPHE },
+                Lint::Cycle { table: "[modmap]", keys: vec![Key::KEY_F1, Key::KEY_F2, Key::KEY_F3] },
+                Lint::NotAdvertised { rule: "[modmap] KEY_CAPSLOCK".to_string(), key: Key::BTN_LEFT },
+            ]
+        );
+    }
+
+    #[test]
+    fn swaps_and_retired_keys_are_fine() {
+        let config = Config {
+            keys: vec![(Key::KEY_CAPSLOCK, Key::KEY_BACKSPACE)],
+            swaps: vec![(Key::KEY_LEFTALT, Key::KEY_LEFTMETA)],
+            remaps: vec![(Key::KEY_ESC, Key::KEY_GRAVE), (Key::KEY_GRAVE, Key::KEY_ESC)],
+            ..Config::default()
+        };
+        assert_eq!(lint(&config), []);
+    }
+}
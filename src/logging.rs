@@ -0,0 +1,455 @@
+//! Log output for the binaries, through `tracing`.
+//!
+//! The daemon logs with fields as well as a message, e.g. `device` on everything about one
+//! keyboard, `kind` on errors and the counts on the heartbeat. Under systemd they go straight
+//! to the journal as fields of their own (`journalctl --user DEVICE="Keychron K2"`); the JSON
+//! format gives them as members of each line, and the text format leaves them out. Which
+//! levels are logged is up to `RUST_LOG` if it is set, with the usual `tracing` directives
+//! (e.g. `RUST_LOG=qwertdvert::explain=off,debug`), and to the given level otherwise. Either
+//! can be changed while the daemon runs with [`set_filter`], as the `log-level` control command
+//! does.
+//!
+//! Warnings and errors are throttled, so a fault that keeps failing the same way, like a
+//! virtual keyboard that refuses every write, doesn't flood the journal. Each place in the
+//! code that logs them prints the same message at most once per [`REPEAT_INTERVAL`], and at
+//! most [`BURST`] lines in all per interval; what is held back is counted and reported as
+//! "Last message repeated N times" before that place's next line, or by [`flush`].
+
+use std::any::TypeId;
+use std::fmt::{self, Write as _};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use tracing::field::{Field, FieldSet, Value, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{error, warn, Event, Level, Metadata, Subscriber};
+use tracing_core::callsite::{DefaultCallsite, Identifier};
+use tracing_core::metadata::Kind;
+use tracing_core::Interest;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+// Throttling
+// REPEAT_INTERVAL: A warning or error is printed at most once per interval; repeats in between
+// are collapsed into a "last message repeated N times" summary.
+// BURST: Lines one place in the code may print per interval, however much they differ.
+pub const REPEAT_INTERVAL: Duration = Duration::from_secs(10);
+pub const BURST: u32 = 5;
+
+// FILTER: Handle on the filter set up by init(), for changing it at runtime.
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+// HELD_BACK: What each place that logged warnings or errors last printed and has held back
+// since. There are only a few dozen such places, so a list does.
+static HELD_BACK: Mutex<Vec<HeldBack>> = Mutex::new(Vec::new());
+
+/// Where and how log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Plain human-readable lines on stderr, as journald already adds timestamps.
+    Text,
+    /// One JSON object per line on stderr, for log aggregation.
+    Json,
+    /// Straight to the journal, with each field as a journal field and the level as its
+    /// priority.
+    Journald,
+}
+
+impl LogFormat {
+    /// Journald when stderr goes to the journal, as under systemd, and text otherwise.
+    pub fn detect() -> Self {
+        if std::env::var_os("JOURNAL_STREAM").is_some() { LogFormat::Journald } else { LogFormat::Text }
+    }
+}
+
+/// Sets up logging for the process, logging `level` and above unless `RUST_LOG` says
+/// otherwise. Without a journal to write to, journald falls back to text.
+pub fn init(format: LogFormat, level: LevelFilter) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::default().add_directive(level.into()));
+    let stderr = |format: Format| tracing_subscriber::fmt::layer().with_writer(std::io::stderr).event_format(format);
+    let (output, journal_error): (Box<dyn Layer<Registry> + Send + Sync>, _) = match format {
+        LogFormat::Text => (stderr(Format::Text).boxed(), None),
+        LogFormat::Json => (stderr(Format::Json).boxed(), None),
+        LogFormat::Journald => match tracing_journald::layer() {
+            Ok(journal) => (journal.with_field_prefix(None).boxed(), None),
+            Err(e) => (stderr(Format::Text).boxed(), Some(e)),
+        },
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = FILTER.set(handle);
+    tracing_subscriber::registry().with(Throttle(output).with_filter(filter)).init();
+    if let Some(e) = journal_error {
+        warn!("Failed to log to the journal; logging to stderr instead: {e}");
+    }
+}
+
+/// Logs what `directives` say from now on: a level such as `debug`, or directives in the
+/// `RUST_LOG` syntax. Fails if they don't parse, or if logging wasn't set up by [`init`].
+pub fn set_filter(directives: &str) -> Result<(), String> {
+    let handle = FILTER.get().ok_or("logging was not set up by qwertdvert")?;
+    let filter = EnvFilter::try_new(directives).map_err(|e| format!("invalid log level '{directives}': {e}"))?;
+    handle.reload(filter).map_err(|e| e.to_string())
+}
+
+/// The filter in effect, as directives, if logging was set up by [`init`].
+pub fn filter() -> Option<String> {
+    FILTER.get()?.with_current(|filter| filter.to_string()).ok()
+}
+
+/// Reports the lines held back everywhere, e.g. once a fault has cleared and the place that
+/// logged it has nothing more to say.
+pub fn flush() {
+    let summaries: Vec<_> = HELD_BACK.lock().unwrap().iter_mut().filter_map(HeldBack::take_summary).collect();
+    for (level, repeats, summary) in summaries {
+        match level {
+            Level::ERROR => error!(repeats, "{summary}"),
+            _ => warn!(repeats, "{summary}"),
+        }
+    }
+}
+
+/// The lines one place in the code printed and held back in the current interval.
+struct HeldBack {
+    callsite: Identifier,
+    level: Level,
+    last_message: String,
+    /// When the first line of the interval was printed.
+    since: Instant,
+    printed: u32,
+    /// Lines held back: repeats of the last message, then any others.
+    repeats: u32,
+    others: u32,
+}
+
+impl HeldBack {
+    /// Whether `message` should be printed now, counting it if not.
+    fn admit(&mut self, message: &str, now: Instant) -> bool {
+        if now.duration_since(self.since) >= REPEAT_INTERVAL {
+            self.since = now;
+            self.printed = 0;
+        }
+        if message == self.last_message && self.printed > 0 {
+            self.repeats += 1;
+            return false;
+        }
+        if self.printed >= BURST {
+            self.others += 1;
+            return false;
+        }
+        self.printed += 1;
+        self.last_message = message.to_string();
+        true
+    }
+
+    /// The level, count and text of the summary of what was held back, if anything was,
+    /// starting the count again.
+    fn take_summary(&mut self) -> Option<(Level, u32, String)> {
+        let repeated = |repeats| match repeats {
+            1 => "Last message repeated once".to_string(),
+            repeats => format!("Last message repeated {repeats} times"),
+        };
+        let others = |others| match others {
+            1 => "1 more message like it was held back".to_string(),
+            others => format!("{others} more messages like it were held back"),
+        };
+        let summary = match (self.repeats, self.others) {
+            (0, 0) => return None,
+            (repeats, 0) => repeated(repeats),
+            (0, count) => others(count),
+            (repeats, count) => format!("{}, and {}", repeated(repeats), others(count)),
+        };
+        let count = self.repeats + self.others;
+        (self.repeats, self.others) = (0, 0);
+        Some((self.level, count, summary))
+    }
+}
+
+/// The summary lines' metadata, one per level, for reporting them from inside the throttle.
+static ERROR_SUMMARY: DefaultCallsite = DefaultCallsite::new(&ERROR_SUMMARY_METADATA);
+static ERROR_SUMMARY_METADATA: Metadata<'static> = summary_metadata(Level::ERROR, &ERROR_SUMMARY);
+static WARN_SUMMARY: DefaultCallsite = DefaultCallsite::new(&WARN_SUMMARY_METADATA);
+static WARN_SUMMARY_METADATA: Metadata<'static> = summary_metadata(Level::WARN, &WARN_SUMMARY);
+
+const fn summary_metadata(level: Level, callsite: &'static DefaultCallsite) -> Metadata<'static> {
+    Metadata::new(
+        "held back",
+        module_path!(),
+        level,
+        Some(file!()),
+        Some(line!()),
+        Some(module_path!()),
+        FieldSet::new(&["message", "repeats"], Identifier(callsite)),
+        Kind::EVENT,
+    )
+}
+
+/// Holds back warnings and errors as the module docs describe, passing everything else on to
+/// the output layer untouched.
+struct Throttle<L>(L);
+
+impl<L: Layer<Registry>> Throttle<L> {
+    /// Passes the summary of what `held_back` held back on to the output layer.
+    fn report(&self, held_back: &mut HeldBack, ctx: &Context<'_, Registry>) {
+        let Some((level, repeats, summary)) = held_back.take_summary() else {
+            return;
+        };
+        let metadata = if level == Level::ERROR { &ERROR_SUMMARY_METADATA } else { &WARN_SUMMARY_METADATA };
+        let fields = metadata.fields();
+        let (Some(message), Some(repeats_field)) = (fields.field("message"), fields.field("repeats")) else {
+            return;
+        };
+        let summary = format_args!("{summary}");
+        let values: [(&Field, Option<&dyn Value>); 2] = [(&message, Some(&summary)), (&repeats_field, Some(&repeats))];
+        self.0.on_event(&Event::new(metadata, &fields.value_set(&values)), ctx.clone());
+    }
+}
+
+impl<L: Layer<Registry>> Layer<Registry> for Throttle<L> {
+    fn on_layer(&mut self, subscriber: &mut Registry) {
+        self.0.on_layer(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.0.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, Registry>) -> bool {
+        self.0.enabled(metadata, ctx)
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, Registry>) {
+        self.0.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, Registry>) {
+        self.0.on_record(span, values, ctx);
+    }
+
+    fn on_follows_from(&self, span: &Id, follows: &Id, ctx: Context<'_, Registry>) {
+        self.0.on_follows_from(span, follows, ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, Registry>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::WARN {
+            self.0.on_event(event, ctx);
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let now = Instant::now();
+        let mut held_back = HELD_BACK.lock().unwrap();
+        let index = match held_back.iter().position(|held| held.callsite == metadata.callsite()) {
+            Some(index) => index,
+            None => {
+                held_back.push(HeldBack {
+                    callsite: metadata.callsite(),
+                    level: *metadata.level(),
+                    last_message: String::new(),
+                    since: now,
+                    printed: 0,
+                    repeats: 0,
+                    others: 0,
+                });
+                held_back.len() - 1
+            }
+        };
+        let held = &mut held_back[index];
+        if held.admit(&fields.message, now) {
+            self.report(held, &ctx);
+            self.0.on_event(event, ctx);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, Registry>) {
+        self.0.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, Registry>) {
+        self.0.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, Registry>) {
+        self.0.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: Context<'_, Registry>) {
+        self.0.on_id_change(old, new, ctx);
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.0.max_level_hint()
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else {
+            // SAFETY: Passed on unchanged, with the output layer's own guarantees.
+            unsafe { self.0.downcast_raw(id) }
+        }
+    }
+}
+
+/// The stderr formats.
+enum Format {
+    Text,
+    Json,
+}
+
+impl<S, N> FormatEvent<S, N> for Format
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, _: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        match self {
+            Format::Text if *metadata.level() == Level::WARN => writeln!(writer, "Warning: {}", fields.message),
+            Format::Text => writeln!(writer, "{}", fields.message),
+            Format::Json => {
+                let mut timestamp = String::new();
+                SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+                write!(
+                    writer,
+                    "{{\"timestamp\":\"{}\",\"level\":\"{}\",\"target\":\"{}\",\"message\":{}",
+                    timestamp,
+                    metadata.level(),
+                    metadata.target(),
+                    json_string(&fields.message)
+                )?;
+                for (name, value) in &fields.others {
+                    write!(writer, ",{}:{}", json_string(name), value)?;
+                }
+                writeln!(writer, "}}")
+            }
+        }
+    }
+}
+
+/// An event's message, and its other fields as JSON values.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    others: Vec<(&'static str, String)>,
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.message, "{value:?}");
+            }
+            name => self.others.push((name, json_string(&format!("{value:?}")))),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message.push_str(value),
+            name => self.others.push((name, json_string(value))),
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.others.push((field.name(), value.to_string()));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.others.push((field.name(), value.to_string()));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.others.push((field.name(), value.to_string()));
+    }
+}
+
+/// Quotes and escapes a string for inclusion in a JSON document.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held_back(now: Instant) -> HeldBack {
+        HeldBack {
+            callsite: Identifier(&WARN_SUMMARY),
+            level: Level::WARN,
+            last_message: String::new(),
+            since: now,
+            printed: 0,
+            repeats: 0,
+            others: 0,
+        }
+    }
+
+    #[test]
+    fn repeats_collapse_until_the_interval_is_up() {
+        let start = Instant::now();
+        let mut held = held_back(start);
+        assert!(held.admit("Failed to write", start));
+        for seconds in 1..5 {
+            assert!(!held.admit("Failed to write", start + Duration::from_secs(seconds)));
+        }
+        assert!(held.admit("Failed to write", start + REPEAT_INTERVAL));
+        assert_eq!(held.take_summary(), Some((Level::WARN, 4, "Last message repeated 4 times".to_string())));
+        assert_eq!(held.take_summary(), None);
+    }
+
+    #[test]
+    fn differing_messages_are_capped_at_the_burst() {
+        let start = Instant::now();
+        let mut held = held_back(start);
+        for n in 0..BURST {
+            assert!(held.admit(&format!("Keyboard {n} failed"), start));
+        }
+        assert!(!held.admit("Keyboard 5 failed", start));
+        assert!(!held.admit("Keyboard 6 failed", start));
+        assert!(!held.admit(&format!("Keyboard {} failed", BURST - 1), start));
+        assert_eq!(
+            held.take_summary(),
+            Some((
+                Level::WARN,
+                3,
+                "Last message repeated once, and 2 more messages like it were held back".to_string()
+            ))
+        );
+        assert!(held.admit("Keyboard 7 failed", start + REPEAT_INTERVAL));
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("a \"b\"\\\n\t\u{1}"), r#""a \"b\"\\\n\t\u0001""#);
+    }
+
+    #[test]
+    fn one_line_held_back_is_reported_in_the_singular() {
+        let mut held = held_back(Instant::now());
+        held.others = 1;
+        assert_eq!(held.take_summary(), Some((Level::WARN, 1, "1 more message like it was held back".to_string())));
+    }
+}
@@ -0,0 +1,103 @@
+//! Following whether the user's login session is the active one on its seat.
+//!
+//! When another user's session or a text console is switched to, the keyboards stay grabbed,
+//! so anything typed there still comes through the virtual keyboard. It should come through
+//! as typed, so keys are passed through unmapped until the session is active again. The
+//! session is the one in `$XDG_SESSION_ID`, or else the user's graphical session, as the
+//! daemon usually runs as a systemd user service outside any session.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use dbus::arg::prop_cast;
+use dbus::blocking::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
+use dbus::blocking::Connection;
+use dbus::channel::BusType;
+use dbus::Message;
+use tracing::{info, warn};
+
+use crate::bus::{connect, dispatch_and_wait};
+use crate::shutdown::Shutdown;
+
+// Addressing
+const LOGIN1: &str = "org.freedesktop.login1";
+const MANAGER_PATH: &str = "/org/freedesktop/login1";
+const USER_PATH: &str = "/org/freedesktop/login1/user/self";
+const MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const USER_INTERFACE: &str = "org.freedesktop.login1.User";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+// CALL_TIMEOUT: How long to wait for logind to answer.
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sets the flag shared with the event loop, logging only actual changes.
+fn set_session_active(flag: &AtomicBool, active: bool) {
+    if flag.swap(active, Ordering::Relaxed) != active {
+        if active {
+            info!("Session active again; remapping");
+        } else {
+            info!("Session switched away from; keys are passed through unmapped");
+        }
+    }
+}
+
+/// The object path of the session to follow, or None if the user has no graphical session.
+fn find_session(connection: &Connection) -> Result<Option<dbus::Path<'static>>, dbus::Error> {
+    if let Some(id) = std::env::var("XDG_SESSION_ID").ok().filter(|id| !id.is_empty()) {
+        let manager = connection.with_proxy(LOGIN1, MANAGER_PATH, CALL_TIMEOUT);
+        let (path,): (dbus::Path<'static>,) = manager.method_call(MANAGER_INTERFACE, "GetSession", (id,))?;
+        return Ok(Some(path));
+    }
+    let user = connection.with_proxy(LOGIN1, USER_PATH, CALL_TIMEOUT);
+    let (_, path): (String, dbus::Path<'static>) = user.get(USER_INTERFACE, "Display")?;
+    // logind gives "/" when there is no such session.
+    Ok((&*path != "/").then_some(path))
+}
+
+/// Keeps `active` in step with the session's `Active` property until shutdown. Without a
+/// system bus or a session to follow, it logs a warning and `active` stays set.
+pub fn spawn_session_watcher(active: Arc<AtomicBool>, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let watch = || -> Result<Option<Connection>, dbus::Error> {
+            let connection = connect(BusType::System)?;
+            let Some(session) = find_session(&connection)? else {
+                return Ok(None);
+            };
+            let proxy = connection.with_proxy(LOGIN1, session, CALL_TIMEOUT);
+            let changed_active = active.clone();
+            proxy.match_signal(move |changed: PropertiesPropertiesChanged, _: &Connection, _: &Message| {
+                if changed.interface_name == SESSION_INTERFACE
+                    && let Some(&now_active) = prop_cast::<bool>(&changed.changed_properties, "Active")
+                {
+                    set_session_active(&changed_active, now_active);
+                }
+                true
+            })?;
+            set_session_active(&active, proxy.get(SESSION_INTERFACE, "Active")?);
+            Ok(Some(connection))
+        };
+        let connection = match watch() {
+            Ok(Some(connection)) => connection,
+            Ok(None) => {
+                warn!("No login session to follow; keys are remapped whichever session is active");
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to follow the login session; keys are remapped whichever session is active: {}", e);
+                return;
+            }
+        };
+        loop {
+            match dispatch_and_wait(&connection, &[], &shutdown) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    warn!("Lost the system bus; keys are remapped whichever session is active: {}", e);
+                    set_session_active(&active, true);
+                    return;
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,422 @@
+//! Text macros: a trigger key that types a string.
+//!
+//! Macros run after the layout, so the trigger is matched by what the key types and the text
+//! is typed as on a US QWERTY layout. Injected keys normally go out as fast as they are
+//! generated; slow consumers (VNC sessions, some Electron apps) can lose characters from such
+//! a burst, so each macro may pause between keys and hold each key down for a while. While a
+//! macro is typing, other keys wait behind it so nothing is typed out of order.
+//!
+//! Characters with no key on a US QWERTY layout are typed as their Unicode code point, with
+//! Ctrl+Shift+U, the code point in hex, then Space, which IBus and GTK turn into the character.
+//!
+//! Snippets are text expansions: when the last characters typed spell a snippet's
+//! abbreviation, the abbreviation is erased with Backspace and the snippet's text typed in
+//! its place. What was typed is followed from the keys coming out of the layout and Shift,
+//! and forgotten on any key that doesn't type a character (arrows, Enter, shortcuts).
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use evdev::Key;
+
+use crate::pipeline::{KeyEvent, Stage};
+use crate::remap::RemapRule;
+
+/// A trigger key and the keys it types.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Macro {
+    /// The key that starts the macro, as it comes out of the layout.
+    pub trigger: Key,
+    /// The keys to type, each with the modifiers held for it.
+    pub keys: Vec<(Key, &'static [Key])>,
+    /// Pause between one typed character and the next.
+    pub key_delay: Duration,
+    /// How long each key is held down.
+    pub press_time: Duration,
+}
+
+/// An abbreviation that is replaced by a text when typed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snippet {
+    pub abbreviation: String,
+    /// The keys that type the replacement, each with the modifiers held for it.
+    pub keys: Vec<(Key, &'static [Key])>,
+}
+
+// Modifiers held while typing a character.
+const SHIFT: &[Key] = &[Key::KEY_LEFTSHIFT];
+const CTRL_SHIFT: &[Key] = &[Key::KEY_LEFTCTRL, Key::KEY_LEFTSHIFT];
+
+/// How characters with no key on a US QWERTY layout are typed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnicodeInput {
+    /// Ctrl+Shift+U, the code point in hex, then Space, as IBus and GTK understand it.
+    #[default]
+    CtrlShiftU,
+    /// Not at all; such characters are an error.
+    Off,
+}
+
+impl std::str::FromStr for UnicodeInput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ctrl-shift-u" => Ok(UnicodeInput::CtrlShiftU),
+            "off" => Ok(UnicodeInput::Off),
+            other => Err(format!("unicode_input must be 'ctrl-shift-u' or 'off', not '{other}'")),
+        }
+    }
+}
+
+/// The key (and whether it needs Shift) that types `c` on a US QWERTY layout.
+fn char_key(c: char) -> Option<(Key, bool)> {
+    if c.is_ascii_alphabetic() {
+        let key = format!("KEY_{}", c.to_ascii_uppercase()).parse().ok()?;
+        return Some((key, c.is_ascii_uppercase()));
+    }
+    Some(match c {
+        '1' => (Key::KEY_1, false),
+        '2' => (Key::KEY_2, false),
+        '3' => (Key::KEY_3, false),
+        '4' => (Key::KEY_4, false),
+        '5' => (Key::KEY_5, false),
+        '6' => (Key::KEY_6, false),
+        '7' => (Key::KEY_7, false),
+        '8' => (Key::KEY_8, false),
+        '9' => (Key::KEY_9, false),
+        '0' => (Key::KEY_0, false),
+        '!' => (Key::KEY_1, true),
+        '@' => (Key::KEY_2, true),
+        '#' => (Key::KEY_3, true),
+        '$' => (Key::KEY_4, true),
+        '%' => (Key::KEY_5, true),
+        '^' => (Key::KEY_6, true),
+        '&' => (Key::KEY_7, true),
+        '*' => (Key::KEY_8, true),
+        '(' => (Key::KEY_9, true),
+        ')' => (Key::KEY_0, true),
+        '-' => (Key::KEY_MINUS, false),
+        '_' => (Key::KEY_MINUS, true),
+        '=' => (Key::KEY_EQUAL, false),
+        '+' => (Key::KEY_EQUAL, true),
+        '[' => (Key::KEY_LEFTBRACE, false),
+        '{' => (Key::KEY_LEFTBRACE, true),
+        ']' => (Key::KEY_RIGHTBRACE, false),
+        '}' => (Key::KEY_RIGHTBRACE, true),
+        '\\' => (Key::KEY_BACKSLASH, false),
+        '|' => (Key::KEY_BACKSLASH, true),
+        ';' => (Key::KEY_SEMICOLON, false),
+        ':' => (Key::KEY_SEMICOLON, true),
+        '\'' => (Key::KEY_APOSTROPHE, false),
+        '"' => (Key::KEY_APOSTROPHE, true),
+        '`' => (Key::KEY_GRAVE, false),
+        '~' => (Key::KEY_GRAVE, true),
+        ',' => (Key::KEY_COMMA, false),
+        '<' => (Key::KEY_COMMA, true),
+        '.' => (Key::KEY_DOT, false),
+        '>' => (Key::KEY_DOT, true),
+        '/' => (Key::KEY_SLASH, false),
+        '?' => (Key::KEY_SLASH, true),
+        ' ' => (Key::KEY_SPACE, false),
+        '\n' => (Key::KEY_ENTER, false),
+        '\t' => (Key::KEY_TAB, false),
+        _ => return None,
+    })
+}
+
+/// The character `key` types on a US QWERTY layout, with or without Shift, if it types one.
+pub(crate) fn key_char(key: Key, shift: bool) -> Option<char> {
+    (' '..='~').find(|&c| char_key(c) == Some((key, shift)))
+}
+
+/// The keys that type `text`, or the first character that can't be typed.
+pub fn text_keys(text: &str, unicode: UnicodeInput) -> Result<Vec<(Key, &'static [Key])>, char> {
+    let mut keys = Vec::new();
+    for c in text.chars() {
+        match (char_key(c), unicode) {
+            (Some((key, shifted)), _) => keys.push((key, if shifted { SHIFT } else { &[] })),
+            (None, UnicodeInput::CtrlShiftU) => {
+                keys.push((Key::KEY_U, CTRL_SHIFT));
+                for digit in format!("{:x}", c as u32).chars() {
+                    keys.push((char_key(digit).ok_or(c)?.0, &[]));
+                }
+                keys.push((Key::KEY_SPACE, &[]));
+            }
+            (None, UnicodeInput::Off) => return Err(c),
+        }
+    }
+    Ok(keys)
+}
+
+/// The text `keys` type, as [`text_keys`] gave them.
+pub(crate) fn keys_text(keys: &[(Key, &[Key])]) -> String {
+    let mut text = String::new();
+    let mut keys = keys.iter();
+    while let Some(&(key, modifiers)) = keys.next() {
+        if key == Key::KEY_U && modifiers == CTRL_SHIFT {
+            let digits = keys.by_ref().take_while(|&&(key, _)| key != Key::KEY_SPACE);
+            let hex: String = digits.filter_map(|&(key, _)| key_char(key, false)).collect();
+            text.extend(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32));
+        } else {
+            let shifted = modifiers == SHIFT;
+            text.extend((' '..='~').chain(['\n', '\t']).find(|&c| char_key(c) == Some((key, shifted))));
+        }
+    }
+    text
+}
+
+/// Macros stage: types the text of a macro when its trigger is pressed, and expands snippets.
+pub struct Macros {
+    macros: Vec<Macro>,
+    snippets: Vec<Snippet>,
+    /// The character each key types, without and with Shift; filled in when there are snippets.
+    chars: HashMap<(u16, bool), char>,
+    /// The last characters typed, as many as the longest abbreviation.
+    recent: String,
+    /// Shift keys held down.
+    shifts: BTreeSet<u16>,
+    /// The key that completed a snippet, whose release and repeats are swallowed.
+    swallowed: Option<u16>,
+    /// Events still to be output, each with the pause before it.
+    queue: VecDeque<(Duration, KeyEvent)>,
+    /// When the front of the queue is due.
+    next_due: Instant,
+}
+
+impl Macros {
+    pub fn new(macros: Vec<Macro>) -> Self {
+        Macros {
+            macros,
+            snippets: Vec::new(),
+            chars: HashMap::new(),
+            recent: String::new(),
+            shifts: BTreeSet::new(),
+            swallowed: None,
+            queue: VecDeque::new(),
+            next_due: Instant::now(),
+        }
+    }
+
+    /// Also expands `snippets`.
+    pub fn with_snippets(mut self, snippets: Vec<Snippet>) -> Self {
+        if !snippets.is_empty() {
+            self.chars = (' '..='~')
+                .chain(['\n', '\t'])
+                .filter_map(|c| char_key(c).map(|(key, shifted)| ((key.code(), shifted), c)))
+                .collect();
+        }
+        self.snippets = snippets;
+        self
+    }
+
+    /// Follows what is being typed, and returns the snippet `event` completes, if any.
+    fn track(&mut self, event: &KeyEvent) -> Option<usize> {
+        if event.code == Key::KEY_LEFTSHIFT.code() || event.code == Key::KEY_RIGHTSHIFT.code() {
+            match event.value {
+                1 => self.shifts.insert(event.code),
+                0 => self.shifts.remove(&event.code),
+                _ => false,
+            };
+            return None;
+        }
+        if event.value != 1 {
+            return None;
+        }
+        if event.code == Key::KEY_BACKSPACE.code() {
+            self.recent.pop();
+            return None;
+        }
+        let typed = match event.rule {
+            Some(RemapRule::ModifierPassthrough) => None,
+            _ => self.chars.get(&(event.code, !self.shifts.is_empty())),
+        };
+        let Some(&c) = typed else {
+            self.recent.clear();
+            return None;
+        };
+        self.recent.push(c);
+        let longest = self.snippets.iter().map(|snippet| snippet.abbreviation.chars().count()).max().unwrap_or(0);
+        while self.recent.chars().count() > longest {
+            self.recent.remove(0);
+        }
+        self.snippets.iter().position(|snippet| self.recent.ends_with(&snippet.abbreviation))
+    }
+
+    /// Queues the events that type `keys`.
+    fn type_keys(&mut self, keys: Vec<(Key, &[Key])>, key_delay: Duration, press_time: Duration, rule: RemapRule) {
+        let typed = |key: Key, value| KeyEvent { rule: Some(rule), ..KeyEvent::new(key.code(), value) };
+        for (i, (key, modifiers)) in keys.into_iter().enumerate() {
+            let mut before = if i == 0 { Duration::ZERO } else { key_delay };
+            for &modifier in modifiers {
+                self.enqueue(std::mem::take(&mut before), typed(modifier, 1));
+            }
+            self.enqueue(before, typed(key, 1));
+            self.enqueue(press_time, typed(key, 0));
+            for &modifier in modifiers.iter().rev() {
+                self.enqueue(Duration::ZERO, typed(modifier, 0));
+            }
+        }
+    }
+
+    fn enqueue(&mut self, delay: Duration, event: KeyEvent) {
+        if self.queue.is_empty() {
+            self.next_due = Instant::now() + delay;
+        }
+        self.queue.push_back((delay, event));
+    }
+
+    /// Outputs every queued event that is due by `now`.
+    fn release_due(&mut self, now: Instant, out: &mut Vec<KeyEvent>) {
+        while self.next_due <= now
+            && let Some((_, event)) = self.queue.pop_front()
+        {
+            out.push(event);
+            if let Some(&(delay, _)) = self.queue.front() {
+                self.next_due = now + delay;
+            }
+        }
+    }
+}
+
+impl Stage for Macros {
+    fn process(&mut self, event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        if self.swallowed == Some(event.code) {
+            if event.value == 0 {
+                self.swallowed = None;
+            }
+            return;
+        }
+        if !self.snippets.is_empty()
+            && let Some(index) = self.track(&event)
+        {
+            // The completing key is not typed, so one Backspace fewer than the abbreviation.
+            let Snippet { abbreviation, keys } = self.snippets[index].clone();
+            self.recent.clear();
+            self.swallowed = Some(event.code);
+            let shifted = std::mem::take(&mut self.shifts);
+            for &shift in &shifted {
+                self.enqueue(Duration::ZERO, KeyEvent { rule: Some(RemapRule::Snippet), ..KeyEvent::new(shift, 0) });
+            }
+            let erase = vec![(Key::KEY_BACKSPACE, &[][..]); abbreviation.chars().count() - 1];
+            self.type_keys(erase, Duration::ZERO, Duration::ZERO, RemapRule::Snippet);
+            self.type_keys(keys, Duration::ZERO, Duration::ZERO, RemapRule::Snippet);
+            for &shift in &shifted {
+                self.enqueue(Duration::ZERO, KeyEvent { rule: Some(RemapRule::Snippet), ..KeyEvent::new(shift, 1) });
+            }
+            self.shifts = shifted;
+            self.release_due(Instant::now(), out);
+            return;
+        }
+        let Some(index) = self.macros.iter().position(|m| m.trigger.code() == event.code) else {
+            if self.queue.is_empty() {
+                out.push(event);
+            } else {
+                // Wait behind the macro being typed.
+                self.enqueue(Duration::ZERO, event);
+            }
+            return;
+        };
+        // The trigger's own release and autorepeat type nothing.
+        if event.value != 1 {
+            return;
+        }
+
+        let Macro { keys, key_delay, press_time, .. } = self.macros[index].clone();
+        self.type_keys(keys, key_delay, press_time, RemapRule::Macro);
+        self.release_due(Instant::now(), out);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        (!self.queue.is_empty()).then_some(self.next_due)
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<KeyEvent>) {
+        self.release_due(now, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{run, tick};
+
+    const SHIFT_KEY: u16 = Key::KEY_LEFTSHIFT.code();
+    const BACKSPACE: u16 = Key::KEY_BACKSPACE.code();
+    const F1: u16 = Key::KEY_F1.code();
+    const A: u16 = Key::KEY_A.code();
+    const B: u16 = Key::KEY_B.code();
+    const K: u16 = Key::KEY_K.code();
+    const O: u16 = Key::KEY_O.code();
+    const T: u16 = Key::KEY_T.code();
+    const W: u16 = Key::KEY_W.code();
+    const X: u16 = Key::KEY_X.code();
+
+    const KEY_DELAY: Duration = Duration::from_millis(10);
+    const PRESS_TIME: Duration = Duration::from_millis(5);
+
+    #[test]
+    fn snippet_replaces_its_abbreviation() {
+        let snippet = Snippet { abbreviation: "btW".to_string(), keys: text_keys("ok", UnicodeInput::Off).unwrap() };
+        let mut stage = Macros::new(Vec::new()).with_snippets(vec![snippet]);
+        assert_eq!(run(&mut stage, &[(B, 1), (B, 0), (T, 1), (T, 0)]), [(B, 1), (B, 0), (T, 1), (T, 0)]);
+        assert_eq!(run(&mut stage, &[(SHIFT_KEY, 1)]), [(SHIFT_KEY, 1)]);
+        // The completing key is never typed, so one Backspace fewer than the abbreviation,
+        // and Shift is let go of for the text and pressed again after it.
+        assert_eq!(
+            run(&mut stage, &[(W, 1)]),
+            [
+                (SHIFT_KEY, 0),
+                (BACKSPACE, 1),
+                (BACKSPACE, 0),
+                (BACKSPACE, 1),
+                (BACKSPACE, 0),
+                (O, 1),
+                (O, 0),
+                (K, 1),
+                (K, 0),
+                (SHIFT_KEY, 1),
+            ]
+        );
+        assert_eq!(run(&mut stage, &[(W, 2), (W, 0)]), []);
+        assert_eq!(run(&mut stage, &[(SHIFT_KEY, 0), (W, 1)]), [(SHIFT_KEY, 0), (W, 1)]);
+    }
+
+    #[test]
+    fn macro_waits_between_keys_and_holds_each_down() {
+        let text_macro = Macro {
+            trigger: Key::KEY_F1,
+            keys: text_keys("ab", UnicodeInput::Off).unwrap(),
+            key_delay: KEY_DELAY,
+            press_time: PRESS_TIME,
+        };
+        let mut stage = Macros::new(vec![text_macro]);
+        assert_eq!(run(&mut stage, &[(F1, 1)]), [(A, 1)]);
+        // Keys typed meanwhile wait behind the macro.
+        assert_eq!(run(&mut stage, &[(F1, 0), (X, 1)]), []);
+
+        let released = stage.deadline().unwrap();
+        assert_eq!(tick(&mut stage, released - Duration::from_millis(1)), []);
+        assert_eq!(tick(&mut stage, released), [(A, 0)]);
+        assert_eq!(stage.deadline(), Some(released + KEY_DELAY));
+        let pressed = released + KEY_DELAY;
+        assert_eq!(tick(&mut stage, pressed), [(B, 1)]);
+        assert_eq!(stage.deadline(), Some(pressed + PRESS_TIME));
+        assert_eq!(tick(&mut stage, pressed + PRESS_TIME), [(B, 0), (X, 1)]);
+        assert_eq!(stage.deadline(), None);
+        assert_eq!(run(&mut stage, &[(X, 0)]), [(X, 0)]);
+    }
+
+    #[test]
+    fn characters_without_a_key_are_typed_with_ctrl_shift_u() {
+        let keys = text_keys("é", UnicodeInput::CtrlShiftU).unwrap();
+        assert_eq!(
+            keys,
+            [(Key::KEY_U, CTRL_SHIFT), (Key::KEY_E, &[][..]), (Key::KEY_9, &[][..]), (Key::KEY_SPACE, &[][..])]
+        );
+        assert_eq!(text_keys("é", UnicodeInput::Off), Err('é'));
+
+        let text = "A€ b\n";
+        assert_eq!(keys_text(&text_keys(text, UnicodeInput::CtrlShiftU).unwrap()), text);
+    }
+}
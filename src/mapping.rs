@@ -0,0 +1,518 @@
+//! The effective mapping, for `qwertdvertctl dump-map`.
+//!
+//! [`EffectiveMap`] is what each physical key sends once everything in the config has been
+//! applied: `[modmap]` swaps and remaps, overloads and combos, the layout in use and its `[keys]`
+//! overrides, the Shift, AltGr, shortcut and mirror layers, and the macros and snippets.
+//! [`EffectiveMap::to_json`] writes it for scripts, and [`EffectiveMap::diagram`] draws the main
+//! block of the keyboard as ASCII art to check a config at a glance. Keyboards given a layout
+//! of their own in `device_layouts` type differently; the map is for the layout every other
+//! keyboard uses.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use evdev::Key;
+
+use crate::config::Config;
+use crate::layout::{key_name, Action, ActiveLayout};
+use crate::lint::typing_keys;
+use crate::logging::json_string;
+use crate::macros::{key_char, keys_text};
+use crate::mirror::mirrored;
+
+// ROWS: The rows of the main block drawn by the diagram, each with its indent in columns.
+const ROWS: [(usize, &[Key]); 4] = [
+    (
+        0,
+        &[
+            Key::KEY_GRAVE,
+            Key::KEY_1,
+            Key::KEY_2,
+            Key::KEY_3,
+            Key::KEY_4,
+            Key::KEY_5,
+            Key::KEY_6,
+            Key::KEY_7,
+            Key::KEY_8,
+            Key::KEY_9,
+            Key::KEY_0,
+            Key::KEY_MINUS,
+            Key::KEY_EQUAL,
+        ],
+    ),
+    (
+        6,
+        &[
+            Key::KEY_Q,
+            Key::KEY_W,
+            Key::KEY_E,
+            Key::KEY_R,
+            Key::KEY_T,
+            Key::KEY_Y,
+            Key::KEY_U,
+            Key::KEY_I,
+            Key::KEY_O,
+            Key::KEY_P,
+            Key::KEY_LEFTBRACE,
+            Key::KEY_RIGHTBRACE,
+            Key::KEY_BACKSLASH,
+        ],
+    ),
+    (
+        8,
+        &[
+            Key::KEY_A,
+            Key::KEY_S,
+            Key::KEY_D,
+            Key::KEY_F,
+            Key::KEY_G,
+            Key::KEY_H,
+            Key::KEY_J,
+            Key::KEY_K,
+            Key::KEY_L,
+            Key::KEY_SEMICOLON,
+            Key::KEY_APOSTROPHE,
+        ],
+    ),
+    (
+        10,
+        &[
+            Key::KEY_Z,
+            Key::KEY_X,
+            Key::KEY_C,
+            Key::KEY_V,
+            Key::KEY_B,
+            Key::KEY_N,
+            Key::KEY_M,
+            Key::KEY_COMMA,
+            Key::KEY_DOT,
+            Key::KEY_SLASH,
+        ],
+    ),
+];
+
+/// A key sent, with or without Shift held.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chord {
+    pub key: Key,
+    pub shift: bool,
+}
+
+impl Chord {
+    /// What `action` sends for `key`, when Shift is held to begin with if `shift`.
+    fn new(key: Key, action: Action, shift: bool) -> Self {
+        match action {
+            Action::Key(to) => Chord { key: to, shift },
+            Action::Shifted(to) => Chord { key: to, shift: true },
+            Action::Unshifted(to) => Chord { key: to, shift: false },
+            Action::Passthrough => Chord { key, shift },
+        }
+    }
+
+    /// The character this types with a US desktop layout, or the start of the key's name if it
+    /// types none, at most three columns wide.
+    fn label(self) -> String {
+        match key_char(self.key, self.shift) {
+            Some(c) => c.to_string(),
+            None => key_name(self.key).chars().take(3).collect(),
+        }
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.shift {
+            write!(f, "Shift+{:?}", self.key)
+        } else {
+            write!(f, "{:?}", self.key)
+        }
+    }
+}
+
+/// What one physical key sends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMapping {
+    /// The physical key.
+    pub key: Key,
+    /// Pressed on its own.
+    pub plain: Chord,
+    /// Pressed with Shift held.
+    pub shifted: Chord,
+    /// Pressed with AltGr held, sent with AltGr still held; None if no AltGr key is configured.
+    pub altgr: Option<Key>,
+    /// Pressed with a shortcut modifier held, which leaves the layout out.
+    pub shortcut: Key,
+    /// For an overloaded key, what a tap types and what holding it holds; the key then never
+    /// sends `plain` or `shifted`.
+    pub overload: Option<(Chord, Key)>,
+    /// Pressed while the mirror key is held, on its own and with Shift; None if no mirror key is
+    /// configured or the key has no key opposite it.
+    pub mirrored: Option<(Chord, Chord)>,
+}
+
+impl KeyMapping {
+    /// Whether the key sends itself whatever is held.
+    fn unchanged(&self) -> bool {
+        self.plain == Chord { key: self.key, shift: false }
+            && self.shifted == Chord { key: self.key, shift: true }
+            && self.altgr.is_none_or(|to| to == self.key)
+            && self.shortcut == self.key
+            && self.overload.is_none()
+            && self.mirrored.is_none()
+    }
+
+    /// What the key sends, for keys the diagram doesn't draw.
+    fn describe(&self) -> String {
+        if let Some((tap, hold)) = self.overload {
+            return format!("tap {tap}, hold {hold:?}");
+        }
+        let mut description = self.plain.to_string();
+        if self.shifted != (Chord { key: self.plain.key, shift: true }) {
+            description += &format!(", with Shift {}", self.shifted);
+        }
+        if let Some(to) = self.altgr.filter(|&to| to != self.shortcut) {
+            description += &format!(", with AltGr {to:?}");
+        }
+        if self.shortcut != self.plain.key {
+            description += &format!(", with shortcut modifiers {:?}", self.shortcut);
+        }
+        description
+    }
+}
+
+/// What every key sends with the layout in use, as set up by a config.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffectiveMap {
+    /// The layout in use.
+    pub layout: String,
+    /// The letter, digit and punctuation keys, and every other key that doesn't send itself,
+    /// in key code order.
+    pub keys: Vec<KeyMapping>,
+    /// The physical keys of each combo and what pressing them together sends.
+    pub combos: Vec<(Vec<Key>, Chord)>,
+    /// The physical key that mirrors the keyboard while held, if any.
+    pub mirror_key: Option<Key>,
+    /// The physical key of each macro and the text it types.
+    pub macros: Vec<(Key, String)>,
+    /// Each snippet's abbreviation and the text it is replaced with.
+    pub snippets: Vec<(String, String)>,
+}
+
+impl EffectiveMap {
+    /// The mapping `config` sets up, typing with `layout`.
+    pub fn new(config: &Config, layout: &ActiveLayout) -> Self {
+        let modmap: HashMap<Key, Key> =
+            config.swaps.iter().flat_map(|&(a, b)| [(a, b), (b, a)]).chain(config.remaps.iter().copied()).collect();
+        let acts_as = |key: Key| modmap.get(&key).copied().unwrap_or(key);
+        let mapped = layout.get();
+        let tap = |key: Key, shift: bool| {
+            let action = if shift { mapped.map_shifted(key) } else { mapped.map(key) };
+            Chord::new(key, action, shift)
+        };
+
+        // Overloads and combos come after [modmap], so they name keys by what they act as.
+        let keys = (1..=0xff)
+            .map(Key::new)
+            .map(|key| {
+                let logical = acts_as(key);
+                KeyMapping {
+                    key,
+                    plain: tap(logical, false),
+                    shifted: tap(logical, true),
+                    altgr: config.altgr.map(|_| Chord::new(logical, mapped.map_altgr(logical), false).key),
+                    shortcut: logical,
+                    overload: config
+                        .overloads
+                        .iter()
+                        .find(|overload| overload.key == logical)
+                        .map(|overload| (tap(overload.tap, overload.tap_shifted), overload.hold)),
+                    mirrored: config.mirror_key.map(|_| Key::new(mirrored(logical.code()))).and_then(|opposite| {
+                        (opposite != logical).then(|| (tap(opposite, false), tap(opposite, true)))
+                    }),
+                }
+            })
+            .filter(|mapping| typing_keys().any(|key| key == mapping.key) || !mapping.unchanged())
+            .collect::<Vec<KeyMapping>>();
+        let physical = |key: Key| (1..=0xff).map(Key::new).find(|&physical| acts_as(physical) == key).unwrap_or(key);
+        let combos = config
+            .combos
+            .iter()
+            .map(|combo| (combo.keys.iter().map(|&key| physical(key)).collect(), tap(combo.output, false)))
+            .collect();
+        // Macros come after the layout, so a macro's key is the one that types its trigger.
+        let typing = |trigger: Key| {
+            keys.iter()
+                .find(|mapping| mapping.overload.is_none() && mapping.plain == Chord { key: trigger, shift: false })
+                .map_or(trigger, |mapping| mapping.key)
+        };
+        let macros = config.macros.iter().map(|text_macro| (typing(text_macro.trigger), keys_text(&text_macro.keys)));
+        let snippets = config.snippets.iter().map(|snippet| (snippet.abbreviation.clone(), keys_text(&snippet.keys)));
+        EffectiveMap {
+            layout: layout.name(),
+            mirror_key: config.mirror_key.map(physical),
+            macros: macros.collect(),
+            snippets: snippets.collect(),
+            keys,
+            combos,
+        }
+    }
+
+    /// The map as a JSON object: `layout`; `keys`, keyed by physical key name, each with what it
+    /// sends `plain`, `shifted`, with `altgr` (if configured) and with a `shortcut` modifier held,
+    /// or its `tap` and `hold` if it is overloaded, and `mirrored` and `mirrored_shifted` if the
+    /// mirror layer moves it; `combos`, each with its `keys` and what it `sends`; the
+    /// `mirror_key`, or null; `macros`, each with its `key` and the text it `types`; and
+    /// `snippets`, each with its `abbreviation` and the text it `types`. What a key sends is an
+    /// evdev key name, prefixed with `Shift+` if Shift is held with it.
+    pub fn to_json(&self) -> String {
+        let keys: Vec<String> = self
+            .keys
+            .iter()
+            .map(|mapping| {
+                let mut fields = Vec::new();
+                match mapping.overload {
+                    Some((tap, hold)) => {
+                        fields.push(format!("\"tap\":\"{tap}\""));
+                        fields.push(format!("\"hold\":\"{hold:?}\""));
+                    }
+                    None => {
+                        fields.push(format!("\"plain\":\"{}\"", mapping.plain));
+                        fields.push(format!("\"shifted\":\"{}\"", mapping.shifted));
+                        if let Some(to) = mapping.altgr {
+                            fields.push(format!("\"altgr\":\"{to:?}\""));
+                        }
+                        fields.push(format!("\"shortcut\":\"{:?}\"", mapping.shortcut));
+                    }
+                }
+                if let Some((plain, shifted)) = mapping.mirrored {
+                    fields.push(format!("\"mirrored\":\"{plain}\""));
+                    fields.push(format!("\"mirrored_shifted\":\"{shifted}\""));
+                }
+                format!("\"{:?}\":{{{}}}", mapping.key, fields.join(","))
+            })
+            .collect();
+        let combos: Vec<String> = self
+            .combos
+            .iter()
+            .map(|(keys, sends)| {
+                let keys: Vec<String> = keys.iter().map(|key| format!("\"{key:?}\"")).collect();
+                format!("{{\"keys\":[{}],\"sends\":\"{sends}\"}}", keys.join(","))
+            })
+            .collect();
+        let mirror_key = match self.mirror_key {
+            Some(key) => format!("\"{key:?}\""),
+            None => "null".to_string(),
+        };
+        let macros: Vec<String> = self
+            .macros
+            .iter()
+            .map(|(key, text)| format!("{{\"key\":\"{key:?}\",\"types\":{}}}", json_string(text)))
+            .collect();
+        let snippets: Vec<String> = self
+            .snippets
+            .iter()
+            .map(|(abbreviation, text)| {
+                format!("{{\"abbreviation\":{},\"types\":{}}}", json_string(abbreviation), json_string(text))
+            })
+            .collect();
+        format!(
+            "{{\"layout\":{},\"keys\":{{{}}},\"combos\":[{}],\"mirror_key\":{},\"macros\":[{}],\"snippets\":[{}]}}",
+            json_string(&self.layout),
+            keys.join(","),
+            combos.join(","),
+            mirror_key,
+            macros.join(","),
+            snippets.join(",")
+        )
+    }
+
+    /// The main block of the keyboard with what each key types, Shift above and without below,
+    /// read with a US desktop layout, again with the mirror key held if there is one, followed by
+    /// the other keys that don't send themselves, the combos, the macros and the snippets.
+    pub fn diagram(&self) -> String {
+        let by_key: HashMap<Key, &KeyMapping> = self.keys.iter().map(|mapping| (mapping.key, mapping)).collect();
+        let mut lines = vec![format!("{} layout, with Shift above and without below:", self.layout), String::new()];
+        lines.extend(block(&by_key, |mapping| (mapping.shifted, mapping.plain)));
+        if let Some(mirror_key) = self.mirror_key {
+            lines.push(String::new());
+            lines.push(format!("With {mirror_key:?} held, mirrored:"));
+            lines.push(String::new());
+            lines.extend(block(&by_key, |mapping| match mapping.mirrored {
+                Some((plain, shifted)) => (shifted, plain),
+                None => (mapping.shifted, mapping.plain),
+            }));
+        }
+
+        let drawn = |key: &Key| ROWS.iter().any(|(_, keys)| keys.contains(key));
+        let others: Vec<String> = self
+            .keys
+            .iter()
+            .filter(|mapping| mapping.overload.is_some() || (!drawn(&mapping.key) && !mapping.unchanged()))
+            .map(|mapping| format!("  {:?} -> {}", mapping.key, mapping.describe()))
+            .chain(self.combos.iter().map(|(keys, sends)| {
+                let keys: Vec<String> = keys.iter().map(|key| format!("{key:?}")).collect();
+                format!("  {} together -> {sends}", keys.join("+"))
+            }))
+            .chain(self.macros.iter().map(|(key, text)| format!("  {key:?} types {text:?}")))
+            .chain(
+                self.snippets.iter().map(|(abbreviation, text)| format!("  {abbreviation:?} is replaced by {text:?}")),
+            )
+            .collect();
+        if !others.is_empty() {
+            lines.push(String::new());
+            let overloaded = self.keys.iter().any(|mapping| mapping.overload.is_some());
+            lines.push(
+                if overloaded { "Other keys (* taps one key and holds another):" } else { "Other keys:" }.to_string(),
+            );
+            lines.extend(others);
+        }
+        lines.join("\n")
+    }
+}
+
+/// The lines drawing the main block, each key with the chords `label` gives it, the one with
+/// Shift above the one without. Overloaded keys are marked `*` above what a tap types.
+fn block(by_key: &HashMap<Key, &KeyMapping>, label: impl Fn(&KeyMapping) -> (Chord, Chord)) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut border = String::new();
+    for (indent, keys) in ROWS {
+        let top = format!("{}{}+", " ".repeat(indent), "+---".repeat(keys.len()));
+        lines.push(merge_borders(&border, &top));
+        let row = |cell: &dyn Fn(&KeyMapping) -> String| {
+            let cells: Vec<String> = keys.iter().map(|key| format!("{:^3}", cell(by_key[key]))).collect();
+            format!("{}|{}|", " ".repeat(indent), cells.join("|"))
+        };
+        lines.push(row(&|mapping| match mapping.overload {
+            Some(_) => "*".to_string(),
+            None => label(mapping).0.label(),
+        }));
+        lines.push(row(&|mapping| match mapping.overload {
+            Some((tap, _)) => tap.label(),
+            None => label(mapping).1.label(),
+        }));
+        border = top;
+    }
+    lines.push(border);
+    lines
+}
+
+/// The line between two rows: the top edge of the row `below` it, stretched to cover the
+/// bottom edge of the row `above` it. Rows are staggered, so only the lower row's key edges are
+/// marked.
+fn merge_borders(above: &str, below: &str) -> String {
+    let edge = |line: &str| line.find('+').map(|start| start..line.len());
+    let span = match (edge(above), edge(below)) {
+        (Some(above), Some(below)) => above.start.min(below.start)..above.end.max(below.end),
+        (above, below) => above.or(below).unwrap_or_default(),
+    };
+    let below: Vec<char> = below.chars().collect();
+    (0..span.end)
+        .map(|i| match below.get(i) {
+            _ if i == span.start || i == span.end - 1 => '+',
+            Some('+') => '+',
+            _ if i > span.start => '-',
+            _ => ' ',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use std::time::Duration;
+
+    use crate::combo::Combo;
+    use crate::layout::Dvorak;
+    use crate::macros::{text_keys, Macro, Snippet, UnicodeInput};
+    use crate::taphold::Overload;
+
+    fn map(config: &Config) -> EffectiveMap {
+        EffectiveMap::new(config, &ActiveLayout::new(Arc::new(Dvorak), config.keys.clone()))
+    }
+
+    #[test]
+    fn json_gives_every_layer_of_a_key() {
+        let config = Config {
+            keys: vec![(Key::KEY_SEMICOLON, Key::KEY_BACKSPACE)],
+            swaps: vec![(Key::KEY_CAPSLOCK, Key::KEY_ESC)],
+            overloads: vec![Overload {
+                key: Key::KEY_SPACE,
+                hold: Key::KEY_LEFTCTRL,
+                tap: Key::KEY_SPACE,
+                tap_shifted: false,
+            }],
+            combos: vec![Combo { keys: vec![Key::KEY_J, Key::KEY_K], output: Key::KEY_ESC }],
+            ..Config::default()
+        };
+        let json = map(&config).to_json();
+        assert!(
+            json.starts_with("{\"layout\":\"dvorak\",\"keys\":{\"KEY_ESC\":{\"plain\":\"KEY_CAPSLOCK\","),
+            "{json}"
+        );
+        for entry in [
+            "\"KEY_Q\":{\"plain\":\"KEY_APOSTROPHE\",\"shifted\":\"Shift+KEY_APOSTROPHE\",\"shortcut\":\"KEY_Q\"}",
+            "\"KEY_SEMICOLON\":{\"plain\":\"KEY_BACKSPACE\",",
+            "\"KEY_SPACE\":{\"tap\":\"KEY_SPACE\",\"hold\":\"KEY_LEFTCTRL\"}",
+            "\"combos\":[{\"keys\":[\"KEY_J\",\"KEY_K\"],\"sends\":\"KEY_ESC\"}],\"mirror_key\":null,",
+        ] {
+            assert!(json.contains(entry), "{entry} not in {json}");
+        }
+        assert!(!json.contains("\"KEY_LEFTSHIFT\""), "{json}");
+    }
+
+    #[test]
+    fn diagram_draws_the_main_block() {
+        let config = Config { swaps: vec![(Key::KEY_CAPSLOCK, Key::KEY_ESC)], ..Config::default() };
+        let diagram = map(&config).diagram();
+        let lines: Vec<&str> = diagram.lines().collect();
+        assert_eq!(lines[0], "dvorak layout, with Shift above and without below:");
+        assert_eq!(lines[2], "+---+---+---+---+---+---+---+---+---+---+---+---+---+");
+        assert_eq!(lines[3], "| ~ | ! | @ | # | $ | % | ^ | & | * | ( | ) | { | } |");
+        assert_eq!(lines[4], "| ` | 1 | 2 | 3 | 4 | 5 | 6 | 7 | 8 | 9 | 0 | [ | ] |");
+        assert_eq!(lines[5], "+-----+---+---+---+---+---+---+---+---+---+---+---+---+---+");
+        assert_eq!(lines[10], "        | a | o | e | u | i | d | h | t | n | s | - |");
+        assert!(diagram.ends_with("Other keys:\n  KEY_ESC -> KEY_CAPSLOCK\n  KEY_CAPSLOCK -> KEY_ESC"), "{diagram}");
+    }
+
+    #[test]
+    fn mirror_layer_macros_and_snippets_are_mapped() {
+        let config = Config {
+            mirror_key: Some(Key::KEY_SPACE),
+            macros: vec![Macro {
+                trigger: Key::KEY_S,
+                keys: text_keys("Olé", UnicodeInput::CtrlShiftU).unwrap(),
+                key_delay: Duration::ZERO,
+                press_time: Duration::ZERO,
+            }],
+            snippets: vec![Snippet {
+                abbreviation: "ty".to_string(),
+                keys: text_keys("thank you\n", UnicodeInput::Off).unwrap(),
+            }],
+            ..Config::default()
+        };
+        let map = map(&config);
+        let json = map.to_json();
+        for entry in [
+            "\"KEY_Q\":{\"plain\":\"KEY_APOSTROPHE\",\"shifted\":\"Shift+KEY_APOSTROPHE\",\"shortcut\":\"KEY_Q\",\
+             \"mirrored\":\"KEY_L\",\"mirrored_shifted\":\"Shift+KEY_L\"}",
+            "\"KEY_GRAVE\":{\"plain\":\"KEY_GRAVE\",\"shifted\":\"Shift+KEY_GRAVE\",\"shortcut\":\"KEY_GRAVE\"}",
+            "\"mirror_key\":\"KEY_SPACE\",\"macros\":[{\"key\":\"KEY_SEMICOLON\",\"types\":\"Olé\"}],\
+             \"snippets\":[{\"abbreviation\":\"ty\",\"types\":\"thank you\\n\"}]}",
+        ] {
+            assert!(json.contains(entry), "{entry} not in {json}");
+        }
+
+        let diagram = map.diagram();
+        let lines: Vec<&str> = diagram.lines().collect();
+        assert_eq!(lines[16], "With KEY_SPACE held, mirrored:");
+        assert_eq!(lines[19], "| ~ | ) | ( | * | & | ^ | % | $ | # | @ | ! | { | } |");
+        assert_eq!(lines[20], "| ` | 0 | 9 | 8 | 7 | 6 | 5 | 4 | 3 | 2 | 1 | [ | ] |");
+        assert_eq!(lines[26], "        | s | n | t | h | d | i | u | e | o | a | - |");
+        assert!(
+            diagram.ends_with("Other keys:\n  KEY_SEMICOLON types \"Olé\"\n  \"ty\" is replaced by \"thank you\\n\""),
+            "{diagram}"
+        );
+    }
+}
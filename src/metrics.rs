@@ -0,0 +1,156 @@
+//! A Prometheus `/metrics` endpoint on localhost, for scraping the daemon's counters.
+//!
+//! Everything is counted since the daemon started: events written and dropped, failed
+//! writes, and events read from each keyboard, whose rate is that keyboard's throughput.
+//! With `latency_histogram = true`, the latency percentiles of the `latency` control command
+//! are served as summaries too. It listens on 127.0.0.1 (port `metrics_port`) only, as the
+//! counts say how much and when someone types.
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::os::fd::AsFd;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::shutdown::Shutdown;
+use crate::stats::{Counters, LatencyHistogram};
+
+// REQUEST_TIMEOUT: How long a scraper has to send its request before it is hung up on.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+// ACCEPT_RETRY: The pause after a failed accept, which would fail again straight away while no fd is free.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// Serves `/metrics` on `port` until shutdown. Returns None, after a warning, if the port
+/// can't be listened on.
+pub(crate) fn spawn_metrics_server(
+    port: u16,
+    counters: Arc<Counters>,
+    latency: Option<Arc<Mutex<LatencyHistogram>>>,
+    shutdown: Arc<Shutdown>,
+) -> Option<JoinHandle<()>> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .and_then(|listener| listener.set_nonblocking(true).map(|()| listener));
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to serve metrics on port {}: {}", port, e);
+            return None;
+        }
+    };
+    info!("Serving metrics on http://127.0.0.1:{}/metrics", port);
+    Some(std::thread::spawn(move || {
+        while shutdown.wait_readable(&[listener.as_fd()], None) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = serve(&counters, latency.as_deref(), stream) {
+                        warn!("Metrics request failed: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    if shutdown.wait(ACCEPT_RETRY) {
+                        break;
+                    }
+                }
+            }
+        }
+    }))
+}
+
+/// Answers one HTTP request: the metrics for `GET /metrics`, 404 for anything else.
+fn serve(counters: &Counters, latency: Option<&Mutex<LatencyHistogram>>, mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Read the headers too; closing with them unread would reset the connection under the
+    // reply.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", render(counters, latency)),
+        _ => ("404 Not Found", "Not found; try /metrics\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// The metrics in the Prometheus text format.
+fn render(counters: &Counters, latency: Option<&Mutex<LatencyHistogram>>) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}");
+    };
+    let load = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+    metric(
+        "qwertdvert_events_processed_total",
+        "counter",
+        "Events written to the virtual keyboard.",
+        load(&counters.processed_total),
+    );
+    metric(
+        "qwertdvert_events_dropped_total",
+        "counter",
+        "Events dropped because the writer fell behind.",
+        load(&counters.dropped_total),
+    );
+    metric(
+        "qwertdvert_write_failures_total",
+        "counter",
+        "Failed writes to the virtual keyboard.",
+        load(&counters.write_failures_total),
+    );
+    metric(
+        "qwertdvert_devices_grabbed",
+        "gauge",
+        "Keyboards grabbed now.",
+        counters.devices_grabbed.load(Ordering::Relaxed) as u64,
+    );
+
+    let name = "qwertdvert_device_events_read_total";
+    let _ = writeln!(out, "# HELP {name} Events read from each keyboard.\n# TYPE {name} counter");
+    for (device, count) in counters.events_read.lock().unwrap().iter() {
+        let _ = writeln!(out, "{name}{{device=\"{}\"}} {}", escape(device), load(count));
+    }
+
+    if let Some(latency) = latency {
+        let name = "qwertdvert_latency_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Time keys take to get through: from the keyboard (total) or from the event loop \
+             (hop) to the virtual keyboard.\n# TYPE {name} summary"
+        );
+        let latency = latency.lock().unwrap();
+        for (stage, histogram) in latency.stages() {
+            for quantile in [0.5, 0.9, 0.99, 0.999] {
+                let seconds = histogram.value_at_quantile(quantile) as f64 / 1e6;
+                let _ = writeln!(out, "{name}{{stage=\"{stage}\",quantile=\"{quantile}\"}} {seconds}");
+            }
+            let micros: u64 = histogram
+                .iter_recorded()
+                .map(|value| value.value_iterated_to() * value.count_at_value())
+                .sum();
+            let _ = writeln!(out, "{name}_sum{{stage=\"{stage}\"}} {}", micros as f64 / 1e6);
+            let _ = writeln!(out, "{name}_count{{stage=\"{stage}\"}} {}", histogram.len());
+        }
+    }
+    out
+}
+
+/// `value` escaped for a label in the text format.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
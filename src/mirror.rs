@@ -0,0 +1,205 @@
+//! One-handed mirror typing (half-QWERTY): while a key, normally Space, is held, the keyboard
+//! is mirrored left to right, so one hand reaches the other half's keys at the same fingers.
+//!
+//! The mirror key is decided like a dual-function key: it is a tap, typing itself, if it is
+//! released within the tapping term and before any key pressed after it is released;
+//! otherwise it turns the mirror on until it is released. Keys pressed before it is decided
+//! are held back and replayed after. Mirroring changes physical keys, so the layout still
+//! applies to the mirrored key.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use evdev::Key;
+
+use crate::pipeline::{KeyEvent, Stage};
+
+// MIRRORED: Each key and the one opposite it; the mirror works both ways.
+const MIRRORED: [(Key, Key); 20] = [
+    (Key::KEY_1, Key::KEY_0),
+    (Key::KEY_2, Key::KEY_9),
+    (Key::KEY_3, Key::KEY_8),
+    (Key::KEY_4, Key::KEY_7),
+    (Key::KEY_5, Key::KEY_6),
+    (Key::KEY_Q, Key::KEY_P),
+    (Key::KEY_W, Key::KEY_O),
+    (Key::KEY_E, Key::KEY_I),
+    (Key::KEY_R, Key::KEY_U),
+    (Key::KEY_T, Key::KEY_Y),
+    (Key::KEY_A, Key::KEY_SEMICOLON),
+    (Key::KEY_S, Key::KEY_L),
+    (Key::KEY_D, Key::KEY_K),
+    (Key::KEY_F, Key::KEY_J),
+    (Key::KEY_G, Key::KEY_H),
+    (Key::KEY_Z, Key::KEY_SLASH),
+    (Key::KEY_X, Key::KEY_DOT),
+    (Key::KEY_C, Key::KEY_COMMA),
+    (Key::KEY_V, Key::KEY_M),
+    (Key::KEY_B, Key::KEY_N),
+];
+
+/// The key opposite `code` on the mirrored keyboard, or `code` itself if it has none.
+pub(crate) fn mirrored(code: u16) -> u16 {
+    MIRRORED
+        .iter()
+        .flat_map(|&(a, b)| [(a, b), (b, a)])
+        .find(|(from, _)| from.code() == code)
+        .map_or(code, |(_, to)| to.code())
+}
+
+/// Where the mirror key is at.
+enum State {
+    Up,
+    /// Pressed, but not yet decided as a tap or a hold.
+    Pending { deadline: Instant },
+    /// Held, mirroring the keys pressed.
+    Mirroring,
+}
+
+/// Modmap stage that mirrors the keyboard while the mirror key is held.
+pub struct Mirror {
+    key: u16,
+    tapping_term: Duration,
+    state: State,
+    /// Events that arrived while the mirror key was pending, replayed once it is decided.
+    buffer: Vec<KeyEvent>,
+    /// Output code of each key held down, by input code, so its release matches its press.
+    held: HashMap<u16, u16>,
+}
+
+impl Mirror {
+    pub fn new(key: Key, tapping_term: Duration) -> Self {
+        Mirror {
+            key: key.code(),
+            tapping_term,
+            state: State::Up,
+            buffer: Vec::new(),
+            held: HashMap::new(),
+        }
+    }
+
+    /// Decides the pending mirror key as `state` and replays what was held back.
+    fn decide(&mut self, state: State, out: &mut Vec<KeyEvent>) {
+        self.state = state;
+        for event in std::mem::take(&mut self.buffer) {
+            self.process(event, out);
+        }
+    }
+}
+
+impl Stage for Mirror {
+    fn process(&mut self, mut event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        if event.code == self.key {
+            match (&self.state, event.value) {
+                (State::Up, 1) => {
+                    self.state = State::Pending { deadline: Instant::now() + self.tapping_term };
+                }
+                (State::Pending { .. }, 0) => {
+                    out.push(KeyEvent::new(self.key, 1));
+                    out.push(KeyEvent::new(self.key, 0));
+                    self.decide(State::Up, out);
+                }
+                (State::Mirroring, 0) => self.state = State::Up,
+                // Autorepeat of the mirror key is dropped.
+                _ => {}
+            }
+            return;
+        }
+        if let State::Pending { .. } = self.state {
+            let released_after_press =
+                event.value == 0 && self.buffer.iter().any(|earlier| earlier.code == event.code && earlier.value == 1);
+            self.buffer.push(event);
+            if released_after_press {
+                self.decide(State::Mirroring, out);
+            }
+            return;
+        }
+        event.code = match event.value {
+            1 => {
+                let code = match self.state {
+                    State::Mirroring => mirrored(event.code),
+                    _ => event.code,
+                };
+                self.held.insert(event.code, code);
+                code
+            }
+            0 => self.held.remove(&event.code).unwrap_or(event.code),
+            _ => self.held.get(&event.code).copied().unwrap_or(event.code),
+        };
+        out.push(event);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::Pending { deadline } => Some(deadline),
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<KeyEvent>) {
+        if matches!(self.state, State::Pending { deadline } if deadline <= now) {
+            self.decide(State::Mirroring, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{run, tick};
+    use crate::taphold::DEFAULT_TAPPING_TERM;
+
+    const SPACE: u16 = Key::KEY_SPACE.code();
+    const F: u16 = Key::KEY_F.code();
+    const J: u16 = Key::KEY_J.code();
+
+    fn mirror() -> Mirror {
+        Mirror::new(Key::KEY_SPACE, DEFAULT_TAPPING_TERM)
+    }
+
+    fn tick_past_deadline(stage: &mut Mirror) -> Vec<(u16, i32)> {
+        let deadline = stage.deadline().expect("the mirror key is pending");
+        tick(stage, deadline)
+    }
+
+    #[test]
+    fn tap_types_the_mirror_key() {
+        let mut stage = mirror();
+        assert_eq!(run(&mut stage, &[(SPACE, 1), (SPACE, 2)]), []);
+        assert_eq!(run(&mut stage, &[(SPACE, 0)]), [(SPACE, 1), (SPACE, 0)]);
+        assert_eq!(stage.deadline(), None);
+        assert_eq!(run(&mut stage, &[(F, 1), (F, 0)]), [(F, 1), (F, 0)]);
+    }
+
+    #[test]
+    fn hold_past_the_tapping_term_mirrors() {
+        let mut stage = mirror();
+        assert_eq!(run(&mut stage, &[(SPACE, 1)]), []);
+        assert_eq!(tick_past_deadline(&mut stage), []);
+        assert_eq!(run(&mut stage, &[(F, 1), (F, 2), (F, 0)]), [(J, 1), (J, 2), (J, 0)]);
+        assert_eq!(run(&mut stage, &[(J, 1), (J, 0)]), [(F, 1), (F, 0)]);
+        assert_eq!(run(&mut stage, &[(SPACE, 0), (F, 1), (F, 0)]), [(F, 1), (F, 0)]);
+    }
+
+    #[test]
+    fn key_tapped_while_pending_decides_a_hold() {
+        let mut stage = mirror();
+        assert_eq!(run(&mut stage, &[(SPACE, 1), (F, 1)]), []);
+        assert_eq!(run(&mut stage, &[(F, 0)]), [(J, 1), (J, 0)]);
+        assert_eq!(stage.deadline(), None);
+        assert_eq!(run(&mut stage, &[(SPACE, 0)]), []);
+    }
+
+    #[test]
+    fn release_matches_its_press_when_the_mirror_key_changes() {
+        let mut stage = mirror();
+        assert_eq!(run(&mut stage, &[(SPACE, 1)]), []);
+        tick_past_deadline(&mut stage);
+        assert_eq!(run(&mut stage, &[(F, 1), (SPACE, 0)]), [(J, 1)]);
+        assert_eq!(run(&mut stage, &[(F, 2), (F, 0)]), [(J, 2), (J, 0)]);
+
+        assert_eq!(run(&mut stage, &[(F, 1), (SPACE, 1)]), [(F, 1)]);
+        tick_past_deadline(&mut stage);
+        assert_eq!(run(&mut stage, &[(F, 0)]), [(F, 0)]);
+    }
+}
@@ -0,0 +1,43 @@
+//! systemd service notifications (`sd_notify`): readiness, status text and watchdog pings.
+//!
+//! With `Type=notify` systemd passes a datagram socket in `$NOTIFY_SOCKET`, and with
+//! `WatchdogSec=` the interval in `$WATCHDOG_USEC`. Outside systemd neither is set and
+//! nothing is sent.
+
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+use tracing::debug;
+
+/// Sends `state` (newline-separated `KEY=VALUE` assignments) to systemd, if it is listening.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy();
+    // A leading '@' names a socket in the abstract namespace.
+    let address = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(path.as_ref()),
+    };
+    let sent = address.and_then(|address| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &address)
+    });
+    if let Err(e) = sent {
+        debug!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// How often systemd expects a watchdog ping, if it has a watchdog on this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    // WATCHDOG_PID, when set, says which process the watchdog is for.
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID")
+        && pid.to_string_lossy().parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
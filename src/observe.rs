@@ -0,0 +1,103 @@
+//! `qwertdvert --observe`: reads the keyboards the config selects without grabbing them, and
+//! reports what each key would be remapped to.
+//!
+//! No virtual keyboard is created and nothing is grabbed, so what is typed reaches the desktop
+//! as usual, unmapped. That makes it safe to check which keyboards are detected and what the
+//! layout and config do with each key before going live. A running daemon has the keyboards
+//! grabbed, so nothing is read from them while it runs.
+
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use evdev::{Device, EventType};
+use nix::errno::Errno;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
+
+use crate::config::Config;
+use crate::daemon::keyboard_layout;
+use crate::enumeration::find_keyboards;
+use crate::error::{DaemonError, DeviceError};
+use crate::layout::{ActiveLayout, Dvorak};
+use crate::pipeline::{KeyEvent, Pipeline};
+use crate::remap::ModifierState;
+use crate::source::KeyboardSource;
+
+/// The keyboards being observed, each with the pipeline the daemon would give it.
+pub struct Observer {
+    epoll: Epoll,
+    keyboards: Vec<(Device, String, Pipeline)>,
+    layout: String,
+}
+
+impl Observer {
+    /// Opens the keyboards `config` selects, without grabbing them.
+    pub fn new(config: &Config) -> Result<Self, DaemonError> {
+        let shared = Arc::new(ActiveLayout::new(Arc::new(Dvorak), config.keys.clone()));
+        shared.select(&config.layout)?;
+        let modifiers = Arc::new(ModifierState::default());
+        modifiers.set_sticky_keys(config.sticky_keys);
+        let epoll_error = |source| DeviceError::Epoll { device: "observe".to_string(), source };
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC).map_err(epoll_error)?;
+        let mut keyboards = Vec::new();
+        for (index, device) in find_keyboards(&config.devices)?.into_iter().enumerate() {
+            let name = KeyboardSource::name(&device);
+            let layout = keyboard_layout(config, &shared, &name, device.device_id());
+            // SAFETY: The device owns the fd and is kept in `keyboards` for as long as epoll is.
+            let fd = unsafe { BorrowedFd::borrow_raw(device.as_raw_fd()) };
+            epoll.add(fd, EpollEvent::new(EpollFlags::EPOLLIN, index as u64)).map_err(epoll_error)?;
+            keyboards.push((device, name, Pipeline::for_config(config, layout, modifiers.clone())));
+        }
+        Ok(Observer { epoll, keyboards, layout: shared.name() })
+    }
+
+    /// The names of the keyboards observed.
+    pub fn keyboards(&self) -> Vec<&str> {
+        self.keyboards.iter().map(|(_, name, _)| name.as_str()).collect()
+    }
+
+    /// The name of the layout keys are mapped with, unless a keyboard has its own.
+    pub fn layout(&self) -> &str {
+        &self.layout
+    }
+
+    /// Reads the keyboards until one fails, calling `on_key` with the time since the start, the
+    /// keyboard, each key event read and the events it would have been remapped to. Events a
+    /// stage emits from a timer are reported with no key event read.
+    pub fn run(mut self, mut on_key: impl FnMut(Duration, &str, Option<KeyEvent>, &[KeyEvent])) -> DaemonError {
+        let start = Instant::now();
+        let mut ready = [EpollEvent::empty(); 16];
+        loop {
+            let deadline = self.keyboards.iter().filter_map(|(_, _, pipeline)| pipeline.deadline()).min();
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let millis = deadline.saturating_duration_since(Instant::now()).as_micros().div_ceil(1000);
+                    EpollTimeout::try_from(millis).unwrap_or(EpollTimeout::MAX)
+                }
+                None => EpollTimeout::NONE,
+            };
+            let count = match self.epoll.wait(&mut ready, timeout) {
+                Ok(count) => count,
+                Err(Errno::EINTR) => 0,
+                Err(source) => return DeviceError::Epoll { device: "observe".to_string(), source }.into(),
+            };
+            for event in &ready[..count] {
+                let (device, name, pipeline) = &mut self.keyboards[event.data() as usize];
+                let events = match device.fetch_events() {
+                    Ok(events) => events,
+                    Err(source) => return DeviceError::Read { device: name.clone(), source }.into(),
+                };
+                for event in events.filter(|event| event.event_type() == EventType::KEY) {
+                    let input = KeyEvent::new(event.code(), event.value());
+                    on_key(start.elapsed(), name, Some(input), pipeline.process(input));
+                }
+            }
+            let now = Instant::now();
+            for (_, name, pipeline) in &mut self.keyboards {
+                if pipeline.deadline().is_some_and(|deadline| deadline <= now) {
+                    on_key(start.elapsed(), name, None, pipeline.tick(now));
+                }
+            }
+        }
+    }
+}
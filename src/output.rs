@@ -0,0 +1,525 @@
+//! The virtual uinput keyboard and the writer thread that feeds it.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Mutex};
+
+use evdev::{EventType, MiscType};
+use nix::libc;
+use tracing::{error, info};
+
+use crate::config::{DeviceIdentity, KeyRepeat};
+use crate::error::OutputError;
+use crate::feedback::LEDS;
+use crate::logging;
+use crate::ring::Consumer;
+use crate::stats::{Counters, DropClass, LatencyHistogram};
+
+// Name of the virtual keyboard, as shown by e.g. `libinput list-devices`, unless the config
+// gives it another.
+pub(crate) const OUTPUT_DEVICE_NAME: &str = "QwertDvert";
+// OUTPUT_DEVICE_PHYS: Physical path of the virtual keyboard, which marks it as ours whatever
+// name and IDs it is given, so it is never grabbed.
+pub(crate) const OUTPUT_DEVICE_PHYS: &str = "qwertdvert/input0";
+// UINPUT_PATH: The uinput device node.
+const UINPUT_PATH: &str = "/dev/uinput";
+
+// The uinput ioctls (linux/uinput.h).
+nix::ioctl_none!(ui_dev_create, b'U', 1);
+nix::ioctl_none!(ui_dev_destroy, b'U', 2);
+nix::ioctl_write_ptr!(ui_dev_setup, b'U', 3, libc::uinput_setup);
+nix::ioctl_write_int!(ui_set_evbit, b'U', 100);
+nix::ioctl_write_int!(ui_set_keybit, b'U', 101);
+nix::ioctl_write_int!(ui_set_mscbit, b'U', 104);
+nix::ioctl_write_int!(ui_set_ledbit, b'U', 105);
+// UI_SET_PHYS takes the string itself, though its size is that of a pointer.
+nix::ioctl_write_ptr_bad!(
+    ui_set_phys,
+    nix::request_code_write!(b'U', 108, size_of::<*const libc::c_char>()),
+    libc::c_char
+);
+
+// EV_REP codes (linux/input-event-codes.h).
+pub(crate) const REP_DELAY: u16 = 0x00;
+pub(crate) const REP_PERIOD: u16 = 0x01;
+
+// Key codes the virtual keyboard can send: every key, but not the mouse, joystick and gamepad
+// buttons in between, which would make it look like a pointer or game controller.
+const KEY_CODES: [std::ops::RangeInclusive<u16>; 2] = [1..=0xff, 0x160..=libc::KEY_MAX];
+
+/// Whether the virtual keyboard can send `key`.
+pub(crate) fn advertised(key: evdev::Key) -> bool {
+    KEY_CODES.iter().any(|codes| codes.contains(&key.code()))
+}
+
+// Channel configuration
+// EVENT_BUFFER_SIZE: Capacity of each keyboard's ring buffer to the writer, in frames of events.
+// A larger buffer reduces blocking during short bursts without meaningfully increasing memory.
+pub const EVENT_BUFFER_SIZE: usize = 8192;
+
+// Error handling
+// MAX_CONSECUTIVE_FAILURES: Maximum consecutive uinput write failures before giving up.
+const MAX_CONSECUTIVE_FAILURES: u32 = 100;
+
+// Backoff timing
+// Initial backoff multiplier for uinput write failures (10ms per failure, capped at 100ms).
+const BACKOFF_BASE_MS: u32 = 10;
+
+/// The virtual keyboard that remapped events are written to. It is removed when dropped.
+pub struct VirtualKeyboard {
+    file: File,
+    /// Whether the kernel generates key repeats itself, so repeats read from the keyboards
+    /// would double them.
+    kernel_repeat: bool,
+}
+
+/// Creates the virtual keyboard, identified as `identity`. With `repeat`, the kernel repeats
+/// held keys on it with that timing.
+pub fn create_uinput_device(
+    identity: &DeviceIdentity,
+    repeat: Option<KeyRepeat>,
+) -> Result<VirtualKeyboard, OutputError> {
+    // Opened for reading too, to hear the LED and repeat changes applications send back, which
+    // the event loop reads until there are none left. Writes to uinput never block anyway.
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(UINPUT_PATH)
+        .map_err(OutputError::Open)?;
+    let fd = file.as_raw_fd();
+    // SAFETY: `fd` is an open uinput file descriptor and the setup struct outlives the call.
+    unsafe {
+        ui_set_evbit(fd, EventType::KEY.0 as _).map_err(OutputError::Configure)?;
+        for code in KEY_CODES.into_iter().flatten() {
+            ui_set_keybit(fd, code as _).map_err(OutputError::Configure)?;
+        }
+        // Scancodes, so they can go out matching the remapped keys.
+        ui_set_evbit(fd, EventType::MISC.0 as _).map_err(OutputError::Configure)?;
+        ui_set_mscbit(fd, MiscType::MSC_SCAN.0 as _).map_err(OutputError::Configure)?;
+        ui_set_evbit(fd, EventType::LED.0 as _).map_err(OutputError::Configure)?;
+        for led in LEDS {
+            ui_set_ledbit(fd, led.0 as _).map_err(OutputError::Configure)?;
+        }
+        if repeat.is_some() {
+            ui_set_evbit(fd, EventType::REPEAT.0 as _).map_err(OutputError::Configure)?;
+        }
+        let phys = std::ffi::CString::new(OUTPUT_DEVICE_PHYS).expect("no NUL in the physical path");
+        ui_set_phys(fd, phys.as_ptr()).map_err(OutputError::Configure)?;
+        let mut setup: libc::uinput_setup = std::mem::zeroed();
+        // The config checks the name fits, leaving a NUL at the end.
+        for (to, &from) in setup.name.iter_mut().zip(identity.name.as_bytes()) {
+            *to = from as libc::c_char;
+        }
+        setup.id = libc::input_id {
+            bustype: identity.bus_type.0,
+            vendor: identity.vendor,
+            product: identity.product,
+            version: identity.version,
+        };
+        ui_dev_setup(fd, &setup).map_err(OutputError::Create)?;
+        ui_dev_create(fd).map_err(OutputError::Create)?;
+    }
+    let mut keyboard = VirtualKeyboard { file, kernel_repeat: false };
+    if let Some(repeat) = repeat {
+        // Written to the device, EV_REP events set its repeat timing.
+        let millis = |duration: std::time::Duration| duration.as_millis().min(i32::MAX as u128) as i32;
+        keyboard
+            .write(EventType::REPEAT.0, REP_DELAY, millis(repeat.delay))
+            .and_then(|()| keyboard.write(EventType::REPEAT.0, REP_PERIOD, millis(repeat.period)))
+            .and_then(|()| keyboard.write(EventType::SYNCHRONIZATION.0, 0, 0))
+            .map_err(OutputError::Repeat)?;
+        keyboard.kernel_repeat = true;
+        info!("Key repeat on the virtual keyboard: {:?} delay, every {:?}", repeat.delay, repeat.period);
+    }
+    Ok(keyboard)
+}
+
+impl VirtualKeyboard {
+    /// Another handle on the device, for reading the events sent to it.
+    pub fn try_clone_file(&self) -> std::io::Result<File> {
+        self.file.try_clone()
+    }
+
+    fn write(&mut self, kind: u16, code: u16, value: i32) -> std::io::Result<()> {
+        // The kernel stamps the event with the time it arrives.
+        let event = libc::input_event {
+            time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            type_: kind,
+            code,
+            value,
+        };
+        self.file.write_all(as_bytes(std::slice::from_ref(&event)))
+    }
+}
+
+/// The bytes of `events`, as written to uinput.
+fn as_bytes(events: &[libc::input_event]) -> &[u8] {
+    // SAFETY: input_event is plain old data with no padding.
+    unsafe { std::slice::from_raw_parts(events.as_ptr().cast(), size_of_val(events)) }
+}
+
+impl Drop for VirtualKeyboard {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is still open; closing it would remove the device too.
+        let _ = unsafe { ui_dev_destroy(self.file.as_raw_fd()) };
+    }
+}
+
+/// Where the writer thread sends remapped events.
+pub enum OutputDevice {
+    Uinput(VirtualKeyboard),
+    #[cfg(feature = "portal")]
+    Portal(crate::portal::RemoteDesktop),
+}
+
+impl EventWriter for OutputDevice {
+    type Error = String;
+
+    fn write_event(&mut self, event: OutputEvent) -> Result<(), Self::Error> {
+        match self {
+            OutputDevice::Uinput(device) => device.write_event(event).map_err(|e| e.to_string()),
+            #[cfg(feature = "portal")]
+            OutputDevice::Portal(session) => session.write_event(event).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn write_frame(&mut self, frame: &[OutputEvent]) -> Result<(), Self::Error> {
+        match self {
+            OutputDevice::Uinput(device) => device.write_frame(frame).map_err(|e| e.to_string()),
+            #[cfg(feature = "portal")]
+            OutputDevice::Portal(session) => session.write_frame(frame).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// An event for the virtual keyboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputEvent {
+    /// A key press (1), release (0) or repeat (2).
+    Key { code: u16, value: i32 },
+    /// SYN_REPORT, which ends a frame.
+    Syn,
+    /// Anything else passed through from a keyboard, e.g. a scancode.
+    Other { kind: u16, code: u16, value: i32 },
+}
+
+impl OutputEvent {
+    pub fn new(kind: EventType, code: u16, value: i32) -> Self {
+        match kind {
+            EventType::KEY => OutputEvent::Key { code, value },
+            EventType::SYNCHRONIZATION if code == 0 => OutputEvent::Syn,
+            _ => OutputEvent::Other { kind: kind.0, code, value },
+        }
+    }
+
+    /// The event's type, code and value, as written to the device.
+    pub fn raw(self) -> (u16, u16, i32) {
+        match self {
+            OutputEvent::Key { code, value } => (EventType::KEY.0, code, value),
+            OutputEvent::Syn => (EventType::SYNCHRONIZATION.0, 0, 0),
+            OutputEvent::Other { kind, code, value } => (kind, code, value),
+        }
+    }
+
+    /// Whether the frame holding the event must never be dropped: a key press or release
+    /// left out would leave a key stuck. Frames of repeats and other events can be dropped
+    /// under load, whole, so none reaches the virtual keyboard torn.
+    pub fn is_critical(self) -> bool {
+        matches!(self, OutputEvent::Key { value: 0 | 1, .. })
+    }
+
+    /// What the event counts as when dropped. Only SYN_REPORTs, which are dropped with the
+    /// frame they end, aren't counted.
+    pub fn drop_class(self) -> Option<DropClass> {
+        match self {
+            OutputEvent::Key { .. } => Some(DropClass::Repeat),
+            OutputEvent::Syn => None,
+            OutputEvent::Other { kind, .. } if kind == EventType::MISC.0 => Some(DropClass::Misc),
+            OutputEvent::Other { .. } => Some(DropClass::Other),
+        }
+    }
+}
+
+/// An event queued for the uinput writer thread, as part of a frame.
+pub struct QueuedEvent {
+    pub event: OutputEvent,
+    /// Pipeline spans for key events, finished by the writer once the event is written.
+    #[cfg(feature = "otel")]
+    pub trace: Option<crate::telemetry::EventTrace>,
+}
+
+impl From<OutputEvent> for QueuedEvent {
+    fn from(event: OutputEvent) -> Self {
+        QueuedEvent {
+            event,
+            #[cfg(feature = "otel")]
+            trace: None,
+        }
+    }
+}
+
+/// A frame queued for the uinput writer thread, written whole.
+pub struct QueuedFrame {
+    pub events: Vec<QueuedEvent>,
+    /// The kernel's timestamp on the events, when they were read from a keyboard rather than
+    /// produced by a timer.
+    pub timestamp: Option<std::time::SystemTime>,
+    pub queued_at: std::time::Instant,
+}
+
+/// Destination for remapped events. Implemented by the uinput device; anything else that
+/// accepts [`OutputEvent`]s can stand in for it, e.g. to exercise the writer's failure
+/// handling with injected errors.
+pub trait EventWriter {
+    type Error: std::fmt::Display;
+
+    fn write_event(&mut self, event: OutputEvent) -> Result<(), Self::Error>;
+
+    /// Writes a frame (everything up to and including a SYN_REPORT). Writers that can should
+    /// write it in one go.
+    fn write_frame(&mut self, frame: &[OutputEvent]) -> Result<(), Self::Error> {
+        frame.iter().try_for_each(|&event| self.write_event(event))
+    }
+}
+
+impl EventWriter for VirtualKeyboard {
+    type Error = std::io::Error;
+
+    fn write_event(&mut self, event: OutputEvent) -> Result<(), Self::Error> {
+        if self.kernel_repeat && matches!(event, OutputEvent::Key { value: 2, .. }) {
+            return Ok(());
+        }
+        let (kind, code, value) = event.raw();
+        self.write(kind, code, value)
+    }
+
+    /// Writes the whole frame with one write, so the events arrive together.
+    fn write_frame(&mut self, frame: &[OutputEvent]) -> Result<(), Self::Error> {
+        let events: Vec<libc::input_event> = frame
+            .iter()
+            .filter(|event| !(self.kernel_repeat && matches!(event, OutputEvent::Key { value: 2, .. })))
+            .map(|event| {
+                let (type_, code, value) = event.raw();
+                libc::input_event { time: libc::timeval { tv_sec: 0, tv_usec: 0 }, type_, code, value }
+            })
+            .collect();
+        self.file.write_all(as_bytes(&events))
+    }
+}
+
+/// What the writer should do after a failed write.
+#[derive(Debug, PartialEq)]
+pub enum FailureAction {
+    /// Sleep for the backoff, then carry on with the next event.
+    Retry(std::time::Duration),
+    /// Too many consecutive failures; stop the writer (and the daemon).
+    GiveUp,
+}
+
+/// Consecutive-failure accounting and backoff for uinput writes.
+#[derive(Default)]
+pub struct FailurePolicy {
+    consecutive_failures: u32,
+}
+
+impl FailurePolicy {
+    pub fn on_failure(&mut self) -> FailureAction {
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+            return FailureAction::GiveUp;
+        }
+        // Continue trying with backoff - don't let temporary failures stop the writer
+        let backoff_ms = BACKOFF_BASE_MS * self.consecutive_failures.min(10);
+        FailureAction::Retry(std::time::Duration::from_millis(backoff_ms as u64))
+    }
+
+    /// Resets the failure count. Returns true if the writer was recovering from failures.
+    pub fn on_success(&mut self) -> bool {
+        std::mem::take(&mut self.consecutive_failures) > 0
+    }
+}
+
+/// Drains the keyboards' ring buffers into `writer`, a frame at a time, until they are closed,
+/// which happens once the event loop has stopped, so the writer sleeps until there is a frame
+/// to write or nothing more to do. Returns an error if writes keep failing. `sleep` is used for backoff so
+/// callers can substitute a fake clock.
+pub fn run_writer<W: EventWriter>(
+    writer: &mut W,
+    rx: &mut Consumer<QueuedFrame>,
+    counters: &Counters,
+    latency: Option<&Mutex<LatencyHistogram>>,
+    #[cfg(feature = "otel")] telemetry: Option<&crate::telemetry::Telemetry>,
+    mut sleep: impl FnMut(std::time::Duration),
+) -> Result<(), OutputError> {
+    let mut policy = FailurePolicy::default();
+    let mut events = Vec::new();
+
+    loop {
+        counters.writer_waiting.store(true, Ordering::Relaxed);
+        let received = rx.recv();
+        counters.writer_waiting.store(false, Ordering::Relaxed);
+        counters.writer_wakeups.fetch_add(1, Ordering::Relaxed);
+        match received {
+            Ok(frame) => {
+                events.clear();
+                events.extend(frame.events.iter().map(|queued| queued.event));
+                #[cfg(feature = "otel")]
+                let write_start = std::time::SystemTime::now();
+                let result = writer.write_frame(&events);
+                #[cfg(feature = "otel")]
+                if let Some(telemetry) = telemetry {
+                    let write_end = std::time::SystemTime::now();
+                    for trace in frame.events.into_iter().filter_map(|queued| queued.trace) {
+                        telemetry.written(trace, write_start, write_end, result.is_ok());
+                    }
+                }
+                match result {
+                    Err(e) => {
+                        counters.failures.fetch_add(1, Ordering::Relaxed);
+                        counters.write_failures_total.fetch_add(1, Ordering::Relaxed);
+                        let message = format!("Failed to write to uinput device: {e}");
+                        counters.record_error(&message);
+                        error!(kind = "output", "{message}");
+                        match policy.on_failure() {
+                            FailureAction::Retry(backoff) => sleep(backoff),
+                            FailureAction::GiveUp => {
+                                logging::flush();
+                                return Err(OutputError::TooManyFailures(policy.consecutive_failures));
+                            }
+                        }
+                    }
+                    Ok(()) => {
+                        if policy.on_success() {
+                            logging::flush();
+                        }
+                        counters.events_processed.fetch_add(events.len() as u64, Ordering::Relaxed);
+                        counters.processed_total.fetch_add(events.len() as u64, Ordering::Relaxed);
+                        if let (Some(latency), Some(timestamp)) = (latency, frame.timestamp) {
+                            latency.lock().unwrap().record(timestamp, frame.queued_at);
+                        }
+                    }
+                }
+            }
+            Err(mpsc::RecvError) => {
+                // The event loop is gone, and everything it queued has been written.
+                info!("Uinput writer thread exiting");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::ring::rings;
+
+    /// Fails or succeeds each frame as told, in order, then succeeds.
+    struct FlakyWriter {
+        failures: VecDeque<bool>,
+    }
+
+    impl EventWriter for FlakyWriter {
+        type Error = &'static str;
+
+        fn write_event(&mut self, _event: OutputEvent) -> Result<(), Self::Error> {
+            match self.failures.pop_front().unwrap_or(false) {
+                true => Err("injected failure"),
+                false => Ok(()),
+            }
+        }
+    }
+
+    /// Runs the writer over one single-event frame per entry in `failures`, returning its
+    /// result and every backoff it slept.
+    fn run(failures: Vec<bool>) -> (Result<(), OutputError>, Vec<Duration>) {
+        let (producers, mut rx) = rings(failures.len());
+        let tx = producers.add();
+        drop(producers);
+        for _ in &failures {
+            let events = vec![OutputEvent::Key { code: 30, value: 1 }.into()];
+            tx.try_send(QueuedFrame { events, timestamp: None, queued_at: Instant::now() }).unwrap();
+        }
+        drop(tx);
+        let mut writer = FlakyWriter { failures: failures.into() };
+        let mut slept = Vec::new();
+        let result = run_writer(
+            &mut writer,
+            &mut rx,
+            &Counters::default(),
+            None,
+            #[cfg(feature = "otel")]
+            None,
+            |backoff| slept.push(backoff),
+        );
+        (result, slept)
+    }
+
+    fn millis(values: impl IntoIterator<Item = u64>) -> Vec<Duration> {
+        values.into_iter().map(Duration::from_millis).collect()
+    }
+
+    #[test]
+    fn backoff_grows_by_ten_milliseconds_up_to_a_hundred() {
+        let mut policy = FailurePolicy::default();
+        let backoffs: Vec<FailureAction> = (0..12).map(|_| policy.on_failure()).collect();
+        let expected: Vec<FailureAction> = millis([10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 100, 100])
+            .into_iter()
+            .map(FailureAction::Retry)
+            .collect();
+        assert_eq!(backoffs, expected);
+    }
+
+    #[test]
+    fn policy_gives_up_on_the_hundredth_failure_in_a_row() {
+        let mut policy = FailurePolicy::default();
+        for _ in 1..MAX_CONSECUTIVE_FAILURES {
+            assert!(matches!(policy.on_failure(), FailureAction::Retry(_)));
+        }
+        assert_eq!(policy.on_failure(), FailureAction::GiveUp);
+    }
+
+    #[test]
+    fn success_resets_the_policy() {
+        let mut policy = FailurePolicy::default();
+        assert!(!policy.on_success());
+        policy.on_failure();
+        policy.on_failure();
+        assert!(policy.on_success());
+        assert_eq!(policy.on_failure(), FailureAction::Retry(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn writer_gives_up_after_a_hundred_failures() {
+        let (result, slept) = run(vec![true; 100]);
+        assert!(matches!(result, Err(OutputError::TooManyFailures(100))));
+        assert_eq!(slept.len(), 99);
+        assert_eq!(slept[..3], millis([10, 20, 30]));
+        assert!(slept[9..].iter().all(|&backoff| backoff == Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn writer_starts_over_after_a_success() {
+        let mut failures = vec![true; 99];
+        failures.push(false);
+        failures.extend([true; 99]);
+        let (result, slept) = run(failures);
+        assert!(result.is_ok());
+        assert_eq!(slept.len(), 198);
+        assert_eq!(slept[99..102], millis([10, 20, 30]));
+    }
+
+    #[test]
+    fn writer_stops_cleanly_once_the_ring_closes() {
+        let (result, slept) = run(vec![false, true, false]);
+        assert!(result.is_ok());
+        assert_eq!(slept, millis([10]));
+    }
+}
@@ -0,0 +1,172 @@
+//! Key event processing as an ordered chain of stages.
+//!
+//! Each key event read from a keyboard runs through the stages in order, and whatever comes
+//! out of the last stage is written to the virtual keyboard. A stage may pass an event on
+//! unchanged, rewrite it, swallow it, or turn it into several events. The intended order is
+//!
+//! ```text
+//! debounce → modmap (swaps, combos, tap-hold, mirror, sticky keys) → layers → layout → macros → repeat → output
+//! ```
+//!
+//! so a new feature is a new stage slotted in at the right point rather than another arm in
+//! one big match. Output is handled by the event loop once the chain has run.
+//!
+//! Stages that act on time as well as on events (debounce, tap-hold, autorepeat) report a
+//! [`Stage::deadline`]; the event loop wakes up by then and calls [`Pipeline::tick`].
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::combo::Combos;
+use crate::config::Config;
+use crate::debounce::Debounce;
+use crate::layout::{ActiveLayout, Layout};
+use crate::macros::Macros;
+use crate::mirror::Mirror;
+use crate::remap::{LayoutStage, ModifierState, RemapRule, ShortcutLayer, Swaps};
+use crate::repeat::Autorepeat;
+use crate::sticky::StickyKeys;
+use crate::taphold::TapHold;
+
+/// A key press (1), release (0) or autorepeat (2) moving through the pipeline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub code: u16,
+    pub value: i32,
+    /// The rule that decided `code`, once a stage has decided it. Reported by the explain trace.
+    pub rule: Option<RemapRule>,
+    /// Pressed while AltGr was held, so the layout stage maps it through the layout's AltGr table.
+    pub altgr: bool,
+    /// Turned into another key by `swaps`, `[modmap]` or `caps_lock` before the layout saw it.
+    /// Reported by the explain trace alongside `rule`, which later stages still decide.
+    pub swapped: bool,
+}
+
+impl KeyEvent {
+    pub fn new(code: u16, value: i32) -> Self {
+        KeyEvent { code, value, rule: None, altgr: false, swapped: false }
+    }
+}
+
+/// One step of the pipeline.
+pub trait Stage: Send {
+    /// Handles one event, pushing whatever should reach the next stage onto `out`.
+    fn process(&mut self, event: KeyEvent, out: &mut Vec<KeyEvent>);
+
+    /// When the stage next needs [`tick`](Stage::tick) to be called, if ever.
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Acts on any timers due at `now`, pushing events for the next stage onto `out`.
+    fn tick(&mut self, _now: Instant, _out: &mut Vec<KeyEvent>) {}
+}
+
+/// The stages for one keyboard, run in order. Each keyboard gets its own pipeline so stage
+/// state (held keys, tap-hold timers and so on) isn't shared between devices; only the
+/// shortcut modifiers are, through [`ModifierState`].
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+    current: Vec<KeyEvent>,
+    next: Vec<KeyEvent>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Self {
+        Pipeline {
+            stages,
+            current: Vec::new(),
+            next: Vec::new(),
+        }
+    }
+
+    /// The standard chain: shortcut layer, then `layout`.
+    pub fn with_layout(layout: Arc<dyn Layout>) -> Self {
+        let layout = Arc::new(ActiveLayout::new(layout, Vec::new()));
+        Pipeline::new(vec![Box::new(ShortcutLayer::default()), Box::new(LayoutStage::new(layout))])
+    }
+
+    /// The standard chain for `config`: debouncing, key swaps and remaps, combos, tap-hold
+    /// overloads and the mirror key (if any are configured), sticky keys, the shortcut and AltGr
+    /// layers, the active layout, text macros and snippets (if any), then autorepeat if the daemon
+    /// repeats keys itself. `modifiers` is shared by every keyboard's pipeline.
+    pub fn for_config(config: &Config, layout: Arc<ActiveLayout>, modifiers: Arc<ModifierState>) -> Self {
+        let mut stages: Vec<Box<dyn Stage>> = Vec::new();
+        if !config.debounce.is_zero() || !config.debounce_keys.is_empty() {
+            stages.push(Box::new(Debounce::new(config.debounce, &config.debounce_keys)));
+        }
+        if !config.swaps.is_empty() || !config.remaps.is_empty() {
+            stages.push(Box::new(Swaps::new(&config.swaps).with_remaps(&config.remaps)));
+        }
+        if !config.combos.is_empty() {
+            stages.push(Box::new(Combos::new(config.combos.clone(), config.combo_term)));
+        }
+        if !config.overloads.is_empty() {
+            stages.push(Box::new(TapHold::new(config.overloads.clone(), config.tapping_term)));
+        }
+        if let Some(key) = config.mirror_key {
+            stages.push(Box::new(Mirror::new(key, config.tapping_term)));
+        }
+        stages.push(Box::new(StickyKeys::new(modifiers.clone())));
+        stages.push(Box::new(ShortcutLayer::new(modifiers, &config.shortcut_modifiers).with_altgr(config.altgr)));
+        stages.push(Box::new(LayoutStage::new(layout)));
+        if !config.macros.is_empty() || !config.snippets.is_empty() {
+            stages.push(Box::new(Macros::new(config.macros.clone()).with_snippets(config.snippets.clone())));
+        }
+        if config.daemon_repeat {
+            stages.push(Box::new(Autorepeat::new(config.key_repeat.unwrap_or_default())));
+        }
+        Pipeline::new(stages)
+    }
+
+    /// Runs `event` through every stage and returns the events to output.
+    pub fn process(&mut self, event: KeyEvent) -> &[KeyEvent] {
+        self.current.clear();
+        self.current.push(event);
+        self.run(None)
+    }
+
+    /// The earliest deadline of any stage.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.stages.iter().filter_map(|stage| stage.deadline()).min()
+    }
+
+    /// Fires the timers due at `now` and returns the events to output. Events a stage emits
+    /// from its timer run through the stages after it.
+    pub fn tick(&mut self, now: Instant) -> &[KeyEvent] {
+        self.current.clear();
+        self.run(Some(now))
+    }
+
+    fn run(&mut self, now: Option<Instant>) -> &[KeyEvent] {
+        for stage in &mut self.stages {
+            self.next.clear();
+            for event in self.current.drain(..) {
+                stage.process(event, &mut self.next);
+            }
+            if let Some(now) = now {
+                stage.tick(now, &mut self.next);
+            }
+            std::mem::swap(&mut self.current, &mut self.next);
+        }
+        &self.current
+    }
+}
+
+/// Runs `events` through `stage` on its own, returning what it passes on as (code, value) pairs.
+#[cfg(test)]
+pub(crate) fn run(stage: &mut impl Stage, events: &[(u16, i32)]) -> Vec<(u16, i32)> {
+    let mut out = Vec::new();
+    for &(code, value) in events {
+        stage.process(KeyEvent::new(code, value), &mut out);
+    }
+    out.iter().map(|event| (event.code, event.value)).collect()
+}
+
+/// Fires the timers of `stage` due at `now`, returning what it passes on as (code, value) pairs.
+#[cfg(test)]
+pub(crate) fn tick(stage: &mut impl Stage, now: Instant) -> Vec<(u16, i32)> {
+    let mut out = Vec::new();
+    stage.tick(now, &mut out);
+    out.iter().map(|event| (event.code, event.value)).collect()
+}
@@ -0,0 +1,259 @@
+//! Capture and injection through the XDG desktop portals, for running without access to
+//! /dev/input or /dev/uinput (e.g. in a Flatpak sandbox).
+//!
+//! Keys are captured with the InputCapture portal, which delivers them over an EIS socket
+//! (see [`crate::ei`]), and remapped keys are injected with the RemoteDesktop portal's
+//! `NotifyKeyboardKeycode`. The compositor only starts a capture when the pointer is pushed
+//! against the barrier we place along the top edge of the first screen, and ends it with its
+//! own release shortcut; outside a capture, keys reach applications unremapped.
+
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::channel::Sender;
+use dbus::message::MatchRule;
+use dbus::{Message, Path};
+use evdev::InputEvent;
+use tracing::info;
+
+use crate::ei::EiKeyboard;
+use crate::error::PortalError;
+use crate::output::{EventWriter, OutputEvent};
+use crate::source::KeyboardSource;
+
+// Portal addressing
+const PORTAL_BUS_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_OBJECT_PATH: &str = "/org/freedesktop/portal/desktop";
+const INPUT_CAPTURE: &str = "org.freedesktop.portal.InputCapture";
+const REMOTE_DESKTOP: &str = "org.freedesktop.portal.RemoteDesktop";
+
+// Timing
+// METHOD_TIMEOUT: How long a portal method call may take to return.
+// RESPONSE_TIMEOUT: How long to wait for the user to answer a portal dialog.
+const METHOD_TIMEOUT: Duration = Duration::from_secs(10);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Device and capability bitmasks; both portals use 1 for the keyboard.
+const KEYBOARD: u32 = 1;
+// Request.Response codes.
+const RESPONSE_CANCELLED: u32 = 1;
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value))
+}
+
+fn bus_error(e: dbus::Error) -> PortalError {
+    PortalError::Bus(e.to_string())
+}
+
+/// A session bus connection with the portal Request/Response handshake.
+struct Portal {
+    connection: Connection,
+    next_token: u32,
+}
+
+impl Portal {
+    fn connect() -> Result<Self, PortalError> {
+        Ok(Portal { connection: Connection::new_session().map_err(bus_error)?, next_token: 0 })
+    }
+
+    fn method(&self, interface: &str, method: &str) -> Message {
+        Message::new_method_call(PORTAL_BUS_NAME, PORTAL_OBJECT_PATH, interface, method)
+            .expect("portal names are valid")
+    }
+
+    fn call(&self, message: Message) -> Result<Message, PortalError> {
+        self.connection
+            .channel()
+            .send_with_reply_and_block(message, METHOD_TIMEOUT)
+            .map_err(bus_error)
+    }
+
+    /// Calls a portal method that answers through a Request object and waits for its
+    /// results. `build` appends the arguments, given the options with `handle_token` set.
+    fn request(
+        &mut self,
+        interface: &str,
+        method: &'static str,
+        mut options: PropMap,
+        build: impl FnOnce(Message, PropMap) -> Message,
+    ) -> Result<PropMap, PortalError> {
+        self.next_token += 1;
+        let token = format!("qwertdvert{}", self.next_token);
+        // The Request object path is predictable, so subscribe before calling to not miss
+        // a fast response.
+        let sender = self.connection.unique_name().trim_start_matches(':').replace('.', "_");
+        let request_path = format!("{PORTAL_OBJECT_PATH}/request/{sender}/{token}");
+        let rule = MatchRule::new_signal("org.freedesktop.portal.Request", "Response")
+            .with_path(Path::new(request_path).expect("request path is valid"));
+        let response: Arc<Mutex<Option<(u32, PropMap)>>> = Arc::default();
+        let slot = response.clone();
+        let match_token = self
+            .connection
+            .add_match(rule, move |result: (u32, PropMap), _, _| {
+                *slot.lock().unwrap() = Some(result);
+                false
+            })
+            .map_err(bus_error)?;
+
+        options.insert("handle_token".to_string(), variant(token));
+        let message = build(self.method(interface, method), options);
+        let called = self.call(message);
+        let deadline = std::time::Instant::now() + RESPONSE_TIMEOUT;
+        let result = called.and_then(|_| loop {
+            if let Some(result) = response.lock().unwrap().take() {
+                break Ok(result);
+            }
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() {
+                break Err(PortalError::Failed { method, code: u32::MAX });
+            }
+            self.connection.process(remaining).map_err(bus_error)?;
+        });
+        let _ = self.connection.remove_match(match_token);
+
+        match result? {
+            (0, results) => Ok(results),
+            (RESPONSE_CANCELLED, _) => Err(PortalError::Cancelled(method)),
+            (code, _) => Err(PortalError::Failed { method, code }),
+        }
+    }
+
+    /// Creates a session with `method` and returns its object path.
+    fn create_session(
+        &mut self,
+        interface: &str,
+        method: &'static str,
+        options: PropMap,
+        build: impl FnOnce(Message, PropMap) -> Message,
+    ) -> Result<Path<'static>, PortalError> {
+        let mut options = options;
+        options.insert("session_handle_token".to_string(), variant("qwertdvert".to_string()));
+        let results = self.request(interface, method, options, build)?;
+        results
+            .get("session_handle")
+            .and_then(|handle| handle.0.as_str())
+            .and_then(|handle| Path::new(handle.to_string()).ok())
+            .ok_or(PortalError::Missing { method, field: "session_handle" })
+    }
+}
+
+/// Keys captured by an InputCapture session. The session lives as long as the portal
+/// connection, so the keyboard keeps it.
+pub struct PortalKeyboard {
+    keyboard: EiKeyboard,
+    _portal: Portal,
+}
+
+impl AsRawFd for PortalKeyboard {
+    fn as_raw_fd(&self) -> RawFd {
+        self.keyboard.as_raw_fd()
+    }
+}
+
+impl KeyboardSource for PortalKeyboard {
+    fn name(&self) -> String {
+        self.keyboard.name()
+    }
+
+    fn grab(&mut self) -> io::Result<()> {
+        self.keyboard.grab()
+    }
+
+    fn fetch_events(&mut self, out: &mut Vec<InputEvent>) -> io::Result<()> {
+        self.keyboard.fetch_events(out)
+    }
+}
+
+/// Starts an InputCapture session and connects to its EIS socket.
+pub fn capture_keyboard() -> Result<PortalKeyboard, PortalError> {
+    let mut portal = Portal::connect()?;
+    let mut options = PropMap::new();
+    options.insert("capabilities".to_string(), variant(KEYBOARD));
+    let session = portal.create_session(INPUT_CAPTURE, "CreateSession", options, |message, options| {
+        message.append2("", options)
+    })?;
+
+    let zones = portal.request(INPUT_CAPTURE, "GetZones", PropMap::new(), |message, options| {
+        message.append2(&session, options)
+    })?;
+    let zone_set = zones
+        .get("zone_set")
+        .and_then(|zone_set| zone_set.0.as_u64())
+        .ok_or(PortalError::Missing { method: "GetZones", field: "zone_set" })? as u32;
+    // Each zone is (width, height, x, y); the barrier runs along the top of the first one.
+    let zone: Vec<i64> = zones
+        .get("zones")
+        .and_then(|zones| zones.0.as_iter()?.next())
+        .and_then(|zone| zone.as_iter())
+        .map(|fields| fields.filter_map(|field| field.as_i64()).collect())
+        .filter(|fields: &Vec<i64>| fields.len() == 4)
+        .ok_or(PortalError::Missing { method: "GetZones", field: "zones" })?;
+    let (width, x, y) = (zone[0] as i32, zone[2] as i32, zone[3] as i32);
+    let mut barrier = PropMap::new();
+    barrier.insert("barrier_id".to_string(), variant(1u32));
+    barrier.insert("position".to_string(), variant((x, y, x + width - 1, y)));
+    portal.request(INPUT_CAPTURE, "SetPointerBarriers", PropMap::new(), |message, options| {
+        message.append3(&session, options, vec![barrier]).append1(zone_set)
+    })?;
+
+    portal.call(portal.method(INPUT_CAPTURE, "Enable").append2(&session, PropMap::new()))?;
+    let fd: OwnedFd = portal
+        .call(portal.method(INPUT_CAPTURE, "ConnectToEIS").append2(&session, PropMap::new()))?
+        .read1()
+        .map_err(|e| PortalError::Bus(e.to_string()))?;
+    info!("Input capture enabled; push the pointer against the top of the screen to start capturing");
+    Ok(PortalKeyboard { keyboard: EiKeyboard::new("Portal input capture".to_string(), fd), _portal: portal })
+}
+
+/// Injects keys through a RemoteDesktop session.
+pub struct RemoteDesktop {
+    portal: Portal,
+    session: Path<'static>,
+}
+
+impl RemoteDesktop {
+    /// Starts a RemoteDesktop session for keyboard input, asking the user to allow it.
+    pub fn connect() -> Result<Self, PortalError> {
+        let mut portal = Portal::connect()?;
+        let session = portal.create_session(REMOTE_DESKTOP, "CreateSession", PropMap::new(), |message, options| {
+            message.append1(options)
+        })?;
+        let mut options = PropMap::new();
+        options.insert("types".to_string(), variant(KEYBOARD));
+        portal.request(REMOTE_DESKTOP, "SelectDevices", options, |message, options| {
+            message.append2(&session, options)
+        })?;
+        portal.request(REMOTE_DESKTOP, "Start", PropMap::new(), |message, options| {
+            message.append3(&session, "", options)
+        })?;
+        Ok(RemoteDesktop { portal, session })
+    }
+}
+
+impl EventWriter for RemoteDesktop {
+    type Error = PortalError;
+
+    fn write_event(&mut self, event: OutputEvent) -> Result<(), Self::Error> {
+        // The compositor synthesises its own frames and key repeat.
+        let OutputEvent::Key { code, value: value @ (0 | 1) } = event else {
+            return Ok(());
+        };
+        let mut message = self
+            .portal
+            .method(REMOTE_DESKTOP, "NotifyKeyboardKeycode")
+            .append3(&self.session, PropMap::new(), code as i32)
+            .append1(value as u32);
+        message.set_no_reply(true);
+        self.portal
+            .connection
+            .send(message)
+            .map_err(|()| PortalError::Bus("failed to queue NotifyKeyboardKeycode".to_string()))?;
+        self.portal.connection.channel().flush();
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! `qwertdvert record-layout`: build a layout file by pressing, for each character in turn,
+//! the key that should type it.
+//!
+//! The keyboards are grabbed while recording, so the presses don't reach the terminal. The
+//! daemon must not be running, as it holds the grab itself.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::path::PathBuf;
+
+use evdev::{Device, EventType, Key};
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
+
+use crate::enumeration::{find_keyboards, DeviceFilter};
+use crate::error::{ConfigError, DaemonError, DeviceError};
+use crate::layout::{file_path, key_name, save_file};
+
+/// The keys to record, as (key as on US QWERTY, what it types), row by row.
+const TARGETS: &[(Key, &str)] = &[
+    (Key::KEY_GRAVE, "`"),
+    (Key::KEY_1, "1"),
+    (Key::KEY_2, "2"),
+    (Key::KEY_3, "3"),
+    (Key::KEY_4, "4"),
+    (Key::KEY_5, "5"),
+    (Key::KEY_6, "6"),
+    (Key::KEY_7, "7"),
+    (Key::KEY_8, "8"),
+    (Key::KEY_9, "9"),
+    (Key::KEY_0, "0"),
+    (Key::KEY_MINUS, "-"),
+    (Key::KEY_EQUAL, "="),
+    (Key::KEY_Q, "q"),
+    (Key::KEY_W, "w"),
+    (Key::KEY_E, "e"),
+    (Key::KEY_R, "r"),
+    (Key::KEY_T, "t"),
+    (Key::KEY_Y, "y"),
+    (Key::KEY_U, "u"),
+    (Key::KEY_I, "i"),
+    (Key::KEY_O, "o"),
+    (Key::KEY_P, "p"),
+    (Key::KEY_LEFTBRACE, "["),
+    (Key::KEY_RIGHTBRACE, "]"),
+    (Key::KEY_BACKSLASH, "\\"),
+    (Key::KEY_A, "a"),
+    (Key::KEY_S, "s"),
+    (Key::KEY_D, "d"),
+    (Key::KEY_F, "f"),
+    (Key::KEY_G, "g"),
+    (Key::KEY_H, "h"),
+    (Key::KEY_J, "j"),
+    (Key::KEY_K, "k"),
+    (Key::KEY_L, "l"),
+    (Key::KEY_SEMICOLON, ";"),
+    (Key::KEY_APOSTROPHE, "'"),
+    (Key::KEY_Z, "z"),
+    (Key::KEY_X, "x"),
+    (Key::KEY_C, "c"),
+    (Key::KEY_V, "v"),
+    (Key::KEY_B, "b"),
+    (Key::KEY_N, "n"),
+    (Key::KEY_M, "m"),
+    (Key::KEY_COMMA, ","),
+    (Key::KEY_DOT, "."),
+    (Key::KEY_SLASH, "/"),
+];
+
+// Keys with a meaning of their own while recording.
+// SKIP: leave the current character where it is. FINISH: save what has been recorded so far.
+const SKIP: Key = Key::KEY_ENTER;
+const FINISH: Key = Key::KEY_ESC;
+
+/// Prompts on stdout for every key in [`TARGETS`], pressed on the keyboards `devices` selects,
+/// and writes the layout file for `name`. Returns where it was written.
+pub fn record_layout(name: &str, devices: &DeviceFilter) -> Result<PathBuf, DaemonError> {
+    // Fail before grabbing anything if there is nowhere to save the layout.
+    file_path(name).ok_or(ConfigError::InvalidValue {
+        flag: "record-layout",
+        reason: "neither XDG_CONFIG_HOME nor HOME is set".to_string(),
+    })?;
+    let mut keyboards = find_keyboards(devices)?;
+    let epoll_error = |source| DeviceError::Epoll { device: "record-layout".to_string(), source };
+    let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC).map_err(epoll_error)?;
+    for (index, keyboard) in keyboards.iter_mut().enumerate() {
+        let device = keyboard.name().unwrap_or("Unknown").to_string();
+        keyboard.grab().map_err(|source| DeviceError::Grab { device, source })?;
+        let fd = unsafe { BorrowedFd::borrow_raw(keyboard.as_raw_fd()) };
+        epoll.add(fd, EpollEvent::new(EpollFlags::EPOLLIN, index as u64)).map_err(epoll_error)?;
+    }
+
+    println!("Recording layout '{name}'. For each character, press the key you want to type it.");
+    println!("Enter leaves a character on its usual key; Esc saves what you have recorded so far.");
+    // Physical key -> the key it should type.
+    let mut keys: HashMap<Key, Key> = HashMap::new();
+    'targets: for &(target, label) in TARGETS {
+        print!("Press the key that should type '{label}': ");
+        let _ = std::io::stdout().flush();
+        loop {
+            let pressed = next_press(&epoll, &mut keyboards)?;
+            if pressed == FINISH {
+                println!();
+                break 'targets;
+            }
+            if pressed == SKIP {
+                println!("(skipped)");
+                break;
+            }
+            if let Some((_, other)) = TARGETS.iter().find(|(key, _)| keys.get(&pressed) == Some(key)) {
+                print!("\nThat key already types '{other}'; press another: ");
+                let _ = std::io::stdout().flush();
+                continue;
+            }
+            println!("{}", key_name(pressed));
+            keys.insert(pressed, target);
+            break;
+        }
+    }
+
+    let mut text = format!(
+        "# Recorded by `qwertdvert record-layout {name}`. Each physical key = the key it types,\n\
+         # as on a US QWERTY layout. Keys not listed are left alone.\n[keys]\n"
+    );
+    for &(physical, _) in TARGETS {
+        if let Some(&target) = keys.get(&physical).filter(|&&target| target != physical) {
+            text.push_str(&format!("{} = \"{}\"\n", key_name(physical), key_name(target)));
+        }
+    }
+    Ok(save_file(name, &text, "record-layout")?)
+}
+
+/// Waits for the next key press on any keyboard.
+fn next_press(epoll: &Epoll, keyboards: &mut [Device]) -> Result<Key, DeviceError> {
+    let mut ready = [EpollEvent::empty(); 4];
+    loop {
+        let count = epoll
+            .wait(&mut ready, EpollTimeout::NONE)
+            .map_err(|source| DeviceError::Epoll { device: "record-layout".to_string(), source })?;
+        for event in &ready[..count] {
+            let keyboard = &mut keyboards[event.data() as usize];
+            let device = keyboard.name().unwrap_or("Unknown").to_string();
+            let events = keyboard.fetch_events().map_err(|source| DeviceError::Read { device, source })?;
+            let press = events
+                .filter(|event| event.event_type() == EventType::KEY && event.value() == 1)
+                .map(|event| Key::new(event.code()))
+                .last();
+            if let Some(key) = press {
+                return Ok(key);
+            }
+        }
+    }
+}
@@ -0,0 +1,457 @@
+//! The modifier-aware rules for when the layout applies, and the explain trace.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use evdev::Key;
+use tracing::info;
+
+use crate::layout::{Action, ActiveLayout};
+use crate::pipeline::{KeyEvent, Stage};
+
+/// Shortcut modifier keys (Ctrl/Alt/Super unless configured otherwise) held down, counted
+/// over every keyboard, so holding Ctrl on one keyboard and pressing a letter on another
+/// still types the QWERTY shortcut. Each keyboard's [`ShortcutLayer`] adds the modifiers it
+/// presses and takes away those it releases. AltGr is counted the same way, separately.
+/// Whether sticky keys are on is kept here too, for every keyboard's [`StickyKeys`] stage.
+///
+/// [`StickyKeys`]: crate::sticky::StickyKeys
+#[derive(Default)]
+pub struct ModifierState {
+    held: AtomicU32,
+    altgr: AtomicU32,
+    sticky_keys: AtomicBool,
+}
+
+impl ModifierState {
+    pub fn shortcut_held(&self) -> bool {
+        self.held.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn altgr_held(&self) -> bool {
+        self.altgr.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn sticky_keys(&self) -> bool {
+        self.sticky_keys.load(Ordering::Relaxed)
+    }
+
+    /// Turns sticky keys on or off, returning whether they were on. Modifiers latched at the
+    /// time are released with each keyboard's next key event.
+    pub fn set_sticky_keys(&self, enabled: bool) -> bool {
+        self.sticky_keys.swap(enabled, Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Display for ModifierState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} shortcut modifier presses held, {} AltGr presses held, sticky keys {}",
+            self.held.load(Ordering::Relaxed),
+            self.altgr.load(Ordering::Relaxed),
+            if self.sticky_keys() { "on" } else { "off" }
+        )
+    }
+}
+
+/// The modifiers that keep keys on the QWERTY layer while held, unless the config says otherwise.
+pub const SHORTCUT_MODIFIERS: [Key; 6] = [
+    Key::KEY_LEFTCTRL,
+    Key::KEY_RIGHTCTRL,
+    Key::KEY_LEFTALT,
+    Key::KEY_RIGHTALT,
+    Key::KEY_LEFTMETA,
+    Key::KEY_RIGHTMETA,
+];
+
+/// Modmap stage: swaps physical keys in pairs, and turns some into others one way round,
+/// before anything else sees them, so a swapped or remapped Caps Lock acts as Ctrl for
+/// shortcuts and the layout alike.
+pub struct Swaps {
+    swaps: HashMap<u16, u16>,
+}
+
+impl Swaps {
+    pub fn new(pairs: &[(Key, Key)]) -> Self {
+        let swaps = pairs
+            .iter()
+            .flat_map(|&(a, b)| [(a.code(), b.code()), (b.code(), a.code())])
+            .collect();
+        Swaps { swaps }
+    }
+
+    /// Also turns each `from` key into its `to` key, without the reverse.
+    pub fn with_remaps(mut self, remaps: &[(Key, Key)]) -> Self {
+        self.swaps.extend(remaps.iter().map(|&(from, to)| (from.code(), to.code())));
+        self
+    }
+}
+
+impl Stage for Swaps {
+    fn process(&mut self, mut event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        if let Some(&code) = self.swaps.get(&event.code) {
+            event.code = code;
+            event.swapped = true;
+        }
+        out.push(event);
+    }
+}
+
+/// Layers stage: while a shortcut modifier (Ctrl/Alt/Super by default) is held, on this
+/// keyboard or another sharing the same [`ModifierState`], keys stay on the QWERTY layer so
+/// shortcuts keep their physical positions. Such events are marked so the layout stage leaves
+/// them alone.
+///
+/// The AltGr key (ISO_Level3_Shift), if one is configured, is never a shortcut modifier: while
+/// it is held, events are marked for the layout's AltGr table instead.
+pub struct ShortcutLayer {
+    modifiers: Arc<ModifierState>,
+    /// Codes of the keys that count as shortcut modifiers.
+    shortcut_keys: BTreeSet<u16>,
+    /// Shortcut modifiers this keyboard holds down, by code.
+    held: BTreeSet<u16>,
+    /// Code of the AltGr key, and whether this keyboard holds it down.
+    altgr: Option<u16>,
+    altgr_down: bool,
+}
+
+impl ShortcutLayer {
+    pub fn new(modifiers: Arc<ModifierState>, shortcut_keys: &[Key]) -> Self {
+        let shortcut_keys = shortcut_keys.iter().map(|key| key.code()).collect();
+        ShortcutLayer { modifiers, shortcut_keys, held: BTreeSet::new(), altgr: None, altgr_down: false }
+    }
+
+    /// Treats `key` as AltGr rather than as a shortcut modifier.
+    pub fn with_altgr(mut self, key: Option<Key>) -> Self {
+        self.altgr = key.map(|key| key.code());
+        if let Some(code) = self.altgr {
+            self.shortcut_keys.remove(&code);
+        }
+        self
+    }
+}
+
+impl Default for ShortcutLayer {
+    fn default() -> Self {
+        ShortcutLayer::new(Arc::default(), &SHORTCUT_MODIFIERS)
+    }
+}
+
+impl Stage for ShortcutLayer {
+    fn process(&mut self, mut event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        if self.shortcut_keys.contains(&event.code) {
+            match event.value {
+                1 if self.held.insert(event.code) => {
+                    self.modifiers.held.fetch_add(1, Ordering::Relaxed);
+                }
+                0 if self.held.remove(&event.code) => {
+                    self.modifiers.held.fetch_sub(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+        }
+        if self.altgr == Some(event.code) {
+            match event.value {
+                1 if !self.altgr_down => {
+                    self.altgr_down = true;
+                    self.modifiers.altgr.fetch_add(1, Ordering::Relaxed);
+                }
+                0 if self.altgr_down => {
+                    self.altgr_down = false;
+                    self.modifiers.altgr.fetch_sub(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+        } else if self.modifiers.altgr_held() {
+            event.altgr = true;
+        }
+        if self.modifiers.shortcut_held() {
+            event.rule = Some(RemapRule::ModifierPassthrough);
+        }
+        out.push(event);
+    }
+}
+
+impl Drop for ShortcutLayer {
+    /// A keyboard unplugged, or a pipeline replaced, with modifiers down no longer holds them.
+    fn drop(&mut self) {
+        self.modifiers.held.fetch_sub(self.held.len() as u32, Ordering::Relaxed);
+        if self.altgr_down {
+            self.modifiers.altgr.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Layout stage: applies the active layout to every event no earlier stage has decided, through
+/// its AltGr table for events pressed with AltGr held, and its shifted table for those pressed
+/// with Shift held. A mapping that needs Shift the other way round from how it is held gets
+/// Shift pressed or released around its press and each repeat.
+pub struct LayoutStage {
+    layout: Arc<ActiveLayout>,
+    /// What each key held down was mapped to, by input code, so its repeats and release match
+    /// its press even if a modifier or the layout changes in between.
+    held: HashMap<u16, Mapped>,
+    /// Shift keys held down, by output code.
+    shifts: BTreeSet<u16>,
+}
+
+/// A key's output, the rule that decided it, and the Shift state it needs, if any.
+#[derive(Clone, Copy)]
+struct Mapped {
+    code: u16,
+    rule: RemapRule,
+    shift: Option<bool>,
+}
+
+impl LayoutStage {
+    pub fn new(layout: Arc<ActiveLayout>) -> Self {
+        LayoutStage { layout, held: HashMap::new(), shifts: BTreeSet::new() }
+    }
+
+    fn map(&self, event: &KeyEvent) -> Mapped {
+        let input = event.code;
+        let layout = self.layout.get();
+        let (action, rule) = match event.rule {
+            Some(rule) => return Mapped { code: input, rule, shift: None },
+            None if event.altgr => (layout.map_altgr(Key::new(input)), RemapRule::AltGr),
+            None if layout.is_overridden(Key::new(input)) => match self.shifts.is_empty() {
+                true => (layout.map(Key::new(input)), RemapRule::Override),
+                false => (layout.map_shifted(Key::new(input)), RemapRule::Override),
+            },
+            None if !self.shifts.is_empty() => (layout.map_shifted(Key::new(input)), RemapRule::Layout),
+            None => (layout.map(Key::new(input)), RemapRule::Layout),
+        };
+        match action {
+            // An override that keeps a key as it is still decided it.
+            Action::Key(key) if key.code() != input || rule == RemapRule::Override => {
+                Mapped { code: key.code(), rule, shift: None }
+            }
+            Action::Shifted(key) => Mapped { code: key.code(), rule, shift: Some(true) },
+            Action::Unshifted(key) => Mapped { code: key.code(), rule, shift: Some(false) },
+            _ => Mapped { code: input, rule: RemapRule::Unmapped, shift: None },
+        }
+    }
+}
+
+impl Stage for LayoutStage {
+    fn process(&mut self, mut event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        let input = event.code;
+        let pressed = match event.value {
+            1 => None,
+            0 => self.held.remove(&input),
+            _ => self.held.get(&input).copied(),
+        };
+        let mapped = pressed.unwrap_or_else(|| self.map(&event));
+        if event.value == 1 {
+            self.held.insert(input, mapped);
+        }
+        event.code = mapped.code;
+        event.rule = Some(mapped.rule);
+        if event.code == Key::KEY_LEFTSHIFT.code() || event.code == Key::KEY_RIGHTSHIFT.code() {
+            match event.value {
+                1 => self.shifts.insert(event.code),
+                0 => self.shifts.remove(&event.code),
+                _ => false,
+            };
+        }
+        let shift = |code: u16, value| KeyEvent { rule: Some(mapped.rule), ..KeyEvent::new(code, value) };
+        match mapped.shift {
+            Some(true) if event.value != 0 && self.shifts.is_empty() => {
+                out.push(shift(Key::KEY_LEFTSHIFT.code(), 1));
+                out.push(event);
+                out.push(shift(Key::KEY_LEFTSHIFT.code(), 0));
+            }
+            Some(false) if event.value != 0 && !self.shifts.is_empty() => {
+                out.extend(self.shifts.iter().map(|&code| shift(code, 0)));
+                out.push(event);
+                out.extend(self.shifts.iter().map(|&code| shift(code, 1)));
+            }
+            _ => out.push(event),
+        }
+    }
+}
+
+/// The rule that decided a key event's output code, reported by the explain trace.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RemapRule {
+    /// Translated by an entry in the layout table (or its shifted table).
+    Layout,
+    /// Translated by an entry in the layout's AltGr table.
+    AltGr,
+    /// Translated by an override in the config file's `[keys]`.
+    Override,
+    /// Passed through unchanged because a shortcut modifier was held.
+    ModifierPassthrough,
+    /// Passed through unchanged because the layout has no entry for the key.
+    Unmapped,
+    /// Typed by tapping an overloaded (tap-hold) key.
+    Overload,
+    /// Typed by a text macro.
+    Macro,
+    /// Typed by pressing a combo's keys together.
+    Combo,
+    /// Typed, or erased, to expand a snippet.
+    Snippet,
+    /// Passed through unchanged because remapping is paused.
+    Paused,
+}
+
+impl std::fmt::Display for RemapRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RemapRule::Layout => "layout entry",
+            RemapRule::AltGr => "AltGr layout entry",
+            RemapRule::Override => "[keys] override",
+            RemapRule::ModifierPassthrough => "modifier passthrough",
+            RemapRule::Unmapped => "unmapped passthrough",
+            RemapRule::Overload => "tap-hold tap",
+            RemapRule::Macro => "text macro",
+            RemapRule::Combo => "combo",
+            RemapRule::Snippet => "snippet",
+            RemapRule::Paused => "paused passthrough",
+        })
+    }
+}
+
+/// What decided `output`, as the explain trace names it: its rule, after `swap/modmap` if a swap
+/// or remap turned it into another key first. A swapped key the layout left alone is only that.
+pub fn describe_rule(output: &KeyEvent) -> String {
+    match (output.swapped, output.rule.unwrap_or(RemapRule::Unmapped)) {
+        (true, RemapRule::Unmapped) => "swap/modmap".to_string(),
+        (true, rule) => format!("swap/modmap, {rule}"),
+        (false, rule) => rule.to_string(),
+    }
+}
+
+/// Logs one line of the explain trace. Keys are logged by name, so this reveals what is typed.
+pub fn explain_key_event(device_name: &str, input: Key, output: &KeyEvent) {
+    let action = match output.value {
+        0 => "release",
+        1 => "press",
+        _ => "repeat",
+    };
+    info!(
+        target: "qwertdvert::explain",
+        device = device_name, "{:?} {} -> {:?} ({})", input, action, Key::new(output.code), describe_rule(output)
+    );
+}
+
+/// Returns true for keys whose only purpose is modifying other keys.
+pub fn is_modifier(key: Key) -> bool {
+    matches!(
+        key,
+        Key::KEY_LEFTCTRL
+            | Key::KEY_RIGHTCTRL
+            | Key::KEY_LEFTALT
+            | Key::KEY_RIGHTALT
+            | Key::KEY_LEFTMETA
+            | Key::KEY_RIGHTMETA
+            | Key::KEY_LEFTSHIFT
+            | Key::KEY_RIGHTSHIFT
+    )
+}
+
+/// Parses a key name as written in the config file: the evdev name (`KEY_SPACE`), the same
+/// without the prefix, in any case (`space`), or a short alias for the left-hand modifiers
+/// and Caps Lock (`ctrl`, `alt`, `shift`, `super`/`meta`, `caps`), and `escape`.
+pub fn parse_key(name: &str) -> Option<Key> {
+    let name = name.to_ascii_uppercase();
+    let name = match name.as_str() {
+        "CTRL" => "LEFTCTRL",
+        "ALT" => "LEFTALT",
+        "SHIFT" => "LEFTSHIFT",
+        "SUPER" | "META" => "LEFTMETA",
+        "CAPS" => "CAPSLOCK",
+        "ESCAPE" => "ESC",
+        other => other,
+    };
+    match name.starts_with("KEY_") || name.starts_with("BTN_") {
+        true => name.parse().ok(),
+        false => format!("KEY_{name}").parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::layout::{Dvorak, Layout, ProgrammerDvorak};
+    use crate::pipeline::Pipeline;
+
+    use super::*;
+
+    /// A keyboard's swaps, shortcut layer and layout, sharing `modifiers` with other keyboards.
+    fn keyboard(modifiers: &Arc<ModifierState>, layout: Arc<dyn Layout>, overrides: &[(Key, Key)]) -> Pipeline {
+        let layout = Arc::new(ActiveLayout::new(layout, overrides.to_vec()));
+        Pipeline::new(vec![
+            Box::new(Swaps::new(&[]).with_remaps(&[(Key::KEY_CAPSLOCK, Key::KEY_ESC)])),
+            Box::new(ShortcutLayer::new(modifiers.clone(), &SHORTCUT_MODIFIERS)),
+            Box::new(LayoutStage::new(layout)),
+        ])
+    }
+
+    /// The output of one key event, as (key, value, rule).
+    fn type_key(pipeline: &mut Pipeline, key: Key, value: i32) -> Vec<(Key, i32, Option<RemapRule>)> {
+        let outputs = pipeline.process(KeyEvent::new(key.code(), value));
+        outputs.iter().map(|output| (Key::new(output.code), output.value, output.rule)).collect()
+    }
+
+    #[test]
+    fn maps_through_the_layout() {
+        let mut pipeline = keyboard(&Arc::default(), Arc::new(Dvorak), &[]);
+        assert_eq!(type_key(&mut pipeline, Key::KEY_S, 1), [(Key::KEY_O, 1, Some(RemapRule::Layout))]);
+        assert_eq!(type_key(&mut pipeline, Key::KEY_ENTER, 1), [(Key::KEY_ENTER, 1, Some(RemapRule::Unmapped))]);
+    }
+
+    #[test]
+    fn shortcut_modifiers_are_shared_between_keyboards() {
+        let modifiers = Arc::new(ModifierState::default());
+        let mut left = keyboard(&modifiers, Arc::new(Dvorak), &[]);
+        let mut right = keyboard(&modifiers, Arc::new(Dvorak), &[]);
+        type_key(&mut left, Key::KEY_LEFTCTRL, 1);
+        assert!(modifiers.shortcut_held());
+        let shortcut = Some(RemapRule::ModifierPassthrough);
+        assert_eq!(type_key(&mut right, Key::KEY_C, 1), [(Key::KEY_C, 1, shortcut)]);
+        assert_eq!(type_key(&mut right, Key::KEY_C, 0), [(Key::KEY_C, 0, shortcut)]);
+        type_key(&mut left, Key::KEY_LEFTCTRL, 0);
+        assert!(!modifiers.shortcut_held());
+        assert_eq!(type_key(&mut right, Key::KEY_C, 1), [(Key::KEY_J, 1, Some(RemapRule::Layout))]);
+    }
+
+    #[test]
+    fn a_keyboard_dropped_with_a_modifier_down_no_longer_holds_it() {
+        let modifiers = Arc::new(ModifierState::default());
+        let mut keyboard = keyboard(&modifiers, Arc::new(Dvorak), &[]);
+        type_key(&mut keyboard, Key::KEY_LEFTALT, 1);
+        assert!(modifiers.shortcut_held());
+        drop(keyboard);
+        assert!(!modifiers.shortcut_held());
+    }
+
+    #[test]
+    fn presses_or_releases_shift_for_symbols_that_need_it() {
+        let rule = Some(RemapRule::Layout);
+        let mut pipeline = keyboard(&Arc::default(), Arc::new(ProgrammerDvorak), &[]);
+        assert_eq!(
+            type_key(&mut pipeline, Key::KEY_GRAVE, 1),
+            [(Key::KEY_LEFTSHIFT, 1, rule), (Key::KEY_4, 1, rule), (Key::KEY_LEFTSHIFT, 0, rule)]
+        );
+        assert_eq!(type_key(&mut pipeline, Key::KEY_GRAVE, 0), [(Key::KEY_4, 0, rule)]);
+
+        type_key(&mut pipeline, Key::KEY_LEFTSHIFT, 1);
+        assert_eq!(
+            type_key(&mut pipeline, Key::KEY_EQUAL, 1),
+            [(Key::KEY_LEFTSHIFT, 0, rule), (Key::KEY_GRAVE, 1, rule), (Key::KEY_LEFTSHIFT, 1, rule)]
+        );
+    }
+
+    #[test]
+    fn names_overrides_and_modmaps() {
+        let mut pipeline = keyboard(&Arc::default(), Arc::new(Dvorak), &[(Key::KEY_A, Key::KEY_BACKSPACE)]);
+        assert_eq!(type_key(&mut pipeline, Key::KEY_A, 1), [(Key::KEY_BACKSPACE, 1, Some(RemapRule::Override))]);
+
+        let outputs = pipeline.process(KeyEvent::new(Key::KEY_CAPSLOCK.code(), 1));
+        assert_eq!(outputs[0].code, Key::KEY_ESC.code());
+        assert_eq!(describe_rule(&outputs[0]), "swap/modmap");
+    }
+}
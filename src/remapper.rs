@@ -0,0 +1,215 @@
+//! The remapping core as a state machine with no I/O.
+//!
+//! A [`Remapper`] is one keyboard's worth of remapping: it takes the events read from a
+//! keyboard and returns the events to emit, keeping the modifier state, the pipeline's layers
+//! and timers, and which keys it has pressed on the output. It opens no devices and spawns no
+//! threads, so it can be driven by any input backend, or by a test feeding it events by hand.
+//! The daemon gives each keyboard a remapper of its own, sharing their modifier state through
+//! [`ModifierState`]; a remapper made with [`Remapper::new`] has its own.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Instant;
+
+use evdev::{EventType, InputEvent, Key};
+use smallvec::SmallVec;
+
+use crate::config::Config;
+use crate::error::ConfigError;
+use crate::layout::{ActiveLayout, Dvorak, Layout};
+use crate::pipeline::{KeyEvent, Pipeline};
+use crate::remap::ModifierState;
+
+/// The events one input event (or one timer) produced. Most keys produce one or two, so these
+/// are kept inline.
+pub type Outputs = SmallVec<[RemappedEvent; 4]>;
+
+/// The pipeline's events for one key event (or one timer), before they are turned into
+/// [`RemappedEvent`]s, so the daemon can still report the rule that decided each.
+pub(crate) type KeyEvents = SmallVec<[KeyEvent; 4]>;
+
+/// An event to emit, as (type, code, value).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RemappedEvent {
+    pub event_type: EventType,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl RemappedEvent {
+    /// A press (1), release (0) or autorepeat (2) of `key`.
+    pub fn key(key: Key, value: i32) -> Self {
+        RemappedEvent { event_type: EventType::KEY, code: key.code(), value }
+    }
+}
+
+impl From<RemappedEvent> for InputEvent {
+    fn from(event: RemappedEvent) -> Self {
+        InputEvent::new(event.event_type, event.code, event.value)
+    }
+}
+
+impl From<&KeyEvent> for RemappedEvent {
+    fn from(event: &KeyEvent) -> Self {
+        RemappedEvent::key(Key::new(event.code), event.value)
+    }
+}
+
+/// Remaps one keyboard's events, as the daemon does, without reading or writing any device.
+pub struct Remapper {
+    pipeline: Pipeline,
+    layout: Arc<ActiveLayout>,
+    modifiers: Arc<ModifierState>,
+    /// Keys pressed on the output and not yet released.
+    pressed: BTreeSet<u16>,
+}
+
+impl Remapper {
+    /// A remapper with the layout and pipeline stages `config` sets up.
+    pub fn new(config: &Config) -> Result<Self, ConfigError> {
+        let layout = Arc::new(ActiveLayout::new(Arc::new(Dvorak), config.keys.clone()));
+        layout.select(&config.layout)?;
+        let modifiers = Arc::new(ModifierState::default());
+        modifiers.set_sticky_keys(config.sticky_keys);
+        let pipeline = Pipeline::for_config(config, layout.clone(), modifiers.clone());
+        Ok(Remapper { pipeline, layout, modifiers, pressed: BTreeSet::new() })
+    }
+
+    /// A remapper typing with `layout` and the default settings.
+    pub fn with_layout(layout: Arc<dyn Layout>) -> Self {
+        let config = Config::default();
+        let layout = Arc::new(ActiveLayout::new(layout, config.keys.clone()));
+        let modifiers = Arc::new(ModifierState::default());
+        let pipeline = Pipeline::for_config(&config, layout.clone(), modifiers.clone());
+        Remapper { pipeline, layout, modifiers, pressed: BTreeSet::new() }
+    }
+
+    /// A remapper with the pipeline stages `config` sets up, typing with `layout` and sharing
+    /// `modifiers` with other keyboards, as the daemon's keyboards do.
+    pub(crate) fn for_keyboard(config: &Config, layout: Arc<ActiveLayout>, modifiers: Arc<ModifierState>) -> Self {
+        let pipeline = Pipeline::for_config(config, layout.clone(), modifiers.clone());
+        Remapper { pipeline, layout, modifiers, pressed: BTreeSet::new() }
+    }
+
+    /// Handles one event read from the keyboard and returns the events to emit. Key events go
+    /// through the pipeline; everything else (including SYN_REPORT) is passed through unchanged.
+    pub fn process(&mut self, event: InputEvent) -> Outputs {
+        if event.event_type() != EventType::KEY {
+            let mut outputs = Outputs::new();
+            outputs.push(RemappedEvent { event_type: event.event_type(), code: event.code(), value: event.value() });
+            return outputs;
+        }
+        self.process_key(KeyEvent::new(event.code(), event.value())).iter().map(RemappedEvent::from).collect()
+    }
+
+    /// Runs one key event through the pipeline, as [`process`](Remapper::process) does.
+    pub(crate) fn process_key(&mut self, event: KeyEvent) -> KeyEvents {
+        let events = self.pipeline.process(event);
+        track_pressed(&mut self.pressed, events)
+    }
+
+    /// Passes one key event through unmapped, e.g. while remapping is paused, still keeping
+    /// track of the keys pressed on the output.
+    pub(crate) fn bypass(&mut self, event: KeyEvent) -> KeyEvents {
+        track_pressed(&mut self.pressed, &[event])
+    }
+
+    /// When [`tick`](Remapper::tick) next needs to be called, if ever: a tap-hold key waiting
+    /// to be decided, a debounced key or an autorepeat.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pipeline.deadline()
+    }
+
+    /// Fires the timers due at `now` and returns the events to emit.
+    pub fn tick(&mut self, now: Instant) -> Outputs {
+        self.tick_keys(now).iter().map(RemappedEvent::from).collect()
+    }
+
+    /// Fires the timers due at `now`, as [`tick`](Remapper::tick) does.
+    pub(crate) fn tick_keys(&mut self, now: Instant) -> KeyEvents {
+        let events = self.pipeline.tick(now);
+        track_pressed(&mut self.pressed, events)
+    }
+
+    /// The keys pressed on the output and not yet released.
+    pub fn pressed(&self) -> impl Iterator<Item = Key> + '_ {
+        self.pressed.iter().map(|&code| Key::new(code))
+    }
+
+    /// Releases every key pressed on the output, e.g. before the keyboard is let go. Releases
+    /// that come through later for these keys are dropped.
+    pub fn release_all(&mut self) -> Outputs {
+        std::mem::take(&mut self.pressed).into_iter().map(|code| RemappedEvent::key(Key::new(code), 0)).collect()
+    }
+
+    /// The layout keys are mapped with, which can be switched with [`ActiveLayout::select`].
+    pub fn layout(&self) -> &ActiveLayout {
+        &self.layout
+    }
+
+    /// The shortcut and AltGr modifiers held, and whether sticky keys are on.
+    pub fn modifiers(&self) -> &ModifierState {
+        &self.modifiers
+    }
+}
+
+/// The pipeline's `events` to emit, keeping `pressed` up to date. Releases and repeats of keys
+/// that are not pressed are dropped, so the output never sees them twice.
+fn track_pressed(pressed: &mut BTreeSet<u16>, events: &[KeyEvent]) -> KeyEvents {
+    let mut outputs = KeyEvents::new();
+    for event in events {
+        let known = match event.value {
+            1 => {
+                pressed.insert(event.code);
+                true
+            }
+            0 => pressed.remove(&event.code),
+            _ => pressed.contains(&event.code),
+        };
+        if known {
+            outputs.push(*event);
+        }
+    }
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: Key, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY, key.code(), value)
+    }
+
+    #[test]
+    fn remaps_keys_and_passes_other_events_through() {
+        let mut remapper = Remapper::new(&Config::default()).unwrap();
+        assert_eq!(remapper.process(key(Key::KEY_S, 1)).as_slice(), [RemappedEvent::key(Key::KEY_O, 1)]);
+        let syn = InputEvent::new(EventType::SYNCHRONIZATION, 0, 0);
+        assert_eq!(
+            remapper.process(syn).as_slice(),
+            [RemappedEvent { event_type: EventType::SYNCHRONIZATION, code: 0, value: 0 }]
+        );
+        assert_eq!(remapper.process(key(Key::KEY_S, 0)).as_slice(), [RemappedEvent::key(Key::KEY_O, 0)]);
+    }
+
+    #[test]
+    fn shortcuts_stay_on_qwerty() {
+        let mut remapper = Remapper::with_layout(Arc::new(Dvorak));
+        remapper.process(key(Key::KEY_LEFTCTRL, 1));
+        assert!(remapper.modifiers().shortcut_held());
+        assert_eq!(remapper.process(key(Key::KEY_C, 1)).as_slice(), [RemappedEvent::key(Key::KEY_C, 1)]);
+    }
+
+    #[test]
+    fn release_all_releases_held_keys_once() {
+        let mut remapper = Remapper::new(&Config::default()).unwrap();
+        remapper.process(key(Key::KEY_LEFTSHIFT, 1));
+        remapper.process(key(Key::KEY_S, 1));
+        assert_eq!(remapper.pressed().collect::<Vec<_>>(), [Key::KEY_O, Key::KEY_LEFTSHIFT]);
+        let released = remapper.release_all();
+        assert_eq!(released.as_slice(), [RemappedEvent::key(Key::KEY_O, 0), RemappedEvent::key(Key::KEY_LEFTSHIFT, 0)]);
+        assert!(remapper.process(key(Key::KEY_S, 0)).is_empty());
+        assert_eq!(remapper.pressed().count(), 0);
+    }
+}
@@ -0,0 +1,180 @@
+//! Autorepeat generated by the daemon rather than the keyboard.
+//!
+//! A keyboard's own repeats carry the physical key, and each one is remapped as it arrives,
+//! so a repeat can come out as a different key than the press did once a modifier or layer
+//! changes. Here the keyboard's repeats are dropped, and the last key pressed repeats with
+//! the code it was pressed as until it is released or another key is pressed. Modifiers
+//! don't repeat. A symbol the layout types by pressing or releasing Shift around the key
+//! repeats with the same Shift around each repeat.
+
+use std::time::Instant;
+
+use evdev::Key;
+
+use crate::config::KeyRepeat;
+use crate::pipeline::{KeyEvent, Stage};
+use crate::remap::is_modifier;
+
+/// A press being repeated.
+struct Repeating {
+    press: KeyEvent,
+    next: Instant,
+    /// Modifier presses and releases that came just before the press, and those of them
+    /// undone just after it: the Shift the layout wraps a shifted or unshifted symbol in. Each
+    /// repeat is wrapped the same way.
+    before: Vec<KeyEvent>,
+    after: Vec<KeyEvent>,
+}
+
+impl Repeating {
+    /// Whether `event` undoes a modifier change in `before` that hasn't been undone yet.
+    fn undoes(&self, event: &KeyEvent) -> bool {
+        let changes = self.before.iter().filter(|before| before.code == event.code && before.value != event.value);
+        let undone = self.after.iter().filter(|after| after.code == event.code);
+        event.value != 2 && changes.count() > undone.count()
+    }
+
+    /// The repeat, wrapped in the modifier changes made around the press and undone after it.
+    fn repeat(&self, out: &mut Vec<KeyEvent>) {
+        let wrapped = |before: &&KeyEvent| self.after.iter().any(|after| after.code == before.code);
+        out.extend(self.before.iter().filter(wrapped).copied());
+        out.push(KeyEvent { value: 2, ..self.press });
+        out.extend(self.after.iter().copied());
+    }
+}
+
+/// Last stage: repeats the key last pressed, as it was output.
+pub struct Autorepeat {
+    timing: KeyRepeat,
+    repeating: Option<Repeating>,
+    /// Modifier presses and releases since the last other key event.
+    modifiers: Vec<KeyEvent>,
+}
+
+impl Autorepeat {
+    pub fn new(timing: KeyRepeat) -> Self {
+        Autorepeat { timing, repeating: None, modifiers: Vec::new() }
+    }
+}
+
+impl Stage for Autorepeat {
+    fn process(&mut self, event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        if event.value == 2 {
+            // The keyboard's own repeats.
+            return;
+        }
+        if is_modifier(Key::new(event.code)) {
+            match &mut self.repeating {
+                Some(repeating) if repeating.undoes(&event) => repeating.after.push(event),
+                // Pressing a modifier stops the repeat, as on a keyboard.
+                _ if event.value == 1 => self.repeating = None,
+                // A modifier let go of while the key repeats no longer wraps its repeats.
+                Some(repeating) => {
+                    repeating.before.retain(|before| before.code != event.code);
+                    repeating.after.retain(|after| after.code != event.code);
+                }
+                None => {}
+            }
+            self.modifiers.push(event);
+            out.push(event);
+            return;
+        }
+        let modifiers = std::mem::take(&mut self.modifiers);
+        if event.value == 1 {
+            // Only the last change to each modifier says where it was left.
+            let before = modifiers
+                .iter()
+                .enumerate()
+                .filter(|(i, modifier)| !modifiers[i + 1..].iter().any(|later| later.code == modifier.code))
+                .map(|(_, &modifier)| modifier)
+                .collect();
+            let next = Instant::now() + self.timing.delay;
+            self.repeating = Some(Repeating { press: event, next, before, after: Vec::new() });
+        } else if self.repeating.as_ref().is_some_and(|repeating| repeating.press.code == event.code) {
+            self.repeating = None;
+        }
+        out.push(event);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.repeating.as_ref().map(|repeating| repeating.next)
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<KeyEvent>) {
+        if let Some(repeating) = &mut self.repeating
+            && repeating.next <= now
+        {
+            repeating.repeat(out);
+            // Keep to the interval, but don't catch up on repeats missed while busy.
+            let next = repeating.next + self.timing.period;
+            repeating.next = if next > now { next } else { now + self.timing.period };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::layout::{ActiveLayout, Dvorak, PROGRAMMER_DVORAK};
+    use crate::pipeline::Pipeline;
+    use crate::remap::ModifierState;
+
+    const SHIFT: Key = Key::KEY_LEFTSHIFT;
+
+    fn pipeline() -> Pipeline {
+        let config = Config { layout: PROGRAMMER_DVORAK.to_string(), daemon_repeat: true, ..Config::default() };
+        let layout = Arc::new(ActiveLayout::new(Arc::new(Dvorak), config.keys.clone()));
+        layout.select(&config.layout).unwrap();
+        Pipeline::for_config(&config, layout, Arc::new(ModifierState::default()))
+    }
+
+    fn keys(events: &[KeyEvent]) -> Vec<(Key, i32)> {
+        events.iter().map(|event| (Key::new(event.code), event.value)).collect()
+    }
+
+    fn send(pipeline: &mut Pipeline, key: Key, value: i32) -> Vec<(Key, i32)> {
+        keys(pipeline.process(KeyEvent::new(key.code(), value)))
+    }
+
+    /// What the next `count` repeats send.
+    fn repeats(pipeline: &mut Pipeline, count: usize) -> Vec<Vec<(Key, i32)>> {
+        (0..count).map(|_| keys(pipeline.tick(pipeline.deadline().expect("the key repeats")))).collect()
+    }
+
+    #[test]
+    fn a_shifted_symbol_repeats_with_shift() {
+        let mut pipeline = pipeline();
+        // Programmer Dvorak types "(" on the 5 key, as Shift+9.
+        assert_eq!(send(&mut pipeline, Key::KEY_5, 1), [(SHIFT, 1), (Key::KEY_9, 1), (SHIFT, 0)]);
+        let repeat = vec![(SHIFT, 1), (Key::KEY_9, 2), (SHIFT, 0)];
+        assert_eq!(repeats(&mut pipeline, 3), [repeat.clone(), repeat.clone(), repeat]);
+        assert_eq!(send(&mut pipeline, Key::KEY_5, 0), [(Key::KEY_9, 0)]);
+        assert_eq!(pipeline.deadline(), None);
+    }
+
+    #[test]
+    fn an_unshifted_symbol_repeats_without_shift() {
+        let mut pipeline = pipeline();
+        assert_eq!(send(&mut pipeline, SHIFT, 1), [(SHIFT, 1)]);
+        // With Shift held, Programmer Dvorak types "7" on the 2 key, with Shift let go.
+        assert_eq!(send(&mut pipeline, Key::KEY_2, 1), [(SHIFT, 0), (Key::KEY_7, 1), (SHIFT, 1)]);
+        let repeat = vec![(SHIFT, 0), (Key::KEY_7, 2), (SHIFT, 1)];
+        assert_eq!(repeats(&mut pipeline, 3), [repeat.clone(), repeat.clone(), repeat]);
+
+        // Once Shift is let go for real, the repeats no longer press it again.
+        assert_eq!(send(&mut pipeline, SHIFT, 0), [(SHIFT, 0)]);
+        assert_eq!(repeats(&mut pipeline, 2), [vec![(Key::KEY_7, 2)], vec![(Key::KEY_7, 2)]]);
+    }
+
+    #[test]
+    fn pressing_a_modifier_stops_the_repeat() {
+        let mut pipeline = pipeline();
+        send(&mut pipeline, Key::KEY_5, 1);
+        assert_eq!(repeats(&mut pipeline, 1).len(), 1);
+        send(&mut pipeline, Key::KEY_LEFTCTRL, 1);
+        assert_eq!(pipeline.deadline(), None);
+    }
+}
@@ -0,0 +1,288 @@
+//! Recording the raw events read from the keyboards, and replaying a recording through the
+//! remapping offline.
+//!
+//! With `--record-events PATH` the daemon appends every event it reads, with the kernel's
+//! timestamp and the keyboard it came from, to PATH. `qwertdvert --replay PATH` then runs
+//! those events through a pipeline per keyboard built from the config, as the daemon would,
+//! and prints what each key turned into and what was still held down at the end. That lets a
+//! user capture an intermittent stuck key as it happens, and a developer reproduce it.
+//!
+//! Timing matters to tap-hold, combos, debouncing and repeats, so the replay keeps the gaps
+//! between events while a stage is waiting on a timer. Gaps while none is waiting make no
+//! difference to the output, and are skipped.
+//!
+//! A recording is text, one event per line after a header:
+//!
+//! ```text
+//! # qwertdvert event recording v1
+//! 1712345678.123456 1 16 1 AT Translated Set 2 keyboard
+//! ```
+//!
+//! that is, seconds since the epoch, the event type, code and value, and the keyboard's name.
+//! Every key typed while recording is in the file, passwords included.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use evdev::{EventType, InputEvent, Key};
+
+use crate::config::Config;
+use crate::daemon::keyboard_layout;
+use crate::error::{ConfigError, DaemonError};
+use crate::layout::{ActiveLayout, Dvorak};
+use crate::pipeline::{KeyEvent, Pipeline};
+use crate::remap::ModifierState;
+
+/// The first line of every recording.
+const HEADER: &str = "# qwertdvert event recording v1";
+
+/// Appends the events read from the keyboards to a recording.
+pub(crate) struct EventRecorder {
+    path: PathBuf,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl EventRecorder {
+    /// Starts a new recording at `path`, replacing any file there.
+    pub(crate) fn create(path: &Path) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{HEADER}")?;
+        file.flush()?;
+        Ok(EventRecorder { path: path.to_path_buf(), file: Mutex::new(file) })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records `events`, read together from the keyboard called `device`, and writes them out
+    /// straight away, so the recording is complete however the daemon stops.
+    pub(crate) fn record(&self, device: &str, events: &[InputEvent]) -> std::io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        for event in events {
+            let event = RecordedEvent {
+                time: event.timestamp(),
+                event_type: event.event_type().0,
+                code: event.code(),
+                value: event.value(),
+                device: device.to_string(),
+            };
+            writeln!(file, "{event}")?;
+        }
+        file.flush()
+    }
+}
+
+/// One event from a recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedEvent {
+    /// The kernel's timestamp on the event.
+    pub time: SystemTime,
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+    /// The name of the keyboard it was read from.
+    pub device: String,
+}
+
+impl std::fmt::Display for RecordedEvent {
+    /// The event as a line of a recording.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let since_epoch = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "{}.{:06} {} {} {} {}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros(),
+            self.event_type,
+            self.code,
+            self.value,
+            self.device
+        )
+    }
+}
+
+/// Reads the recording at `path`.
+pub fn load(path: &Path) -> Result<Vec<RecordedEvent>, ConfigError> {
+    let text =
+        std::fs::read_to_string(path).map_err(|source| ConfigError::ReadFile { path: path.to_path_buf(), source })?;
+    parse(&text).map_err(|reason| ConfigError::ParseFile { path: path.to_path_buf(), reason })
+}
+
+/// Parses a recording, failing on the first line that isn't an event.
+fn parse(text: &str) -> Result<Vec<RecordedEvent>, String> {
+    let mut lines = text.lines().enumerate();
+    if lines.next().map(|(_, line)| line) != Some(HEADER) {
+        return Err(format!("not an event recording (the first line should be '{HEADER}')"));
+    }
+    lines
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| parse_event(line).ok_or_else(|| format!("line {}: not an event: '{line}'", index + 1)))
+        .collect()
+}
+
+fn parse_event(line: &str) -> Option<RecordedEvent> {
+    let mut fields = line.splitn(5, ' ');
+    let (secs, micros) = fields.next()?.split_once('.')?;
+    let time = UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?) + Duration::from_micros(micros.parse().ok()?);
+    Some(RecordedEvent {
+        time,
+        event_type: fields.next()?.parse().ok()?,
+        code: fields.next()?.parse().ok()?,
+        value: fields.next()?.parse().ok()?,
+        device: fields.next()?.to_string(),
+    })
+}
+
+/// What a replay found.
+pub struct ReplayReport {
+    /// The layout the events were typed with.
+    pub layout: String,
+    /// Key events replayed.
+    pub keys: usize,
+    /// Keys each keyboard still held down at the end of the recording, as read from it.
+    pub held_in: BTreeMap<String, BTreeSet<u16>>,
+    /// Keys each keyboard's pipeline still held down on the virtual keyboard at the end.
+    pub held_out: BTreeMap<String, BTreeSet<u16>>,
+}
+
+impl ReplayReport {
+    /// Whether the remapping left keys down that were released on the keyboards: stuck keys.
+    pub fn stuck(&self) -> bool {
+        self.held_out
+            .iter()
+            .any(|(device, held)| !held.is_empty() && self.held_in.get(device).is_none_or(BTreeSet::is_empty))
+    }
+}
+
+/// Replays `events` through a pipeline per keyboard built from `config`, calling `on_key` with
+/// the time into the recording, the keyboard, each key event read and the events it turned
+/// into. Events a stage emits from a timer are reported with no key event read.
+pub fn replay(
+    config: &Config,
+    events: &[RecordedEvent],
+    mut on_key: impl FnMut(Duration, &str, Option<KeyEvent>, &[KeyEvent]),
+) -> Result<ReplayReport, DaemonError> {
+    let shared = Arc::new(ActiveLayout::new(Arc::new(Dvorak), config.keys.clone()));
+    shared.select(&config.layout)?;
+    let modifiers = Arc::new(ModifierState::default());
+    modifiers.set_sticky_keys(config.sticky_keys);
+    let mut pipelines: BTreeMap<&str, Pipeline> = BTreeMap::new();
+    let mut report =
+        ReplayReport { layout: shared.name(), keys: 0, held_in: BTreeMap::new(), held_out: BTreeMap::new() };
+    let Some(first) = events.first().map(|event| event.time) else {
+        return Ok(report);
+    };
+
+    let start = Instant::now();
+    // How much of the recording has been skipped over while no timer was waiting.
+    let mut skipped = Duration::ZERO;
+    for event in events.iter().filter(|event| event.event_type == EventType::KEY.0) {
+        let offset = event.time.duration_since(first).unwrap_or_default();
+        let mut at = start + offset.saturating_sub(skipped);
+        run_timers(&mut pipelines, &mut report.held_out, start, skipped, Some(at), &mut on_key);
+        let now = Instant::now();
+        if at > now && pipelines.values().all(|pipeline| pipeline.deadline().is_none()) {
+            skipped += at - now;
+            at = now;
+        }
+        sleep_until(at);
+
+        let pipeline = pipelines.entry(event.device.as_str()).or_insert_with(|| {
+            let layout = keyboard_layout(config, &shared, &event.device, None);
+            Pipeline::for_config(config, layout, modifiers.clone())
+        });
+        let input = KeyEvent::new(event.code, event.value);
+        track_held(&mut report.held_in, &event.device, &[input]);
+        let outputs = pipeline.process(input);
+        track_held(&mut report.held_out, &event.device, outputs);
+        report.keys += 1;
+        on_key(offset, &event.device, Some(input), outputs);
+    }
+    run_timers(&mut pipelines, &mut report.held_out, start, skipped, None, &mut on_key);
+    Ok(report)
+}
+
+/// Runs every pipeline's timers due by `until`, in order, at the time they are due. The replay
+/// started at `start` and has skipped `skipped` of the recording so far.
+fn run_timers(
+    pipelines: &mut BTreeMap<&str, Pipeline>,
+    held_out: &mut BTreeMap<String, BTreeSet<u16>>,
+    start: Instant,
+    skipped: Duration,
+    until: Option<Instant>,
+    on_key: &mut impl FnMut(Duration, &str, Option<KeyEvent>, &[KeyEvent]),
+) {
+    while let Some((deadline, device)) = pipelines
+        .iter()
+        .filter_map(|(&device, pipeline)| pipeline.deadline().map(|deadline| (deadline, device)))
+        .min()
+        .filter(|(deadline, _)| until.is_none_or(|until| *deadline <= until))
+    {
+        sleep_until(deadline);
+        let outputs = pipelines.get_mut(device).unwrap().tick(Instant::now());
+        track_held(held_out, device, outputs);
+        on_key(Instant::now() - start + skipped, device, None, outputs);
+    }
+}
+
+/// Updates the keys `device` holds down with its `events`.
+fn track_held(held: &mut BTreeMap<String, BTreeSet<u16>>, device: &str, events: &[KeyEvent]) {
+    let keys = held.entry(device.to_string()).or_default();
+    for event in events {
+        match event.value {
+            1 => keys.insert(event.code),
+            0 => keys.remove(&event.code),
+            _ => continue,
+        };
+    }
+}
+
+fn sleep_until(at: Instant) {
+    let now = Instant::now();
+    if at > now {
+        std::thread::sleep(at - now);
+    }
+}
+
+/// A key event as printed by the replay: `KEY_Q pressed`.
+pub fn describe(event: &KeyEvent) -> String {
+    let key = Key::new(event.code);
+    match event.value {
+        1 => format!("{key:?} pressed"),
+        0 => format!("{key:?} released"),
+        2 => format!("{key:?} repeated"),
+        value => format!("{key:?} = {value}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_events_read_back() {
+        let event = RecordedEvent {
+            time: UNIX_EPOCH + Duration::from_micros(1_712_345_678_000_042),
+            event_type: EventType::KEY.0,
+            code: Key::KEY_Q.code(),
+            value: 1,
+            device: "AT Translated Set 2 keyboard".to_string(),
+        };
+        assert_eq!(event.to_string(), "1712345678.000042 1 16 1 AT Translated Set 2 keyboard");
+        assert_eq!(parse(&format!("{HEADER}\n{event}\n\n")), Ok(vec![event]));
+    }
+
+    #[test]
+    fn rejects_lines_that_are_not_events() {
+        assert!(parse("1.000000 1 16 1 kbd\n").is_err());
+        assert_eq!(
+            parse(&format!("{HEADER}\n1.000000 1 16 kbd\n")),
+            Err("line 2: not an event: '1.000000 1 16 kbd'".to_string())
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! The ring buffers that carry frames from the keyboards to the writer thread.
+//!
+//! Each keyboard sends into a ring of its own, so a keyboard that floods the writer only
+//! fills, and drops from, its own ring. A ring has one producer and one consumer, so a fixed
+//! ring of slots with a read and a write index needs no lock. The writer reads every ring,
+//! taking the oldest value first by a sequence number stamped on each as it is sent, so frames
+//! from different keyboards go out in the order they were read.
+//!
+//! The writer parks while every ring is empty, and a producer while a frame that can't be
+//! dropped waits for room. Each side raises a flag before parking, and the other side only
+//! takes the lock on the parked thread's handle, and unparks it, once it sees the flag.
+
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvError, SendError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+
+/// A thread that may park waiting on the other side, and whether it has.
+struct Waiter {
+    parked: AtomicBool,
+    thread: Mutex<Option<Thread>>,
+}
+
+impl Waiter {
+    fn new() -> Self {
+        Waiter { parked: AtomicBool::new(false), thread: Mutex::new(None) }
+    }
+
+    /// Parks the calling thread unless `ready`, checked once the thread is marked as parked,
+    /// finds what it waits for. Either the other side's `wake` sees the mark, or `ready` sees
+    /// the change it made before waking.
+    fn park_unless(&self, ready: impl FnOnce() -> bool) {
+        // Each end is `Send`, so the thread is recorded on every park rather than once: the
+        // end may have moved threads.
+        *self.thread.lock().unwrap() = Some(std::thread::current());
+        self.parked.store(true, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        if !ready() {
+            std::thread::park();
+        }
+        self.parked.store(false, Ordering::Relaxed);
+    }
+
+    /// Unparks the thread if it has parked, or is about to. Called after the change it waits
+    /// for.
+    fn wake(&self) {
+        fence(Ordering::SeqCst);
+        if self.parked.load(Ordering::Relaxed)
+            && let Some(thread) = &*self.thread.lock().unwrap()
+        {
+            thread.unpark();
+        }
+    }
+}
+
+/// A value, with its place in the order values were sent across every ring.
+type Slot<T> = UnsafeCell<MaybeUninit<(u64, T)>>;
+
+/// One producer's ring.
+struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+    /// The index of the next slot to read, only ever advanced by the consumer.
+    head: AtomicUsize,
+    /// The index of the next slot to write, only ever advanced by the producer.
+    tail: AtomicUsize,
+    /// Set once the producer is dropped.
+    closed: AtomicBool,
+    /// The producer, while it waits for room.
+    producer: Waiter,
+}
+
+// SAFETY: There is one `Producer` and one `Consumer` per ring, and neither is `Sync`, so only
+// one thread at a time writes slots and only one reads them. The producer only writes a slot
+// before `tail` passes it and the consumer only reads one before `head` passes it, so no slot
+// is ever accessed from two threads at once.
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn new(capacity: usize) -> Self {
+        Ring {
+            slots: (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            producer: Waiter::new(),
+        }
+    }
+
+    fn slot(&self, index: usize) -> *mut MaybeUninit<(u64, T)> {
+        // The capacity is a power of two, so this is the index modulo the capacity.
+        self.slots[index & (self.slots.len() - 1)].get()
+    }
+
+    fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    /// Whether the producer is gone and everything it sent has been read.
+    fn is_done(&self) -> bool {
+        self.closed.load(Ordering::Acquire) && self.len() == 0
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let (head, tail) = (*self.head.get_mut(), *self.tail.get_mut());
+        for index in (0..tail.wrapping_sub(head)).map(|offset| head.wrapping_add(offset)) {
+            // SAFETY: The slots from head up to tail hold values nobody has read.
+            unsafe { (*self.slot(index)).assume_init_drop() };
+        }
+    }
+}
+
+/// What the consumer and every producer share.
+struct Hub<T> {
+    /// The rings, until the consumer has read everything from one whose producer is gone.
+    rings: Mutex<Vec<Arc<Ring<T>>>>,
+    /// Counts the rings added, so the consumer only looks at `rings` again after one is.
+    added: AtomicUsize,
+    /// The next value's place in the order values were sent.
+    sequence: AtomicU64,
+    /// The `Producers` and `Producer`s not yet dropped.
+    senders: AtomicUsize,
+    /// Set once the consumer is dropped.
+    consumer_gone: AtomicBool,
+    /// The consumer, while it waits for a value.
+    consumer: Waiter,
+    capacity: usize,
+}
+
+/// Creates the consumer, and the source of producers, for rings holding up to `capacity`
+/// values each. The capacity is rounded up to a power of two.
+pub(crate) fn rings<T>(capacity: usize) -> (Producers<T>, Consumer<T>) {
+    let hub = Arc::new(Hub {
+        rings: Mutex::new(Vec::new()),
+        added: AtomicUsize::new(0),
+        sequence: AtomicU64::new(0),
+        senders: AtomicUsize::new(1),
+        consumer_gone: AtomicBool::new(false),
+        consumer: Waiter::new(),
+        capacity: capacity.next_power_of_two(),
+    });
+    let consumer = Consumer { hub: hub.clone(), rings: Vec::new(), added: 0, _not_sync: PhantomData };
+    (Producers(hub, PhantomData), consumer)
+}
+
+/// Adds rings for new producers. The consumer keeps waiting for values until this and every
+/// producer are dropped.
+pub(crate) struct Producers<T>(Arc<Hub<T>>, PhantomData<Cell<()>>);
+
+impl<T> Producers<T> {
+    /// A producer with a ring of its own.
+    pub(crate) fn add(&self) -> Producer<T> {
+        let hub = &self.0;
+        let ring = Arc::new(Ring::new(hub.capacity));
+        hub.senders.fetch_add(1, Ordering::Relaxed);
+        hub.rings.lock().unwrap().push(ring.clone());
+        hub.added.fetch_add(1, Ordering::Release);
+        Producer { ring, hub: hub.clone(), _not_sync: PhantomData }
+    }
+
+    /// How many values each ring holds at most.
+    pub(crate) fn capacity(&self) -> usize {
+        self.0.capacity
+    }
+}
+
+impl<T> Drop for Producers<T> {
+    fn drop(&mut self) {
+        self.0.senders.fetch_sub(1, Ordering::Release);
+        self.0.consumer.wake();
+    }
+}
+
+/// The writing end of one ring. It can be moved to another thread but not shared, since two
+/// threads writing at once would write the same slot.
+pub(crate) struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    hub: Arc<Hub<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T> Producer<T> {
+    /// Adds `value` unless the ring is full or the consumer is gone.
+    pub(crate) fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let (ring, hub) = (&self.ring, &self.hub);
+        if hub.consumer_gone.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(value));
+        }
+        let tail = ring.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(ring.head.load(Ordering::Acquire)) == ring.slots.len() {
+            return Err(TrySendError::Full(value));
+        }
+        let sequence = hub.sequence.fetch_add(1, Ordering::Relaxed);
+        // SAFETY: The slot at `tail` is free, and only this end writes to it.
+        unsafe { (*ring.slot(tail)).write((sequence, value)) };
+        ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        hub.consumer.wake();
+        Ok(())
+    }
+
+    /// Adds `value`, waiting for room if the ring is full. Fails only if the consumer is gone.
+    pub(crate) fn send(&self, mut value: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(value)) => return Err(SendError(value)),
+                Err(TrySendError::Full(full)) => value = full,
+            }
+            let (ring, hub) = (&self.ring, &self.hub);
+            ring.producer
+                .park_unless(|| ring.len() < ring.slots.len() || hub.consumer_gone.load(Ordering::Acquire));
+        }
+    }
+
+    /// How many values are waiting to be read.
+    pub(crate) fn queued(&self) -> usize {
+        self.ring.len()
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.ring.closed.store(true, Ordering::Release);
+        self.hub.senders.fetch_sub(1, Ordering::Release);
+        self.hub.consumer.wake();
+    }
+}
+
+/// The reading end of every ring. It can be moved to another thread but not shared, since two
+/// threads reading at once would both take the same value.
+pub(crate) struct Consumer<T> {
+    hub: Arc<Hub<T>>,
+    /// The hub's rings as of `added`, so reading them takes no lock.
+    rings: Vec<Arc<Ring<T>>>,
+    added: usize,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T> Consumer<T> {
+    /// Picks up rings added since the last look.
+    fn refresh(&mut self) {
+        let added = self.hub.added.load(Ordering::Acquire);
+        if added != self.added {
+            self.rings = self.hub.rings.lock().unwrap().clone();
+            self.added = added;
+        }
+    }
+
+    /// Takes the oldest value across the rings, if there is one.
+    fn try_recv(&mut self) -> Option<T> {
+        self.refresh();
+        let oldest = self
+            .rings
+            .iter()
+            .filter(|ring| ring.len() > 0)
+            .min_by_key(|ring| {
+                let head = ring.head.load(Ordering::Relaxed);
+                // SAFETY: The ring isn't empty, so the slot at `head` was written before `tail`
+                // passed it, and only this end reads it.
+                unsafe { (*ring.slot(head)).assume_init_ref().0 }
+            })?;
+        let head = oldest.head.load(Ordering::Relaxed);
+        // SAFETY: As above; the value is read once, then `head` moves past it.
+        let (_, value) = unsafe { (*oldest.slot(head)).assume_init_read() };
+        oldest.head.store(head.wrapping_add(1), Ordering::Release);
+        oldest.producer.wake();
+        Some(value)
+    }
+
+    /// Takes the oldest value, waiting for one if every ring is empty. Fails once every ring is
+    /// empty and every producer is gone.
+    pub(crate) fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            if let Some(value) = self.try_recv() {
+                return Ok(value);
+            }
+            if self.hub.senders.load(Ordering::Acquire) == 0 {
+                // Anything sent just before the last producer went is still to be read.
+                return self.try_recv().ok_or(RecvError);
+            }
+            // Forget the rings of keyboards that are gone.
+            if self.rings.iter().any(|ring| ring.is_done()) {
+                self.hub.rings.lock().unwrap().retain(|ring| !ring.is_done());
+                self.rings.retain(|ring| !ring.is_done());
+            }
+            let hub = self.hub.clone();
+            hub.consumer.park_unless(|| {
+                self.refresh();
+                hub.senders.load(Ordering::Acquire) == 0 || self.rings.iter().any(|ring| ring.len() > 0)
+            });
+        }
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.hub.consumer_gone.store(true, Ordering::Release);
+        for ring in self.hub.rings.lock().unwrap().iter() {
+            ring.producer.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn values_wrap_around_the_ring_in_order() {
+        let (producers, mut consumer) = rings(4);
+        let producer = producers.add();
+        for value in 0..10 {
+            producer.try_send(value).unwrap();
+            producer.try_send(value + 100).unwrap();
+            assert_eq!(consumer.recv(), Ok(value));
+            assert_eq!(consumer.recv(), Ok(value + 100));
+        }
+        assert_eq!(producer.queued(), 0);
+    }
+
+    #[test]
+    fn a_full_ring_refuses_values_until_one_is_read() {
+        let (producers, mut consumer) = rings(3);
+        let producer = producers.add();
+        for value in 0..4 {
+            producer.try_send(value).unwrap();
+        }
+        assert_eq!(producer.try_send(4), Err(TrySendError::Full(4)));
+        // Another keyboard's ring has room of its own.
+        producers.add().try_send(5).unwrap();
+        assert_eq!(consumer.recv(), Ok(0));
+        producer.try_send(4).unwrap();
+        assert_eq!(producer.queued(), 4);
+    }
+
+    #[test]
+    fn values_from_every_ring_are_read_in_the_order_sent() {
+        let (producers, mut consumer) = rings(8);
+        let (first, second) = (producers.add(), producers.add());
+        first.try_send(1).unwrap();
+        second.try_send(2).unwrap();
+        first.try_send(3).unwrap();
+        drop(producers);
+        drop((first, second));
+        let read: Vec<i32> = std::iter::from_fn(|| consumer.recv().ok()).collect();
+        assert_eq!(read, [1, 2, 3]);
+    }
+
+    #[test]
+    fn the_consumer_reads_what_is_left_once_every_producer_is_gone() {
+        let (producers, mut consumer) = rings(4);
+        let producer = producers.add();
+        producer.try_send(1).unwrap();
+        drop(producer);
+        let late = producers.add();
+        drop(producers);
+        assert_eq!(consumer.recv(), Ok(1));
+        late.try_send(2).unwrap();
+        drop(late);
+        assert_eq!(consumer.recv(), Ok(2));
+        assert_eq!(consumer.recv(), Err(RecvError));
+    }
+
+    #[test]
+    fn producers_fail_once_the_consumer_is_gone() {
+        let (producers, consumer) = rings(1);
+        let producer = producers.add();
+        producer.try_send(1).unwrap();
+        drop(consumer);
+        assert_eq!(producer.try_send(2), Err(TrySendError::Disconnected(2)));
+        assert_eq!(producer.send(3), Err(SendError(3)));
+    }
+
+    #[test]
+    fn unread_values_are_dropped_with_the_ring() {
+        let value = Arc::new(());
+        let (producers, consumer) = rings(4);
+        let producer = producers.add();
+        for _ in 0..3 {
+            producer.try_send(value.clone()).unwrap();
+        }
+        assert_eq!(Arc::strong_count(&value), 4);
+        drop((producers, producer, consumer));
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn each_side_waits_for_the_other_across_threads() {
+        let (producers, mut consumer) = rings(2);
+        let producer = producers.add();
+        drop(producers);
+        let sender = std::thread::spawn(move || {
+            for value in 0..1000 {
+                producer.send(value).unwrap();
+            }
+        });
+        std::thread::sleep(Duration::from_millis(10));
+        let read: Vec<i32> = std::iter::from_fn(|| consumer.recv().ok()).collect();
+        sender.join().unwrap();
+        assert_eq!(read, (0..1000).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,165 @@
+//! MSC_SCAN events to go with remapped keys.
+//!
+//! A keyboard reports each key's scancode in an MSC_SCAN event just before the key event.
+//! Forwarded as read, it would name the physical key while the key event names the remapped
+//! one, so the scancode sent is instead the one for the key actually output: the one the
+//! keyboard itself reported for that key if it has been pressed, otherwise, for USB keyboards,
+//! the key's HID usage.
+
+use std::collections::HashMap;
+
+use evdev::Key;
+
+// HID_USAGE_PAGE: USB keyboards report scancodes as HID usages on the keyboard page (0x07).
+const HID_USAGE_PAGE: i32 = 0x70000;
+// HID_USAGES: HID keyboard usages and the keys the kernel reports them as (hid-input.c).
+const HID_USAGES: [(i32, Key); 105] = [
+    (0x04, Key::KEY_A),
+    (0x05, Key::KEY_B),
+    (0x06, Key::KEY_C),
+    (0x07, Key::KEY_D),
+    (0x08, Key::KEY_E),
+    (0x09, Key::KEY_F),
+    (0x0a, Key::KEY_G),
+    (0x0b, Key::KEY_H),
+    (0x0c, Key::KEY_I),
+    (0x0d, Key::KEY_J),
+    (0x0e, Key::KEY_K),
+    (0x0f, Key::KEY_L),
+    (0x10, Key::KEY_M),
+    (0x11, Key::KEY_N),
+    (0x12, Key::KEY_O),
+    (0x13, Key::KEY_P),
+    (0x14, Key::KEY_Q),
+    (0x15, Key::KEY_R),
+    (0x16, Key::KEY_S),
+    (0x17, Key::KEY_T),
+    (0x18, Key::KEY_U),
+    (0x19, Key::KEY_V),
+    (0x1a, Key::KEY_W),
+    (0x1b, Key::KEY_X),
+    (0x1c, Key::KEY_Y),
+    (0x1d, Key::KEY_Z),
+    (0x1e, Key::KEY_1),
+    (0x1f, Key::KEY_2),
+    (0x20, Key::KEY_3),
+    (0x21, Key::KEY_4),
+    (0x22, Key::KEY_5),
+    (0x23, Key::KEY_6),
+    (0x24, Key::KEY_7),
+    (0x25, Key::KEY_8),
+    (0x26, Key::KEY_9),
+    (0x27, Key::KEY_0),
+    (0x28, Key::KEY_ENTER),
+    (0x29, Key::KEY_ESC),
+    (0x2a, Key::KEY_BACKSPACE),
+    (0x2b, Key::KEY_TAB),
+    (0x2c, Key::KEY_SPACE),
+    (0x2d, Key::KEY_MINUS),
+    (0x2e, Key::KEY_EQUAL),
+    (0x2f, Key::KEY_LEFTBRACE),
+    (0x30, Key::KEY_RIGHTBRACE),
+    (0x31, Key::KEY_BACKSLASH),
+    (0x33, Key::KEY_SEMICOLON),
+    (0x34, Key::KEY_APOSTROPHE),
+    (0x35, Key::KEY_GRAVE),
+    (0x36, Key::KEY_COMMA),
+    (0x37, Key::KEY_DOT),
+    (0x38, Key::KEY_SLASH),
+    (0x39, Key::KEY_CAPSLOCK),
+    (0x3a, Key::KEY_F1),
+    (0x3b, Key::KEY_F2),
+    (0x3c, Key::KEY_F3),
+    (0x3d, Key::KEY_F4),
+    (0x3e, Key::KEY_F5),
+    (0x3f, Key::KEY_F6),
+    (0x40, Key::KEY_F7),
+    (0x41, Key::KEY_F8),
+    (0x42, Key::KEY_F9),
+    (0x43, Key::KEY_F10),
+    (0x44, Key::KEY_F11),
+    (0x45, Key::KEY_F12),
+    (0x46, Key::KEY_SYSRQ),
+    (0x47, Key::KEY_SCROLLLOCK),
+    (0x48, Key::KEY_PAUSE),
+    (0x49, Key::KEY_INSERT),
+    (0x4a, Key::KEY_HOME),
+    (0x4b, Key::KEY_PAGEUP),
+    (0x4c, Key::KEY_DELETE),
+    (0x4d, Key::KEY_END),
+    (0x4e, Key::KEY_PAGEDOWN),
+    (0x4f, Key::KEY_RIGHT),
+    (0x50, Key::KEY_LEFT),
+    (0x51, Key::KEY_DOWN),
+    (0x52, Key::KEY_UP),
+    (0x53, Key::KEY_NUMLOCK),
+    (0x54, Key::KEY_KPSLASH),
+    (0x55, Key::KEY_KPASTERISK),
+    (0x56, Key::KEY_KPMINUS),
+    (0x57, Key::KEY_KPPLUS),
+    (0x58, Key::KEY_KPENTER),
+    (0x59, Key::KEY_KP1),
+    (0x5a, Key::KEY_KP2),
+    (0x5b, Key::KEY_KP3),
+    (0x5c, Key::KEY_KP4),
+    (0x5d, Key::KEY_KP5),
+    (0x5e, Key::KEY_KP6),
+    (0x5f, Key::KEY_KP7),
+    (0x60, Key::KEY_KP8),
+    (0x61, Key::KEY_KP9),
+    (0x62, Key::KEY_KP0),
+    (0x63, Key::KEY_KPDOT),
+    (0x64, Key::KEY_102ND),
+    (0x65, Key::KEY_COMPOSE),
+    (0xe0, Key::KEY_LEFTCTRL),
+    (0xe1, Key::KEY_LEFTSHIFT),
+    (0xe2, Key::KEY_LEFTALT),
+    (0xe3, Key::KEY_LEFTMETA),
+    (0xe4, Key::KEY_RIGHTCTRL),
+    (0xe5, Key::KEY_RIGHTSHIFT),
+    (0xe6, Key::KEY_RIGHTALT),
+    (0xe7, Key::KEY_RIGHTMETA),
+];
+
+/// The scancodes one keyboard reports, learned as it is typed on.
+#[derive(Default)]
+pub struct ScanCodes {
+    /// Scancode of each key the keyboard has reported one for, by code.
+    learned: HashMap<u16, i32>,
+    /// The scancode just read, for the key event that follows it.
+    pending: Option<i32>,
+    /// Whether the keyboard reports scancodes at all.
+    reported: bool,
+    /// Whether its scancodes are HID usages.
+    usb: bool,
+}
+
+impl ScanCodes {
+    /// Notes an MSC_SCAN event read from the keyboard.
+    pub fn read(&mut self, scan: i32) {
+        self.pending = Some(scan);
+        self.reported = true;
+        self.usb = scan & !0xffff == HID_USAGE_PAGE;
+    }
+
+    /// Notes a key event read from the keyboard, pairing it with the scancode before it.
+    pub fn key(&mut self, code: u16) {
+        if let Some(scan) = self.pending.take() {
+            self.learned.insert(code, scan);
+        }
+    }
+
+    /// The scancode to send with an output event for `code`, if the keyboard reports
+    /// scancodes and this key's is known.
+    pub fn scan_code(&self, code: u16) -> Option<i32> {
+        if !self.reported {
+            return None;
+        }
+        self.learned.get(&code).copied().or_else(|| {
+            HID_USAGES
+                .iter()
+                .find(|(_, key)| self.usb && key.code() == code)
+                .map(|(usage, _)| HID_USAGE_PAGE | usage)
+        })
+    }
+}
@@ -0,0 +1,85 @@
+//! Keeping the path every key takes quick under load: scheduling the threads it passes
+//! through, the event loop and the uinput writer, and keeping the daemon's memory resident.
+//!
+//! Under a heavy compile, both compete for the CPU with everything else and keys can lag
+//! noticeably. With `realtime_priority` set they run under SCHED_FIFO instead, ahead of every
+//! normal thread; with `nice`, merely ahead of (or behind) other programs. `cpu_affinity`
+//! keeps them on the CPUs given, e.g. off the efficiency cores of a big.LITTLE machine. A
+//! realtime priority needs CAP_SYS_NICE or an RLIMIT_RTPRIO that allows it, and a negative
+//! nice value likewise; a thread that can't be given what is asked for carries on as it is,
+//! with a warning.
+//!
+//! Under memory pressure, the kernel may also page out code or data the remapping needs, and
+//! the next key waits on the disk to bring it back. `lock_memory` locks every page the daemon
+//! has touched by the time it starts remapping into memory.
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+use tracing::{info, warn};
+
+use crate::config::ThreadScheduling;
+
+/// Schedules the calling thread, `thread` in the log, as `scheduling` asks.
+pub(crate) fn schedule(thread: &str, scheduling: &ThreadScheduling) {
+    if !scheduling.cpu_affinity.is_empty() {
+        match set_affinity(&scheduling.cpu_affinity) {
+            Ok(()) => info!("The {} runs on CPUs {:?}", thread, scheduling.cpu_affinity),
+            Err(e) => warn!("Failed to keep the {} to CPUs {:?}: {}", thread, scheduling.cpu_affinity, e),
+        }
+    }
+    if let Some(nice) = scheduling.nice {
+        // SAFETY: Plain syscalls; gettid() is the calling thread, which setpriority() takes
+        // on its own on Linux.
+        match unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, nice) } {
+            0 => info!("The {} runs with nice value {}", thread, nice),
+            _ => warn!("Failed to give the {} nice value {}: {}", thread, nice, Errno::last()),
+        }
+    }
+    if let Some(priority) = scheduling.realtime_priority {
+        set_realtime(thread, priority);
+    }
+}
+
+/// Keeps the calling thread to `cpus`.
+fn set_affinity(cpus: &[usize]) -> nix::Result<()> {
+    let mut set = CpuSet::new();
+    for &cpu in cpus {
+        set.set(cpu)?;
+    }
+    // Pid 0 is the calling thread.
+    sched_setaffinity(Pid::from_raw(0), &set)
+}
+
+/// Switches the calling thread to SCHED_FIFO at `priority`.
+fn set_realtime(thread: &str, priority: u8) {
+    let param = libc::sched_param { sched_priority: priority.into() };
+    // SAFETY: pthread_self() is the calling thread, and `param` outlives the call.
+    let errno = unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    match errno {
+        0 => info!("The {} runs with realtime priority {}", thread, priority),
+        libc::EPERM => warn!(
+            "Not allowed to give the {} realtime priority {}; it needs CAP_SYS_NICE or an rtprio limit of at least {}",
+            thread, priority, priority
+        ),
+        errno => warn!("Failed to give the {} realtime priority {}: {}", thread, priority, Errno::from_raw(errno)),
+    }
+}
+
+/// Locks the pages of the daemon's memory that are resident now, and the rest as they are
+/// first touched, without faulting in every thread's whole stack up front. Memory mapped
+/// later, e.g. for a keyboard plugged in afterwards, isn't locked, but little is once the
+/// daemon is running.
+pub(crate) fn lock_memory() {
+    // SAFETY: A plain syscall.
+    match unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_ONFAULT) } {
+        0 => info!("Locked the daemon's memory"),
+        _ => match Errno::last() {
+            Errno::ENOMEM | Errno::EPERM => {
+                warn!("Not allowed to lock the daemon's memory; it needs CAP_IPC_LOCK or a higher memlock limit")
+            }
+            e => warn!("Failed to lock the daemon's memory: {}", e),
+        },
+    }
+}
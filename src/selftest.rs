@@ -0,0 +1,305 @@
+//! `qwertdvert --self-test`: types a known sequence on a virtual keyboard and checks what comes
+//! out of the daemon.
+//!
+//! The test creates a source keyboard through uinput and runs a daemon, with the config given,
+//! that grabs only that keyboard. Every key of the main block is tapped on it, alone, with
+//! Shift and with Ctrl, and the events the daemon's virtual keyboard emits are compared with
+//! what the remapping says they should be. That covers what a real keyboard goes through:
+//! opening and grabbing it, the event loop, the writer thread and the virtual keyboard. The
+//! virtual keyboard is grabbed by the test, so nothing typed reaches the desktop.
+//!
+//! A daemon that is already running would grab the test keyboard itself and type the sequence
+//! into the focused window, so the test refuses to run alongside one.
+
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, Device, EventType, InputEvent, Key};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+
+use crate::config::Config;
+use crate::control::send_command;
+use crate::daemon::{keyboard_layout, Daemon};
+use crate::enumeration::DeviceFilter;
+use crate::error::{ConfigError, DaemonError, OutputError};
+use crate::layout::{ActiveLayout, Dvorak};
+use crate::pipeline::{KeyEvent, Pipeline};
+use crate::remap::ModifierState;
+use crate::xkb::XkbCheck;
+
+// SOURCE_NAME / OUTPUT_NAME: Names of the test keyboard and of the daemon's virtual keyboard
+// during the test, so neither is mistaken for a real one.
+const SOURCE_NAME: &str = "QwertDvert self-test keyboard";
+const OUTPUT_NAME: &str = "QwertDvert self-test";
+
+// Timing
+// SETTLE_TIMEOUT: How long the daemon gets to set up, and the virtual keyboard to go quiet.
+// KEY_DELAY: Pause between injected events, as between real key presses.
+// QUIET: How long the virtual keyboard must stay quiet for the output to be complete.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+const KEY_DELAY: Duration = Duration::from_millis(5);
+const QUIET: Duration = Duration::from_millis(500);
+
+/// The keys tapped, alone and with Shift: the main block of a US keyboard.
+const KEYS: &[Key] = &[
+    Key::KEY_GRAVE,
+    Key::KEY_1,
+    Key::KEY_2,
+    Key::KEY_3,
+    Key::KEY_4,
+    Key::KEY_5,
+    Key::KEY_6,
+    Key::KEY_7,
+    Key::KEY_8,
+    Key::KEY_9,
+    Key::KEY_0,
+    Key::KEY_MINUS,
+    Key::KEY_EQUAL,
+    Key::KEY_Q,
+    Key::KEY_W,
+    Key::KEY_E,
+    Key::KEY_R,
+    Key::KEY_T,
+    Key::KEY_Y,
+    Key::KEY_U,
+    Key::KEY_I,
+    Key::KEY_O,
+    Key::KEY_P,
+    Key::KEY_LEFTBRACE,
+    Key::KEY_RIGHTBRACE,
+    Key::KEY_BACKSLASH,
+    Key::KEY_A,
+    Key::KEY_S,
+    Key::KEY_D,
+    Key::KEY_F,
+    Key::KEY_G,
+    Key::KEY_H,
+    Key::KEY_J,
+    Key::KEY_K,
+    Key::KEY_L,
+    Key::KEY_SEMICOLON,
+    Key::KEY_APOSTROPHE,
+    Key::KEY_Z,
+    Key::KEY_X,
+    Key::KEY_C,
+    Key::KEY_V,
+    Key::KEY_B,
+    Key::KEY_N,
+    Key::KEY_M,
+    Key::KEY_COMMA,
+    Key::KEY_DOT,
+    Key::KEY_SLASH,
+    Key::KEY_SPACE,
+];
+
+/// The keys tapped with Ctrl, to check shortcuts stay where they are on QWERTY.
+const SHORTCUT_KEYS: &[Key] = &[Key::KEY_Z, Key::KEY_X, Key::KEY_C, Key::KEY_V, Key::KEY_A, Key::KEY_S];
+
+/// What the self-test found.
+pub struct SelfTestReport {
+    /// The layout the daemon typed with.
+    pub layout: String,
+    /// Key events typed on the test keyboard.
+    pub typed: usize,
+    /// Where the events the daemon emitted differ from those expected, if anywhere.
+    pub mismatches: Vec<String>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Runs the self-test with `config`'s layout and remapping settings. Errors are those that kept
+/// the daemon from running at all; a daemon that ran but emitted the wrong events, or none,
+/// fails the report instead.
+pub fn self_test(mut config: Config) -> Result<SelfTestReport, DaemonError> {
+    if send_command("status").is_ok() {
+        return Err(ConfigError::InvalidValue {
+            flag: "--self-test",
+            reason: "the daemon is running and would grab the test keyboard; stop it first (systemctl --user stop \
+                     qwertdvert-daemon.service)"
+                .to_string(),
+        }
+        .into());
+    }
+    // Only the test keyboard, opened directly, and nothing that could pause remapping.
+    config.devices = DeviceFilter { names: vec![SOURCE_NAME.to_string()], ..DeviceFilter::default() };
+    config.device_layouts.clear();
+    config.device_helper = None;
+    config.polkit_helper = None;
+    config.virtual_keyboard.name = OUTPUT_NAME.to_string();
+    config.xkb_check = XkbCheck::Off;
+    config.game_apps.clear();
+    #[cfg(feature = "portal")]
+    {
+        config.portal = false;
+    }
+
+    let sequence = sequence();
+    let (layout, expected) = expected_events(&config, &sequence)?;
+
+    let mut source = create_source().map_err(OutputError::TestKeyboard)?;
+    let daemon = Arc::new(Daemon::new(config));
+    let running = daemon.clone();
+    let handle = std::thread::spawn(move || running.run());
+    let mut report = SelfTestReport { layout, typed: sequence.len(), mismatches: Vec::new() };
+
+    let emitted = (|| {
+        let mut output = None;
+        wait_for("the daemon's virtual keyboard", || {
+            output = find_output();
+            output.is_some() || handle.is_finished()
+        })?;
+        wait_for("the daemon to grab the test keyboard", || {
+            daemon.grabbed_keyboards().iter().any(|name| name == SOURCE_NAME) || handle.is_finished()
+        })?;
+        let Some(mut output) = output.filter(|_| !handle.is_finished()) else {
+            return Ok(None);
+        };
+        output.grab().map_err(|e| format!("failed to grab the daemon's virtual keyboard: {e}"))?;
+        for &(key, value) in &sequence {
+            source
+                .emit(&[InputEvent::new(EventType::KEY, key.code(), value)])
+                .map_err(|e| format!("failed to type on the test keyboard: {e}"))?;
+            std::thread::sleep(KEY_DELAY);
+        }
+        read_until_quiet(&mut output).map(Some)
+    })();
+
+    daemon.shutdown();
+    let result = handle.join().unwrap_or(Ok(()));
+    match emitted {
+        Ok(Some(emitted)) => report.mismatches = compare(&expected, &emitted),
+        Ok(None) => {
+            result?;
+            report.mismatches.push("the daemon stopped before anything was typed".to_string());
+        }
+        Err(problem) => report.mismatches.push(problem),
+    }
+    Ok(report)
+}
+
+/// The key events typed on the test keyboard, in order.
+fn sequence() -> Vec<(Key, i32)> {
+    let mut events = Vec::new();
+    let tap = |events: &mut Vec<_>, key| events.extend([(key, 1), (key, 0)]);
+    for &key in KEYS {
+        tap(&mut events, key);
+    }
+    events.push((Key::KEY_LEFTSHIFT, 1));
+    for &key in KEYS {
+        tap(&mut events, key);
+    }
+    events.push((Key::KEY_LEFTSHIFT, 0));
+    events.push((Key::KEY_LEFTCTRL, 1));
+    for &key in SHORTCUT_KEYS {
+        tap(&mut events, key);
+    }
+    events.push((Key::KEY_LEFTCTRL, 0));
+    events
+}
+
+/// The name of the layout `config` types with, and the key events the daemon should emit for
+/// `sequence`, as `config`'s pipeline gives them. Repeats are left out, as the kernel may add
+/// its own.
+fn expected_events(config: &Config, sequence: &[(Key, i32)]) -> Result<(String, Vec<(Key, i32)>), DaemonError> {
+    let shared = Arc::new(ActiveLayout::new(Arc::new(Dvorak), config.keys.clone()));
+    shared.select(&config.layout)?;
+    let modifiers = Arc::new(ModifierState::default());
+    modifiers.set_sticky_keys(config.sticky_keys);
+    let layout = keyboard_layout(config, &shared, SOURCE_NAME, None);
+    let mut pipeline = Pipeline::for_config(config, layout, modifiers);
+    let mut expected = Vec::new();
+    let mut keep = |events: &[KeyEvent]| {
+        let events = events.iter().filter(|event| event.value != 2);
+        expected.extend(events.map(|event| (Key::new(event.code), event.value)));
+    };
+    for &(key, value) in sequence {
+        keep(pipeline.process(KeyEvent::new(key.code(), value)));
+    }
+    // Let tap-hold, combo and macro timers run out, as they do while the output settles.
+    for _ in 0..sequence.len() {
+        let Some(deadline) = pipeline.deadline() else {
+            break;
+        };
+        keep(pipeline.tick(deadline));
+    }
+    Ok((shared.name(), expected))
+}
+
+/// A keyboard with every key the daemon's virtual keyboard has, on a USB bus so the daemon
+/// takes it for a real one.
+fn create_source() -> std::io::Result<VirtualDevice> {
+    let mut keys = AttributeSet::<Key>::new();
+    for code in Key::KEY_ESC.code()..=Key::KEY_MICMUTE.code() {
+        keys.insert(Key::new(code));
+    }
+    VirtualDeviceBuilder::new()?.name(SOURCE_NAME).with_keys(&keys)?.build()
+}
+
+fn find_output() -> Option<Device> {
+    evdev::enumerate()
+        .map(|(_, device)| device)
+        .find(|device| device.name() == Some(OUTPUT_NAME))
+}
+
+/// Polls `ready` until it returns true, failing after the settle timeout.
+fn wait_for(what: &str, mut ready: impl FnMut() -> bool) -> Result<(), String> {
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+    while !ready() {
+        if Instant::now() >= deadline {
+            return Err(format!("timed out waiting for {what}"));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+/// The key events `output` emits until it has been quiet for a while, without repeats.
+fn read_until_quiet(output: &mut Device) -> Result<Vec<(Key, i32)>, String> {
+    let mut events = Vec::new();
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+    loop {
+        // SAFETY: `output` owns the descriptor and outlives the borrow.
+        let fd = unsafe { BorrowedFd::borrow_raw(output.as_raw_fd()) };
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        let timeout = PollTimeout::try_from(QUIET).unwrap_or(PollTimeout::MAX);
+        match poll(&mut fds, timeout) {
+            Ok(0) => return Ok(events),
+            Ok(_) => {}
+            Err(e) => return Err(format!("failed to wait for the daemon's virtual keyboard: {e}")),
+        }
+        let fetched = output.fetch_events().map_err(|e| format!("failed to read the daemon's virtual keyboard: {e}"))?;
+        events.extend(
+            fetched
+                .filter(|event| event.event_type() == EventType::KEY && event.value() != 2)
+                .map(|event| (Key::new(event.code()), event.value())),
+        );
+        if Instant::now() >= deadline {
+            return Err("the daemon's virtual keyboard never went quiet".to_string());
+        }
+    }
+}
+
+/// Where `emitted` differs from `expected`, one line per difference, up to the first few.
+fn compare(expected: &[(Key, i32)], emitted: &[(Key, i32)]) -> Vec<String> {
+    let describe = |event: Option<&(Key, i32)>| match event {
+        Some((key, 1)) => format!("{key:?} pressed"),
+        Some((key, 0)) => format!("{key:?} released"),
+        Some((key, value)) => format!("{key:?} = {value}"),
+        None => "nothing".to_string(),
+    };
+    let mut mismatches: Vec<String> = (0..expected.len().max(emitted.len()))
+        .filter(|&i| expected.get(i) != emitted.get(i))
+        .take(5)
+        .map(|i| format!("event {}: expected {}, got {}", i + 1, describe(expected.get(i)), describe(emitted.get(i))))
+        .collect();
+    if expected.len() != emitted.len() {
+        mismatches.push(format!("expected {} key events, got {}", expected.len(), emitted.len()));
+    }
+    mismatches
+}
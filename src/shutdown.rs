@@ -0,0 +1,129 @@
+//! Asking the daemon's threads to stop.
+//!
+//! The threads block on the request rather than checking a flag at an interval: a thread with
+//! a timer waits on it with [`Shutdown::wait`], and one serving a socket or a bus waits for its
+//! own fd and the request together with [`Shutdown::wait_readable`]. The event loop watches
+//! the request's eventfd with the rest of its fds. With the `tokio` feature, tasks on the event
+//! loop's runtime wait for it with [`Shutdown::wait_async`].
+
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::eventfd::{EfdFlags, EventFd};
+
+/// A request to stop, which any number of threads can wait for.
+pub struct Shutdown {
+    requested: AtomicBool,
+    /// Written on the first request and never read, so it stays readable from then on.
+    fd: EventFd,
+    /// Wakes the tasks waiting in [`Shutdown::wait_async`].
+    #[cfg(feature = "tokio")]
+    notify: tokio::sync::Notify,
+}
+
+impl Shutdown {
+    pub fn new() -> nix::Result<Self> {
+        let fd = EventFd::from_flags(EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+        Ok(Shutdown {
+            requested: AtomicBool::new(false),
+            fd,
+            #[cfg(feature = "tokio")]
+            notify: tokio::sync::Notify::new(),
+        })
+    }
+
+    /// Wakes every thread waiting for the request.
+    pub fn request(&self) {
+        if !self.requested.swap(true, Ordering::Relaxed) {
+            let _ = self.fd.write(1);
+            #[cfg(feature = "tokio")]
+            self.notify.notify_waiters();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// Sleeps for `timeout`, or less if the request comes first. Returns whether it came.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.is_requested() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            self.poll(&[], Some(left));
+        }
+        true
+    }
+
+    /// Like [`wait`](Shutdown::wait), for a task on a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_async(&self, timeout: Duration) -> bool {
+        // Created before the flag is checked, so a request in between still wakes it.
+        let notified = self.notify.notified();
+        if self.is_requested() {
+            return true;
+        }
+        tokio::time::timeout(timeout, notified).await.is_ok() || self.is_requested()
+    }
+
+    /// Waits until one of `fds` is readable, or `timeout` passes if there is one. Returns false
+    /// if the request came first.
+    pub fn wait_readable(&self, fds: &[BorrowedFd<'_>], timeout: Option<Duration>) -> bool {
+        if !self.is_requested() {
+            self.poll(fds, timeout);
+        }
+        !self.is_requested()
+    }
+
+    /// Polls `fds` and the eventfd once; a signal or a failed poll just returns early.
+    fn poll(&self, fds: &[BorrowedFd<'_>], timeout: Option<Duration>) {
+        let mut poll_fds: Vec<PollFd> = fds.iter().map(|fd| PollFd::new(*fd, PollFlags::POLLIN)).collect();
+        poll_fds.push(PollFd::new(self.fd.as_fd(), PollFlags::POLLIN));
+        // Rounded up, so the last few microseconds of a wait aren't spun through.
+        let timeout = match timeout {
+            Some(timeout) => PollTimeout::try_from(timeout.as_micros().div_ceil(1000)).unwrap_or(PollTimeout::MAX),
+            None => PollTimeout::NONE,
+        };
+        match poll(&mut poll_fds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            // Only a bad fd or no memory; better to wake late than to spin.
+            Err(_) => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+}
+
+impl AsFd for Shutdown {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn wait_returns_at_the_timeout_without_a_request() {
+        let shutdown = Shutdown::new().unwrap();
+        let start = Instant::now();
+        assert!(!shutdown.wait(Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn a_request_wakes_a_waiting_thread() {
+        let shutdown = Arc::new(Shutdown::new().unwrap());
+        let waiter = shutdown.clone();
+        let handle = std::thread::spawn(move || (waiter.wait(Duration::from_secs(60)), waiter.wait_readable(&[], None)));
+        std::thread::sleep(Duration::from_millis(20));
+        shutdown.request();
+        assert_eq!(handle.join().unwrap(), (true, false));
+    }
+}
@@ -0,0 +1,129 @@
+//! Where the event loop reads key events from: a keyboard the daemon opened itself, or one
+//! opened and grabbed by the privileged device helper and passed over as a file descriptor.
+
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+
+use evdev::InputEvent;
+
+use crate::enumeration::DeviceId;
+
+// EVIOCGID: the evdev ioctl that reads a device's bus, vendor, product and version.
+nix::ioctl_read!(eviocgid, b'E', 0x02, nix::libc::input_id);
+
+/// A keyboard the event loop reads from.
+pub trait KeyboardSource: AsRawFd + Send {
+    fn name(&self) -> String;
+
+    /// The keyboard's vendor:product ID, if it has one.
+    fn device_id(&self) -> Option<DeviceId> {
+        None
+    }
+
+    /// Takes exclusive access to the keyboard so its events only reach the daemon.
+    fn grab(&mut self) -> io::Result<()>;
+
+    /// Appends whatever events are ready to `out`. Fails with `WouldBlock` if there are none.
+    fn fetch_events(&mut self, out: &mut Vec<InputEvent>) -> io::Result<()>;
+
+    /// Writes `events` to the keyboard, e.g. to set its LEDs. Keyboards that can't take them
+    /// ignore them.
+    fn send_events(&mut self, _events: &[InputEvent]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A keyboard of either kind, as handed to the event loop.
+pub type BoxedKeyboard = Box<dyn KeyboardSource>;
+
+impl KeyboardSource for evdev::Device {
+    fn name(&self) -> String {
+        evdev::Device::name(self).unwrap_or("Unknown").to_string()
+    }
+
+    fn device_id(&self) -> Option<DeviceId> {
+        Some(DeviceId { vendor: self.input_id().vendor(), product: self.input_id().product() })
+    }
+
+    fn grab(&mut self) -> io::Result<()> {
+        evdev::Device::grab(self)
+    }
+
+    fn fetch_events(&mut self, out: &mut Vec<InputEvent>) -> io::Result<()> {
+        out.extend(evdev::Device::fetch_events(self)?);
+        Ok(())
+    }
+
+    fn send_events(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        evdev::Device::send_events(self, events)
+    }
+}
+
+/// A keyboard opened and grabbed by the device helper. The grab belongs to the open file, so
+/// it stays in place after the helper exits.
+pub struct PassedKeyboard {
+    name: String,
+    fd: OwnedFd,
+}
+
+impl PassedKeyboard {
+    pub fn new(name: String, fd: OwnedFd) -> Self {
+        PassedKeyboard { name, fd }
+    }
+
+    /// The keyboard's vendor:product ID.
+    pub fn id(&self) -> io::Result<DeviceId> {
+        let mut id = nix::libc::input_id { bustype: 0, vendor: 0, product: 0, version: 0 };
+        unsafe { eviocgid(self.fd.as_raw_fd(), &mut id) }.map_err(io::Error::from)?;
+        Ok(DeviceId { vendor: id.vendor, product: id.product })
+    }
+}
+
+impl AsRawFd for PassedKeyboard {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl KeyboardSource for PassedKeyboard {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn device_id(&self) -> Option<DeviceId> {
+        self.id().ok()
+    }
+
+    fn grab(&mut self) -> io::Result<()> {
+        // Already grabbed by the helper.
+        Ok(())
+    }
+
+    fn fetch_events(&mut self, out: &mut Vec<InputEvent>) -> io::Result<()> {
+        const BATCH: usize = 64;
+        let mut raw = [nix::libc::input_event {
+            time: nix::libc::timeval { tv_sec: 0, tv_usec: 0 },
+            type_: 0,
+            code: 0,
+            value: 0,
+        }; BATCH];
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(raw.as_mut_ptr().cast::<u8>(), std::mem::size_of_val(&raw))
+        };
+        let read = nix::unistd::read(self.fd.as_raw_fd(), bytes).map_err(io::Error::from)?;
+        if read == 0 {
+            // evdev returns ENODEV once the device is gone; treat EOF the same way.
+            return Err(io::Error::from_raw_os_error(nix::libc::ENODEV));
+        }
+        let count = read / std::mem::size_of::<nix::libc::input_event>();
+        out.extend(raw[..count].iter().map(|event| InputEvent::from(*event)));
+        Ok(())
+    }
+
+    fn send_events(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        let raw: Vec<nix::libc::input_event> = events.iter().map(|event| *event.as_ref()).collect();
+        let bytes = unsafe { std::slice::from_raw_parts(raw.as_ptr().cast::<u8>(), std::mem::size_of_val(&raw[..])) };
+        nix::unistd::write(&self.fd, bytes).map_err(io::Error::from)?;
+        Ok(())
+    }
+}
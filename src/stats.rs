@@ -0,0 +1,422 @@
+//! Counters for the heartbeat and the optional typing-speed figure, and the files in the
+//! runtime directory through which the tray and status output see them.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use hdrhistogram::Histogram;
+use tracing::{error, info, warn};
+
+use crate::event_loop::Periodic;
+use crate::status::state_changed;
+
+// Typing statistics (opt-in via --typing-stats)
+// TYPING_STATS_WINDOW: Rolling window over which key presses are counted.
+const TYPING_STATS_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+// TYPING_STATS_INTERVAL: How often the figure is published for the tray and status output.
+const TYPING_STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+// CHARS_PER_WORD: Conventional word length used to turn keys-per-minute into WPM.
+const CHARS_PER_WORD: usize = 5;
+// TYPING_STATS_FILE: File name under $XDG_RUNTIME_DIR/qwertdvert read by the tray.
+pub const TYPING_STATS_FILE: &str = "typing-stats";
+
+// Latency histogram (opt-in via latency_histogram)
+// LATENCY_MAX_MICROS: The longest latency recorded exactly; anything longer counts as this.
+const LATENCY_MAX_MICROS: u64 = 60_000_000;
+
+// Drop alerts
+// DROP_ALERT_FILE: Written under $XDG_RUNTIME_DIR/qwertdvert when an alert fires, for the tray
+// and status output. It stays until the daemon exits so a brief overload is not missed.
+pub const DROP_ALERT_FILE: &str = "drop-alert";
+
+/// Rolling count of typed keys. Only press timestamps are kept, never key codes.
+#[derive(Default)]
+pub struct TypingStats {
+    presses: VecDeque<Instant>,
+}
+
+impl TypingStats {
+    pub fn record_press(&mut self, now: Instant) {
+        self.presses.push_back(now);
+        self.prune(now);
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(&oldest) = self.presses.front() {
+            if now.duration_since(oldest) <= TYPING_STATS_WINDOW {
+                break;
+            }
+            self.presses.pop_front();
+        }
+    }
+
+    /// Returns key presses in the last minute.
+    pub fn keys_per_minute(&mut self, now: Instant) -> usize {
+        self.prune(now);
+        self.presses.len() * 60 / TYPING_STATS_WINDOW.as_secs() as usize
+    }
+}
+
+/// Presses per output key since the daemon started (opt-in via `key_histogram`). Only the
+/// counts are kept, never the order or timing of keys, so nothing typed can be reconstructed.
+#[derive(Default)]
+pub struct KeyHistogram {
+    counts: BTreeMap<u16, u64>,
+}
+
+impl KeyHistogram {
+    pub fn record_press(&mut self, code: u16) {
+        *self.counts.entry(code).or_default() += 1;
+    }
+
+    /// The counts as a JSON object keyed by key name, most pressed first.
+    pub fn to_json(&self) -> String {
+        let mut counts: Vec<_> = self.counts.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let entries: Vec<String> = counts
+            .into_iter()
+            .map(|(&code, count)| format!("\"{:?}\":{}", evdev::Key::new(code), count))
+            .collect();
+        format!("{{{}}}", entries.join(","))
+    }
+}
+
+/// How long keys take to get through the daemon (opt-in via `latency_histogram`), in
+/// microseconds: `total` from the kernel's timestamp on the keyboard's events to the write to
+/// the virtual keyboard, and `hop` from the event loop queueing them to the writer writing
+/// them.
+pub struct LatencyHistogram {
+    total: Histogram<u64>,
+    hop: Histogram<u64>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        let histogram = || Histogram::new_with_bounds(1, LATENCY_MAX_MICROS, 3).expect("valid histogram bounds");
+        LatencyHistogram { total: histogram(), hop: histogram() }
+    }
+}
+
+impl LatencyHistogram {
+    /// Records a frame written just now that the keyboard stamped `read` and the event loop
+    /// queued at `queued`.
+    pub fn record(&mut self, read: SystemTime, queued: Instant) {
+        self.hop.saturating_record(queued.elapsed().as_micros() as u64);
+        // Sources that don't stamp their events leave the time at zero, far beyond the range.
+        if let Ok(total) = SystemTime::now().duration_since(read)
+            && total.as_micros() <= LATENCY_MAX_MICROS as u128
+        {
+            self.total.saturating_record(total.as_micros() as u64);
+        }
+    }
+
+    /// Percentiles of both, in microseconds, as key=value lines.
+    pub fn report(&self) -> String {
+        let summary = |name: &str, histogram: &Histogram<u64>| {
+            let quantiles = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p999", 0.999)]
+                .map(|(label, quantile)| format!("{name}_{label}_us={}", histogram.value_at_quantile(quantile)));
+            format!("{name}_count={}\n{}\n{name}_max_us={}", histogram.len(), quantiles.join("\n"), histogram.max())
+        };
+        format!("{}\n{}", summary("total", &self.total), summary("hop", &self.hop))
+    }
+
+    /// Both histograms by name, as in the report.
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn stages(&self) -> [(&'static str, &Histogram<u64>); 2] {
+        [("total", &self.total), ("hop", &self.hop)]
+    }
+}
+
+/// Directory for runtime state shared with the tray (`$XDG_RUNTIME_DIR/qwertdvert`).
+pub fn runtime_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("qwertdvert"))
+}
+
+/// Reads the `key=value` lines of a file the daemon publishes in the runtime directory.
+fn read_runtime_file(name: &str) -> Option<BTreeMap<String, String>> {
+    let contents = std::fs::read_to_string(runtime_dir()?.join(name)).ok()?;
+    let fields = contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.trim().to_string()))
+        .collect();
+    Some(fields)
+}
+
+/// Reads the daemon's published typing speed as (keys per minute, words per minute).
+pub fn read_typing_stats() -> Option<(u32, u32)> {
+    let fields = read_runtime_file(TYPING_STATS_FILE)?;
+    Some((fields.get("kpm")?.parse().ok()?, fields.get("wpm")?.parse().ok()?))
+}
+
+/// Reads the daemon's latest drop alert as (events dropped, window in seconds).
+pub fn read_drop_alert() -> Option<(u64, u64)> {
+    let fields = read_runtime_file(DROP_ALERT_FILE)?;
+    Some((fields.get("dropped")?.parse().ok()?, fields.get("window_secs")?.parse().ok()?))
+}
+
+/// Writes the stats file atomically so the tray never reads a partial update.
+fn publish_typing_stats(path: &std::path::Path, keys_per_minute: usize) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(
+        &tmp,
+        format!("kpm={}\nwpm={}\n", keys_per_minute, keys_per_minute / CHARS_PER_WORD),
+    )?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Counters reported by the periodic heartbeat. Event counts are reset at each heartbeat.
+#[derive(Default)]
+pub struct Counters {
+    pub devices_grabbed: AtomicUsize,
+    pub events_processed: AtomicU64,
+    pub events_dropped: AtomicU64,
+    pub failures: AtomicU64,
+    /// Drop alerts raised since the last heartbeat.
+    pub drop_alerts: AtomicU64,
+    /// Events dropped since start; never reset, so the drop alert can measure its own window.
+    pub dropped_total: AtomicU64,
+    /// Events written since start; never reset, unlike `events_processed`.
+    pub processed_total: AtomicU64,
+    /// Failed writes to the virtual keyboard since start; never reset.
+    pub write_failures_total: AtomicU64,
+    /// Times the writer thread has woken up for an event. The systemd watchdog is only fed
+    /// while it moves or the writer is waiting for events.
+    pub writer_wakeups: AtomicU64,
+    /// Set while the writer thread waits for events, so an idle writer isn't taken for a stuck
+    /// one.
+    pub writer_waiting: AtomicBool,
+    /// Names of the grabbed devices, in the order they were grabbed.
+    pub grabbed_names: Mutex<Vec<String>>,
+    /// Events dropped since the last heartbeat, by keyboard and kind of event.
+    pub drops_by_source: Mutex<BTreeMap<(String, DropClass), u64>>,
+    /// Events read since start, by keyboard name; a keyboard plugged back in carries on
+    /// counting where it left off.
+    pub events_read: Mutex<BTreeMap<String, Arc<AtomicU64>>>,
+    /// The latest error the daemon carried on after, for the status file.
+    pub last_error: Mutex<Option<String>>,
+}
+
+impl Counters {
+    /// Counts an event from `device` dropped because the writer fell behind.
+    pub fn record_drop(&self, device: &str, class: DropClass) {
+        self.events_dropped.fetch_add(1, Ordering::Relaxed);
+        self.dropped_total.fetch_add(1, Ordering::Relaxed);
+        *self.drops_by_source.lock().unwrap().entry((device.to_string(), class)).or_default() += 1;
+        state_changed();
+    }
+
+    /// Remembers `error` as the latest one.
+    pub fn record_error(&self, error: &impl std::fmt::Display) {
+        *self.last_error.lock().unwrap() = Some(error.to_string());
+        state_changed();
+    }
+
+    /// The count of events read from `device`, for its keyboard to add to without a lock.
+    pub fn events_read_from(&self, device: &str) -> Arc<AtomicU64> {
+        self.events_read.lock().unwrap().entry(device.to_string()).or_default().clone()
+    }
+}
+
+/// The kinds of event dropped under load. Key presses and releases never are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DropClass {
+    /// Key repeats.
+    Repeat,
+    /// Scancodes and other EV_MSC events.
+    Misc,
+    /// Everything else, e.g. LED or switch events.
+    Other,
+}
+
+impl std::fmt::Display for DropClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DropClass::Repeat => "repeats",
+            DropClass::Misc => "misc events",
+            DropClass::Other => "other events",
+        })
+    }
+}
+
+/// The drops in `drops`, one keyboard at a time, e.g. `Keyboard A: 12 repeats, 4 misc events`.
+fn drop_summary(drops: &BTreeMap<(String, DropClass), u64>) -> String {
+    let mut summary = String::new();
+    let mut last_device = None;
+    for ((device, class), count) in drops {
+        if last_device == Some(device) {
+            summary.push_str(", ");
+        } else {
+            if last_device.is_some() {
+                summary.push_str("; ");
+            }
+            summary.push_str(&format!("{device}: "));
+            last_device = Some(device);
+        }
+        summary.push_str(&format!("{count} {class}"));
+    }
+    summary
+}
+
+/// Keeps `devices_grabbed` and `grabbed_names` accurate however a keyboard is dropped.
+pub struct GrabGuard(Arc<Counters>, String);
+
+impl GrabGuard {
+    pub fn new(counters: Arc<Counters>, name: &str) -> Self {
+        counters.devices_grabbed.fetch_add(1, Ordering::Relaxed);
+        counters.grabbed_names.lock().unwrap().push(name.to_string());
+        state_changed();
+        GrabGuard(counters, name.to_string())
+    }
+}
+
+impl Drop for GrabGuard {
+    fn drop(&mut self) {
+        self.0.devices_grabbed.fetch_sub(1, Ordering::Relaxed);
+        let mut names = self.0.grabbed_names.lock().unwrap();
+        if let Some(index) = names.iter().position(|name| *name == self.1) {
+            names.remove(index);
+        }
+        drop(names);
+        state_changed();
+    }
+}
+
+/// Publishes the typing speed to the runtime directory for the tray, and removes the file at
+/// shutdown.
+pub struct TypingStatsPublisher {
+    stats: Arc<Mutex<TypingStats>>,
+    path: PathBuf,
+}
+
+impl TypingStatsPublisher {
+    /// Publishes the figure straight away. Returns None if there is nowhere to publish it.
+    pub fn new(stats: Arc<Mutex<TypingStats>>) -> Option<Self> {
+        let Some(dir) = runtime_dir() else {
+            warn!("XDG_RUNTIME_DIR is not set; typing statistics will not be published");
+            return None;
+        };
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            warn!("Failed to create {}: {}", dir.display(), e);
+            return None;
+        }
+        let mut publisher = TypingStatsPublisher { stats, path: dir.join(TYPING_STATS_FILE) };
+        publisher.tick();
+        Some(publisher)
+    }
+}
+
+impl Periodic for TypingStatsPublisher {
+    fn interval(&self) -> std::time::Duration {
+        TYPING_STATS_INTERVAL
+    }
+
+    fn tick(&mut self) {
+        let kpm = self.stats.lock().unwrap().keys_per_minute(Instant::now());
+        if let Err(e) = publish_typing_stats(&self.path, kpm) {
+            error!("Failed to write typing statistics to {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn finish(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Heartbeat: a compact health summary so long-running problems show up in the journal.
+pub struct Heartbeat {
+    pub interval: std::time::Duration,
+    pub counters: Arc<Counters>,
+}
+
+impl Periodic for Heartbeat {
+    fn interval(&self) -> std::time::Duration {
+        self.interval
+    }
+
+    fn tick(&mut self) {
+        let Heartbeat { interval, counters } = self;
+        let devices = counters.devices_grabbed.load(Ordering::Relaxed);
+        let processed = counters.events_processed.swap(0, Ordering::Relaxed);
+        let dropped = counters.events_dropped.swap(0, Ordering::Relaxed);
+        let failures = counters.failures.swap(0, Ordering::Relaxed);
+        let drop_alerts = counters.drop_alerts.swap(0, Ordering::Relaxed);
+        info!(
+            devices_grabbed = devices, events_processed = processed, events_dropped = dropped, failures = failures,
+            drop_alerts = drop_alerts,
+            "Heartbeat: {} devices grabbed, {} events processed, {} dropped ({} drop alerts), {} failures in the last {} min",
+            devices, processed, dropped, drop_alerts, failures, interval.as_secs() / 60
+        );
+        // Which keyboards and events the drops came from, to tell whether the buffer or
+        // a single busy keyboard is the problem.
+        let drops = std::mem::take(&mut *counters.drops_by_source.lock().unwrap());
+        if !drops.is_empty() {
+            info!("Dropped in the last {} min: {}", interval.as_secs() / 60, drop_summary(&drops));
+        }
+    }
+}
+
+/// Warns when more than `threshold` events are dropped within one `window`, which means the
+/// event buffer is too small for the load (autorepeat goes missing first). Each alert is
+/// logged, counted in `drop_alerts`, and published for the tray; the file is removed at
+/// shutdown.
+pub struct DropAlert {
+    threshold: u64,
+    window: std::time::Duration,
+    counters: Arc<Counters>,
+    path: Option<PathBuf>,
+    /// `dropped_total` at the start of the window.
+    dropped_before: u64,
+}
+
+impl DropAlert {
+    pub fn new(threshold: u64, window: std::time::Duration, counters: Arc<Counters>) -> Self {
+        let path = runtime_dir()
+            .filter(|dir| std::fs::create_dir_all(dir).is_ok())
+            .map(|dir| dir.join(DROP_ALERT_FILE));
+        let dropped_before = counters.dropped_total.load(Ordering::Relaxed);
+        DropAlert { threshold, window, counters, path, dropped_before }
+    }
+}
+
+impl Periodic for DropAlert {
+    fn interval(&self) -> std::time::Duration {
+        self.window
+    }
+
+    fn tick(&mut self) {
+        let DropAlert { threshold, window, counters, path, dropped_before } = self;
+        let total = counters.dropped_total.load(Ordering::Relaxed);
+        let dropped = total - *dropped_before;
+        if dropped > *threshold {
+            counters.drop_alerts.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                events_dropped = dropped, window_secs = window.as_secs(),
+                "Dropped {} events in the last {} s (alert threshold {}); the output device is not keeping up and autorepeat will be missing",
+                dropped, window.as_secs(), threshold
+            );
+            if let Some(path) = path
+                && let Err(e) = publish_drop_alert(path, dropped, *window)
+            {
+                error!("Failed to write drop alert to {}: {}", path.display(), e);
+            }
+        }
+        *dropped_before = total;
+    }
+
+    fn finish(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Writes the latest alert atomically, like the typing stats file.
+fn publish_drop_alert(path: &std::path::Path, dropped: u64, window: std::time::Duration) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, format!("dropped={}\nwindow_secs={}\n", dropped, window.as_secs()))?;
+    std::fs::rename(&tmp, path)
+}
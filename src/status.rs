@@ -0,0 +1,159 @@
+//! The status file: the daemon's state as JSON in `$XDG_RUNTIME_DIR/qwertdvert/status.json`,
+//! for bars like waybar and scripts that want it without asking over the control socket.
+//!
+//! ```json
+//! {"layout":"dvorak","paused":false,"devices":["AT Translated Set 2 keyboard"],"last_error":null}
+//! ```
+//!
+//! `last_error` is the latest error the daemon carried on after, such as a keyboard that went
+//! away or a failed write to the virtual keyboard. The file is replaced whole whenever
+//! something in it changes, so it is never read half written, and removed when the daemon
+//! exits. Whatever changes the state calls [`state_changed`], which wakes the thread writing
+//! the file.
+
+use std::os::fd::{AsFd, BorrowedFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+
+use nix::sys::eventfd::{EfdFlags, EventFd};
+use tracing::{error, warn};
+
+use crate::layout::ActiveLayout;
+use crate::logging::json_string;
+use crate::shutdown::Shutdown;
+use crate::stats::{runtime_dir, Counters};
+
+// STATUS_FILE: File name under $XDG_RUNTIME_DIR/qwertdvert.
+pub const STATUS_FILE: &str = "status.json";
+
+/// Daemon state the status file reports.
+pub struct StatusState {
+    pub layout: Arc<ActiveLayout>,
+    pub paused: Arc<AtomicBool>,
+    pub counters: Arc<Counters>,
+}
+
+impl StatusState {
+    /// The file's contents for the state as it is now.
+    fn to_json(&self) -> String {
+        let devices: Vec<String> =
+            self.counters.grabbed_names.lock().unwrap().iter().map(|name| json_string(name)).collect();
+        let last_error = match &*self.counters.last_error.lock().unwrap() {
+            Some(error) => json_string(error),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"layout\":{},\"paused\":{},\"devices\":[{}],\"last_error\":{}}}\n",
+            json_string(&self.layout.name()),
+            self.paused.load(Ordering::Relaxed),
+            devices.join(","),
+            last_error
+        )
+    }
+}
+
+/// The eventfds of every `StateWatch`, written by `state_changed`.
+static WATCHERS: Mutex<Vec<Weak<EventFd>>> = Mutex::new(Vec::new());
+
+/// Wakes whatever reports the daemon's state after it changes: remapping paused or resumed, a
+/// layout switched to, a keyboard grabbed or released, an error or a dropped event.
+pub(crate) fn state_changed() {
+    WATCHERS.lock().unwrap().retain(|watcher| match watcher.upgrade() {
+        Some(fd) => {
+            let _ = fd.write(1);
+            true
+        }
+        None => false,
+    });
+}
+
+/// An eventfd that becomes readable whenever the daemon's state changes.
+pub(crate) struct StateWatch(Arc<EventFd>);
+
+impl StateWatch {
+    pub(crate) fn new() -> nix::Result<Self> {
+        let fd = Arc::new(EventFd::from_flags(EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?);
+        WATCHERS.lock().unwrap().push(Arc::downgrade(&fd));
+        Ok(StateWatch(fd))
+    }
+
+    /// Resets the eventfd, before the state is read, so a change made while it is read wakes
+    /// the watcher again.
+    pub(crate) fn clear(&self) {
+        let _ = self.0.read();
+    }
+}
+
+impl AsFd for StateWatch {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+/// Keeps the status file up to date until shutdown, then removes it. Returns None if there
+/// is nowhere to write it.
+pub(crate) fn spawn_status_publisher(state: StatusState, shutdown: Arc<Shutdown>) -> Option<JoinHandle<()>> {
+    let Some(dir) = runtime_dir() else {
+        warn!("XDG_RUNTIME_DIR is not set; the status file will not be written");
+        return None;
+    };
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        warn!("Failed to create {}: {}", dir.display(), e);
+        return None;
+    }
+    let watch = match StateWatch::new() {
+        Ok(watch) => watch,
+        Err(e) => {
+            warn!("Failed to watch the daemon's state; the status file will not be written: {}", e);
+            return None;
+        }
+    };
+    let path = dir.join(STATUS_FILE);
+    Some(std::thread::spawn(move || {
+        let mut last = None;
+        loop {
+            watch.clear();
+            let current = state.to_json();
+            if last.as_ref() != Some(&current) {
+                if let Err(e) = publish(&path, &current) {
+                    error!("Failed to write the status file {}: {}", path.display(), e);
+                }
+                last = Some(current);
+            }
+            if !shutdown.wait_readable(&[watch.as_fd()], None) {
+                break;
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+    }))
+}
+
+/// Replaces the file atomically, like the typing stats file.
+fn publish(path: &Path, contents: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+
+    fn readable(watch: &StateWatch) -> bool {
+        let mut fds = [PollFd::new(watch.as_fd(), PollFlags::POLLIN)];
+        poll(&mut fds, PollTimeout::ZERO).unwrap() > 0
+    }
+
+    #[test]
+    fn a_state_change_wakes_every_watch() {
+        let (first, second) = (StateWatch::new().unwrap(), StateWatch::new().unwrap());
+        // Other tests change the state too, so only a wake can be relied on, not its absence.
+        first.clear();
+        second.clear();
+        state_changed();
+        assert!(readable(&first) && readable(&second));
+    }
+}
@@ -0,0 +1,127 @@
+//! Sticky keys: a modifier tapped on its own stays down for the next key, so shortcuts can
+//! be typed one key at a time, e.g. Ctrl then C.
+//!
+//! A tapped modifier's release is held back until a key pressed after it is released.
+//! Tapping it again before then releases it instead. Modifiers held down while typing work as
+//! usual. Whether sticky keys are on is part of the shared [`ModifierState`], so it can be
+//! switched at runtime for every keyboard at once.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use evdev::Key;
+
+use crate::pipeline::{KeyEvent, Stage};
+use crate::remap::{is_modifier, ModifierState};
+
+/// Modmap stage that latches tapped modifiers while sticky keys are on.
+pub struct StickyKeys {
+    modifiers: Arc<ModifierState>,
+    /// Modifiers pressed with no other key pressed since, by code.
+    tapping: BTreeSet<u16>,
+    /// Modifiers tapped and held down for the next key, by code.
+    latched: BTreeSet<u16>,
+    /// Keys pressed while modifiers are latched and not yet released, by code.
+    using: BTreeSet<u16>,
+}
+
+impl StickyKeys {
+    pub fn new(modifiers: Arc<ModifierState>) -> Self {
+        StickyKeys {
+            modifiers,
+            tapping: BTreeSet::new(),
+            latched: BTreeSet::new(),
+            using: BTreeSet::new(),
+        }
+    }
+
+    /// Releases every latched modifier.
+    fn unlatch(&mut self, out: &mut Vec<KeyEvent>) {
+        out.extend(std::mem::take(&mut self.latched).into_iter().map(|code| KeyEvent::new(code, 0)));
+    }
+}
+
+impl Stage for StickyKeys {
+    fn process(&mut self, event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        if !self.modifiers.sticky_keys() {
+            self.tapping.clear();
+            self.using.clear();
+            self.unlatch(out);
+            out.push(event);
+            return;
+        }
+        if is_modifier(Key::new(event.code)) {
+            match event.value {
+                // Pressing a latched modifier again takes over from the latch; it is down already.
+                1 if self.latched.remove(&event.code) => return,
+                1 => {
+                    self.tapping.insert(event.code);
+                }
+                0 if self.tapping.remove(&event.code) => {
+                    self.latched.insert(event.code);
+                    return;
+                }
+                _ => {}
+            }
+            out.push(event);
+            return;
+        }
+        match event.value {
+            1 => {
+                self.tapping.clear();
+                if !self.latched.is_empty() {
+                    self.using.insert(event.code);
+                }
+                out.push(event);
+            }
+            0 => {
+                out.push(event);
+                if self.using.remove(&event.code) && self.using.is_empty() {
+                    self.unlatch(out);
+                }
+            }
+            _ => out.push(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::run;
+
+    const CTRL: u16 = Key::KEY_LEFTCTRL.code();
+    const C: u16 = Key::KEY_C.code();
+    const V: u16 = Key::KEY_V.code();
+
+    fn sticky(enabled: bool) -> (StickyKeys, Arc<ModifierState>) {
+        let modifiers = Arc::new(ModifierState::default());
+        modifiers.set_sticky_keys(enabled);
+        (StickyKeys::new(modifiers.clone()), modifiers)
+    }
+
+    #[test]
+    fn a_tapped_modifier_latches_for_the_next_key_only() {
+        let (mut stage, _) = sticky(true);
+        assert_eq!(run(&mut stage, &[(CTRL, 1), (CTRL, 0)]), [(CTRL, 1)]);
+        assert_eq!(run(&mut stage, &[(C, 1), (C, 0)]), [(C, 1), (C, 0), (CTRL, 0)]);
+        assert_eq!(run(&mut stage, &[(V, 1), (V, 0)]), [(V, 1), (V, 0)]);
+    }
+
+    #[test]
+    fn tapping_a_latched_modifier_again_releases_it() {
+        let (mut stage, _) = sticky(true);
+        assert_eq!(run(&mut stage, &[(CTRL, 1), (CTRL, 0), (CTRL, 1), (CTRL, 0)]), [(CTRL, 1), (CTRL, 0)]);
+        assert_eq!(run(&mut stage, &[(C, 1), (C, 0)]), [(C, 1), (C, 0)]);
+    }
+
+    #[test]
+    fn turning_sticky_keys_off_stops_latching() {
+        let (mut stage, modifiers) = sticky(true);
+        assert_eq!(run(&mut stage, &[(CTRL, 1), (CTRL, 0)]), [(CTRL, 1)]);
+        assert!(modifiers.set_sticky_keys(false));
+        // The latch is let go with the next key event.
+        assert_eq!(run(&mut stage, &[(C, 1), (C, 0)]), [(CTRL, 0), (C, 1), (C, 0)]);
+        assert_eq!(run(&mut stage, &[(CTRL, 1), (CTRL, 0), (C, 1)]), [(CTRL, 1), (CTRL, 0), (C, 1)]);
+    }
+}
@@ -0,0 +1,216 @@
+//! Dual-function keys: a key that acts as a modifier while held and types something else when
+//! tapped, e.g. Space as Shift, or a tapped Left Shift typing "(" (space-cadet shift).
+//!
+//! A press of an overloaded key is held back until it is decided. It is a tap if the key is
+//! released within the tapping term and before any key pressed after it is released; it is a
+//! hold once the tapping term passes or a key pressed after it is released while it is still
+//! down (permissive hold). Keys pressed in the meantime are held back too and replayed after
+//! the decision, so they see the right modifier.
+
+use std::time::{Duration, Instant};
+
+use evdev::Key;
+
+use crate::pipeline::{KeyEvent, Stage};
+use crate::remap::RemapRule;
+
+// DEFAULT_TAPPING_TERM: How long an overloaded key may be held and still count as a tap.
+pub const DEFAULT_TAPPING_TERM: Duration = Duration::from_millis(200);
+
+/// What an overloaded key does.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Overload {
+    /// The physical key.
+    pub key: Key,
+    /// Output while the key is held, normally a modifier.
+    pub hold: Key,
+    /// Output typed when the key is tapped.
+    pub tap: Key,
+    /// Type `tap` with Shift held, e.g. KEY_9 for "(".
+    pub tap_shifted: bool,
+}
+
+/// An overloaded key pressed but not yet decided.
+struct Pending {
+    overload: usize,
+    deadline: Instant,
+}
+
+/// Modmap stage that resolves overloaded keys into their tap or hold output.
+pub struct TapHold {
+    overloads: Vec<Overload>,
+    tapping_term: Duration,
+    pending: Option<Pending>,
+    /// Events that arrived while a key was pending, replayed once it is decided.
+    buffer: Vec<KeyEvent>,
+    /// Overloaded keys currently acting as their hold output, by physical code.
+    holding: Vec<(u16, u16)>,
+}
+
+impl TapHold {
+    pub fn new(overloads: Vec<Overload>, tapping_term: Duration) -> Self {
+        TapHold {
+            overloads,
+            tapping_term,
+            pending: None,
+            buffer: Vec::new(),
+            holding: Vec::new(),
+        }
+    }
+
+    /// Decides the pending key as a hold and replays what was held back.
+    fn hold(&mut self, out: &mut Vec<KeyEvent>) {
+        if let Some(pending) = self.pending.take() {
+            let overload = &self.overloads[pending.overload];
+            self.holding.push((overload.key.code(), overload.hold.code()));
+            out.push(KeyEvent::new(overload.hold.code(), 1));
+            self.replay(out);
+        }
+    }
+
+    /// Decides the pending key as a tap and replays what was held back.
+    fn tap(&mut self, out: &mut Vec<KeyEvent>) {
+        if let Some(pending) = self.pending.take() {
+            let overload = &self.overloads[pending.overload];
+            let event = |code: u16, value| KeyEvent { rule: Some(RemapRule::Overload), ..KeyEvent::new(code, value) };
+            if overload.tap_shifted {
+                out.push(event(Key::KEY_LEFTSHIFT.code(), 1));
+            }
+            out.push(event(overload.tap.code(), 1));
+            out.push(event(overload.tap.code(), 0));
+            if overload.tap_shifted {
+                out.push(event(Key::KEY_LEFTSHIFT.code(), 0));
+            }
+            self.replay(out);
+        }
+    }
+
+    /// Runs the held-back events through again; one of them may start a new pending key.
+    fn replay(&mut self, out: &mut Vec<KeyEvent>) {
+        for event in std::mem::take(&mut self.buffer) {
+            self.process(event, out);
+        }
+    }
+}
+
+impl Stage for TapHold {
+    fn process(&mut self, event: KeyEvent, out: &mut Vec<KeyEvent>) {
+        if let Some(pending) = &self.pending {
+            if event.code == self.overloads[pending.overload].key.code() {
+                if event.value == 0 {
+                    self.tap(out);
+                }
+                // Autorepeat of an undecided key is dropped.
+                return;
+            }
+            let released_after_press =
+                event.value == 0 && self.buffer.iter().any(|earlier| earlier.code == event.code && earlier.value == 1);
+            self.buffer.push(event);
+            if released_after_press {
+                self.hold(out);
+            }
+            return;
+        }
+
+        if let Some(index) = self.holding.iter().position(|&(key, _)| key == event.code) {
+            if event.value == 0 {
+                let (_, hold) = self.holding.remove(index);
+                out.push(KeyEvent::new(hold, 0));
+            }
+            // Modifiers don't need autorepeat.
+            return;
+        }
+
+        if event.value == 1
+            && let Some(overload) = self.overloads.iter().position(|overload| overload.key.code() == event.code)
+        {
+            self.pending = Some(Pending { overload, deadline: Instant::now() + self.tapping_term });
+            return;
+        }
+        out.push(event);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.deadline)
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<KeyEvent>) {
+        if self.pending.as_ref().is_some_and(|pending| pending.deadline <= now) {
+            self.hold(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{run, tick};
+
+    const SPACE: u16 = Key::KEY_SPACE.code();
+    const SHIFT: u16 = Key::KEY_LEFTSHIFT.code();
+    const NINE: u16 = Key::KEY_9.code();
+    const A: u16 = Key::KEY_A.code();
+
+    /// Space as Shift when held, and Left Shift typing "(" when tapped.
+    fn tap_hold() -> TapHold {
+        TapHold::new(
+            vec![
+                Overload { key: Key::KEY_SPACE, hold: Key::KEY_LEFTSHIFT, tap: Key::KEY_SPACE, tap_shifted: false },
+                Overload { key: Key::KEY_LEFTSHIFT, hold: Key::KEY_LEFTSHIFT, tap: Key::KEY_9, tap_shifted: true },
+            ],
+            DEFAULT_TAPPING_TERM,
+        )
+    }
+
+    fn tick_past_deadline(stage: &mut TapHold) -> Vec<(u16, i32)> {
+        let deadline = stage.deadline().expect("a key is pending");
+        tick(stage, deadline)
+    }
+
+    #[test]
+    fn a_quick_tap_types_the_tap_key() {
+        let mut stage = tap_hold();
+        assert_eq!(run(&mut stage, &[(SPACE, 1)]), []);
+        assert!(stage.deadline().is_some());
+        assert_eq!(run(&mut stage, &[(SPACE, 0)]), [(SPACE, 1), (SPACE, 0)]);
+        assert_eq!(stage.deadline(), None);
+
+        assert_eq!(run(&mut stage, &[(SHIFT, 1), (SHIFT, 0)]), [(SHIFT, 1), (NINE, 1), (NINE, 0), (SHIFT, 0)]);
+    }
+
+    #[test]
+    fn holding_past_the_tapping_term_holds_the_modifier() {
+        for key in [SPACE, SHIFT] {
+            let mut stage = tap_hold();
+            assert_eq!(run(&mut stage, &[(key, 1)]), []);
+            assert_eq!(tick_past_deadline(&mut stage), [(SHIFT, 1)]);
+            assert_eq!(run(&mut stage, &[(A, 1), (A, 0), (key, 2)]), [(A, 1), (A, 0)]);
+            assert_eq!(run(&mut stage, &[(key, 0)]), [(SHIFT, 0)]);
+        }
+    }
+
+    #[test]
+    fn a_key_tapped_while_undecided_makes_it_a_hold() {
+        for key in [SPACE, SHIFT] {
+            let mut stage = tap_hold();
+            assert_eq!(run(&mut stage, &[(key, 1), (A, 1)]), []);
+            assert_eq!(run(&mut stage, &[(A, 0)]), [(SHIFT, 1), (A, 1), (A, 0)]);
+            assert_eq!(stage.deadline(), None);
+            assert_eq!(run(&mut stage, &[(key, 0)]), [(SHIFT, 0)]);
+        }
+    }
+
+    #[test]
+    fn releasing_first_is_still_a_tap_before_the_interrupting_key() {
+        let mut stage = tap_hold();
+        assert_eq!(run(&mut stage, &[(SPACE, 1), (A, 1)]), []);
+        assert_eq!(run(&mut stage, &[(SPACE, 0)]), [(SPACE, 1), (SPACE, 0), (A, 1)]);
+        assert_eq!(run(&mut stage, &[(A, 0)]), [(A, 0)]);
+
+        let mut stage = tap_hold();
+        assert_eq!(
+            run(&mut stage, &[(SHIFT, 1), (A, 1), (SHIFT, 0)]),
+            [(SHIFT, 1), (NINE, 1), (NINE, 0), (SHIFT, 0), (A, 1)]
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! OpenTelemetry export of per-event pipeline spans (capture, transform, write).
+//!
+//! Enabled with the `otel` cargo feature. The exporter follows the standard
+//! `OTEL_EXPORTER_OTLP_*` environment variables (default endpoint http://localhost:4318).
+
+use std::time::SystemTime;
+
+use opentelemetry::trace::{Span, TraceContextExt, Tracer, TracerProvider};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+
+/// The in-flight root span for one key event and its context for child spans.
+pub struct EventTrace {
+    context: Context,
+}
+
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+    tracer: SdkTracer,
+}
+
+impl Telemetry {
+    pub fn init() -> Result<Telemetry, opentelemetry_otlp::ExporterBuildError> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder().with_http().build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                opentelemetry_sdk::Resource::builder()
+                    .with_service_name("qwertdvert")
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer("qwertdvert");
+        Ok(Telemetry { provider, tracer })
+    }
+
+    /// Records the capture (kernel timestamp to read) and transform spans for a key event.
+    #[allow(clippy::too_many_arguments)]
+    pub fn key_event(
+        &self,
+        device: &str,
+        kernel_time: SystemTime,
+        read_at: SystemTime,
+        transform_start: SystemTime,
+        transform_end: SystemTime,
+        input_code: u16,
+        output_code: u16,
+        rule: &str,
+    ) -> EventTrace {
+        let root = self
+            .tracer
+            .span_builder("key_event")
+            .with_start_time(kernel_time)
+            .with_attributes([
+                KeyValue::new("device", device.to_string()),
+                KeyValue::new("input_code", i64::from(input_code)),
+                KeyValue::new("output_code", i64::from(output_code)),
+            ])
+            .start(&self.tracer);
+        let context = Context::current_with_span(root);
+
+        self.child(&context, "capture", kernel_time, read_at, Vec::new());
+        self.child(
+            &context,
+            "transform",
+            transform_start,
+            transform_end,
+            vec![KeyValue::new("rule", rule.to_string())],
+        );
+        EventTrace { context }
+    }
+
+    /// Records the write span and closes the event's root span.
+    pub fn written(&self, trace: EventTrace, write_start: SystemTime, write_end: SystemTime, ok: bool) {
+        self.child(
+            &trace.context,
+            "write",
+            write_start,
+            write_end,
+            vec![KeyValue::new("ok", ok)],
+        );
+        trace.context.span().end_with_timestamp(write_end);
+    }
+
+    fn child(&self, parent: &Context, name: &'static str, start: SystemTime, end: SystemTime, attributes: Vec<KeyValue>) {
+        let mut span = self
+            .tracer
+            .span_builder(name)
+            .with_start_time(start)
+            .with_attributes(attributes)
+            .start_with_context(&self.tracer, parent);
+        span.set_attribute(KeyValue::new(
+            "latency_us",
+            end.duration_since(start).map(|d| d.as_micros() as i64).unwrap_or(0),
+        ));
+        span.end_with_timestamp(end);
+    }
+
+    pub fn shutdown(&self) {
+        if let Err(e) = self.provider.shutdown() {
+            tracing::warn!("Failed to flush OpenTelemetry spans: {e}");
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Reloading the config file when it is saved.
+//!
+//! The directory is watched rather than the file, since many editors save by writing a new
+//! file and renaming it over the old one, and so that a config file created after the daemon
+//! started is noticed too. A save usually arrives as several events (truncate, write, close),
+//! so a reload waits until the file has been left alone for `SETTLE_TIME`.
+
+use std::os::fd::AsFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use tracing::warn;
+
+use crate::shutdown::Shutdown;
+
+// SETTLE_TIME: How long the file must go unchanged after an event before it is reloaded.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Calls `on_change` each time the file at `path` is written, created or replaced, until
+/// shutdown. Deleting the file is not a change; the running config stays as it is. Fails if
+/// the file's directory can't be watched, e.g. because it doesn't exist.
+pub fn spawn_config_watcher(
+    path: PathBuf,
+    shutdown: Arc<Shutdown>,
+    mut on_change: impl FnMut() + Send + 'static,
+) -> nix::Result<JoinHandle<()>> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(Errno::EINVAL);
+    };
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)?;
+    inotify.add_watch(
+        dir,
+        AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_MOVED_TO,
+    )?;
+    let name = name.to_os_string();
+    Ok(std::thread::spawn(move || {
+        // When the file last changed, if it has changed since it was last reloaded.
+        let mut changed_at: Option<Instant> = None;
+        loop {
+            // Once the file has changed, wait no longer than it has left to settle.
+            let timeout = changed_at.map(|at| SETTLE_TIME.saturating_sub(at.elapsed()));
+            if !shutdown.wait_readable(&[inotify.as_fd()], timeout) {
+                break;
+            }
+            let events = match inotify.read_events() {
+                Ok(events) => events,
+                Err(Errno::EAGAIN) => Vec::new(),
+                Err(e) => {
+                    warn!("Failed to read config file changes; it will no longer be reloaded when saved: {}", e);
+                    return;
+                }
+            };
+            if events.iter().any(|event| event.name.as_ref() == Some(&name)) {
+                changed_at = Some(Instant::now());
+            }
+            if changed_at.is_some_and(|at| at.elapsed() >= SETTLE_TIME) {
+                changed_at = None;
+                on_change();
+            }
+        }
+    }))
+}
@@ -0,0 +1,134 @@
+//! Checking that the desktop types QWERTY, as the daemon assumes.
+//!
+//! The layouts map QWERTY key positions to Dvorak letters and send the QWERTY keys that type
+//! them. If the desktop's own keyboard layout is Dvorak already, every letter is remapped
+//! twice and comes out as garbage. At startup the desktop's layouts are looked up where they
+//! are usually kept: KDE's `kxkbrc`, GNOME's input sources, and the system-wide X11 keymap
+//! from systemd-localed. None of them is authoritative on every desktop, so a match is a
+//! warning (or, if asked, a pause) rather than a hard error.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Looks up a desktop's layouts, if it keeps them in that place.
+type LayoutSource = fn() -> Option<Vec<String>>;
+
+// SOURCES: Where desktops keep their layouts, most specific first.
+const SOURCES: [(&str, LayoutSource); 3] = [
+    ("KDE's keyboard settings", kde_layouts),
+    ("GNOME's input sources", gnome_layouts),
+    ("the system keymap", system_layouts),
+];
+
+/// What to do when the desktop's layout looks like Dvorak already.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum XkbCheck {
+    /// Log a warning and remap as usual.
+    #[default]
+    Warn,
+    /// Log a warning and start with remapping paused.
+    Pause,
+    /// Don't look.
+    Off,
+}
+
+impl std::str::FromStr for XkbCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(XkbCheck::Warn),
+            "pause" => Ok(XkbCheck::Pause),
+            "off" => Ok(XkbCheck::Off),
+            other => Err(format!("xkb_check must be 'warn', 'pause' or 'off', not '{other}'")),
+        }
+    }
+}
+
+/// Whether an XKB layout or variant name is some kind of Dvorak, e.g. `dvorak`,
+/// `dvorak-intl` or `dvp` (Programmer Dvorak).
+fn is_dvorak(name: &str) -> bool {
+    name.contains("dvorak") || name == "dvp"
+}
+
+/// Pairs comma-separated XKB layouts with their variants, as `layout(variant)`.
+fn layouts(layouts: &str, variants: &str) -> Vec<String> {
+    let mut variants = variants.split(',');
+    layouts
+        .split(',')
+        .filter(|layout| !layout.is_empty())
+        .map(|layout| match variants.next().filter(|variant| !variant.is_empty()) {
+            Some(variant) => format!("{layout}({variant})"),
+            None => layout.to_string(),
+        })
+        .collect()
+}
+
+/// The layouts KDE Plasma switches between, if it manages them.
+fn kde_layouts() -> Option<Vec<String>> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    let text = std::fs::read_to_string(dir.join("kxkbrc")).ok()?;
+    let setting = |name: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .unwrap_or("")
+    };
+    (setting("Use") == "true").then(|| layouts(setting("LayoutList"), setting("VariantList")))
+}
+
+/// The layouts in GNOME's input sources, e.g. `[('xkb', 'us+dvorak')]`, when running under
+/// GNOME.
+fn gnome_layouts() -> Option<Vec<String>> {
+    if !std::env::var("XDG_CURRENT_DESKTOP").is_ok_and(|desktop| desktop.split(':').any(|name| name == "GNOME")) {
+        return None;
+    }
+    let output = Command::new("gsettings")
+        .args(["get", "org.gnome.desktop.input-sources", "sources"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let sources = String::from_utf8_lossy(&output.stdout);
+    let layouts = sources
+        .split("('xkb', '")
+        .skip(1)
+        .filter_map(|source| source.split('\'').next())
+        .map(|source| match source.split_once('+') {
+            Some((layout, variant)) => format!("{layout}({variant})"),
+            None => source.to_string(),
+        })
+        .collect();
+    Some(layouts)
+}
+
+/// The system-wide X11 keymap from systemd-localed, which desktops without their own layout
+/// settings use.
+#[cfg(feature = "dbus")]
+fn system_layouts() -> Option<Vec<String>> {
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+
+    let connection = dbus::blocking::Connection::new_system().ok()?;
+    let locale = connection.with_proxy(
+        "org.freedesktop.locale1",
+        "/org/freedesktop/locale1",
+        std::time::Duration::from_secs(5),
+    );
+    let layout: String = locale.get("org.freedesktop.locale1", "X11Layout").ok()?;
+    let variant: String = locale.get("org.freedesktop.locale1", "X11Variant").ok()?;
+    Some(layouts(&layout, &variant))
+}
+
+#[cfg(not(feature = "dbus"))]
+fn system_layouts() -> Option<Vec<String>> {
+    None
+}
+
+/// The Dvorak layout set for the desktop, and where, if there is one. The first place that
+/// has layouts at all is the one the desktop uses.
+pub fn dvorak_layout() -> Option<(&'static str, String)> {
+    let (source, layouts) = SOURCES.into_iter().find_map(|(source, find)| Some((source, find()?)))?;
+    let layout = layouts.into_iter().find(|layout| layout.split(['(', ')']).any(is_dvorak))?;
+    Some((source, layout))
+}
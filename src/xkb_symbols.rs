@@ -0,0 +1,383 @@
+//! Translating an XKB symbols file, as X11 and Wayland desktops keep their layouts in, into a
+//! layout, for `qwertdvert import-layout --format xkb`.
+//!
+//! An XKB layout says which symbol each key position types at each level: `key <AD01> { [ q, Q
+//! ] };` makes the first letter key type q, and Q with Shift. The daemon types on a desktop set
+//! to US QWERTY, so each symbol is looked up on the US layout to find the key, and whether
+//! Shift, that types it there. The first level becomes the layout's `[keys]` and the second
+//! `[shifted]`, where it is not simply the first level shifted. Symbols a US layout has no key for
+//! (accented letters, dead keys) and the AltGr levels are left out and listed.
+//!
+//! Files are made of `xkb_symbols "variant" { ... };` blocks, which often start by including
+//! another (`include "latin"`); includes are read from the same directory and applied first.
+
+use std::collections::BTreeMap;
+
+use evdev::Key;
+
+use crate::import::{Table, Translation};
+use crate::layout::{key_name, Action};
+
+// Includes nested deeper than this are taken to be a loop.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// The key at an XKB key position, for the positions of the main block.
+fn position_key(name: &str) -> Option<Key> {
+    const ROWS: [(&str, &[Key]); 4] = [
+        (
+            "AE",
+            &[
+                Key::KEY_1,
+                Key::KEY_2,
+                Key::KEY_3,
+                Key::KEY_4,
+                Key::KEY_5,
+                Key::KEY_6,
+                Key::KEY_7,
+                Key::KEY_8,
+                Key::KEY_9,
+                Key::KEY_0,
+                Key::KEY_MINUS,
+                Key::KEY_EQUAL,
+            ],
+        ),
+        (
+            "AD",
+            &[
+                Key::KEY_Q,
+                Key::KEY_W,
+                Key::KEY_E,
+                Key::KEY_R,
+                Key::KEY_T,
+                Key::KEY_Y,
+                Key::KEY_U,
+                Key::KEY_I,
+                Key::KEY_O,
+                Key::KEY_P,
+                Key::KEY_LEFTBRACE,
+                Key::KEY_RIGHTBRACE,
+            ],
+        ),
+        (
+            "AC",
+            &[
+                Key::KEY_A,
+                Key::KEY_S,
+                Key::KEY_D,
+                Key::KEY_F,
+                Key::KEY_G,
+                Key::KEY_H,
+                Key::KEY_J,
+                Key::KEY_K,
+                Key::KEY_L,
+                Key::KEY_SEMICOLON,
+                Key::KEY_APOSTROPHE,
+                Key::KEY_BACKSLASH,
+            ],
+        ),
+        (
+            "AB",
+            &[
+                Key::KEY_Z,
+                Key::KEY_X,
+                Key::KEY_C,
+                Key::KEY_V,
+                Key::KEY_B,
+                Key::KEY_N,
+                Key::KEY_M,
+                Key::KEY_COMMA,
+                Key::KEY_DOT,
+                Key::KEY_SLASH,
+            ],
+        ),
+    ];
+    match name {
+        "TLDE" => return Some(Key::KEY_GRAVE),
+        "BKSL" => return Some(Key::KEY_BACKSLASH),
+        "LSGT" => return Some(Key::KEY_102ND),
+        _ => {}
+    }
+    let (row, column) = name.split_at_checked(2)?;
+    let keys = ROWS.iter().find(|(name, _)| *name == row)?.1;
+    keys.get(column.parse::<usize>().ok()?.checked_sub(1)?).copied()
+}
+
+/// The key that types `symbol` on a US layout, and whether with Shift.
+fn us_key(symbol: &str) -> Option<(Key, bool)> {
+    const SYMBOLS: [(Key, &str, &str); 21] = [
+        (Key::KEY_GRAVE, "grave", "asciitilde"),
+        (Key::KEY_1, "1", "exclam"),
+        (Key::KEY_2, "2", "at"),
+        (Key::KEY_3, "3", "numbersign"),
+        (Key::KEY_4, "4", "dollar"),
+        (Key::KEY_5, "5", "percent"),
+        (Key::KEY_6, "6", "asciicircum"),
+        (Key::KEY_7, "7", "ampersand"),
+        (Key::KEY_8, "8", "asterisk"),
+        (Key::KEY_9, "9", "parenleft"),
+        (Key::KEY_0, "0", "parenright"),
+        (Key::KEY_MINUS, "minus", "underscore"),
+        (Key::KEY_EQUAL, "equal", "plus"),
+        (Key::KEY_LEFTBRACE, "bracketleft", "braceleft"),
+        (Key::KEY_RIGHTBRACE, "bracketright", "braceright"),
+        (Key::KEY_BACKSLASH, "backslash", "bar"),
+        (Key::KEY_SEMICOLON, "semicolon", "colon"),
+        (Key::KEY_APOSTROPHE, "apostrophe", "quotedbl"),
+        (Key::KEY_COMMA, "comma", "less"),
+        (Key::KEY_DOT, "period", "greater"),
+        (Key::KEY_SLASH, "slash", "question"),
+    ];
+    if let Some(&(key, unshifted, _)) =
+        SYMBOLS.iter().find(|(_, unshifted, shifted)| symbol == *unshifted || symbol == *shifted)
+    {
+        return Some((key, symbol != unshifted));
+    }
+    // Letters are named after themselves, upper case with Shift.
+    let mut chars = symbol.chars();
+    match (chars.next(), chars.next()) {
+        (Some(letter), None) if letter.is_ascii_alphabetic() => {
+            Some((crate::remap::parse_key(&letter.to_string())?, letter.is_ascii_uppercase()))
+        }
+        _ => None,
+    }
+}
+
+/// Translates the `variant` block of an XKB symbols file (the one marked `default`, or the first,
+/// if `variant` is `None`). `include` reads another symbols file by name, for `include`
+/// statements.
+pub fn translate_xkb(
+    text: &str,
+    variant: Option<&str>,
+    include: &dyn Fn(&str) -> Option<String>,
+) -> Result<Translation, String> {
+    let mut levels: BTreeMap<Key, Vec<String>> = BTreeMap::new();
+    let mut skipped = Vec::new();
+    apply_block(text, variant, include, 0, &mut levels, &mut skipped)?;
+
+    let mut translation = Translation { skipped, ..Translation::default() };
+    let mut altgr = Vec::new();
+    for (key, symbols) in levels {
+        let place = format!("{} ({})", key_name(key), symbols.join(", "));
+        let typed = |symbol: &String| us_key(symbol).ok_or(format!("{place}: no US key types {symbol}"));
+        match symbols.first().filter(|symbol| *symbol != "NoSymbol").map(typed) {
+            Some(Ok((to, shift))) => {
+                let action = if shift { Action::Shifted(to) } else { Action::Key(to) };
+                translation.remap(Table::Keys, key, action, &place);
+                // Without a [shifted] entry, the key types what it types unshifted, with Shift.
+                match symbols.get(1).filter(|symbol| *symbol != "NoSymbol").map(typed) {
+                    Some(Ok((shifted, true))) if shifted == to => {}
+                    Some(Ok((shifted, true))) => {
+                        translation.remap(Table::Shifted, key, Action::Shifted(shifted), &place)
+                    }
+                    Some(Ok((shifted, false))) => {
+                        translation.remap(Table::Shifted, key, Action::Unshifted(shifted), &place)
+                    }
+                    Some(Err(reason)) => translation.skipped.push(reason),
+                    None => {}
+                }
+            }
+            Some(Err(reason)) => translation.skipped.push(reason),
+            None => {}
+        }
+        if symbols.len() > 2 {
+            altgr.push(key_name(key));
+        }
+    }
+    if !altgr.is_empty() {
+        translation.skipped.push(format!("AltGr levels, left to the desktop, on {}", altgr.join(", ")));
+    }
+    Ok(translation)
+}
+
+/// Applies the statements of one `xkb_symbols` block of `text` to `levels`, includes first.
+fn apply_block(
+    text: &str,
+    variant: Option<&str>,
+    include: &dyn Fn(&str) -> Option<String>,
+    depth: usize,
+    levels: &mut BTreeMap<Key, Vec<String>>,
+    skipped: &mut Vec<String>,
+) -> Result<(), String> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err("includes nest too deep; do they include each other?".to_string());
+    }
+    let blocks = blocks(&strip_comments(text));
+    let body = match variant {
+        Some(variant) => blocks.iter().find(|block| block.name == variant),
+        None => blocks.iter().find(|block| block.default).or(blocks.first()),
+    };
+    let Some(body) = body.map(|block| block.body.clone()) else {
+        let names: Vec<&str> = blocks.iter().map(|block| block.name.as_str()).collect();
+        return Err(format!("no xkb_symbols \"{}\" (variants: {})", variant.unwrap_or("default"), names.join(", ")));
+    };
+
+    for statement in statements(&body) {
+        let statement = statement.trim();
+        if let Some(name) = statement.strip_prefix("include").map(|rest| rest.trim().trim_matches('"')) {
+            // `include "latin(type4)+level3(ralt_switch)"` includes each part in turn.
+            for part in name.split(['+', '|']).filter(|part| !part.is_empty()) {
+                let (file, variant) = match part.split_once('(') {
+                    Some((file, variant)) => (file, Some(variant.trim_end_matches(')'))),
+                    None => (part, None),
+                };
+                match include(file) {
+                    Some(text) => apply_block(&text, variant, include, depth + 1, levels, skipped)?,
+                    None => skipped.push(format!("include \"{part}\": no symbols file called {file}")),
+                }
+            }
+            continue;
+        }
+        let statement = statement.trim_start_matches("replace").trim_start_matches("override").trim();
+        let Some(rest) = statement.strip_prefix("key") else {
+            continue;
+        };
+        let Some((position, definition)) = rest.trim().strip_prefix('<').and_then(|rest| rest.split_once('>')) else {
+            continue;
+        };
+        let Some(key) = position_key(position) else {
+            continue;
+        };
+        if let Some(symbols) = symbols(definition) {
+            levels.insert(key, symbols.split(',').map(|symbol| symbol.trim().to_string()).collect());
+        }
+    }
+    Ok(())
+}
+
+/// The first group's symbols in a key's `{ ... }`: the first `[...]` list, or the one given as
+/// `symbols[Group1] = [...]`, skipping the `[Group1]` of `type[Group1]` and the like.
+fn symbols(definition: &str) -> Option<&str> {
+    let mut rest = definition;
+    loop {
+        let (_, list) = rest.split_once('[')?;
+        let (symbols, after) = list.split_once(']')?;
+        if !symbols.trim_start().starts_with("Group") {
+            return Some(symbols);
+        }
+        rest = after;
+    }
+}
+
+/// One `xkb_symbols "name" { ... };` block.
+struct Block {
+    name: String,
+    default: bool,
+    body: String,
+}
+
+/// The blocks of a symbols file, in order.
+fn blocks(text: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("xkb_symbols") {
+        let default = rest[..start].split_whitespace().any(|flag| flag == "default");
+        let after = &rest[start + "xkb_symbols".len()..];
+        let name = after.split('"').nth(1).unwrap_or_default().to_string();
+        let Some(open) = after.find('{') else {
+            break;
+        };
+        let mut depth = 0;
+        let mut end = after.len();
+        for (index, c) in after[open..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = open + index;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        blocks.push(Block { name, default, body: after[open + 1..end].to_string() });
+        rest = &after[end..];
+        // The flags before the next block start after this one's `};`.
+        rest = rest.find(';').map_or("", |semicolon| &rest[semicolon + 1..]);
+    }
+    blocks
+}
+
+/// The `;`-separated statements of a block, not splitting inside `{ ... }`.
+fn statements(body: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (index, c) in body.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ';' if depth == 0 => {
+                statements.push(&body[start..index]);
+                start = index + 1;
+            }
+            // Includes don't end with a semicolon.
+            '\n' if depth == 0 && body[start..index].trim_start().starts_with("include") => {
+                statements.push(&body[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    statements.push(&body[start..]);
+    statements
+}
+
+/// `text` without `//` comments.
+fn strip_comments(text: &str) -> String {
+    text.lines().map(|line| line.split("//").next().unwrap_or_default()).collect::<Vec<_>>().join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{Dvorak, Layout};
+
+    const SYMBOLS: &str = r#"
+default partial alphanumeric_keys
+xkb_symbols "basic" {
+    name[Group1]= "English (US)";
+    key <AD01> { [ q, Q ] };
+};
+
+partial alphanumeric_keys
+xkb_symbols "dvorak" {
+    include "us(basic)"
+    // The top rows
+    key <AE11> { [ bracketleft, braceleft ] };
+    key <AD01> { [ apostrophe, quotedbl, dead_acute, dead_diaeresis ] };
+    key <AD02> { [ comma, less ] };
+    key <AC02> { type[Group1] = "ALPHABETIC", symbols[Group1] = [ o, O ] };
+    key <AC03> { [ eacute, Eacute ] };
+    key <AB01> { [ semicolon, colon ] };
+};
+"#;
+
+    #[test]
+    fn translates_a_variant_like_the_builtin_layout() {
+        let translation =
+            translate_xkb(SYMBOLS, Some("dvorak"), &|file| (file == "us").then(|| SYMBOLS.to_string())).unwrap();
+        for (key, action) in &translation.keys {
+            assert_eq!(*action, Dvorak.map(*key), "{key:?}");
+        }
+        assert_eq!(translation.keys.len(), 5);
+        assert!(translation.shifted.is_empty());
+        assert_eq!(
+            translation.skipped,
+            [
+                "d (eacute, Eacute): no US key types eacute",
+                "AltGr levels, left to the desktop, on q",
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_the_default_variant_and_shifted_symbols() {
+        let text = "xkb_symbols \"other\" { key <AE01> { [ 1, exclam ] }; };\n\
+                    default xkb_symbols \"main\" { key <AE01> { [ ampersand, 1 ] }; };";
+        let translation = translate_xkb(text, None, &|_| None).unwrap();
+        assert_eq!(translation.keys, BTreeMap::from([(Key::KEY_1, Action::Shifted(Key::KEY_7))]));
+        assert_eq!(translation.shifted, BTreeMap::from([(Key::KEY_1, Action::Unshifted(Key::KEY_1))]));
+        assert!(translate_xkb(text, Some("missing"), &|_| None).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! Chaos scenario for device disconnects while typing.
+//!
+//! Creates several virtual source keyboards, starts the daemon, and then repeatedly types on
+//! them while destroying keyboards mid-keypress and creating new ones. After every round it
+//! checks that:
+//!
+//! - the daemon has no files left open for keyboards that disappeared, nor extra threads,
+//! - the virtual QwertDvert keyboard has no keys stuck down,
+//! - the keyboards that are still attached keep being remapped.
+//!
+//! Needs write access to /dev/uinput and read access to the created event devices (the
+//! udev rule installed by scripts/qwertdvert-manage.sh grants both); if /dev/uinput can't be
+//! written, the test passes without doing anything. The daemon is told to grab only the chaos keyboards, so
+//! real keyboards keep working while it runs. It takes a while, so it is ignored by default:
+//!
+//! ```bash
+//! cargo test --test chaos_hotplug -- --ignored --nocapture
+//! ```
+
+use std::fs::OpenOptions;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, Device, EventType, InputEvent, Key};
+
+const INITIAL_KEYBOARDS: usize = 4;
+const ROUNDS: usize = 20;
+// The daemon is started with --device-name so it grabs only keyboards with this prefix.
+const SOURCE_NAME_PREFIX: &str = "AT Translated Set 2 keyboard (chaos";
+const OUTPUT_DEVICE_NAME: &str = "QwertDvert";
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(5);
+const KEY_DELAY: Duration = Duration::from_millis(5);
+
+fn create_source_keyboard(id: usize) -> VirtualDevice {
+    let mut keys = AttributeSet::<Key>::new();
+    for code in Key::KEY_ESC.code()..=Key::KEY_MICMUTE.code() {
+        keys.insert(Key::new(code));
+    }
+    let name = format!("{SOURCE_NAME_PREFIX} {id})");
+    let builder = VirtualDeviceBuilder::new().and_then(|builder| builder.name(&name).with_keys(&keys));
+    builder.and_then(|builder| builder.build()).expect("failed to create a source keyboard")
+}
+
+fn key(device: &mut VirtualDevice, key: Key, value: i32) {
+    device
+        .emit(&[InputEvent::new(EventType::KEY, key.code(), value)])
+        .expect("failed to emit on source keyboard");
+    std::thread::sleep(KEY_DELAY);
+}
+
+fn tap(device: &mut VirtualDevice, k: Key) {
+    key(device, k, 1);
+    key(device, k, 0);
+}
+
+/// The daemon under test, killed when dropped, so a failed assertion doesn't leave it running.
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+impl Daemon {
+    fn thread_count(&self) -> usize {
+        std::fs::read_dir(format!("/proc/{}/task", self.0.id()))
+            .map(|tasks| tasks.count())
+            .unwrap_or(0)
+    }
+
+    fn fd_count(&self) -> usize {
+        std::fs::read_dir(format!("/proc/{}/fd", self.0.id()))
+            .map(|fds| fds.count())
+            .unwrap_or(0)
+    }
+}
+
+fn find_output_device() -> Option<Device> {
+    evdev::enumerate()
+        .map(|(_, device)| device)
+        .find(|device| device.name() == Some(OUTPUT_DEVICE_NAME))
+}
+
+/// Polls `check` until it returns true or the settle timeout expires; false if it expired.
+fn wait_for(mut check: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+    while Instant::now() < deadline {
+        if check() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+fn pressed_output_keys(output: &Device) -> Vec<Key> {
+    output
+        .get_key_state()
+        .map(|keys| keys.iter().collect())
+        .unwrap_or_default()
+}
+
+/// Holds QWERTY Q on `device` and expects the daemon to press Dvorak ' on the output.
+fn assert_remapping(device: &mut VirtualDevice, output: &Device, round: usize) {
+    key(device, Key::KEY_Q, 1);
+    let pressed = wait_for(|| pressed_output_keys(output).contains(&Key::KEY_APOSTROPHE));
+    key(device, Key::KEY_Q, 0);
+    assert!(pressed, "round {round}: timed out waiting for the remapped key press");
+    assert!(
+        wait_for(|| pressed_output_keys(output).is_empty()),
+        "round {round}: timed out waiting for the remapped key release"
+    );
+}
+
+#[test]
+#[ignore = "creates virtual keyboards through /dev/uinput and takes a while"]
+fn unplugging_keyboards_mid_keypress() {
+    if let Err(e) = OpenOptions::new().write(true).open("/dev/uinput") {
+        eprintln!("skipping the chaos scenario: /dev/uinput is not writable: {e}");
+        return;
+    }
+    let mut next_id = 0;
+    let mut new_keyboard = || {
+        next_id += 1;
+        create_source_keyboard(next_id)
+    };
+    // Keyboards present when the daemon starts, and ones attached while it runs.
+    let mut originals: Vec<VirtualDevice> = (0..INITIAL_KEYBOARDS).map(|_| new_keyboard()).collect();
+    let mut late = Vec::new();
+    // Give udev a moment to apply uaccess ACLs to the new event nodes.
+    std::thread::sleep(Duration::from_millis(500));
+
+    let daemon = Command::new(env!("CARGO_BIN_EXE_qwertdvert"))
+        .args(["--device-name", SOURCE_NAME_PREFIX])
+        .env("RUST_LOG", "warn")
+        .spawn()
+        .expect("failed to start the daemon");
+    let mut daemon = Daemon(daemon);
+    let mut output = None;
+    assert!(
+        wait_for(|| {
+            output = find_output_device();
+            output.is_some()
+        }),
+        "timed out waiting for the QwertDvert output device"
+    );
+    let output = output.unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+
+    // Every grabbed keyboard has exactly one open file; all of them are read on one thread.
+    let baseline_fds = daemon.fd_count();
+    let baseline_threads = daemon.thread_count();
+    println!("daemon up with {baseline_fds} open files for {INITIAL_KEYBOARDS} chaos keyboards");
+
+    for round in 0..ROUNDS {
+        for keyboard in originals.iter_mut().chain(late.iter_mut()) {
+            tap(keyboard, Key::KEY_J);
+        }
+
+        // Pull out a keyboard with a key held down, always keeping one original.
+        if originals.len() > 1 {
+            let victim = round % originals.len();
+            key(&mut originals[victim], Key::KEY_J, 1);
+            drop(originals.remove(victim));
+        }
+        if round % 2 == 1 && !late.is_empty() {
+            key(&mut late[0], Key::KEY_K, 1);
+            drop(late.remove(0));
+        }
+        // Keyboards attached at runtime may or may not have been grabbed yet; either way they
+        // must not disturb the keyboards already grabbed.
+        if round % 3 == 0 {
+            late.push(new_keyboard());
+        }
+
+        // Upper bound: each late keyboard may or may not have been picked up.
+        let removed = INITIAL_KEYBOARDS - originals.len();
+        let max_fds = baseline_fds - removed + late.len();
+        assert!(
+            wait_for(|| daemon.fd_count() <= max_fds),
+            "round {round}: keyboard files were not closed (open files: {})",
+            daemon.fd_count()
+        );
+        assert!(
+            daemon.thread_count() <= baseline_threads,
+            "round {round}: the daemon started threads for new keyboards"
+        );
+        assert!(
+            wait_for(|| pressed_output_keys(&output).is_empty()),
+            "round {round}: keys stuck down: {:?}",
+            pressed_output_keys(&output)
+        );
+
+        assert_remapping(&mut originals[0], &output, round);
+        println!("round {round}: ok ({} original and {} late keyboards attached)", originals.len(), late.len());
+
+        if let Ok(Some(status)) = daemon.0.try_wait() {
+            panic!("daemon exited unexpectedly: {status}");
+        }
+    }
+}